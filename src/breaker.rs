@@ -0,0 +1,198 @@
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+// Externally visible state of a circuit breaker
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    // Requests flow normally
+    Closed,
+    // Requests fail immediately until the cooldown has passed
+    Open,
+    // The cooldown has passed and a single probe request is testing recovery
+    HalfOpen,
+}
+
+#[derive(Debug)]
+enum Inner {
+    Closed { consecutive_failures: u32 },
+    Open { until: Instant },
+    HalfOpen { probe_started: Instant },
+}
+
+// Stops calling a failing upstream after `failure_threshold` consecutive failures, failing
+// fast for `cooldown` before letting one probe request through to test recovery.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            cooldown,
+            inner: Mutex::new(Inner::Closed {
+                consecutive_failures: 0,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        match *self.inner.lock().unwrap() {
+            Inner::Closed { .. } => CircuitState::Closed,
+            Inner::Open { .. } => CircuitState::Open,
+            Inner::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    // Whether a request may be attempted now. After the cooldown the first caller becomes
+    // the probe; everyone else keeps failing fast until the probe reports back.
+    pub fn try_acquire(&self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    // Records the outcome of an attempted request: `healthy` when the upstream answered,
+    // even if it rejected the request, and false when it failed or timed out.
+    pub fn record(&self, healthy: bool) {
+        self.record_at(healthy, Instant::now())
+    }
+
+    fn try_acquire_at(&self, now: Instant) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match *inner {
+            Inner::Closed { .. } => true,
+            Inner::Open { until } if now >= until => {
+                info!("Circuit breaker half-open, sending a probe request");
+                *inner = Inner::HalfOpen { probe_started: now };
+                true
+            }
+            Inner::Open { .. } => false,
+            // A probe that never reported back (e.g. its task was cancelled) is replaced
+            Inner::HalfOpen { probe_started } if now >= probe_started + self.cooldown => {
+                *inner = Inner::HalfOpen { probe_started: now };
+                true
+            }
+            Inner::HalfOpen { .. } => false,
+        }
+    }
+
+    fn record_at(&self, healthy: bool, now: Instant) {
+        let mut inner = self.inner.lock().unwrap();
+        match (&*inner, healthy) {
+            (Inner::Closed { .. }, true) => {
+                *inner = Inner::Closed {
+                    consecutive_failures: 0,
+                }
+            }
+            (Inner::Open { .. } | Inner::HalfOpen { .. }, true) => {
+                info!("Circuit breaker closed, upstream recovered");
+                *inner = Inner::Closed {
+                    consecutive_failures: 0,
+                };
+            }
+            (
+                Inner::Closed {
+                    consecutive_failures,
+                },
+                false,
+            ) => {
+                let consecutive_failures = consecutive_failures + 1;
+                if consecutive_failures >= self.failure_threshold {
+                    warn!(
+                        consecutive_failures,
+                        cooldown_secs = self.cooldown.as_secs(),
+                        "Circuit breaker opened after repeated failures"
+                    );
+                    *inner = Inner::Open {
+                        until: now + self.cooldown,
+                    };
+                } else {
+                    *inner = Inner::Closed {
+                        consecutive_failures,
+                    };
+                }
+            }
+            (Inner::HalfOpen { .. }, false) => {
+                warn!("Circuit breaker probe failed, reopening");
+                *inner = Inner::Open {
+                    until: now + self.cooldown,
+                };
+            }
+            // A request started before the circuit opened; the cooldown already applies
+            (Inner::Open { .. }, false) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn test_opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(3, COOLDOWN);
+        let now = Instant::now();
+        for _ in 0..2 {
+            assert!(breaker.try_acquire_at(now));
+            breaker.record_at(false, now);
+        }
+        // A success resets the count
+        breaker.record_at(true, now);
+        for _ in 0..2 {
+            breaker.record_at(false, now);
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        breaker.record_at(false, now);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.try_acquire_at(now + COOLDOWN / 2));
+    }
+
+    #[test]
+    fn test_half_open_probe_closes_on_success() {
+        let breaker = CircuitBreaker::new(1, COOLDOWN);
+        let now = Instant::now();
+        breaker.record_at(false, now);
+
+        let later = now + COOLDOWN;
+        assert!(breaker.try_acquire_at(later));
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        // Only one probe at a time
+        assert!(!breaker.try_acquire_at(later));
+
+        breaker.record_at(true, later);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.try_acquire_at(later));
+    }
+
+    #[test]
+    fn test_half_open_probe_reopens_on_failure() {
+        let breaker = CircuitBreaker::new(1, COOLDOWN);
+        let now = Instant::now();
+        breaker.record_at(false, now);
+
+        let later = now + COOLDOWN;
+        assert!(breaker.try_acquire_at(later));
+        breaker.record_at(false, later);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.try_acquire_at(later + COOLDOWN / 2));
+        assert!(breaker.try_acquire_at(later + COOLDOWN));
+    }
+
+    #[test]
+    fn test_abandoned_probe_is_replaced() {
+        let breaker = CircuitBreaker::new(1, COOLDOWN);
+        let now = Instant::now();
+        breaker.record_at(false, now);
+        assert!(breaker.try_acquire_at(now + COOLDOWN));
+        assert!(breaker.try_acquire_at(now + COOLDOWN * 2));
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Remembers recently relayed messages, shared by all connections, so a client that sends the
+// same message again within the window is answered without relaying it a second time.
+// Messages are identified by Message-ID together with their recipients: a client may split
+// one message's recipients across several transactions, and each of those must still go out.
+#[derive(Debug)]
+pub struct DedupCache {
+    window: Duration,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    relayed_at: HashMap<String, Instant>,
+    // Keys in the order they were recorded, so expired ones are dropped from the front
+    order: VecDeque<(Instant, String)>,
+}
+
+impl DedupCache {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    // The cache key for a message, independent of recipient order and case
+    pub fn key(message_id: &str, recipients: &[String]) -> String {
+        let mut recipients: Vec<String> = recipients
+            .iter()
+            .map(|recipient| recipient.to_ascii_lowercase())
+            .collect();
+        recipients.sort();
+        recipients.dedup();
+        format!("{message_id}\n{}", recipients.join("\n"))
+    }
+
+    // Claims a message for relaying, or returns None if it was relayed within the window or
+    // is being relayed now. Checking and claiming under one lock means two connections
+    // sending the same message at once can't both relay it.
+    pub fn try_claim(&self, key: String) -> Option<Claim<'_>> {
+        self.try_claim_at(key, Instant::now())
+    }
+
+    fn try_claim_at(&self, key: String, now: Instant) -> Option<Claim<'_>> {
+        let mut entries = self.entries.lock().unwrap();
+        self.expire(&mut entries, now);
+        if entries.relayed_at.contains_key(&key) {
+            return None;
+        }
+        entries.relayed_at.insert(key.clone(), now);
+        entries.order.push_back((now, key.clone()));
+        Some(Claim {
+            cache: self,
+            key,
+            claimed_at: now,
+            kept: false,
+        })
+    }
+
+    fn expire(&self, entries: &mut Entries, now: Instant) {
+        while let Some((recorded, _)) = entries.order.front() {
+            if now.saturating_duration_since(*recorded) < self.window {
+                break;
+            }
+            let (recorded, key) = entries.order.pop_front().unwrap();
+            // The key may have been recorded again since; only its latest entry counts
+            if entries.relayed_at.get(&key) == Some(&recorded) {
+                entries.relayed_at.remove(&key);
+            }
+        }
+    }
+}
+
+// A message being relayed. Unless kept once the message is relayed (or queued), the claim
+// is released when dropped, so a failed message's retry is relayed.
+#[derive(Debug)]
+pub struct Claim<'a> {
+    cache: &'a DedupCache,
+    key: String,
+    claimed_at: Instant,
+    kept: bool,
+}
+
+impl Claim<'_> {
+    pub fn keep(mut self) {
+        self.kept = true;
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        if self.kept {
+            return;
+        }
+        let mut entries = self.cache.entries.lock().unwrap();
+        // Past the window the key may have been claimed again by another relay
+        if entries.relayed_at.get(&self.key) == Some(&self.claimed_at) {
+            entries.relayed_at.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicates_are_remembered_for_the_window() {
+        let cache = DedupCache::new(Duration::from_secs(60));
+        let now = Instant::now();
+        let key = DedupCache::key(
+            "<1@example.com>",
+            &["b@example.com".to_string(), "A@example.com".to_string()],
+        );
+        cache.try_claim_at(key.clone(), now).unwrap().keep();
+
+        // Same message, recipients in another order and case
+        let resent = DedupCache::key(
+            "<1@example.com>",
+            &["a@example.com".to_string(), "b@example.com".to_string()],
+        );
+        assert!(cache
+            .try_claim_at(resent, now + Duration::from_secs(59))
+            .is_none());
+        // Another share of the recipients is a different delivery
+        let other = DedupCache::key("<1@example.com>", &["c@example.com".to_string()]);
+        assert!(cache
+            .try_claim_at(other, now + Duration::from_secs(59))
+            .is_some());
+
+        assert!(cache
+            .try_claim_at(key, now + Duration::from_secs(60))
+            .is_some());
+        assert!(cache.entries.lock().unwrap().relayed_at.is_empty());
+    }
+
+    #[test]
+    fn test_released_claim_can_be_taken_again() {
+        let cache = DedupCache::new(Duration::from_secs(60));
+        let key = DedupCache::key("<1@example.com>", &["a@example.com".to_string()]);
+        let claim = cache.try_claim(key.clone()).unwrap();
+        // A second connection sending the same message while the first relays it
+        assert!(cache.try_claim(key.clone()).is_none());
+        // The first relay failed
+        drop(claim);
+        cache.try_claim(key.clone()).unwrap().keep();
+        assert!(cache.try_claim(key).is_none());
+    }
+}
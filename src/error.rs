@@ -23,27 +23,67 @@ pub enum ConfigError {
     InvalidSenderAddress(String),
     InvalidDomain(String),
     InvalidPort(u16),
+    InvalidBindAddress(String),
+    InvalidTlsConfig(String),
+    InvalidDkimConfig(String),
+    InvalidHttpClientConfig(String),
+    InvalidSmtpUpstream(String),
+    InvalidContentType(String),
+    InvalidSendPath(String),
+    InvalidHeaderName(String),
+    InvalidEndpointUrl(url::ParseError),
+    InsecureEndpoint(String),
+    InvalidSelftestRecipient(String),
+    InvalidSenderDomainCheck(String),
+    // The sender's domain is not among those verified on the Email Communication Services resource
+    UnverifiedSenderDomain(String),
+    EndpointCloudMismatch(String, &'static str), // endpoint, the selected cloud's ACS domain
 }
 
 #[derive(Debug)]
 pub enum SmtpError {
     InvalidCommand(String),
+    InvalidArguments(String),
     InvalidSequence(String),
     MessageTooLarge(usize, usize), // actual, max
     InvalidAddress(String),
+    // RCPT TO address that is not syntactically an email address
+    InvalidRecipient(String),
+    // Non-ASCII address given without the SMTPUTF8 parameter
+    SmtpUtf8Required(String),
+    // A command other than EHLO/HELO/QUIT/NOOP opened the session
+    MissingGreeting,
     MissingFrom,
     NoRecipients,
+    TooManyRecipients(usize), // max
+    // The recipient's domain is only open to authenticated clients
+    RelayDenied(String),
+    // The global message rate limit has been reached
+    RateLimited,
+    // The sender has used up its quota for the current window
+    QuotaExceeded,
+    // New transactions are paused by an operator
+    NotAcceptingMail,
+    // The message was not relayed within the configured max age
+    MessageExpired,
+    // The client sent data before the greeting banner
+    EarlyTalker,
     DataCorrupted,
+    UpstreamRejected(String),
 }
 
 #[derive(Debug)]
 pub enum AcsError {
-    ApiRequest(String),
+    // The request could not be completed; the cause is available via `source()`
+    ApiRequest(Box<dyn std::error::Error + Send + Sync>),
+    BadRequest(String),
     AuthenticationFailed,
     Unauthorized,
     RateLimited,
     ServiceUnavailable,
-    InvalidResponse(String),
+    // Not attempted: the circuit breaker is open after repeated failures
+    CircuitOpen,
+    InvalidResponse(Box<dyn std::error::Error + Send + Sync>),
 }
 
 #[derive(Debug)]
@@ -53,6 +93,12 @@ pub enum EmailError {
     MissingContent,
     InvalidEncoding(String),
     UnsupportedContentType(String),
+    // HTML body contains scripts or similar, rejected by the HTML policy
+    ActiveContent,
+    TooManyHeaders(usize),        // max
+    HeaderSectionTooLarge(usize), // max bytes
+    Serialization(serde_json::Error),
+    SigningFailed(String),
 }
 
 #[derive(Debug)]
@@ -61,6 +107,10 @@ pub enum NetworkError {
     Timeout,
     DnsResolution(String),
     TlsHandshake(String),
+    Http(reqwest::Error),
+    Io(std::io::Error),
+    // Delivery to an upstream SMTP server failed; the cause is available via `source()`
+    Upstream(Box<dyn std::error::Error + Send + Sync>),
 }
 
 impl fmt::Display for SmtpRelayError {
@@ -84,6 +134,42 @@ impl fmt::Display for ConfigError {
             ConfigError::InvalidSenderAddress(addr) => write!(f, "Invalid sender address: {addr}"),
             ConfigError::InvalidDomain(domain) => write!(f, "Invalid domain: {domain}"),
             ConfigError::InvalidPort(port) => write!(f, "Invalid port: {port}"),
+            ConfigError::InvalidBindAddress(addr) => write!(f, "Invalid bind address: {addr}"),
+            ConfigError::InvalidTlsConfig(msg) => write!(f, "Invalid TLS configuration: {msg}"),
+            ConfigError::InvalidDkimConfig(msg) => write!(f, "Invalid DKIM configuration: {msg}"),
+            ConfigError::InvalidSmtpUpstream(msg) => {
+                write!(f, "Invalid upstream SMTP configuration: {msg}")
+            }
+            ConfigError::InvalidContentType(ct) => {
+                write!(f, "Invalid content type (expected type/subtype): {ct}")
+            }
+            ConfigError::InvalidSendPath(path) => {
+                write!(
+                    f,
+                    "Invalid ACS send path (expected an absolute path): {path}"
+                )
+            }
+            ConfigError::InvalidHeaderName(name) => write!(f, "Invalid header name: {name}"),
+            ConfigError::InvalidHttpClientConfig(msg) => {
+                write!(f, "Invalid HTTP client configuration: {msg}")
+            }
+            ConfigError::InvalidEndpointUrl(_) => write!(f, "Invalid endpoint URL"),
+            ConfigError::InsecureEndpoint(endpoint) => {
+                write!(f, "ACS endpoint must use https: {endpoint}")
+            }
+            ConfigError::EndpointCloudMismatch(endpoint, domain) => write!(
+                f,
+                "ACS endpoint {endpoint} is not in the selected cloud (expected a host under {domain})"
+            ),
+            ConfigError::InvalidSelftestRecipient(addr) => {
+                write!(f, "Invalid self-test recipient: {addr}")
+            }
+            ConfigError::InvalidSenderDomainCheck(msg) => {
+                write!(f, "Invalid sender domain check: {msg}")
+            }
+            ConfigError::UnverifiedSenderDomain(domain) => {
+                write!(f, "Sender domain is not verified in ACS: {domain}")
+            }
         }
     }
 }
@@ -92,14 +178,32 @@ impl fmt::Display for SmtpError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             SmtpError::InvalidCommand(cmd) => write!(f, "Invalid SMTP command: {cmd}"),
+            SmtpError::InvalidArguments(cmd) => write!(f, "Invalid command arguments: {cmd}"),
             SmtpError::InvalidSequence(seq) => write!(f, "Invalid command sequence: {seq}"),
             SmtpError::MessageTooLarge(actual, max) => {
                 write!(f, "Message too large: {actual} bytes (max: {max})")
             }
             SmtpError::InvalidAddress(addr) => write!(f, "Invalid email address: {addr}"),
+            SmtpError::InvalidRecipient(addr) => write!(f, "Invalid recipient address: {addr}"),
+            SmtpError::SmtpUtf8Required(addr) => {
+                write!(f, "Non-ASCII address without SMTPUTF8: {addr}")
+            }
+            SmtpError::MissingGreeting => write!(f, "Missing EHLO/HELO command"),
             SmtpError::MissingFrom => write!(f, "Missing MAIL FROM command"),
             SmtpError::NoRecipients => write!(f, "No recipients specified"),
+            SmtpError::TooManyRecipients(max) => write!(f, "Too many recipients (max: {max})"),
+            SmtpError::RelayDenied(addr) => {
+                write!(f, "Relaying requires authentication: {addr}")
+            }
+            SmtpError::RateLimited => write!(f, "Message rate limit exceeded"),
+            SmtpError::QuotaExceeded => write!(f, "Sender quota exceeded"),
+            SmtpError::NotAcceptingMail => write!(f, "Not accepting new mail while paused"),
+            SmtpError::MessageExpired => write!(f, "Message expired before it could be relayed"),
+            SmtpError::EarlyTalker => write!(f, "Client sent data before the greeting"),
             SmtpError::DataCorrupted => write!(f, "DATA section corrupted"),
+            SmtpError::UpstreamRejected(msg) => {
+                write!(f, "Upstream server rejected message: {msg}")
+            }
         }
     }
 }
@@ -111,8 +215,10 @@ impl fmt::Display for AcsError {
             AcsError::Unauthorized => write!(f, "Unauthorized (403)"),
             AcsError::RateLimited => write!(f, "Rate limited (429)"),
             AcsError::ServiceUnavailable => write!(f, "Service unavailable (5xx)"),
-            AcsError::ApiRequest(msg) => write!(f, "API request failed: {msg}"),
-            AcsError::InvalidResponse(resp) => write!(f, "Invalid response from ACS: {resp}"),
+            AcsError::CircuitOpen => write!(f, "Circuit breaker open, request not attempted"),
+            AcsError::ApiRequest(_) => write!(f, "API request failed"),
+            AcsError::BadRequest(msg) => write!(f, "Request rejected by ACS: {msg}"),
+            AcsError::InvalidResponse(_) => write!(f, "Invalid response from ACS"),
         }
     }
 }
@@ -125,6 +231,13 @@ impl fmt::Display for EmailError {
             EmailError::MissingContent => write!(f, "Missing content in email"),
             EmailError::InvalidEncoding(enc) => write!(f, "Invalid encoding: {enc}"),
             EmailError::UnsupportedContentType(ct) => write!(f, "Unsupported content type: {ct}"),
+            EmailError::ActiveContent => write!(f, "HTML body contains active content"),
+            EmailError::TooManyHeaders(max) => write!(f, "Too many header fields (max: {max})"),
+            EmailError::HeaderSectionTooLarge(max) => {
+                write!(f, "Header section too large (max: {max} bytes)")
+            }
+            EmailError::Serialization(_) => write!(f, "Failed to serialize JSON"),
+            EmailError::SigningFailed(msg) => write!(f, "Failed to DKIM-sign message: {msg}"),
         }
     }
 }
@@ -136,16 +249,74 @@ impl fmt::Display for NetworkError {
             NetworkError::Timeout => write!(f, "Network timeout"),
             NetworkError::DnsResolution(host) => write!(f, "DNS resolution failed for: {host}"),
             NetworkError::TlsHandshake(msg) => write!(f, "TLS handshake failed: {msg}"),
+            NetworkError::Http(e) if e.is_timeout() => write!(f, "HTTP request timed out"),
+            NetworkError::Http(_) => write!(f, "HTTP request failed"),
+            NetworkError::Io(_) => write!(f, "I/O error"),
+            NetworkError::Upstream(_) => write!(f, "Upstream SMTP delivery failed"),
         }
     }
 }
 
-impl std::error::Error for SmtpRelayError {}
-impl std::error::Error for ConfigError {}
+// SmtpRelayError's Display already includes the wrapped error's message, so the chain
+// continues from that error's own cause rather than repeating it.
+impl std::error::Error for SmtpRelayError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SmtpRelayError::Config(e) => e.source(),
+            SmtpRelayError::Smtp(e) => e.source(),
+            SmtpRelayError::Acs(e) => e.source(),
+            SmtpRelayError::Email(e) => e.source(),
+            SmtpRelayError::Network(e) => e.source(),
+        }
+    }
+}
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::InvalidEndpointUrl(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 impl std::error::Error for SmtpError {}
-impl std::error::Error for AcsError {}
-impl std::error::Error for EmailError {}
-impl std::error::Error for NetworkError {}
+impl std::error::Error for AcsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AcsError::ApiRequest(e) | AcsError::InvalidResponse(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+impl std::error::Error for EmailError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EmailError::Serialization(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl std::error::Error for NetworkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NetworkError::Http(e) => Some(e),
+            NetworkError::Io(e) => Some(e),
+            NetworkError::Upstream(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+// Renders an error followed by each cause in its source chain, separated by ": "
+pub fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut rendered = err.to_string();
+    let mut source = err.source();
+    while let Some(cause) = source {
+        rendered.push_str(": ");
+        rendered.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    rendered
+}
 
 // Convenient conversion from anyhow::Error
 impl From<anyhow::Error> for SmtpRelayError {
@@ -154,6 +325,31 @@ impl From<anyhow::Error> for SmtpRelayError {
     }
 }
 
+// Transport failures talking to ACS are network errors, retryable like any other outage
+impl From<reqwest::Error> for SmtpRelayError {
+    fn from(err: reqwest::Error) -> Self {
+        SmtpRelayError::Network(NetworkError::Http(err))
+    }
+}
+
+impl From<std::io::Error> for SmtpRelayError {
+    fn from(err: std::io::Error) -> Self {
+        SmtpRelayError::Network(NetworkError::Io(err))
+    }
+}
+
+impl From<serde_json::Error> for SmtpRelayError {
+    fn from(err: serde_json::Error) -> Self {
+        SmtpRelayError::Email(EmailError::Serialization(err))
+    }
+}
+
+impl From<url::ParseError> for SmtpRelayError {
+    fn from(err: url::ParseError) -> Self {
+        SmtpRelayError::Config(ConfigError::InvalidEndpointUrl(err))
+    }
+}
+
 // HTTP status code mapping for ACS errors
 impl AcsError {
     pub fn from_status_code(status: u16, body: &str) -> Self {
@@ -162,7 +358,402 @@ impl AcsError {
             403 => AcsError::Unauthorized,
             429 => AcsError::RateLimited,
             502..=504 => AcsError::ServiceUnavailable,
-            _ => AcsError::ApiRequest(format!("HTTP {status}: {body}")),
+            400..=499 => AcsError::BadRequest(format!("HTTP {status}: {body}")),
+            _ => AcsError::ApiRequest(format!("HTTP {status}: {body}").into()),
+        }
+    }
+
+    // Whether ACS rejected the request in a way that retrying the same message won't fix
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            AcsError::BadRequest(_) | AcsError::AuthenticationFailed | AcsError::Unauthorized
+        )
+    }
+}
+
+// An SMTP reply: basic code, RFC 3463 enhanced status code and text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SmtpReply {
+    pub code: u16,
+    pub enhanced: &'static str,
+    pub text: &'static str,
+}
+
+impl SmtpReply {
+    const fn new(code: u16, enhanced: &'static str, text: &'static str) -> Self {
+        Self {
+            code,
+            enhanced,
+            text,
+        }
+    }
+}
+
+impl SmtpError {
+    // The reply sent to the client when a command fails with this error
+    pub fn reply(&self) -> SmtpReply {
+        match self {
+            SmtpError::InvalidCommand(_) => SmtpReply::new(500, "5.5.1", "Command not recognized"),
+            SmtpError::InvalidArguments(_) => {
+                SmtpReply::new(501, "5.5.4", "Syntax error in parameters or arguments")
+            }
+            SmtpError::InvalidAddress(_) => SmtpReply::new(501, "5.1.3", "Bad address syntax"),
+            SmtpError::InvalidRecipient(_) => {
+                SmtpReply::new(501, "5.1.3", "Bad recipient address syntax")
+            }
+            SmtpError::InvalidSequence(_) => {
+                SmtpReply::new(503, "5.5.1", "Bad sequence of commands")
+            }
+            SmtpError::MissingGreeting => SmtpReply::new(503, "5.5.1", "Send HELO/EHLO first"),
+            SmtpError::MissingFrom => SmtpReply::new(503, "5.5.1", "Need MAIL command"),
+            SmtpError::NoRecipients => SmtpReply::new(503, "5.5.1", "Need RCPT command"),
+            SmtpError::TooManyRecipients(_) => SmtpReply::new(452, "4.5.3", "Too many recipients"),
+            SmtpError::RelayDenied(_) => {
+                SmtpReply::new(550, "5.7.1", "Relaying denied, authentication required")
+            }
+            SmtpError::RateLimited => SmtpReply::new(452, "4.3.2", "Try again later"),
+            SmtpError::QuotaExceeded => SmtpReply::new(452, "4.7.0", "Quota exceeded"),
+            SmtpError::NotAcceptingMail => {
+                SmtpReply::new(421, "4.3.2", "Service not accepting mail")
+            }
+            SmtpError::MessageExpired => SmtpReply::new(451, "4.4.7", "Message expired"),
+            SmtpError::MessageTooLarge(..) => SmtpReply::new(
+                552,
+                "5.3.4",
+                "Message size exceeds fixed maximum message size",
+            ),
+            SmtpError::SmtpUtf8Required(_) => {
+                SmtpReply::new(553, "5.6.7", "Non-ASCII address requires SMTPUTF8")
+            }
+            SmtpError::DataCorrupted => SmtpReply::new(554, "5.6.0", "Message data corrupted"),
+            SmtpError::EarlyTalker => {
+                SmtpReply::new(554, "5.5.0", "Protocol error: data sent before greeting")
+            }
+            SmtpError::UpstreamRejected(_) => {
+                SmtpReply::new(554, "5.0.0", "Message rejected by upstream server")
+            }
+        }
+    }
+}
+
+impl EmailError {
+    // The reply sent to the client when a received message fails with this error
+    pub fn reply(&self) -> SmtpReply {
+        match self {
+            EmailError::ParseFailed(_) => {
+                SmtpReply::new(554, "5.6.0", "Message could not be parsed")
+            }
+            EmailError::MissingSubject => SmtpReply::new(554, "5.6.0", "Message has no subject"),
+            EmailError::MissingContent => SmtpReply::new(554, "5.6.0", "Message has no content"),
+            EmailError::InvalidEncoding(_) => {
+                SmtpReply::new(554, "5.6.0", "Invalid content transfer encoding")
+            }
+            EmailError::UnsupportedContentType(_) => {
+                SmtpReply::new(554, "5.6.1", "Unsupported content type")
+            }
+            EmailError::ActiveContent => {
+                SmtpReply::new(554, "5.7.1", "Active HTML content is not accepted")
+            }
+            EmailError::TooManyHeaders(_) => SmtpReply::new(552, "5.3.4", "Too many headers"),
+            EmailError::HeaderSectionTooLarge(_) => {
+                SmtpReply::new(552, "5.3.4", "Headers too large")
+            }
+            EmailError::Serialization(_) | EmailError::SigningFailed(_) => {
+                SmtpReply::new(554, "5.3.0", "Message could not be processed")
+            }
+        }
+    }
+}
+
+impl SmtpRelayError {
+    // The reply for a failed relay. Permanent failures get a 5xx reply, so the client stops
+    // retrying a message that has been dead-lettered; anything else is reported as a
+    // temporary relay failure.
+    pub fn reply(&self) -> SmtpReply {
+        match self {
+            SmtpRelayError::Smtp(e) => e.reply(),
+            SmtpRelayError::Email(e) => e.reply(),
+            SmtpRelayError::Config(_) => {
+                SmtpReply::new(554, "5.3.5", "Relay is not configured correctly")
+            }
+            SmtpRelayError::Acs(AcsError::BadRequest(_)) => SmtpReply::new(
+                550,
+                "5.6.0",
+                "Message rejected by Azure Communication Services",
+            ),
+            SmtpRelayError::Acs(AcsError::AuthenticationFailed | AcsError::Unauthorized) => {
+                SmtpReply::new(
+                    554,
+                    "5.7.1",
+                    "Relay not authorized by Azure Communication Services",
+                )
+            }
+            SmtpRelayError::Acs(AcsError::CircuitOpen) => {
+                SmtpReply::new(451, "4.3.0", "ACS temporarily unavailable")
+            }
+            _ => SmtpReply::new(
+                451,
+                "4.3.0",
+                "Failed to relay email to Azure Communication Services",
+            ),
+        }
+    }
+
+    // Whether the failure is permanent, i.e. relaying the same message again cannot succeed
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            SmtpRelayError::Acs(e) => e.is_permanent(),
+            SmtpRelayError::Smtp(e) => e.reply().code >= 500,
+            SmtpRelayError::Email(e) => e.reply().code >= 500,
+            SmtpRelayError::Config(_) => true,
+            SmtpRelayError::Network(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn test_source_chain_reaches_underlying_cause() {
+        let io_error = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "peer reset");
+        let err = SmtpRelayError::Acs(AcsError::ApiRequest(Box::new(io_error)));
+
+        // The relay error's own message covers the ACS layer; its source is the root cause
+        let cause = err.source().expect("api request errors carry a source");
+        assert_eq!(cause.to_string(), "peer reset");
+        assert!(cause.downcast_ref::<std::io::Error>().is_some());
+        assert!(cause.source().is_none());
+
+        assert_eq!(
+            error_chain(&err),
+            "Azure Communication Services error: API request failed: peer reset"
+        );
+        let anyhow_err: anyhow::Error = err.into();
+        assert_eq!(anyhow_err.chain().count(), 2);
+    }
+
+    #[test]
+    fn test_unexpected_status_keeps_body_in_chain() {
+        let err = AcsError::from_status_code(500, "boom");
+        assert_eq!(error_chain(&err), "API request failed: HTTP 500: boom");
+        assert!(SmtpRelayError::Acs(AcsError::RateLimited)
+            .source()
+            .is_none());
+    }
+
+    #[test]
+    fn test_from_reqwest_error_is_network() {
+        let reqwest_error = reqwest::Client::new().get("http://").build().unwrap_err();
+        let err = SmtpRelayError::from(reqwest_error);
+        assert!(matches!(
+            err,
+            SmtpRelayError::Network(NetworkError::Http(_))
+        ));
+        assert!(!err.is_permanent());
+        assert!(err.source().is_some());
+    }
+
+    #[test]
+    fn test_from_serde_json_error_is_email() {
+        let json_error = serde_json::from_str::<u8>("not json").unwrap_err();
+        let err = SmtpRelayError::from(json_error);
+        assert!(matches!(
+            err,
+            SmtpRelayError::Email(EmailError::Serialization(_))
+        ));
+        assert!(err.source().is_some());
+    }
+
+    #[test]
+    fn test_from_url_parse_error_is_config() {
+        let url_error = url::Url::parse("not a url").unwrap_err();
+        let err = SmtpRelayError::from(url_error);
+        assert!(matches!(
+            err,
+            SmtpRelayError::Config(ConfigError::InvalidEndpointUrl(_))
+        ));
+        assert_eq!(
+            error_chain(&err),
+            "Configuration error: Invalid endpoint URL: relative URL without a base"
+        );
+    }
+
+    #[test]
+    fn test_smtp_error_replies() {
+        let cases = [
+            (SmtpError::InvalidCommand("FROB".into()), 500, "5.5.1"),
+            (
+                SmtpError::InvalidArguments("MAIL FROM <x>".into()),
+                501,
+                "5.5.4",
+            ),
+            (SmtpError::InvalidAddress("x@".into()), 501, "5.1.3"),
+            (
+                SmtpError::InvalidRecipient("not an email".into()),
+                501,
+                "5.1.3",
+            ),
+            (
+                SmtpError::InvalidSequence("DATA after BDAT".into()),
+                503,
+                "5.5.1",
+            ),
+            (SmtpError::MissingGreeting, 503, "5.5.1"),
+            (SmtpError::MissingFrom, 503, "5.5.1"),
+            (SmtpError::NoRecipients, 503, "5.5.1"),
+            (SmtpError::TooManyRecipients(100), 452, "4.5.3"),
+            (SmtpError::RateLimited, 452, "4.3.2"),
+            (SmtpError::QuotaExceeded, 452, "4.7.0"),
+            (SmtpError::NotAcceptingMail, 421, "4.3.2"),
+            (SmtpError::MessageExpired, 451, "4.4.7"),
+            (SmtpError::MessageTooLarge(2048, 1024), 552, "5.3.4"),
+            (
+                SmtpError::SmtpUtf8Required("josé@example.com".into()),
+                553,
+                "5.6.7",
+            ),
+            (SmtpError::DataCorrupted, 554, "5.6.0"),
+            (SmtpError::EarlyTalker, 554, "5.5.0"),
+            (
+                SmtpError::RelayDenied("to@external.example".into()),
+                550,
+                "5.7.1",
+            ),
+            (
+                SmtpError::UpstreamRejected("550 no such user".into()),
+                554,
+                "5.0.0",
+            ),
+        ];
+        for (err, code, enhanced) in cases {
+            let reply = err.reply();
+            assert_eq!((reply.code, reply.enhanced), (code, enhanced), "{err}");
+            assert!(!reply.text.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_email_error_replies() {
+        let json_error = serde_json::from_str::<u8>("not json").unwrap_err();
+        let cases = [
+            (EmailError::ParseFailed("bad".into()), 554, "5.6.0"),
+            (EmailError::MissingSubject, 554, "5.6.0"),
+            (EmailError::MissingContent, 554, "5.6.0"),
+            (EmailError::InvalidEncoding("base64".into()), 554, "5.6.0"),
+            (
+                EmailError::UnsupportedContentType("image/png".into()),
+                554,
+                "5.6.1",
+            ),
+            (EmailError::ActiveContent, 554, "5.7.1"),
+            (EmailError::TooManyHeaders(1000), 552, "5.3.4"),
+            (EmailError::HeaderSectionTooLarge(102400), 552, "5.3.4"),
+            (EmailError::Serialization(json_error), 554, "5.3.0"),
+            (EmailError::SigningFailed("no key".into()), 554, "5.3.0"),
+        ];
+        for (err, code, enhanced) in cases {
+            let reply = err.reply();
+            assert_eq!((reply.code, reply.enhanced), (code, enhanced), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_relay_error_reply_is_temporary_unless_message_is_at_fault() {
+        let reply = SmtpRelayError::Email(EmailError::MissingSubject).reply();
+        assert_eq!(reply.code, 554);
+        let reply = SmtpRelayError::Acs(AcsError::ServiceUnavailable).reply();
+        assert_eq!((reply.code, reply.enhanced), (451, "4.3.0"));
+        let reply = SmtpRelayError::Network(NetworkError::Timeout).reply();
+        assert_eq!(reply.code, 451);
+        let err = SmtpRelayError::Acs(AcsError::CircuitOpen);
+        assert_eq!(err.reply().text, "ACS temporarily unavailable");
+        assert!(!err.is_permanent());
+        let reply = SmtpRelayError::Acs(AcsError::BadRequest("HTTP 400".into())).reply();
+        assert_eq!((reply.code, reply.enhanced), (550, "5.6.0"));
+        let reply = SmtpRelayError::Acs(AcsError::AuthenticationFailed).reply();
+        assert_eq!((reply.code, reply.enhanced), (554, "5.7.1"));
+    }
+
+    #[test]
+    fn test_relay_error_reply_is_permanent_exactly_when_error_is() {
+        let boxed = || -> Box<dyn std::error::Error + Send + Sync> { "cause".into() };
+        let errors = [
+            SmtpRelayError::Config(ConfigError::InvalidConnectionString("x".into())),
+            SmtpRelayError::Config(ConfigError::MissingEndpoint),
+            SmtpRelayError::Config(ConfigError::MissingAccessKey),
+            SmtpRelayError::Config(ConfigError::InvalidSenderAddress("x".into())),
+            SmtpRelayError::Config(ConfigError::InvalidDomain("x".into())),
+            SmtpRelayError::Config(ConfigError::InvalidPort(0)),
+            SmtpRelayError::Config(ConfigError::InvalidBindAddress("x".into())),
+            SmtpRelayError::Config(ConfigError::InvalidTlsConfig("x".into())),
+            SmtpRelayError::Config(ConfigError::InvalidDkimConfig("x".into())),
+            SmtpRelayError::Config(ConfigError::InvalidHttpClientConfig("x".into())),
+            SmtpRelayError::Config(ConfigError::InvalidSmtpUpstream("x".into())),
+            SmtpRelayError::Config(ConfigError::InvalidContentType("x".into())),
+            SmtpRelayError::Config(ConfigError::InvalidSendPath("x".into())),
+            SmtpRelayError::Config(ConfigError::InvalidHeaderName("x".into())),
+            SmtpRelayError::Config(ConfigError::InvalidEndpointUrl(url::ParseError::EmptyHost)),
+            SmtpRelayError::Config(ConfigError::InsecureEndpoint("x".into())),
+            SmtpRelayError::Config(ConfigError::InvalidSelftestRecipient("x".into())),
+            SmtpRelayError::Config(ConfigError::InvalidSenderDomainCheck("x".into())),
+            SmtpRelayError::Config(ConfigError::UnverifiedSenderDomain("x".into())),
+            SmtpRelayError::Config(ConfigError::EndpointCloudMismatch("x".into(), "y")),
+            SmtpRelayError::Smtp(SmtpError::InvalidCommand("x".into())),
+            SmtpRelayError::Smtp(SmtpError::InvalidArguments("x".into())),
+            SmtpRelayError::Smtp(SmtpError::InvalidSequence("x".into())),
+            SmtpRelayError::Smtp(SmtpError::MessageTooLarge(2, 1)),
+            SmtpRelayError::Smtp(SmtpError::InvalidAddress("x".into())),
+            SmtpRelayError::Smtp(SmtpError::InvalidRecipient("x".into())),
+            SmtpRelayError::Smtp(SmtpError::SmtpUtf8Required("x".into())),
+            SmtpRelayError::Smtp(SmtpError::MissingGreeting),
+            SmtpRelayError::Smtp(SmtpError::MissingFrom),
+            SmtpRelayError::Smtp(SmtpError::NoRecipients),
+            SmtpRelayError::Smtp(SmtpError::TooManyRecipients(1)),
+            SmtpRelayError::Smtp(SmtpError::RelayDenied("x".into())),
+            SmtpRelayError::Smtp(SmtpError::RateLimited),
+            SmtpRelayError::Smtp(SmtpError::QuotaExceeded),
+            SmtpRelayError::Smtp(SmtpError::NotAcceptingMail),
+            SmtpRelayError::Smtp(SmtpError::MessageExpired),
+            SmtpRelayError::Smtp(SmtpError::EarlyTalker),
+            SmtpRelayError::Smtp(SmtpError::DataCorrupted),
+            SmtpRelayError::Smtp(SmtpError::UpstreamRejected("x".into())),
+            SmtpRelayError::Acs(AcsError::ApiRequest(boxed())),
+            SmtpRelayError::Acs(AcsError::BadRequest("x".into())),
+            SmtpRelayError::Acs(AcsError::AuthenticationFailed),
+            SmtpRelayError::Acs(AcsError::Unauthorized),
+            SmtpRelayError::Acs(AcsError::RateLimited),
+            SmtpRelayError::Acs(AcsError::ServiceUnavailable),
+            SmtpRelayError::Acs(AcsError::CircuitOpen),
+            SmtpRelayError::Acs(AcsError::InvalidResponse(boxed())),
+            SmtpRelayError::Email(EmailError::ParseFailed("x".into())),
+            SmtpRelayError::Email(EmailError::MissingSubject),
+            SmtpRelayError::Email(EmailError::MissingContent),
+            SmtpRelayError::Email(EmailError::InvalidEncoding("x".into())),
+            SmtpRelayError::Email(EmailError::UnsupportedContentType("x".into())),
+            SmtpRelayError::Email(EmailError::ActiveContent),
+            SmtpRelayError::Email(EmailError::TooManyHeaders(1)),
+            SmtpRelayError::Email(EmailError::HeaderSectionTooLarge(1)),
+            SmtpRelayError::Email(EmailError::Serialization(
+                serde_json::from_str::<u8>("x").unwrap_err(),
+            )),
+            SmtpRelayError::Email(EmailError::SigningFailed("x".into())),
+            SmtpRelayError::Network(NetworkError::ConnectionLost),
+            SmtpRelayError::Network(NetworkError::Timeout),
+            SmtpRelayError::Network(NetworkError::DnsResolution("x".into())),
+            SmtpRelayError::Network(NetworkError::TlsHandshake("x".into())),
+            SmtpRelayError::Network(NetworkError::Http(
+                reqwest::Client::new().get("http://").build().unwrap_err(),
+            )),
+            SmtpRelayError::Network(NetworkError::Io(std::io::Error::other("x"))),
+            SmtpRelayError::Network(NetworkError::Upstream(boxed())),
+        ];
+        // A dead-lettered message must not be retried by the client, nor a queued one refused
+        for err in errors {
+            assert_eq!(err.reply().code >= 500, err.is_permanent(), "{err}");
         }
     }
 }
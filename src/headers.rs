@@ -0,0 +1,57 @@
+// Longest header line to aim for, excluding the CRLF (RFC 5322 section 2.1.1)
+const MAX_LINE_LEN: usize = 78;
+
+// Formats a header field the relay adds to a message as `Name: value\r\n`, folding the value
+// at whitespace so no line exceeds 78 characters. Continuation lines start with a tab, so
+// unfolding restores the value. A word longer than a line can't be folded and is kept whole.
+pub fn fold_header(name: &str, value: &str) -> String {
+    let mut header = format!("{name}:");
+    let mut line_len = header.len();
+    for word in value.split_whitespace() {
+        // The first word stays on the field name's line, however long it is
+        if line_len > name.len() + 1 && line_len + 1 + word.len() > MAX_LINE_LEN {
+            header.push_str("\r\n\t");
+            line_len = 1;
+        } else {
+            header.push(' ');
+            line_len += 1;
+        }
+        header.push_str(word);
+        line_len += word.len();
+    }
+    header.push_str("\r\n");
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fold_header() {
+        assert_eq!(
+            fold_header("Date", "Wed, 1 May 2024 12:00:00 +0000"),
+            "Date: Wed, 1 May 2024 12:00:00 +0000\r\n"
+        );
+
+        let value = "word ".repeat(40);
+        let header = fold_header("X-Long", &value);
+        let lines: Vec<&str> = header.trim_end_matches("\r\n").split("\r\n").collect();
+        assert!(lines.len() > 1, "{header}");
+        assert!(
+            lines.iter().all(|line| line.len() <= MAX_LINE_LEN),
+            "{header}"
+        );
+        assert!(lines[1..].iter().all(|line| line.starts_with('\t')));
+        // Unfolding (RFC 5322 section 2.2.3) gives back the words
+        let unfolded = header.replace("\r\n\t", " ");
+        assert_eq!(unfolded, format!("X-Long: {}\r\n", value.trim_end()));
+
+        // An unbreakable word goes on a line of its own
+        let token = "x".repeat(100);
+        assert_eq!(
+            fold_header("X-Token", &format!("a {token} b")),
+            format!("X-Token: a\r\n\t{token}\r\n\tb\r\n")
+        );
+    }
+}
@@ -1,11 +1,22 @@
 #[cfg(feature = "health-server")]
 use warp::{Filter, Reply};
 
+use crate::breaker::CircuitState;
+#[cfg(feature = "health-server")]
+use crate::headers::fold_header;
 use crate::metrics::MetricsCollector;
+#[cfg(feature = "health-server")]
+use crate::relay::Mailer;
 use anyhow::Result;
 use serde::Serialize;
+#[cfg(feature = "health-server")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "health-server")]
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::net::TcpListener;
+#[cfg(feature = "health-server")]
+use tracing::warn;
 use tracing::{error, info, instrument};
 
 // Health check status
@@ -22,10 +33,37 @@ pub struct HealthStatus {
 pub struct HealthMetrics {
     pub connections_total: u64,
     pub connections_active: u64,
+    pub connection_permits_in_use: u64,
+    pub emails_in_flight: u64,
     pub emails_sent_total: u64,
     pub emails_failed_total: u64,
     pub success_rate_percent: f64,
     pub average_response_time_ms: Option<u64>,
+    pub acs_circuit_state: Option<CircuitState>,
+}
+
+// Identifies the running build; git_sha and build_time are captured by build.rs
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_sha: &'static str,
+    pub build_time: String,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        let build_time = env!("BUILD_TIMESTAMP")
+            .parse::<i64>()
+            .ok()
+            .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+            .map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+            .unwrap_or_default();
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_sha: env!("BUILD_GIT_SHA"),
+            build_time,
+        }
+    }
 }
 
 impl Default for HealthStatus {
@@ -57,46 +95,260 @@ impl HealthStatus {
         self.metrics = Some(HealthMetrics {
             connections_total: metrics_snapshot.connections_total,
             connections_active: metrics_snapshot.connections_active,
+            connection_permits_in_use: metrics_snapshot.connection_permits_in_use,
+            emails_in_flight: metrics_snapshot.emails_in_flight,
             emails_sent_total: metrics_snapshot.emails_sent_total,
             emails_failed_total: metrics_snapshot.emails_failed_total,
             success_rate_percent: metrics_snapshot.get_success_rate() * 100.0,
             average_response_time_ms: metrics_snapshot
                 .get_average_response_time()
                 .map(|d| d.as_millis() as u64),
+            acs_circuit_state: metrics_snapshot.acs_circuit_state,
         });
 
         self
     }
 }
 
+// Opt-in end-to-end check served as POST /admin/selftest: a short message sent through the
+// configured mailer to a fixed recipient, so operators can confirm credentials and
+// connectivity without an SMTP client
+#[cfg(feature = "health-server")]
+#[derive(Clone)]
+pub struct SelfTest {
+    pub mailer: Arc<dyn Mailer>,
+    pub sender: String,
+    pub recipient: String,
+}
+
+#[cfg(feature = "health-server")]
+impl SelfTest {
+    fn message(&self) -> String {
+        let headers = [
+            ("From", self.sender.clone()),
+            ("To", self.recipient.clone()),
+            ("Subject", "ACS SMTP relay self-test".to_string()),
+            ("Date", chrono::Utc::now().to_rfc2822()),
+            (
+                "Message-ID",
+                format!("<selftest-{}@acs-smtp-relay>", uuid::Uuid::new_v4()),
+            ),
+        ];
+        let mut message: String = headers
+            .iter()
+            .map(|(name, value)| fold_header(name, value))
+            .collect();
+        message.push_str(&format!(
+            "\r\nThis message was sent by POST /admin/selftest to check delivery through acs-smtp-relay {}.\r\n",
+            env!("CARGO_PKG_VERSION"),
+        ));
+        message
+    }
+}
+
 // Start a health check HTTP server on a separate port
 #[cfg(feature = "health-server")]
 pub async fn start_health_server(
     bind_addr: std::net::SocketAddr,
     metrics_collector: MetricsCollector,
+    allow_metrics_reset: bool,
+    metrics_auth_token: Option<String>,
+    paused: Arc<AtomicBool>,
+    selftest: Option<SelfTest>,
+    tls: Option<crate::config::HealthTlsConfig>,
 ) -> Result<()> {
+    let (_, server) = bind_health_server(
+        bind_addr,
+        metrics_collector,
+        allow_metrics_reset,
+        metrics_auth_token,
+        paused,
+        selftest,
+        tls,
+    )?;
+    server.await;
+    Ok(())
+}
+
+// A bound health server, run by awaiting (or spawning) it
+#[cfg(feature = "health-server")]
+pub type HealthServerFuture = std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>>;
+
+// Binds the health check HTTP server, returning the bound address and the future that
+// serves it. Bind and TLS setup errors are returned here rather than when serving.
+#[cfg(feature = "health-server")]
+pub fn bind_health_server(
+    bind_addr: std::net::SocketAddr,
+    metrics_collector: MetricsCollector,
+    allow_metrics_reset: bool,
+    metrics_auth_token: Option<String>,
+    paused: Arc<AtomicBool>,
+    selftest: Option<SelfTest>,
+    #[cfg_attr(not(feature = "health-tls"), allow(unused_variables))] tls: Option<
+        crate::config::HealthTlsConfig,
+    >,
+) -> Result<(std::net::SocketAddr, HealthServerFuture)> {
+    let routes = health_routes(
+        metrics_collector,
+        allow_metrics_reset,
+        metrics_auth_token,
+        paused,
+        selftest,
+    );
+
+    #[cfg(feature = "health-tls")]
+    if let Some(tls) = tls {
+        let (bound_addr, server) = warp::serve(routes)
+            .tls()
+            .cert_path(&tls.cert_path)
+            .key_path(&tls.key_path)
+            .try_bind_with_graceful_shutdown(bind_addr, std::future::pending())?;
+        info!(bind_addr = %bound_addr, "Starting health check server with TLS");
+        return Ok((bound_addr, Box::pin(server)));
+    }
+
+    let (bound_addr, server) = warp::serve(routes).try_bind_ephemeral(bind_addr)?;
+    info!(bind_addr = %bound_addr, "Starting health check server");
+    Ok((bound_addr, Box::pin(server)))
+}
+
+// All HTTP routes served by the health server. /health and /version stay open; the
+// metrics and readiness routes require the bearer token when one is configured, and the
+// admin routes are only served when one is (/admin/selftest also only when configured).
+#[cfg(feature = "health-server")]
+fn health_routes(
+    metrics_collector: MetricsCollector,
+    allow_metrics_reset: bool,
+    metrics_auth_token: Option<String>,
+    paused: Arc<AtomicBool>,
+    selftest: Option<SelfTest>,
+) -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone {
     let health = warp::path("health")
         .and(warp::get())
         .and(with_metrics(metrics_collector.clone()))
         .and_then(health_handler);
 
+    let version = warp::path("version")
+        .and(warp::get())
+        .map(|| warp::reply::json(&BuildInfo::current()));
+
+    // Only routed when explicitly enabled; otherwise the request is rejected like any unknown route
+    let metrics_reset = warp::path!("metrics" / "reset")
+        .and(warp::post())
+        .and(warp::any().and_then(move || async move {
+            if allow_metrics_reset {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        }))
+        .untuple_one()
+        .and(require_bearer_token(metrics_auth_token.clone()))
+        .and(with_metrics(metrics_collector.clone()))
+        .and_then(metrics_reset_handler);
+
     let metrics = warp::path("metrics")
         .and(warp::get())
+        .and(require_bearer_token(metrics_auth_token.clone()))
         .and(with_metrics(metrics_collector.clone()))
         .and_then(metrics_handler);
 
     let readiness = warp::path("ready")
         .and(warp::get())
+        .and(require_bearer_token(metrics_auth_token.clone()))
         .and(with_metrics(metrics_collector))
         .and_then(readiness_handler);
 
-    let routes = health.or(metrics).or(readiness);
+    // Pausing refuses new SMTP transactions (MAIL FROM gets 421) while open ones finish
+    let token_configured = metrics_auth_token.is_some();
+    let admin = warp::path("admin")
+        .and(warp::post())
+        .and(warp::any().and_then(move || async move {
+            if token_configured {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        }))
+        .untuple_one()
+        .and(require_bearer_token(metrics_auth_token));
+    let pause = admin
+        .clone()
+        .and(warp::path!("pause"))
+        .and(with_paused(paused.clone(), true))
+        .and_then(set_paused_handler);
+    let resume = admin
+        .clone()
+        .and(warp::path!("resume"))
+        .and(with_paused(paused, false))
+        .and_then(set_paused_handler);
+    let selftest = admin
+        .and(warp::path!("selftest"))
+        .and(with_selftest(selftest))
+        .and_then(selftest_handler);
+
+    health
+        .or(version)
+        .or(metrics_reset)
+        .or(metrics)
+        .or(readiness)
+        .or(pause)
+        .or(resume)
+        .or(selftest)
+        .recover(handle_rejection)
+}
 
-    info!(bind_addr = %bind_addr, "Starting health check server");
+// Rejection for requests missing the configured metrics bearer token
+#[cfg(feature = "health-server")]
+#[derive(Debug)]
+struct Unauthorized;
 
-    warp::serve(routes).run(bind_addr).await;
+#[cfg(feature = "health-server")]
+impl warp::reject::Reject for Unauthorized {}
 
-    Ok(())
+// Passes when no token is configured or the request carries `Authorization: Bearer <token>`
+#[cfg(feature = "health-server")]
+fn require_bearer_token(
+    token: Option<String>,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let token = token.clone();
+            async move {
+                let Some(token) = token else {
+                    return Ok(());
+                };
+                let presented = header
+                    .as_deref()
+                    .and_then(|value| value.strip_prefix("Bearer "));
+                match presented {
+                    Some(presented)
+                        if crate::redact::constant_time_eq(
+                            presented.as_bytes(),
+                            token.as_bytes(),
+                        ) =>
+                    {
+                        Ok(())
+                    }
+                    _ => Err(warp::reject::custom(Unauthorized)),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+// Turns an Unauthorized rejection into a 401, leaving warp's default handling for the rest
+#[cfg(feature = "health-server")]
+async fn handle_rejection(rejection: warp::Rejection) -> Result<impl Reply, warp::Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        Ok(warp::reply::with_header(
+            warp::reply::with_status("Unauthorized", warp::http::StatusCode::UNAUTHORIZED),
+            "WWW-Authenticate",
+            "Bearer",
+        ))
+    } else {
+        Err(rejection)
+    }
 }
 
 #[cfg(feature = "health-server")]
@@ -106,6 +358,82 @@ fn with_metrics(
     warp::any().map(move || metrics.clone())
 }
 
+#[cfg(feature = "health-server")]
+fn with_paused(
+    paused: Arc<AtomicBool>,
+    pause: bool,
+) -> impl Filter<Extract = (Arc<AtomicBool>, bool), Error = std::convert::Infallible> + Clone {
+    warp::any()
+        .map(move || (paused.clone(), pause))
+        .untuple_one()
+}
+
+#[cfg(feature = "health-server")]
+async fn set_paused_handler(
+    paused: Arc<AtomicBool>,
+    pause: bool,
+) -> Result<impl Reply, warp::Rejection> {
+    paused.store(pause, Ordering::Relaxed);
+    if pause {
+        warn!("Mail acceptance paused via HTTP");
+    } else {
+        info!("Mail acceptance resumed via HTTP");
+    }
+    Ok(warp::reply::json(&serde_json::json!({ "paused": pause })))
+}
+
+// Passes the self-test settings on, or rejects like an unknown route when there are none
+#[cfg(feature = "health-server")]
+fn with_selftest(
+    selftest: Option<SelfTest>,
+) -> impl Filter<Extract = (SelfTest,), Error = warp::Rejection> + Clone {
+    warp::any().and_then(move || {
+        let selftest = selftest.clone();
+        async move { selftest.ok_or_else(warp::reject::not_found) }
+    })
+}
+
+// Sends the self-test message and reports the outcome; 502 when the mailer fails
+#[cfg(feature = "health-server")]
+async fn selftest_handler(selftest: SelfTest) -> Result<impl Reply, warp::Rejection> {
+    let started = std::time::Instant::now();
+    let result = selftest
+        .mailer
+        .send(
+            selftest.message().as_bytes(),
+            std::slice::from_ref(&selftest.recipient),
+            &None,
+        )
+        .await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+    let (status, body) = match result {
+        Ok(()) => {
+            info!(recipient = %selftest.recipient, latency_ms, "Self-test message sent");
+            (
+                warp::http::StatusCode::OK,
+                serde_json::json!({
+                    "result": "success",
+                    "recipient": selftest.recipient,
+                    "latency_ms": latency_ms,
+                }),
+            )
+        }
+        Err(e) => {
+            warn!(recipient = %selftest.recipient, latency_ms, error = %e, "Self-test message failed");
+            (
+                warp::http::StatusCode::BAD_GATEWAY,
+                serde_json::json!({
+                    "result": "failure",
+                    "recipient": selftest.recipient,
+                    "latency_ms": latency_ms,
+                    "error": e.to_string(),
+                }),
+            )
+        }
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&body), status))
+}
+
 #[cfg(feature = "health-server")]
 #[instrument(skip(metrics))]
 async fn health_handler(metrics: MetricsCollector) -> Result<impl Reply, warp::Rejection> {
@@ -120,6 +448,16 @@ async fn metrics_handler(metrics: MetricsCollector) -> Result<impl Reply, warp::
     Ok(warp::reply::json(&metrics_snapshot.to_serializable()))
 }
 
+#[cfg(feature = "health-server")]
+#[instrument(skip(metrics))]
+async fn metrics_reset_handler(metrics: MetricsCollector) -> Result<impl Reply, warp::Rejection> {
+    metrics.reset().await;
+    info!("Metrics reset via HTTP");
+    Ok(warp::reply::json(
+        &metrics.get_snapshot().await.to_serializable(),
+    ))
+}
+
 #[cfg(feature = "health-server")]
 #[instrument(skip(metrics))]
 async fn readiness_handler(metrics: MetricsCollector) -> Result<impl Reply, warp::Rejection> {
@@ -131,6 +469,13 @@ async fn readiness_handler(metrics: MetricsCollector) -> Result<impl Reply, warp
     if metrics_snapshot.get_success_rate() < 0.5 && metrics_snapshot.emails_sent_total > 10 {
         status.status = "degraded".to_string();
     }
+    // Sends are failing fast while the ACS circuit breaker is open or probing
+    if metrics_snapshot
+        .acs_circuit_state
+        .is_some_and(|state| state != CircuitState::Closed)
+    {
+        status.status = "degraded".to_string();
+    }
 
     status = status.with_metrics(&metrics).await;
     Ok(warp::reply::json(&status))
@@ -171,6 +516,31 @@ mod tests {
         assert!(health.timestamp > 0);
     }
 
+    #[cfg(feature = "health-server")]
+    #[tokio::test]
+    async fn test_version_endpoint_reports_build_info() {
+        let response = warp::test::request()
+            .path("/version")
+            .reply(&health_routes(
+                MetricsCollector::new(),
+                false,
+                Some("secret".to_string()),
+                Default::default(),
+                None,
+            ))
+            .await;
+        // Served without the bearer token, like /health
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        assert!(!body["git_sha"].as_str().unwrap().is_empty());
+        let build_time = body["build_time"].as_str().unwrap();
+        assert!(
+            chrono::DateTime::parse_from_rfc3339(build_time).is_ok(),
+            "{build_time}"
+        );
+    }
+
     #[tokio::test]
     async fn test_health_status_with_metrics() {
         let collector = MetricsCollector::new();
@@ -184,4 +554,291 @@ mod tests {
         assert_eq!(metrics.emails_sent_total, 1);
         assert_eq!(metrics.connections_total, 1);
     }
+
+    #[cfg(feature = "health-server")]
+    #[tokio::test]
+    async fn test_readiness_reports_open_circuit_breaker() {
+        let breaker = std::sync::Arc::new(crate::breaker::CircuitBreaker::new(
+            1,
+            std::time::Duration::from_secs(60),
+        ));
+        let collector = MetricsCollector::new().with_circuit_breaker(breaker.clone());
+        let ready = || async {
+            let response = warp::test::request()
+                .path("/ready")
+                .reply(&health_routes(
+                    collector.clone(),
+                    false,
+                    None,
+                    Default::default(),
+                    None,
+                ))
+                .await;
+            serde_json::from_slice::<serde_json::Value>(response.body()).unwrap()
+        };
+
+        let body = ready().await;
+        assert_eq!(body["status"], "healthy");
+        assert_eq!(body["metrics"]["acs_circuit_state"], "closed");
+
+        breaker.record(false);
+        let body = ready().await;
+        assert_eq!(body["status"], "degraded");
+        assert_eq!(body["metrics"]["acs_circuit_state"], "open");
+    }
+
+    #[cfg(feature = "health-server")]
+    #[tokio::test]
+    async fn test_metrics_reset_endpoint_zeroes_counters() {
+        let collector = MetricsCollector::new();
+        collector.increment_connections().await;
+        collector.increment_emails_sent().await;
+        collector.increment_emails_failed().await;
+        let uptime_start = collector.get_snapshot().await.uptime_start;
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/metrics/reset")
+            .reply(&health_routes(
+                collector.clone(),
+                true,
+                None,
+                Default::default(),
+                None,
+            ))
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let metrics = collector.get_snapshot().await;
+        assert_eq!(metrics.connections_total, 0);
+        assert_eq!(metrics.emails_sent_total, 0);
+        assert_eq!(metrics.emails_failed_total, 0);
+        assert_eq!(metrics.uptime_start, uptime_start);
+    }
+
+    #[cfg(feature = "health-server")]
+    #[tokio::test]
+    async fn test_metrics_reset_endpoint_disabled_by_default() {
+        let collector = MetricsCollector::new();
+        collector.increment_emails_sent().await;
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/metrics/reset")
+            .reply(&health_routes(
+                collector.clone(),
+                false,
+                None,
+                Default::default(),
+                None,
+            ))
+            .await;
+        assert!(response.status().is_client_error());
+        assert_eq!(collector.get_snapshot().await.emails_sent_total, 1);
+    }
+
+    #[cfg(feature = "health-server")]
+    #[tokio::test]
+    async fn test_metrics_bearer_token() {
+        let routes = health_routes(
+            MetricsCollector::new(),
+            false,
+            Some("s3cret".to_string()),
+            Default::default(),
+            None,
+        );
+
+        let authorized = warp::test::request()
+            .path("/metrics")
+            .header("authorization", "Bearer s3cret")
+            .reply(&routes)
+            .await;
+        assert_eq!(authorized.status(), 200);
+
+        for header in [None, Some("Bearer wrong"), Some("s3cret")] {
+            let mut request = warp::test::request().path("/ready");
+            if let Some(header) = header {
+                request = request.header("authorization", header);
+            }
+            let response = request.reply(&routes).await;
+            assert_eq!(response.status(), 401, "{header:?}");
+        }
+
+        // Orchestrator probes stay open
+        let health = warp::test::request().path("/health").reply(&routes).await;
+        assert_eq!(health.status(), 200);
+    }
+
+    #[cfg(feature = "health-server")]
+    #[tokio::test]
+    async fn test_metrics_open_without_token() {
+        let routes = health_routes(
+            MetricsCollector::new(),
+            false,
+            None,
+            Default::default(),
+            None,
+        );
+        for path in ["/metrics", "/ready", "/health"] {
+            let response = warp::test::request().path(path).reply(&routes).await;
+            assert_eq!(response.status(), 200, "{path}");
+        }
+    }
+
+    #[cfg(feature = "health-server")]
+    #[tokio::test]
+    async fn test_admin_pause_and_resume() {
+        let paused = Arc::new(AtomicBool::new(false));
+        let routes = health_routes(
+            MetricsCollector::new(),
+            false,
+            Some("s3cret".to_string()),
+            paused.clone(),
+            None,
+        );
+        let admin = |path: &'static str| {
+            warp::test::request()
+                .method("POST")
+                .path(path)
+                .header("authorization", "Bearer s3cret")
+        };
+
+        let response = admin("/admin/pause").reply(&routes).await;
+        assert_eq!(response.status(), 200);
+        assert!(paused.load(Ordering::Relaxed));
+
+        let response = admin("/admin/resume").reply(&routes).await;
+        assert_eq!(response.status(), 200);
+        assert!(!paused.load(Ordering::Relaxed));
+
+        let unauthorized = warp::test::request()
+            .method("POST")
+            .path("/admin/pause")
+            .reply(&routes)
+            .await;
+        assert_eq!(unauthorized.status(), 401);
+        assert!(!paused.load(Ordering::Relaxed));
+
+        // Without a token the admin routes are not served at all
+        let routes = health_routes(MetricsCollector::new(), false, None, paused.clone(), None);
+        let response = warp::test::request()
+            .method("POST")
+            .path("/admin/pause")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 404);
+        assert!(!paused.load(Ordering::Relaxed));
+    }
+
+    #[cfg(feature = "health-server")]
+    #[tokio::test]
+    async fn test_admin_selftest_sends_through_mailer() {
+        use crate::error::{AcsError, SmtpRelayError};
+        use crate::relay::CapturingMailer;
+
+        let selftest_routes = |mailer: CapturingMailer| {
+            health_routes(
+                MetricsCollector::new(),
+                false,
+                Some("s3cret".to_string()),
+                Default::default(),
+                Some(SelfTest {
+                    mailer: Arc::new(mailer),
+                    sender: "DoNotReply@sender.example".to_string(),
+                    recipient: "ops@example.com".to_string(),
+                }),
+            )
+        };
+        let selftest = || {
+            warp::test::request()
+                .method("POST")
+                .path("/admin/selftest")
+                .header("authorization", "Bearer s3cret")
+        };
+
+        let mailer = CapturingMailer::new();
+        let response = selftest().reply(&selftest_routes(mailer.clone())).await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["result"], "success");
+        assert_eq!(body["recipient"], "ops@example.com");
+        assert!(body["latency_ms"].is_u64());
+        let sent = mailer.messages();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].recipients, ["ops@example.com"]);
+        let raw_email = String::from_utf8_lossy(&sent[0].raw_email);
+        assert!(raw_email.contains("To: ops@example.com\r\n"), "{raw_email}");
+        assert!(raw_email.contains("Subject: ACS SMTP relay self-test\r\n"));
+
+        let failing =
+            CapturingMailer::failing(|| SmtpRelayError::Acs(AcsError::AuthenticationFailed));
+        let response = selftest().reply(&selftest_routes(failing)).await;
+        assert_eq!(response.status(), 502);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["result"], "failure");
+        assert!(body["error"].as_str().unwrap().contains("401"), "{body}");
+
+        // The token is required, and without a configured recipient nothing is sent
+        let unauthorized = warp::test::request()
+            .method("POST")
+            .path("/admin/selftest")
+            .reply(&selftest_routes(mailer.clone()))
+            .await;
+        assert_eq!(unauthorized.status(), 401);
+        let routes = health_routes(
+            MetricsCollector::new(),
+            false,
+            Some("s3cret".to_string()),
+            Default::default(),
+            None,
+        );
+        assert_eq!(selftest().reply(&routes).await.status(), 404);
+        assert_eq!(mailer.messages().len(), 1);
+    }
+
+    #[cfg(feature = "health-tls")]
+    #[tokio::test]
+    async fn test_health_server_serves_https() {
+        // Reserve a free port, then hand it to the server
+        let bind_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let tls = crate::config::HealthTlsConfig {
+            cert_path: fixtures.join("health_tls_cert.pem"),
+            key_path: fixtures.join("health_tls_key.pem"),
+        };
+        tokio::spawn(start_health_server(
+            bind_addr,
+            MetricsCollector::new(),
+            false,
+            None,
+            Default::default(),
+            None,
+            Some(tls),
+        ));
+
+        let cert = std::fs::read(fixtures.join("health_tls_cert.pem")).unwrap();
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(&cert).unwrap())
+            .build()
+            .unwrap();
+        let url = format!("https://localhost:{}/health", bind_addr.port());
+
+        let mut response = None;
+        for _ in 0..50 {
+            match client.get(&url).send().await {
+                Ok(r) => {
+                    response = Some(r);
+                    break;
+                }
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(20)).await,
+            }
+        }
+        let response = response.expect("health server did not answer over HTTPS");
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["status"], "healthy");
+    }
 }
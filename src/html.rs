@@ -0,0 +1,181 @@
+use crate::config::HtmlPolicy;
+use crate::error::{EmailError, SmtpRelayError};
+use html5ever::buffer_queue::BufferQueue;
+use html5ever::tendril::StrTendril;
+use html5ever::tokenizer::{Tag, TagKind, Token, TokenSink, TokenSinkResult, Tokenizer};
+use std::cell::Cell;
+
+// Elements that run code, load other documents or redirect the reader
+const ACTIVE_ELEMENTS: [&str; 9] = [
+    "script", "iframe", "frame", "frameset", "object", "embed", "applet", "base", "form",
+];
+
+// Attributes holding a URL that a client may follow or load
+const URL_ATTRIBUTES: [&str; 7] = [
+    "href",
+    "src",
+    "action",
+    "formaction",
+    "background",
+    "poster",
+    "xlink:href",
+];
+
+// URL schemes that execute script or render an attacker-controlled document
+const ACTIVE_URL_SCHEMES: [&str; 3] = ["javascript:", "vbscript:", "data:text/html"];
+
+// Applies the HTML policy to a message's HTML body, sanitizing it in place or rejecting it.
+pub fn apply_policy(html: &mut Option<String>, policy: HtmlPolicy) -> Result<(), SmtpRelayError> {
+    let Some(body) = html.as_mut() else {
+        return Ok(());
+    };
+    match policy {
+        HtmlPolicy::Off => {}
+        HtmlPolicy::Sanitize => *body = sanitize(body),
+        HtmlPolicy::Reject if has_active_content(body) => {
+            return Err(SmtpRelayError::Email(EmailError::ActiveContent))
+        }
+        HtmlPolicy::Reject => {}
+    }
+    Ok(())
+}
+
+// Strips scripts, event handlers, dangerous URLs and other markup outside ammonia's
+// allow-list. Inline styles and the layout attributes HTML email relies on are kept.
+pub fn sanitize(html: &str) -> String {
+    ammonia::Builder::default()
+        .add_generic_attributes([
+            "style", "class", "align", "valign", "bgcolor", "width", "height",
+        ])
+        .add_tag_attributes("table", ["border", "cellpadding", "cellspacing"])
+        .clean(html)
+        .to_string()
+}
+
+// Whether the HTML contains anything that would run code or navigate the reader without
+// a click: active elements, `on*` event handlers or script URLs.
+pub fn has_active_content(html: &str) -> bool {
+    let input = BufferQueue::default();
+    input.push_back(StrTendril::from_slice(html));
+    let tokenizer = Tokenizer::new(ActiveContentSink::default(), Default::default());
+    let _ = tokenizer.feed(&input);
+    tokenizer.end();
+    tokenizer.sink.found.get()
+}
+
+#[derive(Default)]
+struct ActiveContentSink {
+    found: Cell<bool>,
+}
+
+impl ActiveContentSink {
+    fn is_active(tag: &Tag) -> bool {
+        let name: &str = &tag.name;
+        // `<meta http-equiv="refresh">` redirects; other meta tags (charset etc.) are harmless
+        if name == "meta" {
+            return tag.attrs.iter().any(|attr| {
+                &*attr.name.local == "http-equiv"
+                    && attr.value.trim().eq_ignore_ascii_case("refresh")
+            });
+        }
+        if ACTIVE_ELEMENTS.contains(&name) {
+            return true;
+        }
+        tag.attrs.iter().any(|attr| {
+            let attr_name: &str = &attr.name.local;
+            if attr_name.starts_with("on") {
+                return true;
+            }
+            if !URL_ATTRIBUTES.contains(&attr_name) {
+                return false;
+            }
+            // Browsers ignore whitespace and control characters inside the scheme
+            let url: String = attr
+                .value
+                .chars()
+                .filter(|c| !c.is_whitespace() && !c.is_control())
+                .collect::<String>()
+                .to_ascii_lowercase();
+            ACTIVE_URL_SCHEMES
+                .iter()
+                .any(|scheme| url.starts_with(scheme))
+        })
+    }
+}
+
+impl TokenSink for ActiveContentSink {
+    type Handle = ();
+
+    fn process_token(&self, token: Token, _line_number: u64) -> TokenSinkResult<()> {
+        if let Token::TagToken(tag) = &token {
+            if tag.kind == TagKind::StartTag && Self::is_active(tag) {
+                self.found.set(true);
+            }
+        }
+        TokenSinkResult::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPTED: &str = "<p style=\"color: red\">Hello</p><script>alert('x')</script>";
+
+    #[test]
+    fn test_sanitize_removes_script() {
+        let mut html = Some(SCRIPTED.to_string());
+        apply_policy(&mut html, HtmlPolicy::Sanitize).unwrap();
+        let html = html.unwrap();
+        assert!(!html.contains("<script"), "{html}");
+        assert!(!html.contains("alert"), "{html}");
+        assert!(html.contains("<p style=\"color: red\">Hello</p>"), "{html}");
+    }
+
+    #[test]
+    fn test_sanitize_removes_event_handlers_and_script_urls() {
+        let html = sanitize("<a href=\"javascript:alert(1)\" onclick=\"steal()\">Click</a>");
+        assert!(!html.contains("javascript"), "{html}");
+        assert!(!html.contains("onclick"), "{html}");
+        assert!(html.contains("Click"), "{html}");
+    }
+
+    #[test]
+    fn test_reject_policy() {
+        let mut html = Some(SCRIPTED.to_string());
+        assert!(matches!(
+            apply_policy(&mut html, HtmlPolicy::Reject),
+            Err(SmtpRelayError::Email(EmailError::ActiveContent))
+        ));
+
+        let mut html = Some("<table border=\"0\"><tr><td>Hi</td></tr></table>".to_string());
+        apply_policy(&mut html, HtmlPolicy::Reject).unwrap();
+    }
+
+    #[test]
+    fn test_off_policy_leaves_html_unchanged() {
+        let mut html = Some(SCRIPTED.to_string());
+        apply_policy(&mut html, HtmlPolicy::Off).unwrap();
+        assert_eq!(html.as_deref(), Some(SCRIPTED));
+    }
+
+    #[test]
+    fn test_has_active_content() {
+        for html in [
+            "<SCRIPT src=x></SCRIPT>",
+            "<img src=x onerror=alert(1)>",
+            "<a href=\" JaVa\tScript:alert(1)\">x</a>",
+            "<meta http-equiv=\"Refresh\" content=\"0; url=https://evil.example\">",
+            "<iframe src=\"https://example.com\"></iframe>",
+        ] {
+            assert!(has_active_content(html), "{html}");
+        }
+        for html in [
+            "<meta charset=\"utf-8\"><p>Hi</p>",
+            "<a href=\"https://example.com\">link</a>",
+            "<p>onclick is just a word here</p>",
+        ] {
+            assert!(!has_active_content(html), "{html}");
+        }
+    }
+}
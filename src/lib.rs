@@ -1,285 +1,1739 @@
 use anyhow::Result;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::BTreeSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use std::time::{Duration, Instant};
+use tokio::io::{self, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::signal;
-use tracing::{error, info, instrument, warn};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use tracing::{error, info, info_span, instrument, warn, Instrument};
+use uuid::Uuid;
 
+pub mod breaker;
 pub mod config;
+pub mod dedup;
+#[cfg(feature = "dkim")]
+pub mod dkim;
 pub mod error;
+pub mod headers;
 #[cfg(feature = "health-server")]
 pub mod health;
+#[cfg(feature = "html-sanitize")]
+pub mod html;
+pub mod logging;
 pub mod metrics;
+pub mod proxy;
+#[cfg(feature = "queue")]
+pub mod queue;
+pub mod quota;
+pub mod rate_limit;
+pub mod redact;
 pub mod relay;
+pub mod sender_domain;
+pub mod server;
+pub mod spool;
+#[cfg(feature = "otel")]
+pub mod telemetry;
+pub mod xforward;
 
-pub use config::{parse_connection_string, AcsConfig, Config};
+pub use config::{
+    parse_connection_string, AcsConfig, AuthCredentials, CloudEnvironment, Config, DkimConfig,
+    EmptyHtml, HealthTlsConfig, HtmlPolicy, HttpClientSettings, LogVerbosity, MailerBackend,
+    RecipientCase, RecipientPolicy, SenderDomainCheck, SessionConfig, SmtpUpstreamConfig,
+    UpstreamTls,
+};
 pub use error::SmtpRelayError;
+use error::{EmailError, SmtpError};
+use logging::Escaped;
 pub use metrics::MetricsCollector;
-use relay::Mailer;
+use relay::{Mailer, Repeatability};
+pub use server::Server;
 
 // Represents the state of a single SMTP transaction (one email).
 #[derive(Default, Clone, Debug)] // Added Debug for easier logging
 struct Transaction {
+    // `Some("")` is the null reverse-path (`MAIL FROM:<>`) used by bounces
     from: Option<String>,
     recipients: Vec<String>,
+    // Client declared SMTPUTF8 on MAIL FROM, allowing UTF-8 envelope addresses (RFC 6531)
+    smtputf8: bool,
+    // When MAIL FROM was accepted; the message max age counts from here
+    started: Option<Instant>,
+    // Original client details from a trusted upstream's XFORWARD before MAIL FROM
+    xforward: xforward::XForward,
+}
+
+impl Transaction {
+    // Whether the envelope is complete enough for message content to follow
+    fn check_ready(&self) -> std::result::Result<(), SmtpError> {
+        if self.from.is_none() {
+            Err(SmtpError::MissingFrom)
+        } else if self.recipients.is_empty() {
+            Err(SmtpError::NoRecipients)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Wall-clock time MAIL FROM was accepted, for queued messages whose age outlives the process
+    #[cfg(feature = "queue")]
+    fn received_at(&self) -> chrono::DateTime<chrono::Utc> {
+        let elapsed = self
+            .started
+            .map(|started| started.elapsed())
+            .unwrap_or_default();
+        chrono::Utc::now() - chrono::Duration::from_std(elapsed).unwrap_or_default()
+    }
+}
+
+// Splits a command line into its uppercased verb and the untouched remainder.
+fn split_command(line: &str) -> (String, &str) {
+    let (verb, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    (verb.to_ascii_uppercase(), args.trim_start())
+}
+
+// Longest client-supplied text echoed back in a reply or written to the logs
+const MAX_ECHOED_COMMAND_LEN: usize = 64;
+
+// Client input made safe to echo in a reply or log line: only printable ASCII and spaces
+// are kept, so control characters can't inject reply lines or terminal escapes.
+fn sanitize_command(input: &str) -> String {
+    input
+        .chars()
+        .filter(|c| c.is_ascii_graphic() || *c == ' ')
+        .take(MAX_ECHOED_COMMAND_LEN)
+        .collect()
+}
+
+// Splits a MAIL FROM/RCPT TO argument into the mailbox and any trailing ESMTP parameters.
+fn split_path(arg: &str) -> (&str, &str) {
+    let arg = arg.trim();
+    match arg.strip_prefix('<').and_then(|rest| rest.split_once('>')) {
+        Some((address, params)) => (address, params.trim()),
+        None => arg.split_once(char::is_whitespace).unwrap_or((arg, "")),
+    }
+}
+
+// Mailbox and recognised ESMTP parameters of a MAIL FROM or RCPT TO command.
+#[derive(Debug, Default, PartialEq)]
+struct EnvelopePath<'a> {
+    address: &'a str,
+    size: Option<usize>,
+    body: Option<&'a str>,
+    smtputf8: bool,
+}
+
+// Parses the arguments of MAIL/RCPT (e.g. `FROM: <a@b.com> SIZE=100`), tolerating whitespace
+// around the colon. Returns None if the keyword before the colon isn't `keyword` or no path
+// follows it.
+fn parse_envelope_path<'a>(args: &'a str, keyword: &str) -> Option<EnvelopePath<'a>> {
+    let (head, rest) = args.split_once(':')?;
+    if !head.trim().eq_ignore_ascii_case(keyword) || rest.trim().is_empty() {
+        return None;
+    }
+    let (address, params) = split_path(rest);
+    let mut path = EnvelopePath {
+        address,
+        ..Default::default()
+    };
+    for param in params.split_whitespace() {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        if name.eq_ignore_ascii_case("SIZE") {
+            path.size = value.parse().ok();
+        } else if name.eq_ignore_ascii_case("BODY") {
+            path.body = Some(value);
+        } else if name.eq_ignore_ascii_case("SMTPUTF8") {
+            path.smtputf8 = true;
+        }
+    }
+    Some(path)
+}
+
+// Decodes an AUTH PLAIN response, base64 of `[authzid] NUL authcid NUL passwd` (RFC 4616),
+// returning the authentication identity and password. The password is never logged.
+fn decode_auth_plain(response: &str) -> Option<(String, String)> {
+    use base64::Engine;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(response)
+        .ok()?;
+    let mut fields = decoded.split(|&b| b == 0);
+    let (_authzid, authcid, passwd) = (fields.next()?, fields.next()?, fields.next()?);
+    if fields.next().is_some() || authcid.is_empty() {
+        return None;
+    }
+    Some((
+        String::from_utf8(authcid.to_vec()).ok()?,
+        String::from_utf8(passwd.to_vec()).ok()?,
+    ))
+}
+
+// Parses the arguments of `BDAT <size> [LAST]` (RFC 3030) into the chunk size and LAST flag.
+fn parse_bdat_args(args: &str) -> Option<(usize, bool)> {
+    let mut parts = args.split_whitespace();
+    let size = parts.next()?.parse().ok()?;
+    let last = match parts.next() {
+        None => false,
+        Some(flag) if flag.eq_ignore_ascii_case("LAST") => true,
+        Some(_) => return None,
+    };
+    parts.next().is_none().then_some((size, last))
+}
+
+// Logs one side of the SMTP dialogue: at info with `LogVerbosity::Commands`, otherwise at
+// debug so busy servers only log transaction-level events at info.
+macro_rules! log_dialogue {
+    ($session:expr, $($arg:tt)+) => {
+        if $session.log_verbosity == LogVerbosity::Commands {
+            info!($($arg)+)
+        } else {
+            tracing::debug!($($arg)+)
+        }
+    };
+}
+
+// Writes raw reply bytes, giving up after the session's write timeout so a client that
+// stops reading can't hold the connection open forever.
+async fn write_reply_bytes(
+    stream: &mut io::WriteHalf<TcpStream>,
+    session: &SessionConfig,
+    bytes: &[u8],
+) -> Result<()> {
+    match tokio::time::timeout(session.write_timeout, stream.write_all(bytes)).await {
+        Ok(result) => Ok(result?),
+        Err(_) => {
+            warn!(
+                write_timeout_secs = session.write_timeout.as_secs_f64(),
+                "Timed out writing reply to client, closing connection"
+            );
+            Err(io::Error::from(io::ErrorKind::TimedOut).into())
+        }
+    }
 }
 
 // Writes a standard SMTP response line to the client stream.
 async fn write_response(
     stream: &mut io::WriteHalf<TcpStream>,
+    session: &SessionConfig,
     code: u16,
     text: &str,
 ) -> Result<()> {
     let response = format!("{code} {text}\r\n");
-    stream.write_all(response.as_bytes()).await?;
-    info!(client_response = %response.trim(), "Sent response");
+    write_reply_bytes(stream, session, response.as_bytes()).await?;
+    log_dialogue!(session, client_response = %Escaped(response.trim()), "Sent response");
     Ok(())
 }
 
+// Formats a status reply, inserting the RFC 3463 enhanced status code when enabled.
+fn format_status(enhanced_codes: bool, enhanced: &str, text: &str) -> String {
+    if enhanced_codes {
+        format!("{enhanced} {text}")
+    } else {
+        text.to_string()
+    }
+}
+
+// Writes a status reply that carries an enhanced status code (RFC 3463).
+async fn write_status(
+    stream: &mut io::WriteHalf<TcpStream>,
+    session: &SessionConfig,
+    code: u16,
+    enhanced: &str,
+    text: &str,
+) -> Result<()> {
+    let text = format_status(session.enhanced_status_codes, enhanced, text);
+    write_response(stream, session, code, &text).await
+}
+
+// Writes the reply a failed command maps to; the error defines the code and text.
+async fn write_error(
+    stream: &mut io::WriteHalf<TcpStream>,
+    session: &SessionConfig,
+    err: &SmtpError,
+) -> Result<()> {
+    let reply = err.reply();
+    write_status(stream, session, reply.code, reply.enhanced, reply.text).await
+}
+
+// Distinct, lowercased domains of the recipients.
+fn recipient_domains(recipients: &[String]) -> BTreeSet<String> {
+    recipients
+        .iter()
+        .filter_map(|r| r.rsplit_once('@'))
+        .map(|(_, domain)| domain.to_lowercase())
+        .collect()
+}
+
+// Emits a single structured audit event (target "audit") describing the outcome of one relayed message.
+fn audit_relay(
+    send_result: &Result<(), SmtpRelayError>,
+    trace: &MessageTrace<'_>,
+    summary: &MessageSummary,
+    transaction: &Transaction,
+    email_size: usize,
+    latency: Duration,
+) {
+    let (result, acs_status) = match send_result {
+        Ok(_) => ("success", "accepted".to_string()),
+        Err(e) => (
+            "failure",
+            match e {
+                SmtpRelayError::Acs(acs_error) => error::error_chain(acs_error),
+                _ => error::error_chain(e),
+            },
+        ),
+    };
+    info!(
+        target: "audit",
+        msg_id = %trace.msg_id,
+        client_helo = trace.client_helo.unwrap_or(""),
+        message_id = %summary.message_id,
+        subject = %summary.subject,
+        envelope_from = transaction.from.as_deref().unwrap_or(""),
+        recipient_count = summary.recipients.len(),
+        email_size,
+        result,
+        %acs_status,
+        latency_ms = latency.as_millis() as u64,
+        "Message relayed"
+    );
+}
+
+// Builds the RFC 5321 section 4.4 trace header recording this hop, e.g.
+// `Received: from client.example.com ([192.0.2.1])` by the relay `with ESMTP id <msg_id>`.
+// The client's hostname, when an upstream MTA forwarded one, goes before its address.
+fn received_header(
+    helo: Option<&str>,
+    client_name: Option<&str>,
+    protocol: &str,
+    peer_addr: &str,
+    server_name: &str,
+    msg_id: &Uuid,
+    date: chrono::DateTime<chrono::Utc>,
+) -> String {
+    let client_ip = peer_addr
+        .parse::<SocketAddr>()
+        .map_or_else(|_| peer_addr.to_string(), |addr| addr.ip().to_string());
+    headers::fold_header(
+        "Received",
+        &format!(
+            "from {helo} ({name}[{client_ip}]) by {server_name} with {protocol} id {msg_id}; {date}",
+            helo = helo.unwrap_or("unknown"),
+            name = client_name.map(|name| format!("{name} ")).unwrap_or_default(),
+            date = date.to_rfc2822(),
+        ),
+    )
+}
+
+// Whether the message's header section contains the named header field.
+fn has_header(email_data: &[u8], name: &str) -> bool {
+    email_data
+        .split(|&b| b == b'\n')
+        .take_while(|line| !line.is_empty() && *line != b"\r")
+        .any(|line| {
+            line.len() > name.len()
+                && line[..name.len()].eq_ignore_ascii_case(name.as_bytes())
+                && line[name.len()] == b':'
+        })
+}
+
+// Removes the named header fields, with any folded continuation lines, from the message's
+// header section. Names match case-insensitively; a trailing `*` matches a prefix.
+fn strip_headers(email_data: &mut Vec<u8>, names: &[String]) {
+    if names.is_empty() {
+        return;
+    }
+    let matches = |field: &[u8]| {
+        names.iter().any(|name| match name.strip_suffix('*') {
+            Some(prefix) => field
+                .get(..prefix.len())
+                .is_some_and(|start| start.eq_ignore_ascii_case(prefix.as_bytes())),
+            None => field.eq_ignore_ascii_case(name.as_bytes()),
+        })
+    };
+
+    let mut kept = Vec::with_capacity(email_data.len());
+    let mut stripping = false;
+    let mut stripped = false;
+    let mut pos = 0;
+    while pos < email_data.len() {
+        let end = email_data[pos..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(email_data.len(), |i| pos + i + 1);
+        let line = &email_data[pos..end];
+        // The blank line ends the header section; the body is left alone
+        if line == b"\r\n" || line == b"\n" {
+            break;
+        }
+        if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+            let field = line
+                .iter()
+                .position(|&b| b == b':')
+                .map(|colon| line[..colon].trim_ascii_end());
+            stripping = field.is_some_and(matches);
+        }
+        if stripping {
+            stripped = true;
+        } else {
+            kept.extend_from_slice(line);
+        }
+        pos = end;
+    }
+    if stripped {
+        kept.extend_from_slice(&email_data[pos..]);
+        *email_data = kept;
+    }
+}
+
+// Waits for a turn to receive a message when concurrent DATA transfers are limited
+async fn acquire_data_permit(session: &SessionConfig) -> Option<OwnedSemaphorePermit> {
+    let semaphore = session.data_transfers.as_ref()?;
+    if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+        return Some(permit);
+    }
+    info!("Concurrent DATA transfer limit reached, waiting for a slot");
+    semaphore.clone().acquire_owned().await.ok()
+}
+
+// Adds the headers the relay is responsible for before handing the message on: a Date
+// when the client omitted one, then the Received trace header on top.
+fn add_relay_headers(
+    email_data: &mut Vec<u8>,
+    session: &SessionConfig,
+    trace: &MessageTrace,
+    protocol: &str,
+    peer_addr: &str,
+) {
+    let now = chrono::Utc::now();
+    if session.date_header && !has_header(email_data, "Date") {
+        let header = headers::fold_header("Date", &now.to_rfc2822());
+        email_data.splice(0..0, header.into_bytes());
+    }
+    if session.received_header {
+        let forwarded_addr = trace.forwarded_addr.map(|addr| addr.to_string());
+        let header = received_header(
+            trace.client_helo,
+            trace.forwarded_name,
+            trace.forwarded_proto.unwrap_or(protocol),
+            forwarded_addr.as_deref().unwrap_or(peer_addr),
+            &session.server_name,
+            &trace.msg_id,
+            now,
+        );
+        email_data.splice(0..0, header.into_bytes());
+    }
+}
+
+// Log fields read from a received message's headers, with control characters escaped.
+struct MessageSummary {
+    subject: String,
+    message_id: String,
+    // The Message-ID as sent, for duplicate detection; message_id is escaped for logs
+    original_message_id: Option<String>,
+    header_count: usize,
+    // Size of the header section, including the blank line that ends it
+    header_bytes: usize,
+    // Addresses in the To, Cc and Bcc headers
+    header_recipients: Vec<String>,
+    // Everyone the message goes to: the envelope recipients, plus the header recipients
+    // when they are merged. Set once check_merged_recipients has passed.
+    recipients: Vec<String>,
+}
+
+impl MessageSummary {
+    // Parses the message as the client sent it, before relay headers are prepended, so
+    // data without a header section is rejected before it reaches the mailer. Only the
+    // header section is read here; the mailer still parses the whole message to build
+    // its payload.
+    fn parse(email_data: &[u8]) -> std::result::Result<Self, EmailError> {
+        let parsed = mail_parser::MessageParser::default()
+            .parse_headers(email_data)
+            .ok_or_else(|| EmailError::ParseFailed("no message headers found".to_string()))?;
+        Ok(Self {
+            subject: Escaped(parsed.subject().unwrap_or("N/A")).to_string(),
+            message_id: Escaped(parsed.message_id().unwrap_or("N/A")).to_string(),
+            original_message_id: parsed.message_id().map(str::to_string),
+            header_count: parsed.headers().len(),
+            header_bytes: (parsed.root_part().raw_body_offset()
+                - parsed.root_part().raw_header_offset()) as usize,
+            header_recipients: [parsed.to(), parsed.cc(), parsed.bcc()]
+                .into_iter()
+                .flatten()
+                .flat_map(|addresses| addresses.iter())
+                .filter_map(|addr| addr.address())
+                .map(str::to_string)
+                .collect(),
+            recipients: Vec::new(),
+        })
+    }
+
+    // Rejects messages whose headers exceed the session's limits
+    fn check_header_limits(&self, session: &SessionConfig) -> std::result::Result<(), EmailError> {
+        if self.header_count > session.max_header_count {
+            return Err(EmailError::TooManyHeaders(session.max_header_count));
+        }
+        if self.header_bytes > session.max_header_bytes {
+            return Err(EmailError::HeaderSectionTooLarge(session.max_header_bytes));
+        }
+        Ok(())
+    }
+
+    // When header recipients are merged into the envelope, holds each added address to the
+    // checks RCPT TO applies, and the merged list to the recipient limit. Returns the
+    // recipients the message goes to.
+    fn check_merged_recipients(
+        &self,
+        session: &SessionConfig,
+        transaction: &Transaction,
+        authenticated: bool,
+    ) -> std::result::Result<Vec<String>, SmtpError> {
+        let mut recipients = transaction.recipients.clone();
+        if !session.merge_header_recipients {
+            return Ok(recipients);
+        }
+        let mut merged: BTreeSet<String> = transaction
+            .recipients
+            .iter()
+            .map(|recipient| recipient.to_ascii_lowercase())
+            .collect();
+        for address in &self.header_recipients {
+            if !config::is_valid_email(address) {
+                // The mailer skips these, so they only matter when recipients are validated
+                if session.validate_recipients {
+                    return Err(SmtpError::InvalidRecipient(address.clone()));
+                }
+                continue;
+            }
+            if !merged.insert(address.to_ascii_lowercase()) {
+                continue;
+            }
+            if !session.may_relay_to(authenticated, address) {
+                return Err(SmtpError::RelayDenied(address.clone()));
+            }
+            if !address.is_ascii() && !transaction.smtputf8 {
+                return Err(SmtpError::SmtpUtf8Required(address.clone()));
+            }
+            recipients.push(address.clone());
+        }
+        if merged.len() > session.max_recipients {
+            return Err(SmtpError::TooManyRecipients(session.max_recipients));
+        }
+        Ok(recipients)
+    }
+}
+
+// Summarizes a received message for relaying, or rejects it with the reply its parse,
+// header-limit or merged-recipient error maps to. `Ok(None)` means the message was rejected.
+async fn summarize_or_reject(
+    write_half: &mut io::WriteHalf<TcpStream>,
+    session: &SessionConfig,
+    transaction: &Transaction,
+    authenticated: bool,
+    email_data: &[u8],
+) -> Result<Option<MessageSummary>> {
+    let summary = MessageSummary::parse(email_data)
+        .and_then(|summary| summary.check_header_limits(session).map(|()| summary));
+    let reply = match summary {
+        Ok(mut summary) => {
+            match summary.check_merged_recipients(session, transaction, authenticated) {
+                Ok(recipients) => {
+                    summary.recipients = recipients;
+                    return Ok(Some(summary));
+                }
+                Err(e) => {
+                    warn!(error = %e, "Rejecting message with header recipients");
+                    e.reply()
+                }
+            }
+        }
+        Err(e) => {
+            warn!(error = %e, "Rejecting message");
+            e.reply()
+        }
+    };
+    write_status(write_half, session, reply.code, reply.enhanced, reply.text).await?;
+    Ok(None)
+}
+
+// Identifies one message in logs: a `msg_id` unique per transaction (unlike the
+// connection's conn_id) and the identity the client claimed in EHLO/HELO.
+struct MessageTrace<'a> {
+    msg_id: Uuid,
+    client_helo: Option<&'a str>,
+    // The original client and protocol, when an upstream MTA forwarded them with XFORWARD
+    forwarded_addr: Option<IpAddr>,
+    forwarded_name: Option<&'a str>,
+    forwarded_proto: Option<&'a str>,
+}
+
+impl<'a> MessageTrace<'a> {
+    // Forwarded client details take the place of the connected peer's
+    fn new(msg_id: Uuid, client_helo: Option<&'a str>, xforward: &'a xforward::XForward) -> Self {
+        Self {
+            msg_id,
+            client_helo: xforward.helo.as_deref().or(client_helo),
+            forwarded_addr: xforward.addr,
+            forwarded_name: xforward.name.as_deref(),
+            forwarded_proto: xforward.proto.as_deref(),
+        }
+    }
+
+    // Span grouping every log line about the message
+    fn span(&self) -> tracing::Span {
+        let span = info_span!(
+            "message",
+            msg_id = %self.msg_id,
+            client_helo = self.client_helo.unwrap_or(""),
+            forwarded_addr = tracing::field::Empty,
+        );
+        if let Some(addr) = self.forwarded_addr {
+            span.record("forwarded_addr", tracing::field::display(addr));
+        }
+        span
+    }
+}
+
+// Why reading a DATA body stopped before the end-of-data marker.
+#[derive(Debug)]
+enum DataError {
+    Disconnected,
+    Io(io::Error),
+    Timeout,
+    // The message grew past the size limit; carries its full size. The rest of the body was
+    // read and discarded, so the session can go on.
+    TooLarge(usize),
+    // Like TooLarge, but the client was still sending after the drain limit; carries the size
+    // so far. The stream is out of sync, so the connection must be closed.
+    TooLargeToDrain(usize),
+}
+
+// Past the size limit, the rest of a DATA body is discarded so the session can go on, but only
+// up to this many times the limit (and at least MIN_DATA_DRAIN_BYTES)
+const DATA_DRAIN_FACTOR: usize = 4;
+const MIN_DATA_DRAIN_BYTES: usize = 1024 * 1024;
+
+// Reads a DATA body up to the `.` terminator, undoing dot-stuffing. Lines are gathered up to
+// their LF however the bytes arrive, so a terminator split across reads is still found.
+async fn read_data_body<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_email_size: usize,
+) -> Result<Vec<u8>, DataError> {
+    let mut email_data = Vec::new();
+    loop {
+        // Read raw bytes: 8-bit message content need not be valid UTF-8. A line may take up
+        // the rest of the limit plus a stuffed dot and CRLF; a longer one is over the limit
+        // and is not buffered any further.
+        let room = max_email_size
+            .saturating_sub(email_data.len())
+            .saturating_add(3);
+        let mut data_line = Vec::new();
+        match tokio::time::timeout(
+            Duration::from_secs(300),
+            (&mut *reader)
+                .take(room as u64)
+                .read_until(b'\n', &mut data_line),
+        )
+        .await
+        {
+            Ok(Ok(0)) => return Err(DataError::Disconnected),
+            Ok(Ok(n)) => {
+                let line_to_write = data_line.strip_prefix(b".").unwrap_or(&data_line);
+                let size = email_data.len() + line_to_write.len();
+                if !data_line.ends_with(b"\n") {
+                    // A line without its LF is only returned short at EOF: the client went
+                    // away mid-message, so what arrived must not be relayed
+                    if n < room {
+                        return Err(DataError::Disconnected);
+                    }
+                    return drain_data_body(reader, size, false, max_email_size).await;
+                }
+                // Only CRLF.CRLF ends the data. A bare-LF `.` line is kept as content:
+                // accepting it would let a client smuggle a second message past upstream
+                // servers that read the line endings differently.
+                if data_line == b".\r\n" {
+                    tracing::debug!("End of DATA marker found");
+                    return Ok(email_data);
+                }
+                // Count the message as stored (dot-unstuffed, without the terminator),
+                // the same size a client declares with SIZE=
+                if size > max_email_size {
+                    return drain_data_body(reader, size, true, max_email_size).await;
+                }
+                email_data.extend_from_slice(line_to_write);
+            }
+            Ok(Err(e)) => return Err(DataError::Io(e)),
+            Err(_) => return Err(DataError::Timeout),
+        }
+    }
+}
+
+// Discards the rest of a DATA body that is over the size limit, up to the terminator. The
+// bytes are scanned as they arrive rather than gathered into lines, so a client sending one
+// endless line holds no memory, and only a bounded amount is read. `size` is the message size
+// so far, and `line_start` whether the next byte begins a line.
+async fn drain_data_body<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    mut size: usize,
+    line_start: bool,
+    max_email_size: usize,
+) -> Result<Vec<u8>, DataError> {
+    // Where the drained bytes stand relative to a `.` line
+    #[derive(Clone, Copy)]
+    enum Position {
+        LineStart,
+        Dot,
+        DotCr,
+        InLine,
+    }
+    let drain_limit = max_email_size
+        .saturating_mul(DATA_DRAIN_FACTOR)
+        .max(MIN_DATA_DRAIN_BYTES);
+    let mut drained = 0usize;
+    let mut position = if line_start {
+        Position::LineStart
+    } else {
+        Position::InLine
+    };
+    loop {
+        let chunk = match tokio::time::timeout(Duration::from_secs(300), reader.fill_buf()).await {
+            Ok(Ok([])) => return Err(DataError::Disconnected),
+            Ok(Ok(chunk)) => chunk,
+            Ok(Err(e)) => return Err(DataError::Io(e)),
+            Err(_) => return Err(DataError::Timeout),
+        };
+        let mut consumed = 0;
+        let mut terminated = false;
+        for &byte in chunk {
+            consumed += 1;
+            // Bytes are counted as stored: a leading dot is dropped by dot-unstuffing
+            position = match (position, byte) {
+                (Position::LineStart, b'.') => Position::Dot,
+                (Position::Dot, b'\r') => Position::DotCr,
+                (Position::DotCr, b'\n') => {
+                    terminated = true;
+                    break;
+                }
+                (Position::DotCr, _) => {
+                    size += 2;
+                    Position::InLine
+                }
+                (_, b'\n') => {
+                    size += 1;
+                    Position::LineStart
+                }
+                (_, _) => {
+                    size += 1;
+                    Position::InLine
+                }
+            };
+        }
+        reader.consume(consumed);
+        if terminated {
+            tracing::debug!("End of DATA marker found");
+            return Err(DataError::TooLarge(size));
+        }
+        drained += consumed;
+        if drained > drain_limit {
+            return Err(DataError::TooLargeToDrain(size));
+        }
+    }
+}
+
+// Relays a fully received message and writes the final reply. Returns whether the message
+// was accepted (relayed or queued); an Err means the reply could not be written.
+async fn relay_message(
+    write_half: &mut io::WriteHalf<TcpStream>,
+    mailer: &dyn Mailer,
+    session: &SessionConfig,
+    transaction: &Transaction,
+    trace: &MessageTrace<'_>,
+    summary: &MessageSummary,
+    email_data: &[u8],
+) -> Result<bool> {
+    let subject = &summary.subject;
+    let message_id = &summary.message_id;
+
+    info!(email_size = email_data.len(), %subject, %message_id, "Received email data. Relaying...");
+
+    // Messages without a Message-ID can't be told apart from a resend, so are always relayed.
+    // The claim is released on any return below that doesn't keep it.
+    let dedup = session.dedup_cache.as_deref().zip(
+        summary
+            .original_message_id
+            .as_deref()
+            .map(|id| dedup::DedupCache::key(id, &transaction.recipients)),
+    );
+    let mut claim = None;
+    if let Some((cache, key)) = dedup {
+        claim = cache.try_claim(key);
+        if claim.is_none() {
+            info!(%subject, %message_id, "Duplicate message suppressed");
+            write_status(
+                write_half,
+                session,
+                250,
+                "2.0.0",
+                "Ok (duplicate suppressed)",
+            )
+            .await?;
+            return Ok(true);
+        }
+    }
+
+    // Like the dedup claim, the quota slot is given back unless the message is accepted
+    let mut quota_slot = None;
+    if let Some(quotas) = &session.sender_quotas {
+        let sender = transaction.from.as_deref().unwrap_or_default();
+        quota_slot = quotas.try_acquire(sender);
+        if quota_slot.is_none() {
+            warn!(sender = %Escaped(sender), %subject, %message_id, "Sender quota exceeded, deferring message");
+            write_error(write_half, session, &SmtpError::QuotaExceeded).await?;
+            return Ok(false);
+        }
+    }
+
+    if let Some(limiter) = &session.rate_limiter {
+        if !limiter.acquire().await {
+            warn!(%subject, %message_id, "Message rate limit exceeded, deferring message");
+            write_error(write_half, session, &SmtpError::RateLimited).await?;
+            return Ok(false);
+        }
+    }
+
+    // Past the max age the message is given up on before it is sent. A send is never cut
+    // short: it may already have reached ACS, and the reply must say what happened.
+    if let Some((max_age, started)) = session.message_max_age.zip(transaction.started) {
+        if started.elapsed() > max_age {
+            warn!(%subject, %message_id, age_ms = started.elapsed().as_millis() as u64, "Message max age reached before relay");
+            write_error(write_half, session, &SmtpError::MessageExpired).await?;
+            return Ok(false);
+        }
+    }
+
+    // Created before the first attempt so a queued retry is recognised as the same message
+    let repeatability = Repeatability::new();
+    let relay_started = Instant::now();
+    session.metrics.increment_emails_in_flight().await;
+    let send_result = mailer
+        .send_repeatable(
+            email_data,
+            &transaction.recipients,
+            &transaction.from,
+            &repeatability,
+        )
+        .await;
+    session.metrics.decrement_emails_in_flight().await;
+    for domain in recipient_domains(&summary.recipients) {
+        session
+            .metrics
+            .record_recipient_domain(&domain, send_result.is_ok())
+            .await;
+    }
+    audit_relay(
+        &send_result,
+        trace,
+        summary,
+        transaction,
+        email_data.len(),
+        relay_started.elapsed(),
+    );
+
+    let e = match send_result {
+        Ok(_) => {
+            info!(%subject, %message_id, "Successfully relayed email");
+            if let Some(claim) = claim {
+                claim.keep();
+            }
+            if let Some(slot) = quota_slot {
+                slot.keep();
+            }
+            write_status(
+                write_half,
+                session,
+                250,
+                "2.0.0",
+                &format!("Ok: queued {} bytes", email_data.len()),
+            )
+            .await?;
+            return Ok(true);
+        }
+        Err(e) => e,
+    };
+
+    // Transient failures are accepted and retried later when a queue is configured
+    #[cfg(feature = "queue")]
+    if let Some(queue) = session.queue.as_ref().filter(|_| !e.is_permanent()) {
+        warn!(error = ?e, %subject, %message_id, "Transient relay failure, queueing for retry");
+        match queue
+            .enqueue(
+                email_data,
+                &transaction.from,
+                &transaction.recipients,
+                &repeatability,
+                transaction.received_at(),
+            )
+            .await
+        {
+            Ok(_) => {
+                if let Some(claim) = claim {
+                    claim.keep();
+                }
+                if let Some(slot) = quota_slot {
+                    slot.keep();
+                }
+                write_status(
+                    write_half,
+                    session,
+                    250,
+                    "2.0.0",
+                    &format!("Ok: queued {} bytes for retry", email_data.len()),
+                )
+                .await?;
+                return Ok(true);
+            }
+            Err(queue_err) => {
+                error!(error = ?queue_err, %message_id, "Failed to queue message for retry");
+            }
+        }
+    }
+    error!(error = ?e, %subject, %message_id, "Failed to relay email");
+    if let Some(dir) = &session.dead_letter_dir {
+        if e.is_permanent() {
+            if let Err(spool_err) = spool::write_dead_letter(
+                dir,
+                email_data,
+                &transaction.from,
+                &transaction.recipients,
+                &error::error_chain(&e),
+            )
+            .await
+            {
+                error!(error = ?spool_err, %message_id, "Failed to write message to dead-letter spool");
+            }
+        }
+    }
+    let reply = e.reply();
+    write_status(write_half, session, reply.code, reply.enhanced, reply.text).await?;
+    Ok(false)
+}
+
 // Handles a single, complete client TCP connection, processing one or more SMTP transactions.
 #[instrument(
     skip_all,
     name = "handle_connection",
     fields(
-        peer_addr = %stream.peer_addr().map_or_else(|_| "unknown".to_string(), |a| a.to_string()),
+        peer_addr = tracing::field::Empty,
         conn_id = %nanoid::nanoid!(8)
     )
 )]
 pub async fn handle_connection(
     stream: TcpStream,
     mailer: Arc<dyn Mailer>,
-    max_email_size: usize,
-    server_name: String,
+    session: Arc<SessionConfig>,
 ) {
-    info!("New client connection");
+    let mut peer_addr = stream
+        .peer_addr()
+        .map_or_else(|_| "unknown".to_string(), |a| a.to_string());
     let (read_half, mut write_half) = io::split(stream);
     let mut reader = BufReader::new(read_half);
     let mut line = String::new();
 
-    if write_response(&mut write_half, 220, &format!("{server_name} ESMTP ready"))
-        .await
-        .is_err()
+    // Behind a load balancer, the PROXY header carries the real client address.
+    // Fail closed: a missing or malformed header drops the connection.
+    if session.proxy_protocol {
+        let mut limited = (&mut reader).take(proxy::MAX_V1_HEADER_LEN as u64);
+        match tokio::time::timeout(session.connection_timeout, limited.read_line(&mut line)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(_)) => {
+                warn!(%peer_addr, "Failed to read PROXY protocol header, closing connection");
+                return;
+            }
+            Err(_) => {
+                warn!(%peer_addr, "Timed out waiting for PROXY protocol header, closing connection");
+                return;
+            }
+        }
+        match proxy::parse_v1_header(&line) {
+            Some(proxy::ProxyHeader::Tcp { source, .. }) => {
+                tracing::debug!(balancer_addr = %peer_addr, client_addr = %source, "Parsed PROXY protocol header");
+                peer_addr = source.to_string();
+            }
+            Some(proxy::ProxyHeader::Unknown) => {
+                tracing::debug!(%peer_addr, "PROXY protocol header did not include client address");
+            }
+            None => {
+                warn!(%peer_addr, header = %Escaped(line.trim()), "Malformed PROXY protocol header, closing connection");
+                return;
+            }
+        }
+    }
+    tracing::Span::current().record("peer_addr", tracing::field::display(&peer_addr));
+    // Only allow-listed upstream MTAs may speak for their clients with XFORWARD
+    let xforward_allowed = peer_addr
+        .parse::<SocketAddr>()
+        .is_ok_and(|addr| session.xforward_peers.contains(&addr.ip()));
+
+    info!("New client connection");
+
+    // Legitimate clients wait for the banner; bots that pipeline ahead of it are dropped
+    if let Some(delay) = session.greet_delay {
+        match tokio::time::timeout(delay, reader.fill_buf()).await {
+            Err(_) => {}
+            Ok(Ok([])) | Ok(Err(_)) => {
+                info!("Client disconnected before greeting");
+                return;
+            }
+            Ok(Ok(early)) => {
+                warn!(
+                    early_bytes = early.len(),
+                    "Client sent data before greeting, closing connection"
+                );
+                let _ = write_error(&mut write_half, &session, &SmtpError::EarlyTalker).await;
+                return;
+            }
+        }
+    }
+
+    if write_response(
+        &mut write_half,
+        &session,
+        220,
+        &format!("{} ESMTP ready", session.server_name),
+    )
+    .await
+    .is_err()
     {
         error!("Failed to send initial 220 response, closing connection.");
         return;
     }
 
     let mut transaction = Transaction::default();
+    // Name the client gave in EHLO/HELO and the protocol that implies, for the Received header
+    let mut client_helo: Option<String> = None;
+    let mut protocol = "SMTP";
+    // Message assembled from BDAT chunks so far in the current transaction, and its msg_id.
+    let mut chunked_data: Vec<u8> = Vec::new();
+    let mut chunked_msg_id: Option<Uuid> = None;
+    // Held from the first BDAT chunk until the message is relayed or abandoned
+    let mut chunked_permit: Option<OwnedSemaphorePermit> = None;
+    // Commands received since the last successfully relayed message.
+    let mut command_count: usize = 0;
+    // Per-connection totals for the disconnect summary
+    let mut messages_relayed: u64 = 0;
+    let mut bytes_received: u64 = 0;
+    // Set by a successful AUTH; lifts the limits configured for unauthenticated clients
+    let mut authenticated = false;
+    // Set by EHLO/HELO, which must open the session (RFC 5321 4.1.4)
+    let mut greeted = false;
+    // XFORWARD attributes received since the last transaction, for the next one
+    let mut xforward = xforward::XForward::default();
+    let mut raw_line = Vec::new();
     loop {
+        // Read raw bytes so a non-UTF-8 command is answered as unrecognized instead of
+        // failing the read and dropping the connection
+        raw_line.clear();
+        let read = reader.read_until(b'\n', &mut raw_line).await;
         line.clear();
-        match reader.read_line(&mut line).await {
+        line.push_str(&String::from_utf8_lossy(&raw_line));
+        match read {
             Ok(0) => {
-                info!("Client disconnected cleanly (EOF)");
+                info!(
+                    messages_relayed,
+                    bytes_received, "Client disconnected cleanly (EOF)"
+                );
                 return;
             }
             Ok(_) => {
-                let cmd = line.trim().to_uppercase();
-                tracing::debug!(raw_command = %line.trim(), "Received command");
+                // Only the verb is case-insensitive; arguments keep the client's original case
+                let (verb, args) = split_command(line.trim());
+                let max_email_size = session.max_email_size_for(authenticated);
+                log_dialogue!(session, raw_command = %Escaped(line.trim()), "Received command");
+
+                // A BDAT chunk that adds to the message is bounded by max_email_size instead;
+                // malformed, rejected and empty ones carry nothing and count like any other
+                let carries_content = verb == "BDAT"
+                    && greeted
+                    && transaction.check_ready().is_ok()
+                    && parse_bdat_args(args).is_some_and(|(chunk_size, _)| chunk_size > 0);
+                if !carries_content {
+                    command_count += 1;
+                }
+                if command_count > session.max_commands {
+                    warn!(
+                        command_count,
+                        max_commands = session.max_commands,
+                        "Too many commands without a message, closing connection"
+                    );
+                    let _ =
+                        write_status(&mut write_half, &session, 421, "4.7.0", "Too many commands")
+                            .await;
+                    return;
+                }
+
+                // BDAT is turned away once its chunk is consumed, to keep the stream in sync
+                if !greeted && !matches!(verb.as_str(), "EHLO" | "HELO" | "QUIT" | "NOOP" | "BDAT")
+                {
+                    warn!(command = %Escaped(&verb), "Command received before EHLO/HELO");
+                    if write_error(&mut write_half, &session, &SmtpError::MissingGreeting)
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                    continue;
+                }
 
-                // RFC-compliant EHLO/HELO/AUTH/NOOP/RSET handling
-                if cmd.starts_with("EHLO") {
+                // RFC-compliant EHLO/HELO/AUTH/NOOP/RSET handling. Commands are read and
+                // answered one at a time, so pipelined commands (RFC 2920) get their replies
+                // in order.
+                if verb == "EHLO" {
+                    greeted = true;
+                    client_helo = args.split_whitespace().next().map(str::to_string);
+                    protocol = "ESMTP";
+                    let xforward_line = if xforward_allowed {
+                        format!("250-XFORWARD {}\r\n", xforward::EHLO_KEYWORDS)
+                    } else {
+                        String::new()
+                    };
                     let ehlo_response = format!(
                         "250-{server_name}\r\n\
+{xforward_line}\
 250-AUTH PLAIN\r\n\
 250-SIZE {max_email_size}\r\n\
-250 HELP"
+250-SMTPUTF8\r\n\
+250-CHUNKING\r\n\
+250-PIPELINING\r\n\
+250 HELP",
+                        server_name = session.server_name,
                     );
                     let response = format!("{ehlo_response}\r\n");
-                    if write_half.write_all(response.as_bytes()).await.is_err() {
+                    if write_reply_bytes(&mut write_half, &session, response.as_bytes())
+                        .await
+                        .is_err()
+                    {
                         return;
                     }
-                    info!(client_response = %ehlo_response.replace("\r\n", " | "), "Sent EHLO response");
-                } else if cmd.starts_with("HELO") {
-                    if write_response(&mut write_half, 250, &server_name)
+                    log_dialogue!(session, client_response = %ehlo_response.replace("\r\n", " | "), "Sent EHLO response");
+                } else if verb == "HELO" {
+                    greeted = true;
+                    client_helo = args.split_whitespace().next().map(str::to_string);
+                    protocol = "SMTP";
+                    if write_response(&mut write_half, &session, 250, &session.server_name)
                         .await
                         .is_err()
                     {
                         return;
                     }
-                } else if cmd.starts_with("AUTH") {
+                } else if verb == "AUTH" && (authenticated || transaction.from.is_some()) {
+                    // RFC 4954 section 4: once authenticated, or within a mail transaction,
+                    // AUTH is refused before any challenge is sent
+                    warn!(authenticated, "AUTH received out of sequence");
+                    let err = SmtpError::InvalidSequence(if authenticated {
+                        "AUTH after successful AUTH".to_string()
+                    } else {
+                        "AUTH during mail transaction".to_string()
+                    });
+                    if write_error(&mut write_half, &session, &err).await.is_err() {
+                        return;
+                    }
+                } else if verb == "AUTH" {
                     // SECURITY NOTE:
-                    // This SMTP server advertises and accepts AUTH PLAIN for compatibility with clients and RFC compliance.
-                    // However, it does NOT validate or check the provided credentials in any way.
-                    // Any username/password is accepted and the server always responds with 235 Authentication successful.
-                    // This is intentional: authentication and access control are expected to be enforced at the network level
-                    // (e.g., via Kubernetes NetworkPolicy, firewalls, or private VPC endpoints). Do NOT expose this server to untrusted networks.
+                    // Credentials are only checked when AUTH_CREDENTIALS is configured. Without it any username/password
+                    // is accepted with 235 Authentication successful, and access control is expected to be enforced at the
+                    // network level (e.g., via Kubernetes NetworkPolicy, firewalls, or private VPC endpoints). Do NOT expose
+                    // such a server to untrusted networks.
                     tracing::debug!("Handling AUTH command");
-                    if cmd.starts_with("AUTH PLAIN") {
-                        // Two-step: "AUTH PLAIN"
-                        if cmd == "AUTH PLAIN" {
-                            if write_response(&mut write_half, 334, "").await.is_err() {
-                                return;
+                    let mut auth_args = args.split_whitespace();
+                    let mechanism = auth_args.next().unwrap_or("").to_ascii_uppercase();
+                    if mechanism == "PLAIN" {
+                        // One-step: the initial response follows the mechanism (RFC 4954)
+                        let initial_response = auth_args.next().map(str::to_string);
+                        let response = match initial_response {
+                            Some(response) => response,
+                            // Two-step: "AUTH PLAIN" without an initial response
+                            None => {
+                                if write_response(&mut write_half, &session, 334, "")
+                                    .await
+                                    .is_err()
+                                {
+                                    return;
+                                }
+                                line.clear();
+                                match reader.read_line(&mut line).await {
+                                    Ok(0) | Err(_) => return,
+                                    Ok(_) => {}
+                                }
+                                tracing::debug!("Received AUTH PLAIN payload after challenge.");
+                                line.trim().to_string()
                             }
-                            line.clear();
-                            if reader.read_line(&mut line).await.is_err() {
+                        };
+                        if response == "*" {
+                            tracing::debug!("Client cancelled AUTH PLAIN");
+                            if write_status(
+                                &mut write_half,
+                                &session,
+                                501,
+                                "5.7.0",
+                                "Authentication cancelled",
+                            )
+                            .await
+                            .is_err()
+                            {
                                 return;
                             }
-                            tracing::debug!("Received AUTH PLAIN payload after challenge.");
+                            continue;
                         }
-                        // For both one-step and two-step, accept the auth
-                        if write_response(&mut write_half, 235, "Authentication successful")
+                        let Some((username, password)) = decode_auth_plain(&response) else {
+                            warn!("Malformed AUTH PLAIN response");
+                            if write_status(
+                                &mut write_half,
+                                &session,
+                                501,
+                                "5.5.2",
+                                "Cannot decode AUTH PLAIN response",
+                            )
                             .await
                             .is_err()
-                        {
-                            return;
-                        }
-                    } else {
-                        warn!(auth_command=%cmd, "Unsupported AUTH mechanism offered by client");
-                        if write_response(&mut write_half, 504, "Unsupported authentication type")
+                            {
+                                return;
+                            }
+                            continue;
+                        };
+                        if !session.auth_credentials.verify(&username, &password) {
+                            warn!(auth_user = %Escaped(&username), "Rejected AUTH PLAIN credentials");
+                            if write_status(
+                                &mut write_half,
+                                &session,
+                                535,
+                                "5.7.8",
+                                "Authentication credentials invalid",
+                            )
                             .await
                             .is_err()
-                        {
-                            return;
-                        }
-                    }
-                } else if cmd.starts_with("MAIL FROM:") {
-                    transaction = Transaction::default(); // Start new transaction
-                    let from_addr = line.trim()[10..].trim();
-                    transaction.from =
-                        Some(from_addr.trim_matches(|c| c == '<' || c == '>').to_string());
-                    tracing::debug!(?transaction, "Started new transaction");
-                    if write_response(&mut write_half, 250, "OK").await.is_err() {
+                            {
+                                return;
+                            }
+                            continue;
+                        }
+                        tracing::debug!(auth_user = %Escaped(&username), "Accepted AUTH PLAIN");
+                        authenticated = true;
+                        if write_status(
+                            &mut write_half,
+                            &session,
+                            235,
+                            "2.7.0",
+                            "Authentication successful",
+                        )
+                        .await
+                        .is_err()
+                        {
+                            return;
+                        }
+                    } else {
+                        warn!(auth_mechanism = %Escaped(&mechanism), "Unsupported AUTH mechanism offered by client");
+                        if write_status(
+                            &mut write_half,
+                            &session,
+                            504,
+                            "5.5.4",
+                            "Unsupported authentication type",
+                        )
+                        .await
+                        .is_err()
+                        {
+                            return;
+                        }
+                    }
+                } else if verb == "XFORWARD" && xforward_allowed {
+                    // Attributes describe the client of the next transaction, not one under way
+                    let result = if transaction.from.is_some() {
+                        Err(SmtpError::InvalidSequence(
+                            "XFORWARD during transaction".to_string(),
+                        ))
+                    } else {
+                        xforward
+                            .apply(args)
+                            .ok_or_else(|| SmtpError::InvalidArguments(line.trim().to_string()))
+                    };
+                    match result {
+                        Ok(()) => {
+                            info!(
+                                forwarded_addr = %xforward.addr.map_or_else(String::new, |addr| addr.to_string()),
+                                forwarded_helo = xforward.helo.as_deref().unwrap_or(""),
+                                "Accepted XFORWARD attributes"
+                            );
+                            if write_status(&mut write_half, &session, 250, "2.0.0", "Ok")
+                                .await
+                                .is_err()
+                            {
+                                return;
+                            }
+                        }
+                        Err(err) => {
+                            warn!(command = %Escaped(line.trim()), "Rejected XFORWARD command");
+                            if write_error(&mut write_half, &session, &err).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                } else if let Some(from_path) =
+                    parse_envelope_path(args, "FROM").filter(|_| verb == "MAIL")
+                {
+                    // RFC 3030: a BDAT transfer ends with BDAT LAST or RSET, not a new MAIL
+                    if chunked_msg_id.is_some() {
+                        warn!(?transaction, "MAIL FROM received during BDAT transfer");
+                        let err = SmtpError::InvalidSequence("MAIL during BDAT".to_string());
+                        if write_error(&mut write_half, &session, &err).await.is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                    // Transactions already under way are finished; new ones are turned away
+                    if session.paused.load(Ordering::Relaxed) {
+                        warn!("Mail acceptance paused, refusing new transaction");
+                        let _ =
+                            write_error(&mut write_half, &session, &SmtpError::NotAcceptingMail)
+                                .await;
                         return;
                     }
-                } else if cmd.starts_with("RCPT TO:") {
+                    transaction = Transaction::default(); // Start new transaction
+                    let from_addr = from_path.address;
+                    transaction.smtputf8 = from_path.smtputf8;
+                    tracing::debug!(declared_size = ?from_path.size, body = ?from_path.body, "MAIL FROM parameters");
+                    if from_path.size.is_some_and(|size| size > max_email_size) {
+                        warn!(
+                            declared_size = ?from_path.size,
+                            max_size = max_email_size,
+                            "Declared message size exceeds maximum limit"
+                        );
+                        transaction = Transaction::default();
+                        let err = SmtpError::MessageTooLarge(
+                            from_path.size.unwrap_or_default(),
+                            max_email_size,
+                        );
+                        if write_error(&mut write_half, &session, &err).await.is_err() {
+                            return;
+                        }
+                    } else if !from_addr.is_ascii() && !transaction.smtputf8 {
+                        warn!(from = %Escaped(from_addr), "Non-ASCII sender without SMTPUTF8");
+                        transaction = Transaction::default();
+                        let err = SmtpError::SmtpUtf8Required(from_addr.to_string());
+                        if write_error(&mut write_half, &session, &err).await.is_err() {
+                            return;
+                        }
+                    } else {
+                        transaction.from = Some(from_addr.to_string());
+                        transaction.xforward = std::mem::take(&mut xforward);
+                        transaction.started = Some(Instant::now());
+                        tracing::debug!(?transaction, "Started new transaction");
+                        if write_status(&mut write_half, &session, 250, "2.1.0", "Ok")
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                } else if let Some(rcpt_path) =
+                    // Only MAIL FROM may carry the null path `<>`
+                    parse_envelope_path(args, "TO")
+                        .filter(|path| verb == "RCPT" && !path.address.is_empty())
+                {
+                    let rcpt_addr = rcpt_path.address;
                     if transaction.from.is_none() {
                         warn!(?transaction, "RCPT TO received before MAIL FROM");
-                        let _ =
-                            write_response(&mut write_half, 503, "Bad sequence of commands").await;
-                        return;
+                        if write_error(&mut write_half, &session, &SmtpError::MissingFrom)
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+                    } else if transaction.recipients.len() >= session.max_recipients {
+                        warn!(
+                            recipient_count = transaction.recipients.len(),
+                            max_recipients = session.max_recipients,
+                            "Recipient limit reached, rejecting RCPT TO"
+                        );
+                        let err = SmtpError::TooManyRecipients(session.max_recipients);
+                        if write_error(&mut write_half, &session, &err).await.is_err() {
+                            return;
+                        }
+                    } else if session.validate_recipients && !config::is_valid_email(rcpt_addr) {
+                        warn!(recipient = %Escaped(rcpt_addr), "Rejecting malformed recipient address");
+                        let err = SmtpError::InvalidRecipient(rcpt_addr.to_string());
+                        if write_error(&mut write_half, &session, &err).await.is_err() {
+                            return;
+                        }
+                    } else if !session.may_relay_to(authenticated, rcpt_addr) {
+                        warn!(recipient = %Escaped(rcpt_addr), "Rejecting relay from unauthenticated client");
+                        let err = SmtpError::RelayDenied(rcpt_addr.to_string());
+                        if write_error(&mut write_half, &session, &err).await.is_err() {
+                            return;
+                        }
+                    } else if !rcpt_addr.is_ascii() && !transaction.smtputf8 {
+                        warn!(recipient = %Escaped(rcpt_addr), "Non-ASCII recipient without SMTPUTF8");
+                        let err = SmtpError::SmtpUtf8Required(rcpt_addr.to_string());
+                        if write_error(&mut write_half, &session, &err).await.is_err() {
+                            return;
+                        }
                     } else {
-                        let rcpt_addr = line.trim()[8..].trim();
-                        transaction
-                            .recipients
-                            .push(rcpt_addr.trim_matches(|c| c == '<' || c == '>').to_string());
+                        transaction.recipients.push(rcpt_addr.to_string());
                         tracing::debug!(?transaction, "Added recipient");
-                        if write_response(&mut write_half, 250, "OK").await.is_err() {
+                        if write_status(&mut write_half, &session, 250, "2.1.5", "Ok")
+                            .await
+                            .is_err()
+                        {
                             return;
                         }
                     }
-                } else if cmd == "DATA" {
-                    if transaction.from.is_none() || transaction.recipients.is_empty() {
-                        warn!(?transaction, "DATA received with incomplete transaction");
-                        let _ =
-                            write_response(&mut write_half, 503, "Bad sequence of commands").await;
-                        return;
-                    }
-
-                    if write_response(&mut write_half, 354, "End data with <CR><LF>.<CR><LF>")
-                        .await
-                        .is_err()
-                    {
+                } else if verb == "MAIL" || verb == "RCPT" {
+                    warn!(command = %Escaped(line.trim()), "Malformed MAIL/RCPT command");
+                    let err = SmtpError::InvalidArguments(line.trim().to_string());
+                    if write_error(&mut write_half, &session, &err).await.is_err() {
                         return;
                     }
+                } else if verb == "BDAT" {
+                    let Some((chunk_size, last)) = parse_bdat_args(args) else {
+                        warn!(command = %Escaped(line.trim()), "Malformed BDAT command");
+                        let err = SmtpError::InvalidArguments(line.trim().to_string());
+                        if write_error(&mut write_half, &session, &err).await.is_err() {
+                            return;
+                        }
+                        continue;
+                    };
 
-                    let mut email_data = Vec::new();
-                    loop {
-                        let mut data_line = String::new();
+                    // The chunk follows the command regardless of whether it is accepted, so an
+                    // unwanted chunk is still consumed to keep the stream in sync.
+                    let rejection = if !greeted {
+                        warn!("BDAT received before EHLO/HELO");
+                        Some(SmtpError::MissingGreeting)
+                    } else if let Err(err) = transaction.check_ready() {
+                        warn!(?transaction, "BDAT received with incomplete transaction");
+                        Some(err)
+                    } else {
+                        let size = chunked_data.len().saturating_add(chunk_size);
+                        (size > max_email_size).then(|| {
+                            error!(
+                                size,
+                                max_size = max_email_size,
+                                "Email size exceeds maximum limit"
+                            );
+                            SmtpError::MessageTooLarge(size, max_email_size)
+                        })
+                    };
+                    if let Some(err) = rejection {
+                        let mut chunk = (&mut reader).take(chunk_size as u64);
                         match tokio::time::timeout(
                             Duration::from_secs(300),
-                            reader.read_line(&mut data_line),
+                            io::copy(&mut chunk, &mut io::sink()),
                         )
                         .await
                         {
-                            Ok(Ok(0)) => {
-                                info!("Client disconnected during DATA");
-                                return;
-                            }
-                            Ok(Ok(_)) => {
-                                if email_data.len() + data_line.len() > max_email_size {
-                                    error!(
-                                        size = email_data.len(),
-                                        max_size = max_email_size,
-                                        "Email size exceeds maximum limit"
-                                    );
-                                    let _ = write_response(&mut write_half, 552, "Requested mail action aborted: exceeded storage allocation").await;
-                                    return; // Abort connection on oversize
-                                }
-                                if data_line == ".\r\n" {
-                                    tracing::debug!("End of DATA marker found");
-                                    break;
-                                }
-                                let line_to_write =
-                                    if let Some(stripped) = data_line.strip_prefix('.') {
-                                        stripped
-                                    } else {
-                                        &data_line
-                                    };
-                                email_data.extend_from_slice(line_to_write.as_bytes());
-                            }
-                            Ok(Err(e)) => {
-                                error!(error = ?e, "Error reading email data");
-                                return;
-                            }
-                            Err(_) => {
-                                warn!("Timeout while reading email data");
+                            Ok(Ok(n)) if n == chunk_size as u64 => {}
+                            _ => {
+                                info!("Client disconnected or timed out during BDAT");
                                 return;
                             }
                         }
+                        if matches!(err, SmtpError::MessageTooLarge(..)) {
+                            transaction = Transaction::default();
+                        }
+                        chunked_data.clear();
+                        chunked_msg_id = None;
+                        chunked_permit = None;
+                        if write_error(&mut write_half, &session, &err).await.is_err() {
+                            return;
+                        }
+                        continue;
                     }
 
+                    if chunked_permit.is_none() {
+                        chunked_permit = acquire_data_permit(&session).await;
+                    }
+                    // The message, and its span, begin with the first chunk
+                    let trace = MessageTrace::new(
+                        *chunked_msg_id.get_or_insert_with(Uuid::new_v4),
+                        client_helo.as_deref(),
+                        &transaction.xforward,
+                    );
+                    let span = trace.span();
+                    let start = chunked_data.len();
+                    chunked_data.resize(start + chunk_size, 0);
+                    let read = tokio::time::timeout(
+                        Duration::from_secs(300),
+                        reader.read_exact(&mut chunked_data[start..]),
+                    )
+                    .instrument(span.clone())
+                    .await;
+                    let entered = span.enter();
+                    match read {
+                        Ok(Ok(_)) => {}
+                        Ok(Err(e)) => {
+                            info!(error = ?e, "Client disconnected during BDAT");
+                            return;
+                        }
+                        Err(_) => {
+                            warn!("Timeout while reading BDAT chunk");
+                            return;
+                        }
+                    }
                     tracing::debug!(
-                        email_size = email_data.len(),
-                        "Finished receiving email data. Relaying..."
+                        chunk_size,
+                        last,
+                        total = chunked_data.len(),
+                        "Received BDAT chunk"
                     );
+                    drop(entered);
+
+                    if !last {
+                        if write_status(
+                            &mut write_half,
+                            &session,
+                            250,
+                            "2.0.0",
+                            &format!("Ok: {chunk_size} octets received"),
+                        )
+                        .await
+                        .is_err()
+                        {
+                            return;
+                        }
+                        continue;
+                    }
 
-                    let parsed_email = mail_parser::MessageParser::default().parse(&email_data);
-                    let subject = parsed_email
-                        .as_ref()
-                        .and_then(|p| p.subject())
-                        .unwrap_or("N/A");
-                    let message_id = parsed_email
-                        .as_ref()
-                        .and_then(|p| p.message_id())
-                        .unwrap_or("N/A");
+                    let mut email_data = std::mem::take(&mut chunked_data);
+                    chunked_msg_id = None;
+                    let _data_permit = chunked_permit.take();
+                    bytes_received += email_data.len() as u64;
+                    session
+                        .metrics
+                        .add_bytes_received(email_data.len() as u64)
+                        .await;
+                    span.in_scope(|| {
+                        tracing::debug!(
+                            email_size = email_data.len(),
+                            "Finished receiving chunked email data. Relaying..."
+                        )
+                    });
+                    let summary = match summarize_or_reject(
+                        &mut write_half,
+                        &session,
+                        &transaction,
+                        authenticated,
+                        &email_data,
+                    )
+                    .instrument(span.clone())
+                    .await
+                    {
+                        Ok(Some(summary)) => summary,
+                        Ok(None) => {
+                            transaction = Transaction::default();
+                            continue;
+                        }
+                        Err(_) => return,
+                    };
+                    strip_headers(&mut email_data, &session.strip_headers);
+                    add_relay_headers(&mut email_data, &session, &trace, protocol, &peer_addr);
+                    match relay_message(
+                        &mut write_half,
+                        mailer.as_ref(),
+                        &session,
+                        &transaction,
+                        &trace,
+                        &summary,
+                        &email_data,
+                    )
+                    .instrument(span)
+                    .await
+                    {
+                        Ok(true) => {
+                            command_count = 0;
+                            messages_relayed += 1;
+                        }
+                        Ok(false) => {}
+                        Err(_) => return,
+                    }
+                    transaction = Transaction::default();
+                } else if verb == "DATA" {
+                    // RFC 3030: DATA cannot follow BDAT within the same transaction
+                    let ready = transaction
+                        .check_ready()
+                        .and_then(|_| match chunked_msg_id {
+                            Some(_) => {
+                                Err(SmtpError::InvalidSequence("DATA after BDAT".to_string()))
+                            }
+                            None => Ok(()),
+                        });
+                    if let Err(err) = ready {
+                        warn!(?transaction, "DATA received with incomplete transaction");
+                        if write_error(&mut write_half, &session, &err).await.is_err() {
+                            return;
+                        }
+                        continue;
+                    }
 
-                    info!(email_size = email_data.len(), %subject, %message_id, "Received email data. Relaying...");
+                    // Held until the message is relayed
+                    let _data_permit = acquire_data_permit(&session).await;
+                    if write_response(
+                        &mut write_half,
+                        &session,
+                        354,
+                        "End data with <CR><LF>.<CR><LF>",
+                    )
+                    .await
+                    .is_err()
+                    {
+                        return;
+                    }
 
-                    match mailer
-                        .send(&email_data, &transaction.recipients, &transaction.from)
+                    let trace = MessageTrace::new(
+                        Uuid::new_v4(),
+                        client_helo.as_deref(),
+                        &transaction.xforward,
+                    );
+                    let span = trace.span();
+                    let mut email_data = match read_data_body(&mut reader, max_email_size)
+                        .instrument(span.clone())
                         .await
                     {
-                        Ok(_) => {
-                            info!(%subject, %message_id, "Successfully relayed email");
-                            if write_response(&mut write_half, 250, "OK: Queued for delivery")
-                                .await
-                                .is_err()
-                            {
+                        Ok(email_data) => email_data,
+                        Err(DataError::TooLarge(size)) => {
+                            span.in_scope(|| {
+                                error!(
+                                    size,
+                                    max_size = max_email_size,
+                                    "Email size exceeds maximum limit"
+                                )
+                            });
+                            let err = SmtpError::MessageTooLarge(size, max_email_size);
+                            if write_error(&mut write_half, &session, &err).await.is_err() {
                                 return;
                             }
+                            // The body was drained, so the client may start a new transaction
+                            transaction = Transaction::default();
+                            continue;
                         }
-                        Err(e) => {
-                            error!(error = ?e, %subject, %message_id, "Failed to relay email");
-                            if write_response(
-                                &mut write_half,
-                                451,
-                                "Failed to relay email to Azure Communication Services",
-                            )
-                            .await
-                            .is_err()
-                            {
-                                return;
-                            }
+                        Err(DataError::TooLargeToDrain(size)) => {
+                            span.in_scope(|| {
+                                error!(
+                                    size,
+                                    max_size = max_email_size,
+                                    "Email size exceeds maximum limit and the client kept sending, closing connection"
+                                )
+                            });
+                            let err = SmtpError::MessageTooLarge(size, max_email_size);
+                            let _ = write_error(&mut write_half, &session, &err).await;
+                            return;
+                        }
+                        Err(DataError::Disconnected) => {
+                            span.in_scope(|| info!("Client disconnected during DATA"));
+                            return;
+                        }
+                        Err(DataError::Io(e)) => {
+                            span.in_scope(|| error!(error = ?e, "Error reading email data"));
+                            return;
+                        }
+                        Err(DataError::Timeout) => {
+                            span.in_scope(|| warn!("Timeout while reading email data"));
+                            return;
+                        }
+                    };
+
+                    bytes_received += email_data.len() as u64;
+                    session
+                        .metrics
+                        .add_bytes_received(email_data.len() as u64)
+                        .await;
+                    span.in_scope(|| {
+                        tracing::debug!(
+                            email_size = email_data.len(),
+                            "Finished receiving email data. Relaying..."
+                        )
+                    });
+                    let summary = match summarize_or_reject(
+                        &mut write_half,
+                        &session,
+                        &transaction,
+                        authenticated,
+                        &email_data,
+                    )
+                    .instrument(span.clone())
+                    .await
+                    {
+                        Ok(Some(summary)) => summary,
+                        Ok(None) => {
+                            transaction = Transaction::default();
+                            continue;
+                        }
+                        Err(_) => return,
+                    };
+                    strip_headers(&mut email_data, &session.strip_headers);
+                    add_relay_headers(&mut email_data, &session, &trace, protocol, &peer_addr);
+                    match relay_message(
+                        &mut write_half,
+                        mailer.as_ref(),
+                        &session,
+                        &transaction,
+                        &trace,
+                        &summary,
+                        &email_data,
+                    )
+                    .instrument(span)
+                    .await
+                    {
+                        Ok(true) => {
+                            command_count = 0;
+                            messages_relayed += 1;
                         }
+                        Ok(false) => {}
+                        Err(_) => return,
                     }
                     transaction = Transaction::default(); // Reset for next email
-                } else if cmd == "QUIT" {
-                    tracing::debug!("Client sent QUIT");
-                    let _ = write_response(&mut write_half, 221, "Bye").await;
+                } else if verb == "QUIT" {
+                    info!(messages_relayed, bytes_received, "Client sent QUIT");
+                    let _ = write_status(&mut write_half, &session, 221, "2.0.0", "Bye").await;
                     return; // Close the connection
-                } else if cmd == "NOOP" {
-                    if write_response(&mut write_half, 250, "OK").await.is_err() {
+                } else if verb == "HELP" {
+                    if write_response(
+                        &mut write_half,
+                        &session,
+                        214,
+                        "Supported commands: EHLO HELO MAIL RCPT DATA BDAT RSET NOOP QUIT AUTH HELP",
+                    )
+                    .await
+                    .is_err()
+                    {
+                        return;
+                    }
+                } else if verb == "NOOP" {
+                    if write_status(&mut write_half, &session, 250, "2.0.0", "Ok")
+                        .await
+                        .is_err()
+                    {
                         return;
                     }
-                } else if cmd == "RSET" {
+                } else if verb == "RSET" {
                     transaction = Transaction::default();
-                    if write_response(&mut write_half, 250, "OK").await.is_err() {
+                    xforward = xforward::XForward::default();
+                    chunked_data.clear();
+                    chunked_msg_id = None;
+                    chunked_permit = None;
+                    if write_status(&mut write_half, &session, 250, "2.0.0", "Ok")
+                        .await
+                        .is_err()
+                    {
                         return;
                     }
                 } else {
-                    warn!(command = %line.trim(), "Unrecognized command");
-                    if write_response(&mut write_half, 500, "Syntax error, command unrecognized")
+                    warn!(command = %Escaped(line.trim()), "Unrecognized command");
+                    // The reply names the verb, so it can't use the error's fixed text
+                    let verb = sanitize_command(&verb);
+                    let text = format!("Command \"{verb}\" not recognized");
+                    let reply = SmtpError::InvalidCommand(verb).reply();
+                    if write_status(&mut write_half, &session, reply.code, reply.enhanced, &text)
                         .await
                         .is_err()
                     {
@@ -319,41 +1773,264 @@ async fn shutdown_signal() {
     info!("Signal received, starting graceful shutdown.");
 }
 
+// Binds a non-blocking SMTP listener. With `only_v6`, IPv6 sockets don't also accept IPv4
+// traffic, so `[::]` and `0.0.0.0` can listen on the same port side by side.
+pub fn bind_listener(addr: SocketAddr, only_v6: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
 // The main application loop. Binds to the listener and hands off connections.
-pub async fn run(
-    listener: TcpListener,
+pub async fn run(listener: TcpListener, mailer: Arc<dyn Mailer>, session: SessionConfig) {
+    run_listeners(vec![listener], mailer, session).await;
+}
+
+// Serves several listeners (e.g. IPv4 and IPv6) from a shared session config until shutdown.
+pub async fn run_listeners(
+    listeners: Vec<TcpListener>,
     mailer: Arc<dyn Mailer>,
-    max_email_size: usize,
-    server_name: String,
+    session: SessionConfig,
 ) {
-    println!(
-        "run: START - server listening on {:?}",
-        listener.local_addr()
+    serve_until(listeners, mailer, session, shutdown_signal()).await;
+}
+
+// Serves the listeners until `shutdown` completes, then stops accepting and gives open
+// connections up to the session's grace period to finish before aborting them.
+pub async fn serve_until(
+    listeners: Vec<TcpListener>,
+    mailer: Arc<dyn Mailer>,
+    session: SessionConfig,
+    shutdown: impl std::future::Future<Output = ()>,
+) {
+    // One limit shared by every listener
+    let limiter = session.max_connections.map(|max| {
+        Arc::new(ConnectionLimiter::new(
+            max,
+            session.connection_warning_threshold,
+            session.metrics.clone(),
+        ))
+    });
+    let grace_period = session.shutdown_grace_period;
+    let session = Arc::new(session);
+    let (stop_accepting, stop_rx) = watch::channel(false);
+    let accept_loops: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| {
+            tokio::spawn(accept_loop(
+                listener,
+                mailer.clone(),
+                session.clone(),
+                limiter.clone(),
+                stop_rx.clone(),
+            ))
+        })
+        .collect();
+    shutdown.await;
+    // What is being interrupted, so operators can judge whether the shutdown was safe
+    let snapshot = session.metrics.get_snapshot().await;
+    info!(
+        connections_active = snapshot.connections_active,
+        emails_in_flight = snapshot.emails_in_flight,
+        "Shutting down server..."
     );
+    let _ = stop_accepting.send(true);
+
+    // Each accept loop hands back the connections it spawned
+    let mut connections: Vec<JoinSet<()>> = Vec::new();
+    for accept_loop in accept_loops {
+        if let Ok(open) = accept_loop.await {
+            connections.push(open);
+        }
+    }
+    let open_connections: usize = connections.iter().map(JoinSet::len).sum();
+    if open_connections > 0 {
+        info!(
+            open_connections,
+            grace_period_secs = grace_period.as_secs(),
+            "Waiting for open connections to finish"
+        );
+    }
+    let drained = tokio::time::timeout(grace_period, async {
+        for open in &mut connections {
+            while open.join_next().await.is_some() {}
+        }
+    })
+    .await;
+    if drained.is_err() {
+        let aborted: usize = connections.iter().map(JoinSet::len).sum();
+        warn!(
+            aborted,
+            "Shutdown grace period elapsed, aborting open connections"
+        );
+        for open in &mut connections {
+            open.shutdown().await;
+        }
+    }
+    info!("run: END - server loop exited (after shutdown)");
+}
+
+// Caps concurrent SMTP sessions across all listeners, warning as the cap is approached
+// so saturation shows up before connections are refused.
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    semaphore: Arc<Semaphore>,
+    max: usize,
+    warning_threshold: usize,
+    metrics: MetricsCollector,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max: usize, warning_threshold: usize, metrics: MetricsCollector) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max)),
+            max,
+            warning_threshold,
+            metrics,
+        }
+    }
+
+    // Takes a slot for a new connection, or None when the server is at capacity
+    pub async fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        let permit = self.semaphore.clone().try_acquire_owned().ok();
+        let available = self.semaphore.available_permits();
+        if permit.is_some() && available < self.warning_threshold {
+            warn!(available, max = self.max, "Approaching connection limit");
+        }
+        self.record_usage().await;
+        permit
+    }
+
+    // Publishes the number of slots in use to the metrics gauge
+    pub async fn record_usage(&self) {
+        let in_use = self.max - self.semaphore.available_permits();
+        self.metrics
+            .set_connection_permits_in_use(in_use as u64)
+            .await;
+    }
+}
+
+// Upper bound on the pause between accept attempts while accepting keeps failing
+const MAX_ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(5);
+
+// Paces retries after failed accepts so resource exhaustion (EMFILE and the like) doesn't
+// spin the accept loop, and tells it apart from a listener that can no longer be used.
+struct AcceptBackoff {
+    initial: Duration,
+    next: Duration,
+}
+
+impl AcceptBackoff {
+    fn new(initial: Duration) -> Self {
+        Self {
+            initial,
+            next: initial,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.next = self.initial;
+    }
+
+    // How long to wait before accepting again, or None if the listener itself is broken
+    fn on_error(&mut self, error: &io::Error) -> Option<Duration> {
+        if matches!(
+            error.kind(),
+            io::ErrorKind::InvalidInput | io::ErrorKind::Unsupported
+        ) {
+            return None;
+        }
+        let delay = self.next;
+        self.next = (self.next * 2).min(MAX_ACCEPT_ERROR_BACKOFF.max(self.initial));
+        Some(delay)
+    }
+}
+
+// Accepts connections on one listener, handing each off to its own task, until told to
+// stop. Returns the connection tasks that are still open so shutdown can wait for them.
+async fn accept_loop(
+    listener: TcpListener,
+    mailer: Arc<dyn Mailer>,
+    session: Arc<SessionConfig>,
+    limiter: Option<Arc<ConnectionLimiter>>,
+    mut stop: watch::Receiver<bool>,
+) -> JoinSet<()> {
     info!(
         "run: START - server listening on {:?}",
         listener.local_addr()
     );
+    let mut connections = JoinSet::new();
+    let mut backoff = AcceptBackoff::new(session.accept_error_backoff);
     loop {
-        tokio::select! {
-            Ok((stream, addr)) = listener.accept() => {
+        // Reap finished connections so the set only holds open ones
+        while connections.try_join_next().is_some() {}
+        let accepted = tokio::select! {
+            _ = stop.wait_for(|&stopped| stopped) => break,
+            accepted = listener.accept() => accepted,
+        };
+        match accepted {
+            Ok((mut stream, addr)) => {
+                backoff.reset();
                 info!("run: Accepted connection from {}", addr);
+                let permit = match &limiter {
+                    Some(limiter) => match limiter.try_acquire().await {
+                        Some(permit) => Some(permit),
+                        None => {
+                            warn!(%addr, "Connection limit reached, refusing connection");
+                            let reply = format_status(
+                                session.enhanced_status_codes,
+                                "4.3.2",
+                                "Too many connections, try again later",
+                            );
+                            // Bounded so a client that never reads can't stall the accept loop
+                            let _ = tokio::time::timeout(
+                                session.write_timeout,
+                                stream.write_all(format!("421 {reply}\r\n").as_bytes()),
+                            )
+                            .await;
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
                 let mailer_clone = mailer.clone();
-                let server_name_clone = server_name.clone();
-                tokio::spawn(async move {
-                    info!("run: Spawning handle_connection for {}", addr);
-                    handle_connection(stream, mailer_clone, max_email_size, server_name_clone).await;
+                let session_clone = session.clone();
+                let limiter_clone = limiter.clone();
+                connections.spawn(async move {
+                    let metrics = session_clone.metrics.clone();
+                    metrics.increment_connections().await;
+                    handle_connection(stream, mailer_clone, session_clone).await;
+                    metrics.decrement_active_connections().await;
                     info!("run: handle_connection for {} returned", addr);
+                    drop(permit);
+                    if let Some(limiter) = limiter_clone {
+                        limiter.record_usage().await;
+                    }
                 });
             }
-            _ = shutdown_signal() => { info!("Shutting down server..."); break; }
-            else => { error!("TCP listener failed"); break; }
+            Err(e) => match backoff.on_error(&e) {
+                Some(delay) => {
+                    warn!(error = ?e, retry_in_ms = delay.as_millis() as u64, "TCP listener failed to accept connection, backing off");
+                    tokio::select! {
+                        _ = stop.wait_for(|&stopped| stopped) => break,
+                        _ = tokio::time::sleep(delay) => {}
+                    }
+                }
+                None => {
+                    error!(error = ?e, "TCP listener is unusable, no longer accepting on it");
+                    break;
+                }
+            },
         }
     }
-    println!("run: END - server loop exited");
-    info!("run: END - server loop exited (after shutdown)");
-    // If you want to ensure all spawned tasks are finished, you could track JoinHandles here.
-    // For now, the server loop is exited and the listener is dropped.
+    connections
 }
 
 // Unit tests for logic contained within this file.
@@ -364,120 +2041,1441 @@ mod tests {
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::net::{TcpListener, TcpStream};
 
-    #[tokio::test]
-    async fn test_email_size_limit_enforced() {
-        use std::sync::Arc;
-        use tokio::io::{AsyncReadExt, AsyncWriteExt};
-        use tokio::net::{TcpListener, TcpStream};
-
-        struct MockMailer;
-        #[async_trait::async_trait]
-        impl Mailer for MockMailer {
-            async fn send(
-                &self,
-                _raw_email: &[u8],
-                _recipients: &[String],
-                _from: &Option<String>,
-            ) -> anyhow::Result<()> {
-                panic!("send should not be called when email size exceeds limit");
-            }
-        }
-
-        let mailer = Arc::new(MockMailer);
+    // Serves one connection with the given settings and returns the connected client,
+    // before anything is read or written, e.g. to send a PROXY header first
+    async fn connect_session(session: SessionConfig, mailer: Arc<dyn Mailer>) -> TcpStream {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
-        let max_email_size = 100;
         tokio::spawn(async move {
             let (stream, _) = listener.accept().await.unwrap();
-            handle_connection(stream, mailer, max_email_size, "acs.local".to_string()).await;
+            handle_connection(stream, mailer, Arc::new(session)).await;
         });
-        let mut stream = TcpStream::connect(addr).await.unwrap();
-        let mut buf = [0u8; 1024];
-        let _ = stream.read(&mut buf).await.unwrap();
+        TcpStream::connect(addr).await.unwrap()
+    }
+
+    // Serves one connection with the given settings and returns the client once the
+    // banner has been read
+    async fn spawn_session(session: SessionConfig, mailer: Arc<dyn Mailer>) -> TcpStream {
+        let mut stream = connect_session(session, mailer).await;
+        read_reply(&mut stream).await;
+        stream
+    }
+
+    // Serves one connection behind the PROXY protocol and returns the client once it has
+    // sent the given header bytes, which may be partial or malformed
+    async fn connect_proxied(session: SessionConfig, header: &[u8]) -> TcpStream {
+        let session = SessionConfig {
+            proxy_protocol: true,
+            ..session
+        };
+        let mut stream = connect_session(session, Arc::new(relay::CapturingMailer::new())).await;
+        stream.write_all(header).await.unwrap();
         stream
-            .write_all(b"EHLO test.example.com\r\n")
+    }
+
+    // Reads one whole reply, waiting for the final line of a multiline one
+    async fn read_reply(stream: &mut TcpStream) -> String {
+        let mut reply = Vec::new();
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(
+                n > 0,
+                "connection closed after {:?}",
+                String::from_utf8_lossy(&reply)
+            );
+            reply.extend_from_slice(&buf[..n]);
+            if let Some(lines) = reply.strip_suffix(b"\r\n") {
+                let last = lines.rsplit(|&b| b == b'\n').next().unwrap_or_default();
+                if last.get(3) != Some(&b'-') {
+                    return String::from_utf8_lossy(&reply).into_owned();
+                }
+            }
+        }
+    }
+
+    // Sends each command in turn and checks how its reply starts
+    async fn dialogue(stream: &mut TcpStream, steps: &[(&str, &str)]) {
+        for (command, expected) in steps {
+            stream.write_all(command.as_bytes()).await.unwrap();
+            let response = read_reply(stream).await;
+            assert!(response.starts_with(expected), "{command:?}: {response}");
+        }
+    }
+
+    // Hangs up and waits for the server to close its end, so everything the session
+    // logs on the way out has been logged
+    async fn close_session(mut stream: TcpStream) {
+        stream.shutdown().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut Vec::new()))
             .await
+            .expect("server did not close the connection")
             .unwrap();
-        let _ = stream.read(&mut buf).await.unwrap();
-        stream
-            .write_all(b"MAIL FROM:<from@example.com>\r\n")
+    }
+
+    #[tokio::test]
+    async fn test_email_size_limit_enforced() {
+        let mailer = relay::CapturingMailer::new();
+        let session = SessionConfig {
+            max_email_size: 100,
+            server_name: "acs.local".to_string(),
+            ..Default::default()
+        };
+        let mut stream = spawn_session(session, Arc::new(mailer.clone())).await;
+        // A body that exceeds the max_email_size
+        let big_body = format!("{}\r\n.\r\n", "a".repeat(200));
+        dialogue(
+            &mut stream,
+            &[
+                ("EHLO test.example.com\r\n", "250"),
+                ("MAIL FROM:<from@example.com>\r\n", "250"),
+                ("RCPT TO:<to@example.com>\r\n", "250"),
+                ("DATA\r\n", "354"),
+                (&big_body, "552 5.3.4"),
+            ],
+        )
+        .await;
+        assert!(mailer.messages().is_empty());
+    }
+
+    // Feeds the DATA reader through a pipe, flushing each fragment as a separate read
+    async fn read_fragmented_data(fragments: &[&'static [u8]]) -> Result<Vec<u8>, DataError> {
+        let (client, server) = tokio::io::duplex(64);
+        let fragments = fragments.to_vec();
+        tokio::spawn(async move {
+            let mut client = client;
+            for fragment in fragments {
+                client.write_all(fragment).await.unwrap();
+                client.flush().await.unwrap();
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        });
+        read_data_body(&mut BufReader::new(server), 1024).await
+    }
+
+    #[tokio::test]
+    async fn test_data_terminator_split_across_reads() {
+        let body = read_fragmented_data(&[b"Subject: Hi\r\n\r\nBody\r", b"\n.", b"\r", b"\n"])
             .await
             .unwrap();
-        let _ = stream.read(&mut buf).await.unwrap();
-        stream
-            .write_all(b"RCPT TO:<to@example.com>\r\n")
+        assert_eq!(body, b"Subject: Hi\r\n\r\nBody\r\n");
+
+        let body = read_fragmented_data(&[b"..leading dot\r\n", b".", b".\r\n", b".\r\n"])
             .await
             .unwrap();
-        let _ = stream.read(&mut buf).await.unwrap();
-        stream.write_all(b"DATA\r\n").await.unwrap();
-        let _ = stream.read(&mut buf).await.unwrap();
-        // Send a body that exceeds the max_email_size
-        let big_body = vec![b'a'; 200];
-        stream.write_all(&big_body).await.unwrap();
-        stream.write_all(b".\r\n").await.unwrap();
-        let n = stream.read(&mut buf).await.unwrap();
-        let response = String::from_utf8_lossy(&buf[..n]);
+        assert_eq!(body, b".leading dot\r\n.\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_oversized_data_is_drained_to_the_terminator() {
+        let mut input: &[u8] = b"0123456789\r\n..abc\r\n.\r\nNOOP\r\n";
+        assert!(matches!(
+            read_data_body(&mut input, 8).await,
+            Err(DataError::TooLarge(18))
+        ));
+        // The next command is left for the session
+        assert_eq!(input, b"NOOP\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_oversized_line_is_drained_without_its_lf() {
+        // Over the limit partway through a line, including a `.\r` line that is not the end
+        let mut input: &[u8] = b"0123456789abc\r\n.\r\r\n.\r\nNOOP\r\n";
+        assert!(matches!(
+            read_data_body(&mut input, 8).await,
+            Err(DataError::TooLarge(18))
+        ));
+        assert_eq!(input, b"NOOP\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_endless_line_is_cut_off_after_the_drain_limit() {
+        // A client that streams one line forever and never sends a LF
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let sender = tokio::spawn(async move {
+            let chunk = [b'a'; 16 * 1024];
+            let mut sent = 0;
+            while client.write_all(&chunk).await.is_ok() {
+                sent += chunk.len();
+            }
+            sent
+        });
+        let result = tokio::time::timeout(
+            Duration::from_secs(10),
+            read_data_body(&mut BufReader::new(server), 100),
+        )
+        .await
+        .expect("the drain should give up");
+        assert!(
+            matches!(result, Err(DataError::TooLargeToDrain(size)) if size > MIN_DATA_DRAIN_BYTES),
+            "{result:?}"
+        );
+        // Reading stopped once the limit was passed
+        let sent = sender.await.unwrap();
+        assert!(sent < 2 * MIN_DATA_DRAIN_BYTES, "{sent}");
+    }
+
+    #[tokio::test]
+    async fn test_data_without_terminator_is_not_accepted() {
+        // The connection closes after a final line missing its CRLF
+        assert!(matches!(
+            read_fragmented_data(&[b"Body\r\n", b"."]).await,
+            Err(DataError::Disconnected)
+        ));
+        assert!(matches!(
+            read_fragmented_data(&[b"Body\r\n", b".\r"]).await,
+            Err(DataError::Disconnected)
+        ));
+        // A bare-LF dot line is content, not the end of the data
+        assert!(matches!(
+            read_fragmented_data(&[b"Body\n.\n", b"More\r\n"]).await,
+            Err(DataError::Disconnected)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mailer_send_receives_from_argument() {
+        let mailer = relay::CapturingMailer::new();
+        let session = SessionConfig {
+            max_email_size: 1000,
+            server_name: "acs.local".to_string(),
+            ..Default::default()
+        };
+        let mut stream = spawn_session(session, Arc::new(mailer.clone())).await;
+        dialogue(
+            &mut stream,
+            &[
+                ("HELO test.example.com\r\n", "250"),
+                ("MAIL FROM:<from@example.com>\r\n", "250"),
+                ("RCPT TO:<to@example.com>\r\n", "250"),
+                ("DATA\r\n", "354"),
+                ("Subject: Hello\r\n\r\nHello\r\n.\r\n", "250"),
+            ],
+        )
+        .await;
+        assert_eq!(
+            mailer.messages()[0].from,
+            Some("from@example.com".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_null_sender_is_accepted() {
+        let mailer = relay::CapturingMailer::new();
+        let mut stream = spawn_session(SessionConfig::default(), Arc::new(mailer.clone())).await;
+        dialogue(
+            &mut stream,
+            &[
+                ("HELO test.example.com\r\n", "250"),
+                ("MAIL FROM:<>\r\n", "250"),
+                ("RCPT TO:<to@example.com>\r\n", "250"),
+                ("DATA\r\n", "354"),
+                ("Subject: Bounce\r\n\r\nUndeliverable\r\n.\r\n", "250"),
+            ],
+        )
+        .await;
+        assert_eq!(mailer.messages()[0].from, Some(String::new()));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_envelope_commands_get_501() {
+        let mut stream = spawn_session(
+            SessionConfig::default(),
+            Arc::new(relay::CapturingMailer::new()),
+        )
+        .await;
+        dialogue(
+            &mut stream,
+            &[
+                ("HELO test.example.com\r\n", "250"),
+                ("MAIL FROM:\r\n", "501"),
+                ("MAIL\r\n", "501"),
+                ("MAIL ÄÖÜFROM:<a@b.com>\r\n", "501"),
+                ("MAIL FROM:<a@b.com>\r\n", "250"),
+                ("RCPT TO:\r\n", "501"),
+                ("RCPT TO:<>\r\n", "501"),
+                ("RCPT €€:<to@example.com>\r\n", "501"),
+                ("RCPT TO:<to@example.com>\r\n", "250"),
+            ],
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_unparseable_message_is_rejected() {
+        let mailer = relay::CapturingMailer::new();
+        let mut stream = spawn_session(SessionConfig::default(), Arc::new(mailer.clone())).await;
+        dialogue(
+            &mut stream,
+            &[
+                ("HELO test.example.com\r\n", "250"),
+                ("MAIL FROM:<from@example.com>\r\n", "250"),
+                ("RCPT TO:<to@example.com>\r\n", "250"),
+                ("DATA\r\n", "354"),
+                ("garbage\r\n.\r\n", "554 5.6.0"),
+                // The transaction is reset after the rejection
+                ("RCPT TO:<to@example.com>\r\n", "503"),
+            ],
+        )
+        .await;
+        assert!(mailer.messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unparseable_bdat_message_is_rejected() {
+        let mailer = relay::CapturingMailer::new();
+        let mut stream = spawn_session(SessionConfig::default(), Arc::new(mailer.clone())).await;
+        dialogue(
+            &mut stream,
+            &[
+                ("HELO test.example.com\r\n", "250"),
+                ("MAIL FROM:<from@example.com>\r\n", "250"),
+                ("RCPT TO:<to@example.com>\r\n", "250"),
+                ("BDAT 9 LAST\r\ngarbage\r\n", "554 5.6.0"),
+                // The transaction is reset after the rejection
+                ("RCPT TO:<to@example.com>\r\n", "503"),
+            ],
+        )
+        .await;
+        assert!(mailer.messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_auth_plain_inline_and_prompted() {
+        let session = || {
+            spawn_session(
+                SessionConfig::default(),
+                Arc::new(relay::CapturingMailer::new()),
+            )
+        };
+        let mut stream = session().await;
+        dialogue(
+            &mut stream,
+            &[
+                ("EHLO client.example.com\r\n", "250"),
+                // Initial response on the same line: no 334 prompt
+                ("AUTH PLAIN AHRlc3QAdGVzdA==\r\n", "235 2.7.0"),
+                // RFC 4954: no second AUTH once authenticated, and no challenge for it
+                ("AUTH PLAIN AHRlc3QAdGVzdA==\r\n", "503 5.5.1"),
+                ("AUTH PLAIN\r\n", "503 5.5.1"),
+                ("NOOP\r\n", "250"),
+            ],
+        )
+        .await;
+
+        let mut stream = session().await;
+        dialogue(
+            &mut stream,
+            &[
+                ("EHLO client.example.com\r\n", "250"),
+                ("AUTH PLAIN not-base64\r\n", "501 5.5.2"),
+                ("AUTH PLAIN\r\n", "334"),
+                ("*\r\n", "501 5.7.0"),
+                // ...nor during a mail transaction
+                ("MAIL FROM:<from@example.com>\r\n", "250"),
+                ("AUTH PLAIN AHRlc3QAdGVzdA==\r\n", "503 5.5.1"),
+                ("RSET\r\n", "250"),
+                ("AUTH PLAIN\r\n", "334"),
+                ("AHRlc3QAdGVzdA==\r\n", "235 2.7.0"),
+                ("NOOP\r\n", "250"),
+            ],
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_malformed_recipient_is_rejected() {
+        let mailer = relay::CapturingMailer::new();
+        let mut stream = spawn_session(SessionConfig::default(), Arc::new(mailer.clone())).await;
+        dialogue(
+            &mut stream,
+            &[
+                ("HELO test.example.com\r\n", "250"),
+                ("MAIL FROM:<from@example.com>\r\n", "250"),
+                (
+                    "RCPT TO:<not an email>\r\n",
+                    "501 5.1.3 Bad recipient address syntax",
+                ),
+                ("RCPT TO:<user@@example.com>\r\n", "501 5.1.3"),
+                ("RCPT TO:<to@example.com>\r\n", "250"),
+                ("DATA\r\n", "354"),
+                ("Subject: Hi\r\n\r\nHello\r\n.\r\n", "250"),
+            ],
+        )
+        .await;
+        assert_eq!(mailer.messages()[0].recipients, ["to@example.com"]);
+    }
+
+    #[tokio::test]
+    async fn test_message_rate_limit_defers_bursts() {
+        let mailer = relay::CapturingMailer::new();
+        let session = SessionConfig {
+            // One message per two seconds: a second message in a burst can't wait that long
+            rate_limiter: Some(Arc::new(rate_limit::RateLimiter::new(0.5))),
+            ..Default::default()
+        };
+        let mut stream = spawn_session(session, Arc::new(mailer.clone())).await;
+        dialogue(&mut stream, &[("HELO client.example\r\n", "250")]).await;
+        let mut final_replies = Vec::new();
+        for _ in 0..3 {
+            dialogue(
+                &mut stream,
+                &[
+                    ("MAIL FROM:<from@example.com>\r\n", "250"),
+                    ("RCPT TO:<to@example.com>\r\n", "250"),
+                    ("DATA\r\n", "354"),
+                ],
+            )
+            .await;
+            stream
+                .write_all(b"Subject: Burst\r\n\r\nHello\r\n.\r\n")
+                .await
+                .unwrap();
+            final_replies.push(read_reply(&mut stream).await);
+        }
+        assert!(final_replies[0].starts_with("250"), "{final_replies:?}");
+        for reply in &final_replies[1..] {
+            assert!(reply.starts_with("452 4.3.2 Try again later"), "{reply}");
+        }
+        assert_eq!(mailer.messages().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_banner_uses_server_name() {
+        let session = SessionConfig {
+            max_email_size: 1000,
+            server_name: "mail.example.com".to_string(),
+            ..Default::default()
+        };
+        let mut stream = connect_session(session, Arc::new(relay::CapturingMailer::new())).await;
+        let banner = read_reply(&mut stream).await;
+        assert_eq!(banner, "220 mail.example.com ESMTP ready\r\n");
+        stream
+            .write_all(b"EHLO client.example.com\r\n")
+            .await
+            .unwrap();
+        let ehlo = read_reply(&mut stream).await;
+        assert!(
+            ehlo.starts_with("250-mail.example.com\r\n"),
+            "Unexpected EHLO: {ehlo}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_auth_plain_checks_configured_credentials() {
+        let session = SessionConfig {
+            unauthenticated_recipient_domains: Some(vec!["internal.example".to_string()]),
+            auth_credentials: AuthCredentials::new(std::collections::HashMap::from([(
+                "user".to_string(),
+                "pass".to_string(),
+            )])),
+            ..Default::default()
+        };
+        let mut stream = spawn_session(session, Arc::new(relay::CapturingMailer::new())).await;
+        dialogue(
+            &mut stream,
+            &[
+                ("HELO test.example.com\r\n", "250"),
+                // "\0user\0wrong"
+                ("AUTH PLAIN AHVzZXIAd3Jvbmc=\r\n", "535 5.7.8"),
+                // Still unauthenticated
+                ("MAIL FROM:<a@example.com>\r\n", "250 2.1.0"),
+                ("RCPT TO:<b@external.example>\r\n", "550 5.7.1"),
+                ("RSET\r\n", "250"),
+                // "\0user\0pass"
+                ("AUTH PLAIN AHVzZXIAcGFzcw==\r\n", "235 2.7.0"),
+                ("MAIL FROM:<a@example.com>\r\n", "250 2.1.0"),
+                ("RCPT TO:<b@external.example>\r\n", "250 2.1.5"),
+            ],
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_merged_header_recipients_are_checked() {
+        let mailer = relay::CapturingMailer::new();
+        let session = SessionConfig {
+            merge_header_recipients: true,
+            max_recipients: 2,
+            unauthenticated_recipient_domains: Some(vec!["internal.example".to_string()]),
+            ..Default::default()
+        };
+        let mut stream = spawn_session(session, Arc::new(mailer.clone())).await;
+        dialogue(&mut stream, &[("HELO test.example.com\r\n", "250")]).await;
+        for (headers, expected) in [
+            // A Cc outside the domains open to unauthenticated clients
+            (
+                "To: a@internal.example\r\nCc: x@external.example\r\n",
+                "550 5.7.1",
+            ),
+            // Three recipients once merged, over the limit of two
+            (
+                "To: a@internal.example, b@internal.example\r\nBcc: c@internal.example\r\n",
+                "452 4.5.3",
+            ),
+            ("To: <user@bad_domain>\r\n", "501 5.1.3"),
+            // The envelope recipient named again, in another case, counts once
+            (
+                "To: A@Internal.example\r\nCc: b@internal.example\r\n",
+                "250 2.0.0",
+            ),
+        ] {
+            let message = format!("Subject: Hi\r\n{headers}\r\nHello\r\n.\r\n");
+            dialogue(
+                &mut stream,
+                &[
+                    ("MAIL FROM:<from@example.com>\r\n", "250"),
+                    ("RCPT TO:<a@internal.example>\r\n", "250"),
+                    ("DATA\r\n", "354"),
+                    (&message, expected),
+                ],
+            )
+            .await;
+        }
+        assert_eq!(mailer.messages().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_merged_header_recipients_count_in_domain_metrics() {
+        let session = SessionConfig {
+            merge_header_recipients: true,
+            ..Default::default()
+        };
+        let metrics = session.metrics.clone();
+        let mut stream = spawn_session(session, Arc::new(relay::CapturingMailer::new())).await;
+        dialogue(
+            &mut stream,
+            &[
+                ("HELO test.example.com\r\n", "250"),
+                ("MAIL FROM:<from@example.com>\r\n", "250"),
+                ("RCPT TO:<a@one.example>\r\n", "250"),
+                ("DATA\r\n", "354"),
+                (
+                    "To: a@one.example\r\nCc: b@Two.example\r\nSubject: Hi\r\n\r\nHello\r\n.\r\n",
+                    "250",
+                ),
+            ],
+        )
+        .await;
+
+        let domains: Vec<_> = metrics
+            .get_snapshot()
+            .await
+            .top_recipient_domains(10)
+            .into_iter()
+            .map(|stats| (stats.domain, stats.sent))
+            .collect();
+        assert_eq!(
+            domains,
+            [
+                ("one.example".to_string(), 1),
+                ("two.example".to_string(), 1)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unauthenticated_clients_get_restricted_limits() {
+        let session = SessionConfig {
+            max_email_size: 1000,
+            unauthenticated_max_email_size: Some(100),
+            unauthenticated_recipient_domains: Some(vec!["internal.example".to_string()]),
+            ..Default::default()
+        };
+        let mut stream = spawn_session(session, Arc::new(relay::CapturingMailer::new())).await;
+
+        // Sends EHLO and returns the advertised SIZE
+        async fn ehlo_size(stream: &mut TcpStream) -> Option<String> {
+            stream
+                .write_all(b"EHLO client.example.com\r\n")
+                .await
+                .unwrap();
+            read_reply(stream)
+                .await
+                .lines()
+                .find_map(|line| line.strip_prefix("250-SIZE ").map(str::to_string))
+        }
+
+        // Before AUTH: the smaller SIZE, and only the internal domain
+        assert_eq!(ehlo_size(&mut stream).await.as_deref(), Some("100"));
+        dialogue(
+            &mut stream,
+            &[
+                ("MAIL FROM:<a@example.com> SIZE=500\r\n", "552 5.3.4"),
+                ("MAIL FROM:<a@example.com>\r\n", "250 2.1.0"),
+                ("RCPT TO:<b@internal.example>\r\n", "250 2.1.5"),
+                ("RCPT TO:<b@external.example>\r\n", "550 5.7.1"),
+                ("RSET\r\n", "250"),
+                ("AUTH PLAIN AHVzZXIAcGFzcw==\r\n", "235 2.7.0"),
+            ],
+        )
+        .await;
+
+        // After AUTH: the full limits, re-advertised on the next EHLO
+        assert_eq!(ehlo_size(&mut stream).await.as_deref(), Some("1000"));
+        dialogue(
+            &mut stream,
+            &[
+                ("MAIL FROM:<a@example.com> SIZE=500\r\n", "250 2.1.0"),
+                ("RCPT TO:<b@external.example>\r\n", "250 2.1.5"),
+            ],
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_commands_are_answered_in_order() {
+        let stream = spawn_session(
+            SessionConfig::default(),
+            Arc::new(relay::CapturingMailer::new()),
+        )
+        .await;
+        let (read_half, mut write_half) = io::split(stream);
+        let mut reader = BufReader::new(read_half);
+        let mut line = String::new();
+
+        write_half
+            .write_all(b"EHLO client.example.com\r\n")
+            .await
+            .unwrap();
+        let mut ehlo = Vec::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+            ehlo.push(line.trim_end().to_string());
+            if !line.starts_with("250-") {
+                break;
+            }
+        }
+        assert!(ehlo.contains(&"250-PIPELINING".to_string()), "{ehlo:?}");
+
+        // The whole envelope in one write, including a rejected recipient
+        write_half
+            .write_all(
+                b"MAIL FROM:<from@example.com>\r\n\
+RCPT TO:<not an address>\r\n\
+RCPT TO:<to@example.com>\r\n\
+DATA\r\n",
+            )
+            .await
+            .unwrap();
+        for expected in ["250 2.1.0", "501 5.1.3", "250 2.1.5", "354"] {
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+            assert!(
+                line.starts_with(expected),
+                "Expected {expected}, got: {line}"
+            );
+        }
+        write_half
+            .write_all(b"Subject: Hi\r\n\r\nHello\r\n.\r\nQUIT\r\n")
+            .await
+            .unwrap();
+        for expected in ["250 2.0.0", "221 2.0.0"] {
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+            assert!(
+                line.starts_with(expected),
+                "Expected {expected}, got: {line}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_help_command_returns_214() {
+        let session = SessionConfig {
+            max_email_size: 1000,
+            server_name: "acs.local".to_string(),
+            ..Default::default()
+        };
+        let mut stream = spawn_session(session, Arc::new(relay::CapturingMailer::new())).await;
+        dialogue(&mut stream, &[("HELO client.example\r\n", "250")]).await;
+        stream.write_all(b"HELP\r\n").await.unwrap();
+        let response = read_reply(&mut stream).await;
         assert!(
-            response.contains("552"),
-            "Expected 552 error, got: {response}"
+            response.starts_with("214"),
+            "Expected 214 response, got: {response}"
+        );
+        assert!(response.contains("MAIL"));
+        assert!(response.contains("QUIT"));
+    }
+
+    #[tokio::test]
+    async fn test_successful_relay_reports_size() {
+        let mut stream = spawn_session(
+            SessionConfig::default(),
+            Arc::new(relay::CapturingMailer::new()),
+        )
+        .await;
+        dialogue(
+            &mut stream,
+            &[
+                ("HELO client.example\r\n", "250"),
+                ("MAIL FROM:<from@example.com>\r\n", "250 2.1.0 Ok"),
+                ("RCPT TO:<to@example.com>\r\n", "250 2.1.5 Ok"),
+                ("DATA\r\n", "354"),
+                // "Subject: Hi\r\n\r\nHello\r\n" is 22 bytes
+                (
+                    "Subject: Hi\r\n\r\nHello\r\n.\r\n",
+                    "250 2.0.0 Ok: queued 22 bytes",
+                ),
+                ("QUIT\r\n", "221 2.0.0"),
+            ],
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_data_transfers_beyond_the_limit_wait() {
+        let session = SessionConfig {
+            data_transfers: Some(Arc::new(tokio::sync::Semaphore::new(2))),
+            ..Default::default()
+        };
+        // Connects and sends everything up to and including DATA
+        async fn start_data(session: &SessionConfig) -> TcpStream {
+            let mut stream =
+                spawn_session(session.clone(), Arc::new(relay::CapturingMailer::new())).await;
+            dialogue(
+                &mut stream,
+                &[
+                    ("HELO client.example\r\n", "250"),
+                    ("MAIL FROM:<from@example.com>\r\n", "250"),
+                    ("RCPT TO:<to@example.com>\r\n", "250"),
+                ],
+            )
+            .await;
+            stream.write_all(b"DATA\r\n").await.unwrap();
+            stream
+        }
+
+        let mut first = start_data(&session).await;
+        assert!(read_reply(&mut first).await.starts_with("354"));
+        let mut second = start_data(&session).await;
+        assert!(read_reply(&mut second).await.starts_with("354"));
+
+        // Both slots are taken, so the third transfer waits for one to be released
+        let mut third = start_data(&session).await;
+        let waiting =
+            tokio::time::timeout(Duration::from_millis(200), read_reply(&mut third)).await;
+        assert!(waiting.is_err(), "Third DATA was not delayed");
+
+        dialogue(
+            &mut first,
+            &[("Subject: Hi\r\n\r\nHello\r\n.\r\n", "250 2.0.0")],
+        )
+        .await;
+        let reply = tokio::time::timeout(Duration::from_secs(5), read_reply(&mut third))
+            .await
+            .unwrap();
+        assert!(reply.starts_with("354"), "{reply}");
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_message_id_is_relayed_once() {
+        let mailer = relay::CapturingMailer::new();
+        // The cache is shared, so a resend on a new connection is caught too
+        let session = SessionConfig {
+            dedup_cache: Some(Arc::new(dedup::DedupCache::new(Duration::from_secs(60)))),
+            ..Default::default()
+        };
+
+        for (recipient, expected) in [
+            ("to@example.com", "250 2.0.0 Ok: queued"),
+            ("to@example.com", "250 2.0.0 Ok (duplicate suppressed)"),
+            // The same message for other recipients is a separate delivery
+            ("other@example.com", "250 2.0.0 Ok: queued"),
+        ] {
+            let mut stream = spawn_session(session.clone(), Arc::new(mailer.clone())).await;
+            let rcpt = format!("RCPT TO:<{recipient}>\r\n");
+            dialogue(
+                &mut stream,
+                &[
+                    ("HELO client.example\r\n", "250"),
+                    ("MAIL FROM:<from@example.com>\r\n", "250 2.1.0"),
+                    (&rcpt, "250 2.1.5"),
+                    ("DATA\r\n", "354"),
+                    (
+                        "Message-ID: <retry@client.example>\r\nSubject: Hi\r\n\r\nHello\r\n.\r\n",
+                        expected,
+                    ),
+                ],
+            )
+            .await;
+        }
+        assert_eq!(mailer.messages().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_sender_over_quota_is_deferred() {
+        let limits = std::collections::HashMap::from([("tenant@example.com".to_string(), 2)]);
+        let session = SessionConfig {
+            sender_quotas: Some(Arc::new(quota::SenderQuotas::new(
+                &limits,
+                Duration::from_secs(3600),
+            ))),
+            ..Default::default()
+        };
+        let mut stream = spawn_session(session, Arc::new(relay::CapturingMailer::new())).await;
+        dialogue(&mut stream, &[("HELO client.example\r\n", "250")]).await;
+
+        for (sender, expected) in [
+            ("tenant@example.com", "250 2.0.0"),
+            ("tenant@example.com", "250 2.0.0"),
+            ("tenant@example.com", "452 4.7.0 Quota exceeded"),
+            // Other senders have no quota
+            ("other@example.com", "250 2.0.0"),
+        ] {
+            let mail = format!("MAIL FROM:<{sender}>\r\n");
+            dialogue(
+                &mut stream,
+                &[
+                    (&mail, "250 2.1.0"),
+                    ("RCPT TO:<to@example.com>\r\n", "250 2.1.5"),
+                    ("DATA\r\n", "354"),
+                    ("Subject: Hi\r\n\r\nHello\r\n.\r\n", expected),
+                ],
+            )
+            .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_relay_does_not_use_quota() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        // Fails the first send with a transient ACS error
+        struct FlakyMailer(AtomicUsize);
+        #[async_trait::async_trait]
+        impl Mailer for FlakyMailer {
+            async fn send(
+                &self,
+                _raw_email: &[u8],
+                _recipients: &[String],
+                _from: &Option<String>,
+            ) -> Result<(), SmtpRelayError> {
+                if self.0.fetch_add(1, Ordering::SeqCst) == 0 {
+                    return Err(SmtpRelayError::Acs(error::AcsError::ServiceUnavailable));
+                }
+                Ok(())
+            }
+        }
+        let limits = std::collections::HashMap::from([("tenant@example.com".to_string(), 1)]);
+        let session = SessionConfig {
+            sender_quotas: Some(Arc::new(quota::SenderQuotas::new(
+                &limits,
+                Duration::from_secs(3600),
+            ))),
+            ..Default::default()
+        };
+        let mut stream = spawn_session(session, Arc::new(FlakyMailer(AtomicUsize::new(0)))).await;
+        dialogue(&mut stream, &[("HELO client.example\r\n", "250")]).await;
+
+        // The retry of the failed message is the only one counted
+        for expected in ["451 4.3.0", "250 2.0.0", "452 4.7.0 Quota exceeded"] {
+            dialogue(
+                &mut stream,
+                &[
+                    ("MAIL FROM:<tenant@example.com>\r\n", "250 2.1.0"),
+                    ("RCPT TO:<to@example.com>\r\n", "250 2.1.5"),
+                    ("DATA\r\n", "354"),
+                    ("Subject: Hi\r\n\r\nHello\r\n.\r\n", expected),
+                ],
+            )
+            .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bdat_chunks_are_assembled_and_relayed() {
+        let mailer = relay::CapturingMailer::new();
+        let mut stream = spawn_session(SessionConfig::default(), Arc::new(mailer.clone())).await;
+
+        // Chunks are raw octets, so neither a leading dot nor a missing CRLF is special
+        dialogue(
+            &mut stream,
+            &[
+                ("HELO client.example\r\n", "250"),
+                ("MAIL FROM:<from@example.com>\r\n", "250 2.1.0 Ok"),
+                ("RCPT TO:<to@example.com>\r\n", "250 2.1.5 Ok"),
+                (
+                    "BDAT 16\r\nSubject: Hi\r\n\r\n.",
+                    "250 2.0.0 Ok: 16 octets received",
+                ),
+                ("BDAT 7 LAST\r\nHello\r\n", "250 2.0.0 Ok: queued 23 bytes"),
+                ("QUIT\r\n", "221 2.0.0"),
+            ],
+        )
+        .await;
+        assert_eq!(
+            mailer.messages()[0].raw_email.as_slice(),
+            b"Subject: Hi\r\n\r\n.Hello\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_received_header_is_prepended() {
+        let mailer = relay::CapturingMailer::new();
+        let session = SessionConfig {
+            server_name: "relay.example.net".to_string(),
+            received_header: true,
+            ..Default::default()
+        };
+        let mut stream = spawn_session(session, Arc::new(mailer.clone())).await;
+        dialogue(
+            &mut stream,
+            &[
+                ("EHLO client.example.com\r\n", "250"),
+                ("MAIL FROM:<from@example.com>\r\n", "250"),
+                ("RCPT TO:<to@example.com>\r\n", "250"),
+                ("DATA\r\n", "354"),
+                ("Subject: Traced\r\n\r\nHello\r\n.\r\n", "250"),
+            ],
+        )
+        .await;
+
+        let raw_email = mailer.messages()[0].raw_email.clone();
+        let text = String::from_utf8(raw_email.clone()).unwrap();
+        assert!(
+            text.starts_with(
+                "Received: from client.example.com ([127.0.0.1]) by relay.example.net with\r\n\tESMTP id "
+            ),
+            "{text}"
+        );
+        assert!(text.ends_with("Subject: Traced\r\n\r\nHello\r\n"));
+
+        // The original headers still parse after the inserted one
+        let parsed = mail_parser::MessageParser::default()
+            .parse(&raw_email)
+            .unwrap();
+        assert_eq!(parsed.subject(), Some("Traced"));
+        assert_eq!(parsed.header_values("Received").count(), 1);
+    }
+
+    #[test]
+    fn test_received_header_format() {
+        let msg_id = Uuid::nil();
+        let date = chrono::DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        assert_eq!(
+            received_header(None, None, "SMTP", "unknown", "relay", &msg_id, date),
+            "Received: from unknown ([unknown]) by relay with SMTP id\r\n\t00000000-0000-0000-0000-000000000000; Wed, 1 May 2024 12:00:00 +0000\r\n"
+        );
+        assert!(received_header(
+            Some("mta"),
+            None,
+            "ESMTP",
+            "[2001:db8::1]:2525",
+            "relay",
+            &msg_id,
+            date
+        )
+        .starts_with("Received: from mta ([2001:db8::1])"));
+    }
+
+    #[test]
+    fn test_long_received_header_is_folded() {
+        let helo = format!("{}.example.com", "mail-gateway-".repeat(4));
+        let server_name = format!("{}.example.net", "relay-".repeat(8));
+        let date = chrono::DateTime::parse_from_rfc3339("2024-05-21T12:00:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let header = received_header(
+            Some(&helo),
+            None,
+            "ESMTP",
+            "192.0.2.1:40000",
+            &server_name,
+            &Uuid::nil(),
+            date,
+        );
+        let lines: Vec<&str> = header.trim_end_matches("\r\n").split("\r\n").collect();
+        assert!(lines.len() > 2, "{header}");
+        assert!(lines.iter().all(|line| line.len() <= 78), "{header}");
+        assert!(lines[1..].iter().all(|line| line.starts_with('\t')));
+
+        let message = format!("{header}Subject: Folded\r\n\r\nHello\r\n");
+        let parsed = mail_parser::MessageParser::default()
+            .parse(message.as_bytes())
+            .unwrap();
+        assert_eq!(parsed.subject(), Some("Folded"));
+        let received = parsed.received().unwrap();
+        assert_eq!(received.from().map(|host| host.to_string()), Some(helo));
+        assert_eq!(
+            received.by().map(|host| host.to_string()),
+            Some(server_name)
+        );
+        assert_eq!(
+            received.with().map(|with| with.to_string()),
+            Some("ESMTP".to_string())
+        );
+        assert_eq!(
+            received.date().map(|date| date.to_timestamp()),
+            Some(date.timestamp())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_date_header_added_when_missing() {
+        let mailer = relay::CapturingMailer::new();
+        let session = SessionConfig {
+            date_header: true,
+            ..Default::default()
+        };
+        let mut stream = spawn_session(session, Arc::new(mailer.clone())).await;
+        dialogue(
+            &mut stream,
+            &[
+                ("EHLO client.example.com\r\n", "250"),
+                ("MAIL FROM:<from@example.com>\r\n", "250"),
+                ("RCPT TO:<to@example.com>\r\n", "250"),
+                ("DATA\r\n", "354"),
+                ("Subject: Undated\r\n\r\nDate: not a header\r\n.\r\n", "250"),
+                ("MAIL FROM:<from@example.com>\r\n", "250"),
+                ("RCPT TO:<to@example.com>\r\n", "250"),
+                ("DATA\r\n", "354"),
+                (
+                    "DATE: Wed, 1 May 2024 12:00:00 +0000\r\nSubject: Dated\r\n\r\nHi\r\n.\r\n",
+                    "250",
+                ),
+            ],
+        )
+        .await;
+
+        let relayed: Vec<Vec<u8>> = mailer
+            .messages()
+            .into_iter()
+            .map(|message| message.raw_email)
+            .collect();
+        assert_eq!(relayed.len(), 2);
+        let parsed = mail_parser::MessageParser::default()
+            .parse(&relayed[0])
+            .unwrap();
+        let date = parsed.date().expect("a valid Date header was added");
+        assert!((chrono::Utc::now().timestamp() - date.to_timestamp()).abs() < 60);
+        assert_eq!(parsed.subject(), Some("Undated"));
+
+        // A message that already has a Date is relayed unchanged
+        assert!(relayed[1].starts_with(b"DATE: Wed, 1 May 2024"));
+        let parsed = mail_parser::MessageParser::default()
+            .parse(&relayed[1])
+            .unwrap();
+        assert_eq!(parsed.header_values("Date").count(), 1);
+    }
+
+    #[test]
+    fn test_has_header() {
+        assert!(has_header(b"Subject: x\r\nDate: now\r\n\r\n", "Date"));
+        assert!(has_header(b"date:now\n\nbody", "Date"));
+        assert!(!has_header(b"Subject: x\r\n\r\nDate: body\r\n", "Date"));
+        assert!(!has_header(b"Dated: x\r\n\r\n", "Date"));
+    }
+
+    #[tokio::test]
+    async fn test_client_that_stops_reading_is_disconnected() {
+        // Set up by hand rather than with spawn_session, to shrink the socket buffers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            // Small buffers so unread replies back up quickly
+            socket2::SockRef::from(&stream)
+                .set_send_buffer_size(4096)
+                .unwrap();
+            handle_connection(
+                stream,
+                Arc::new(relay::CapturingMailer::new()),
+                Arc::new(SessionConfig {
+                    max_commands: usize::MAX,
+                    write_timeout: Duration::from_millis(200),
+                    ..Default::default()
+                }),
+            )
+            .await;
+        });
+
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(4096).unwrap();
+        let stream = socket.connect(addr).await.unwrap();
+        let (_read_half, mut write_half) = stream.into_split();
+        // Keep sending commands without ever reading a reply
+        let client = tokio::spawn(async move {
+            let commands = b"HELP\r\n".repeat(1000);
+            while write_half.write_all(&commands).await.is_ok() {}
+        });
+
+        tokio::time::timeout(Duration::from_secs(10), server)
+            .await
+            .expect("stalled connection should be closed after the write timeout")
+            .unwrap();
+        client.abort();
+    }
+
+    #[tokio::test]
+    async fn test_bdat_enforces_max_email_size_across_chunks() {
+        let mailer = relay::CapturingMailer::new();
+        let session = SessionConfig {
+            max_email_size: 10,
+            ..Default::default()
+        };
+        let mut stream = spawn_session(session, Arc::new(mailer.clone())).await;
+        dialogue(
+            &mut stream,
+            &[
+                ("HELO client.example\r\n", "250"),
+                ("MAIL FROM:<from@example.com>\r\n", "250 2.1.0 Ok"),
+                ("RCPT TO:<to@example.com>\r\n", "250 2.1.5 Ok"),
+                ("BDAT 6\r\n123456", "250 2.0.0"),
+                ("BDAT 6 LAST\r\n789012", "552 5.3.4"),
+                // The rejected chunk was consumed, so the session is still in sync
+                ("NOOP\r\n", "250 2.0.0 Ok"),
+            ],
+        )
+        .await;
+        assert!(mailer.messages().is_empty(), "oversize message was relayed");
+    }
+
+    #[tokio::test]
+    async fn test_empty_and_rejected_bdat_count_toward_command_limit() {
+        let session = || SessionConfig {
+            max_commands: 5,
+            ..Default::default()
+        };
+
+        // HELO, MAIL and RCPT, then empty chunks up to the limit of five
+        let mut stream = spawn_session(session(), Arc::new(relay::CapturingMailer::new())).await;
+        dialogue(
+            &mut stream,
+            &[
+                ("HELO client.example\r\n", "250"),
+                ("MAIL FROM:<from@example.com>\r\n", "250"),
+                ("RCPT TO:<to@example.com>\r\n", "250"),
+            ],
+        )
+        .await;
+        for _ in 0..2 {
+            dialogue(&mut stream, &[("BDAT 0\r\n", "250 2.0.0")]).await;
+        }
+        dialogue(&mut stream, &[("BDAT 0\r\n", "421 4.7.0")]).await;
+        assert_eq!(stream.read(&mut [0u8; 256]).await.unwrap(), 0);
+
+        // Malformed chunks and chunks sent before the greeting
+        let mut stream = spawn_session(session(), Arc::new(relay::CapturingMailer::new())).await;
+        for _ in 0..2 {
+            dialogue(&mut stream, &[("BDAT x\r\n", "501"), ("BDAT 0\r\n", "503")]).await;
+        }
+        dialogue(
+            &mut stream,
+            &[("BDAT 0\r\n", "503"), ("BDAT 0\r\n", "421 4.7.0")],
+        )
+        .await;
+        assert_eq!(stream.read(&mut [0u8; 256]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_mail_during_bdat_transfer_is_rejected() {
+        let mailer = relay::CapturingMailer::new();
+        let mut stream = spawn_session(SessionConfig::default(), Arc::new(mailer.clone())).await;
+        dialogue(
+            &mut stream,
+            &[
+                ("HELO client.example\r\n", "250"),
+                ("MAIL FROM:<from@example.com>\r\n", "250 2.1.0 Ok"),
+                ("RCPT TO:<to@example.com>\r\n", "250 2.1.5 Ok"),
+                ("BDAT 12\r\nSubject: x\r\n", "250 2.0.0"),
+                ("MAIL FROM:<other@example.com>\r\n", "503"),
+                // The chunks received so far are kept
+                ("BDAT 4 LAST\r\n\r\nHi", "250 2.0.0"),
+            ],
+        )
+        .await;
+        let raw_emails: Vec<_> = mailer.messages().into_iter().map(|m| m.raw_email).collect();
+        assert_eq!(raw_emails, [b"Subject: x\r\n\r\nHi".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_messages_over_header_limits_are_rejected() {
+        let mut stream = spawn_session(
+            SessionConfig::default(),
+            Arc::new(relay::CapturingMailer::new()),
+        )
+        .await;
+        dialogue(&mut stream, &[("HELO client.example\r\n", "250")]).await;
+
+        let many_headers: String = (0..5000).map(|i| format!("X-Tag-{i}: {i}\r\n")).collect();
+        let huge_header = format!("X-Blob: {}\r\n", "a".repeat(200 * 1024));
+        for (headers, expected) in [
+            (many_headers, "552 5.3.4 Too many headers"),
+            (huge_header, "552 5.3.4 Headers too large"),
+            ("X-Tag: 1\r\n".to_string(), "250 2.0.0"),
+        ] {
+            let message = format!("Subject: Hi\r\n{headers}\r\nHello\r\n.\r\n");
+            dialogue(
+                &mut stream,
+                &[
+                    ("MAIL FROM:<from@example.com>\r\n", "250"),
+                    ("RCPT TO:<to@example.com>\r\n", "250"),
+                    ("DATA\r\n", "354"),
+                    (&message, expected),
+                ],
+            )
+            .await;
+        }
+    }
+
+    #[test]
+    fn test_message_summary_reads_headers() {
+        let summary = MessageSummary::parse(
+            b"Subject: Report\r\nMessage-ID: <abc@example.com>\r\n\r\nBody\r\n",
+        )
+        .unwrap();
+        assert_eq!(summary.subject, "Report");
+        assert_eq!(summary.message_id, "abc@example.com");
+
+        let summary = MessageSummary::parse(b"From: a@example.com\r\n\r\nBody\r\n").unwrap();
+        assert_eq!(summary.subject, "N/A");
+        assert_eq!(summary.message_id, "N/A");
+
+        for data in [&b"garbage\r\n"[..], b"\r\nBody without headers\r\n"] {
+            assert!(matches!(
+                MessageSummary::parse(data),
+                Err(EmailError::ParseFailed(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_decode_auth_plain() {
+        // "\0test\0test" and "admin\0user\0secret"
+        assert_eq!(
+            decode_auth_plain("AHRlc3QAdGVzdA=="),
+            Some(("test".to_string(), "test".to_string()))
+        );
+        assert_eq!(
+            decode_auth_plain("YWRtaW4AdXNlcgBzZWNyZXQ="),
+            Some(("user".to_string(), "secret".to_string()))
+        );
+        for response in [
+            "not base64!",
+            "dGVzdA==",     // "test": no NUL separators
+            "AAB0ZXN0",     // "\0\0test": empty authcid
+            "dQBwAHgAeQ==", // "u\0p\0x\0y": too many fields
+            "",
+        ] {
+            assert_eq!(decode_auth_plain(response), None, "{response:?}");
+        }
+    }
+
+    #[test]
+    fn test_parse_bdat_args() {
+        assert_eq!(parse_bdat_args("100"), Some((100, false)));
+        assert_eq!(parse_bdat_args("0 last"), Some((0, true)));
+        assert_eq!(parse_bdat_args(""), None);
+        assert_eq!(parse_bdat_args("-1"), None);
+        assert_eq!(parse_bdat_args("10 MORE"), None);
+        assert_eq!(parse_bdat_args("10 LAST extra"), None);
+    }
+
+    #[test]
+    fn test_format_status_enhanced_codes() {
+        assert_eq!(format_status(true, "5.3.4", "Too big"), "5.3.4 Too big");
+        assert_eq!(format_status(false, "5.3.4", "Too big"), "Too big");
+    }
+
+    #[tokio::test]
+    async fn test_enhanced_status_codes_can_be_disabled() {
+        let session = SessionConfig {
+            enhanced_status_codes: false,
+            ..Default::default()
+        };
+        let mut stream = spawn_session(session, Arc::new(relay::CapturingMailer::new())).await;
+        dialogue(&mut stream, &[("HELO client.example\r\n", "250")]).await;
+
+        for (command, expected) in [
+            ("MAIL FROM:<from@example.com>\r\n", "250 Ok\r\n"),
+            ("RCPT TO:<to@example.com>\r\n", "250 Ok\r\n"),
+            ("BOGUS\r\n", "500 Command \"BOGUS\" not recognized\r\n"),
+        ] {
+            stream.write_all(command.as_bytes()).await.unwrap();
+            assert_eq!(read_reply(&mut stream).await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_emails_in_flight_gauge_tracks_relay() {
+        struct SlowMailer;
+        #[async_trait::async_trait]
+        impl Mailer for SlowMailer {
+            async fn send(
+                &self,
+                _raw_email: &[u8],
+                _recipients: &[String],
+                _from: &Option<String>,
+            ) -> Result<(), SmtpRelayError> {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                Ok(())
+            }
+        }
+        let metrics = MetricsCollector::new();
+        let session = SessionConfig {
+            metrics: metrics.clone(),
+            ..Default::default()
+        };
+        let mut stream = spawn_session(session, Arc::new(SlowMailer)).await;
+        dialogue(
+            &mut stream,
+            &[
+                ("HELO client.example\r\n", "250"),
+                ("MAIL FROM:<from@example.com>\r\n", "250"),
+                ("RCPT TO:<to@example.com>\r\n", "250"),
+                ("DATA\r\n", "354"),
+            ],
+        )
+        .await;
+        stream
+            .write_all(b"Subject: Slow\r\n\r\nBody\r\n.\r\n")
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(metrics.get_snapshot().await.emails_in_flight, 1);
+
+        let reply = read_reply(&mut stream).await;
+        assert!(reply.starts_with("250"), "got: {reply}");
+        assert_eq!(metrics.get_snapshot().await.emails_in_flight, 0);
+    }
+
+    #[tokio::test]
+    async fn test_recipient_domain_counters() {
+        // Fails any message addressed to bad.example
+        struct DomainMailer;
+        #[async_trait::async_trait]
+        impl Mailer for DomainMailer {
+            async fn send(
+                &self,
+                _raw_email: &[u8],
+                recipients: &[String],
+                _from: &Option<String>,
+            ) -> Result<(), SmtpRelayError> {
+                if recipients
+                    .iter()
+                    .any(|r| r.to_lowercase().ends_with("@bad.example"))
+                {
+                    return Err(SmtpRelayError::Acs(error::AcsError::ServiceUnavailable));
+                }
+                Ok(())
+            }
+        }
+        let metrics = MetricsCollector::new();
+        let session = SessionConfig {
+            metrics: metrics.clone(),
+            ..Default::default()
+        };
+        let mut stream = spawn_session(session, Arc::new(DomainMailer)).await;
+        dialogue(&mut stream, &[("HELO client.example\r\n", "250")]).await;
+        for (recipient, expected) in [("a@good.example", "250"), ("b@Bad.Example", "451")] {
+            let rcpt = format!("RCPT TO:<{recipient}>\r\n");
+            dialogue(
+                &mut stream,
+                &[
+                    ("MAIL FROM:<from@example.com>\r\n", "250"),
+                    (&rcpt, "250"),
+                    ("DATA\r\n", "354"),
+                    ("Subject: Hi\r\n\r\nBody\r\n.\r\n", expected),
+                ],
+            )
+            .await;
+        }
+
+        let snapshot = metrics.get_snapshot().await;
+        assert_eq!(snapshot.by_recipient_domain["good.example"], (1, 0));
+        assert_eq!(snapshot.by_recipient_domain["bad.example"], (0, 1));
+    }
+
+    #[test]
+    fn test_parse_envelope_path_variants() {
+        for args in [
+            "FROM:<a@b.com>",
+            "from: <a@b.com>",
+            "FROM : <a@b.com>",
+            "FROM:a@b.com",
+            "FROM:\t<a@b.com>  ",
+        ] {
+            assert_eq!(
+                parse_envelope_path(args, "FROM"),
+                Some(EnvelopePath {
+                    address: "a@b.com",
+                    ..Default::default()
+                }),
+                "{args}"
+            );
+        }
+
+        assert_eq!(
+            parse_envelope_path("FROM:<a@b.com> SIZE=1024 BODY=8BITMIME smtputf8", "FROM"),
+            Some(EnvelopePath {
+                address: "a@b.com",
+                size: Some(1024),
+                body: Some("8BITMIME"),
+                smtputf8: true,
+            })
+        );
+        assert_eq!(
+            parse_envelope_path("FROM:<>", "FROM").map(|p| p.address),
+            Some("")
+        );
+        assert_eq!(parse_envelope_path("FROM:", "FROM"), None);
+        assert_eq!(parse_envelope_path("FROM:  ", "FROM"), None);
+        assert_eq!(parse_envelope_path("TO:<a@b.com>", "FROM"), None);
+        assert_eq!(parse_envelope_path("FROM <a@b.com>", "FROM"), None);
+        // 'ı' uppercases to 'I' but must not be treated as the ASCII keyword
+        assert_eq!(parse_envelope_path("FRıM:<a@b.com>", "FROM"), None);
+    }
+
+    #[test]
+    fn test_split_command_uppercases_only_the_verb() {
+        assert_eq!(
+            split_command("MaIl FrOm:<John.Doe@Example.com>"),
+            ("MAIL".to_string(), "FrOm:<John.Doe@Example.com>")
+        );
+        assert_eq!(split_command("quit"), ("QUIT".to_string(), ""));
+    }
+
+    #[test]
+    fn test_split_path_separates_parameters() {
+        assert_eq!(
+            split_path(" <用户@example.com> SMTPUTF8"),
+            ("用户@example.com", "SMTPUTF8")
         );
+        assert_eq!(split_path("<>"), ("", ""));
+        assert_eq!(split_path("a@b.com SIZE=10"), ("a@b.com", "SIZE=10"));
     }
 
     #[tokio::test]
-    async fn test_mailer_send_receives_from_argument() {
-        use std::sync::Mutex;
-        struct DummyMailer {
-            pub last_from: Arc<Mutex<Option<Option<String>>>>,
+    async fn test_run_listeners_serves_ipv4_and_ipv6() {
+        let v4 = bind_listener("127.0.0.1:0".parse().unwrap(), true).unwrap();
+        let v4_addr = v4.local_addr().unwrap();
+        // Some CI sandboxes have no IPv6 loopback; still cover the multi-listener path with IPv4
+        let second = bind_listener("[::1]:0".parse().unwrap(), true)
+            .or_else(|_| bind_listener("127.0.0.1:0".parse().unwrap(), true))
+            .unwrap();
+        let second_addr = second.local_addr().unwrap();
+        tokio::spawn(run_listeners(
+            vec![v4, second],
+            Arc::new(relay::CapturingMailer::new()),
+            SessionConfig::default(),
+        ));
+
+        // A v6-only wildcard must leave the same port free for the IPv4 wildcard
+        if let Ok(v6_any) = bind_listener("[::]:0".parse().unwrap(), true) {
+            let port = v6_any.local_addr().unwrap().port();
+            bind_listener(SocketAddr::from(([0, 0, 0, 0], port)), true).unwrap();
         }
-        #[async_trait::async_trait]
-        impl Mailer for DummyMailer {
-            async fn send(
-                &self,
-                _raw_email: &[u8],
-                _recipients: &[String],
-                from: &Option<String>,
-            ) -> anyhow::Result<()> {
-                let mut guard = self.last_from.lock().unwrap();
-                *guard = Some(from.clone());
-                Ok(())
-            }
+
+        for addr in [v4_addr, second_addr] {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let banner = read_reply(&mut stream).await;
+            assert!(banner.starts_with("220"), "no banner from {addr}");
         }
-        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-        let addr = listener.local_addr().unwrap();
-        let last_from = Arc::new(Mutex::new(None));
-        let mailer = Arc::new(DummyMailer {
-            last_from: last_from.clone(),
-        });
-        let max_email_size = 1000;
-        tokio::spawn(async move {
-            let (stream, _) = listener.accept().await.unwrap();
-            handle_connection(stream, mailer, max_email_size, "acs.local".to_string()).await;
-        });
-        let mut stream = TcpStream::connect(addr).await.unwrap();
-        let mut buf = [0u8; 1024];
-        let _ = stream.read(&mut buf).await.unwrap();
-        stream
-            .write_all(b"HELO test.example.com\r\n")
-            .await
-            .unwrap();
-        let _ = stream.read(&mut buf).await.unwrap();
-        stream
-            .write_all(b"MAIL FROM:<from@example.com>\r\n")
-            .await
-            .unwrap();
-        let _ = stream.read(&mut buf).await.unwrap();
-        stream
-            .write_all(b"RCPT TO:<to@example.com>\r\n")
-            .await
-            .unwrap();
-        let _ = stream.read(&mut buf).await.unwrap();
-        stream.write_all(b"DATA\r\n").await.unwrap();
-        let _ = stream.read(&mut buf).await.unwrap();
-        stream.write_all(b"Hello\r\n.\r\n").await.unwrap();
-        let _ = stream.read(&mut buf).await.unwrap();
-        // Check that the DummyMailer received the correct 'from' argument
-        let from_value = last_from.lock().unwrap().clone();
-        assert_eq!(from_value, Some(Some("from@example.com".to_string())));
     }
 
     #[test]
@@ -509,11 +3507,33 @@ mod tests {
 
     #[tokio::test]
     async fn test_client_addr_in_logs() {
+        let (_guard, rx) = capture_logs();
+
+        let session = SessionConfig {
+            max_email_size: 1000,
+            server_name: "acs.local".to_string(),
+            ..Default::default()
+        };
+        let mut stream = spawn_session(session, Arc::new(relay::CapturingMailer::new())).await;
+        dialogue(
+            &mut stream,
+            &[("HELO test.example.com\r\n", "250"), ("QUIT\r\n", "221")],
+        )
+        .await;
+        let logs: Vec<String> = rx.try_iter().collect();
+        let found = logs.iter().any(|log| log.contains("peer_addr"));
+        assert!(found, "Expected peer_addr in logs, got: {logs:?}");
+    }
+
+    // Installs a thread-local subscriber that forwards plain-text log lines to a channel.
+    fn capture_logs() -> (
+        tracing::subscriber::DefaultGuard,
+        std::sync::mpsc::Receiver<String>,
+    ) {
         use std::sync::mpsc;
-        use std::sync::{Arc, Mutex};
+        use std::sync::Mutex;
         use tracing_subscriber::{fmt, EnvFilter};
 
-        // Set up a channel to capture logs
         let (tx, rx) = mpsc::channel();
         let tx = Arc::new(Mutex::new(tx));
         struct ChannelWriter {
@@ -529,49 +3549,840 @@ mod tests {
                 Ok(())
             }
         }
-        let make_writer = {
-            let tx = tx.clone();
-            move || ChannelWriter { tx: tx.clone() }
-        };
+        let make_writer = move || ChannelWriter { tx: tx.clone() };
         let subscriber = fmt()
             .with_env_filter(EnvFilter::new("info"))
             .with_writer(make_writer)
+            .with_ansi(false)
             .finish();
-        let _guard = tracing::subscriber::set_default(subscriber);
+        (tracing::subscriber::set_default(subscriber), rx)
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_sets_peer_addr() {
+        let (_guard, rx) = capture_logs();
+        let mut stream = connect_proxied(
+            SessionConfig::default(),
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 25\r\n",
+        )
+        .await;
+        assert!(read_reply(&mut stream).await.starts_with("220"));
+        dialogue(&mut stream, &[("QUIT\r\n", "221")]).await;
+        close_session(stream).await;
+
+        let logs: Vec<String> = rx.try_iter().collect();
+        let found = logs
+            .iter()
+            .any(|log| log.contains("peer_addr=203.0.113.7:56324"));
+        assert!(found, "Expected proxied peer_addr in logs, got: {logs:?}");
+    }
+
+    #[tokio::test]
+    async fn test_xforward_from_trusted_peer_replaces_client_details() {
+        let (_guard, rx) = capture_logs();
+
+        let mailer = relay::CapturingMailer::new();
+        let session = |xforward_peers| SessionConfig {
+            server_name: "relay.example.net".to_string(),
+            received_header: true,
+            xforward_peers,
+            ..Default::default()
+        };
+
+        let mut stream = spawn_session(
+            session(vec!["127.0.0.1".parse().unwrap()]),
+            Arc::new(mailer.clone()),
+        )
+        .await;
+        stream.write_all(b"EHLO mta.example.net\r\n").await.unwrap();
+        let ehlo = read_reply(&mut stream).await;
+        assert!(
+            ehlo.contains("250-XFORWARD NAME ADDR PROTO HELO\r\n"),
+            "{ehlo}"
+        );
+        dialogue(
+            &mut stream,
+            &[
+                ("XFORWARD ADDR=bogus\r\n", "501"),
+                (
+                    "XFORWARD NAME=origin.example ADDR=198.51.100.7 PROTO=SMTP\r\n",
+                    "250 2.0.0 Ok",
+                ),
+                ("XFORWARD HELO=origin.example\r\n", "250 2.0.0 Ok"),
+                ("MAIL FROM:<from@example.com>\r\n", "250 2.1.0"),
+                ("XFORWARD ADDR=192.0.2.1\r\n", "503"),
+                ("RCPT TO:<to@example.com>\r\n", "250 2.1.5"),
+                ("DATA\r\n", "354"),
+                ("Subject: Hi\r\n\r\nHello\r\n.\r\n", "250 2.0.0"),
+                ("QUIT\r\n", "221"),
+            ],
+        )
+        .await;
+
+        // The forwarded client is recorded instead of the upstream MTA
+        let raw_email = String::from_utf8(mailer.messages()[0].raw_email.clone()).unwrap();
+        assert!(
+            raw_email.starts_with(
+                "Received: from origin.example (origin.example [198.51.100.7]) by\r\n\trelay.example.net with SMTP"
+            ),
+            "{raw_email}"
+        );
+        let logs: Vec<String> = rx.try_iter().collect();
+        assert!(
+            logs.iter()
+                .any(|log| log.contains("forwarded_addr=198.51.100.7")
+                    && log.contains("Successfully relayed")),
+            "Expected forwarded_addr in logs, got: {logs:?}"
+        );
+
+        // Other peers are neither offered XFORWARD nor may use it
+        let mut stream = spawn_session(session(Vec::new()), Arc::new(mailer.clone())).await;
+        stream.write_all(b"EHLO mta.example.net\r\n").await.unwrap();
+        let ehlo = read_reply(&mut stream).await;
+        assert!(!ehlo.contains("XFORWARD"), "{ehlo}");
+        dialogue(&mut stream, &[("XFORWARD ADDR=198.51.100.7\r\n", "500")]).await;
+    }
+
+    #[tokio::test]
+    async fn test_malformed_proxy_header_closes_connection() {
+        let mut stream =
+            connect_proxied(SessionConfig::default(), b"EHLO client.example.com\r\n").await;
+        // The connection is closed without a greeting.
+        let mut buf = Vec::new();
+        let n = stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(n, 0, "Expected no response, got: {buf:?}");
+    }
+
+    #[tokio::test]
+    async fn test_silent_client_is_dropped_while_awaiting_proxy_header() {
+        let session = || SessionConfig {
+            connection_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        // One client that sends nothing, one that stops partway through the header
+        for (header, what) in [
+            (&b""[..], "silent client"),
+            (b"PROXY TCP4 203.0.113.7", "client with a partial header"),
+        ] {
+            let mut stream = connect_proxied(session(), header).await;
+            let mut buf = Vec::new();
+            let n = tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut buf))
+                .await
+                .unwrap_or_else(|_| panic!("{what} should be dropped"))
+                .unwrap();
+            assert_eq!(n, 0, "Expected no response, got: {buf:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit_event_emitted_for_success_and_failure() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let (_guard, rx) = capture_logs();
+
+        // Succeeds on the first message and fails on the second.
+        struct FlakyMailer {
+            calls: AtomicUsize,
+        }
+        #[async_trait::async_trait]
+        impl Mailer for FlakyMailer {
+            async fn send(
+                &self,
+                _raw_email: &[u8],
+                _recipients: &[String],
+                _from: &Option<String>,
+            ) -> Result<(), SmtpRelayError> {
+                if self.calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                    Ok(())
+                } else {
+                    Err(SmtpRelayError::Acs(error::AcsError::ServiceUnavailable))
+                }
+            }
+        }
+        let mailer = Arc::new(FlakyMailer {
+            calls: AtomicUsize::new(0),
+        });
+        let session = SessionConfig {
+            max_email_size: 1000,
+            server_name: "acs.local".to_string(),
+            ..Default::default()
+        };
+        let mut stream = spawn_session(session, mailer).await;
+        dialogue(&mut stream, &[("HELO client.example\r\n", "250")]).await;
+        for expected in ["250", "451"] {
+            dialogue(
+                &mut stream,
+                &[
+                    ("MAIL FROM:<from@example.com>\r\n", "250"),
+                    ("RCPT TO:<to@example.com>\r\n", "250"),
+                    ("DATA\r\n", "354"),
+                    (
+                        "Subject: Audit\r\nMessage-ID: <audit@example.com>\r\n\r\nHi\r\n.\r\n",
+                        expected,
+                    ),
+                ],
+            )
+            .await;
+        }
+        dialogue(&mut stream, &[("QUIT\r\n", "221")]).await;
+
+        let audit_lines: Vec<String> = rx
+            .try_iter()
+            .filter(|log| log.contains(" audit: "))
+            .collect();
+        assert_eq!(
+            audit_lines.len(),
+            2,
+            "Expected two audit events, got: {audit_lines:?}"
+        );
+        for line in &audit_lines {
+            assert!(line.contains("message_id=audit@example.com"));
+            assert!(line.contains("envelope_from=\"from@example.com\""));
+            assert!(line.contains("recipient_count=1"));
+            assert!(line.contains("latency_ms="));
+        }
+        assert!(audit_lines[0].contains("result=\"success\""));
+        assert!(audit_lines[1].contains("result=\"failure\""));
+        assert!(audit_lines[1].contains("Service unavailable"));
+    }
+
+    #[tokio::test]
+    async fn test_log_verbosity_controls_command_logging() {
+        for verbosity in [LogVerbosity::Transactions, LogVerbosity::Commands] {
+            let (_guard, rx) = capture_logs();
+            let session = SessionConfig {
+                max_email_size: 10,
+                log_verbosity: verbosity,
+                ..Default::default()
+            };
+            let mut stream = spawn_session(session, Arc::new(relay::CapturingMailer::new())).await;
+            dialogue(
+                &mut stream,
+                &[
+                    ("EHLO client.example.com\r\n", "250"),
+                    ("MAIL FROM:<from@example.com>\r\n", "250"),
+                    ("RCPT TO:<to@example.com>\r\n", "250"),
+                    ("DATA\r\n", "354"),
+                    ("S: Hi\r\n.\r\n", "250"),
+                    ("MAIL FROM:<from@example.com>\r\n", "250"),
+                    ("RCPT TO:<to@example.com>\r\n", "250"),
+                    ("DATA\r\n", "354"),
+                    ("This line is too long\r\n.\r\n", "552"),
+                ],
+            )
+            .await;
+
+            let logs: Vec<String> = rx.try_iter().collect();
+            let logged = |needle: &str| logs.iter().any(|log| log.contains(needle));
+            // Transaction-level events and problems are visible at every verbosity
+            assert!(logged("New client connection"), "{verbosity:?}: {logs:?}");
+            assert!(logged("Successfully relayed email"), "{verbosity:?}");
+            assert!(logged("Email size exceeds maximum limit"), "{verbosity:?}");
+            assert_eq!(
+                logged("Sent response"),
+                verbosity == LogVerbosity::Commands,
+                "{verbosity:?}"
+            );
+            assert_eq!(
+                logged("Received command"),
+                verbosity == LogVerbosity::Commands,
+                "{verbosity:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_helo_is_logged_with_message() {
+        let (_guard, rx) = capture_logs();
+
+        let mut stream = spawn_session(
+            SessionConfig::default(),
+            Arc::new(relay::CapturingMailer::new()),
+        )
+        .await;
+        dialogue(
+            &mut stream,
+            &[
+                ("EHLO myhost.example.com\r\n", "250"),
+                ("MAIL FROM:<from@example.com>\r\n", "250"),
+                ("RCPT TO:<to@example.com>\r\n", "250"),
+                ("DATA\r\n", "354"),
+                ("Subject: Helo\r\n\r\nHi\r\n.\r\n", "250"),
+                ("QUIT\r\n", "221"),
+            ],
+        )
+        .await;
+
+        let logs: Vec<String> = rx.try_iter().collect();
+        let audit = logs
+            .iter()
+            .find(|log| log.contains(" audit: "))
+            .expect("no audit event");
+        // Check the event's own fields, not just the span prefix
+        let (_, fields) = audit.split_once(" audit: ").unwrap();
+        assert!(
+            fields.contains("client_helo=\"myhost.example.com\""),
+            "{audit}"
+        );
+        // Every log line within the message span carries the identity too
+        assert!(logs
+            .iter()
+            .any(|log| log.contains("Successfully relayed email")
+                && log.contains("client_helo=\"myhost.example.com\"}")));
+    }
+
+    #[tokio::test]
+    async fn test_each_message_gets_distinct_msg_id() {
+        let (_guard, rx) = capture_logs();
+        let mut stream = spawn_session(
+            SessionConfig::default(),
+            Arc::new(relay::CapturingMailer::new()),
+        )
+        .await;
+        dialogue(&mut stream, &[("HELO client.example\r\n", "250")]).await;
+        for body in [
+            &[("DATA\r\n", "354"), ("Subject: Id\r\n\r\n.\r\n", "250")][..],
+            &[("BDAT 15 LAST\r\nSubject: Id\r\n\r\n", "250")],
+        ] {
+            dialogue(
+                &mut stream,
+                &[
+                    ("MAIL FROM:<from@example.com>\r\n", "250"),
+                    ("RCPT TO:<to@example.com>\r\n", "250"),
+                ],
+            )
+            .await;
+            dialogue(&mut stream, body).await;
+        }
+        dialogue(&mut stream, &[("QUIT\r\n", "221")]).await;
+
+        let logs: Vec<String> = rx.try_iter().collect();
+        let msg_ids: Vec<&str> = logs
+            .iter()
+            .filter(|log| log.contains(" audit: "))
+            .filter_map(|log| log.rsplit("msg_id=").next())
+            .filter_map(|rest| rest.split_whitespace().next())
+            .collect();
+        assert_eq!(msg_ids.len(), 2, "Expected two audit events: {logs:?}");
+        assert_ne!(msg_ids[0], msg_ids[1]);
+        for msg_id in &msg_ids {
+            assert!(Uuid::parse_str(msg_id).is_ok(), "{msg_id} is not a UUID");
+            // Other log lines about the message carry the id through its span
+            assert!(logs
+                .iter()
+                .any(|log| log.contains("Successfully relayed email")
+                    && log.contains(&format!("message{{msg_id={msg_id} "))));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_limiter_warns_near_capacity() {
+        let (_guard, rx) = capture_logs();
+        let metrics = MetricsCollector::new();
+        let limiter = ConnectionLimiter::new(3, 2, metrics.clone());
 
-        struct DummyMailer;
+        let first = limiter.try_acquire().await.expect("slot available");
+        assert!(
+            !rx.try_iter()
+                .any(|log| log.contains("Approaching connection limit")),
+            "no warning expected with two slots left"
+        );
+
+        let second = limiter.try_acquire().await.expect("slot available");
+        let third = limiter.try_acquire().await.expect("slot available");
+        let logs: Vec<String> = rx.try_iter().collect();
+        assert!(
+            logs.iter()
+                .any(|log| log.contains("Approaching connection limit")),
+            "Expected a saturation warning, got: {logs:?}"
+        );
+        assert_eq!(metrics.get_snapshot().await.connection_permits_in_use, 3);
+
+        // Saturated: further connections are refused until a slot frees up
+        assert!(limiter.try_acquire().await.is_none());
+        drop((first, second, third));
+        limiter.record_usage().await;
+        assert_eq!(metrics.get_snapshot().await.connection_permits_in_use, 0);
+        assert!(limiter.try_acquire().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_connections_over_limit_are_refused() {
+        // Goes through accept_loop, which owns the limiter, rather than spawn_session
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let session = Arc::new(SessionConfig::default());
+        let limiter = Arc::new(ConnectionLimiter::new(1, 0, session.metrics.clone()));
+        let (_stop_tx, stop_rx) = watch::channel(false);
+        tokio::spawn(accept_loop(
+            listener,
+            Arc::new(relay::CapturingMailer::new()),
+            session,
+            Some(limiter),
+            stop_rx,
+        ));
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        assert!(read_reply(&mut first).await.starts_with("220"));
+
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(
+            read_reply(&mut second).await,
+            "421 4.3.2 Too many connections, try again later\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_open_connections() {
+        // Takes a while to relay, standing in for a slow ACS response
+        struct SlowMailer;
         #[async_trait::async_trait]
-        impl Mailer for DummyMailer {
+        impl Mailer for SlowMailer {
             async fn send(
                 &self,
                 _raw_email: &[u8],
                 _recipients: &[String],
                 _from: &Option<String>,
-            ) -> anyhow::Result<()> {
+            ) -> Result<(), SmtpRelayError> {
+                tokio::time::sleep(Duration::from_millis(300)).await;
                 Ok(())
             }
         }
+
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
-        let mailer = Arc::new(DummyMailer);
-        let max_email_size = 1000;
-        tokio::spawn(async move {
-            let (stream, _) = listener.accept().await.unwrap();
-            handle_connection(stream, mailer, max_email_size, "acs.local".to_string()).await;
-        });
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve_until(
+            vec![listener],
+            Arc::new(SlowMailer),
+            SessionConfig {
+                shutdown_grace_period: Duration::from_secs(5),
+                ..Default::default()
+            },
+            async {
+                let _ = shutdown_rx.await;
+            },
+        ));
+
         let mut stream = TcpStream::connect(addr).await.unwrap();
-        let mut buf = [0u8; 1024];
-        let _ = stream.read(&mut buf).await.unwrap();
+        read_reply(&mut stream).await;
+        dialogue(
+            &mut stream,
+            &[
+                ("HELO test.example.com\r\n", "250"),
+                ("MAIL FROM:<a@example.com>\r\n", "250"),
+                ("RCPT TO:<b@example.com>\r\n", "250"),
+                ("DATA\r\n", "354"),
+            ],
+        )
+        .await;
         stream
-            .write_all(b"HELO test.example.com\r\n")
+            .write_all(b"Subject: Slow\r\n\r\nBody\r\n.\r\n")
             .await
             .unwrap();
-        let _ = stream.read(&mut buf).await.unwrap();
+
+        // Shut down while the relay is still in flight; the server must wait for it
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown_tx.send(()).unwrap();
+        assert!(
+            read_reply(&mut stream).await.starts_with("250"),
+            "in-flight message should still be relayed"
+        );
+        assert!(
+            !server.is_finished(),
+            "run returned before the connection closed"
+        );
         stream.write_all(b"QUIT\r\n").await.unwrap();
-        let _ = stream.read(&mut buf).await.unwrap();
-        // Collect logs
+        tokio::time::timeout(Duration::from_secs(2), server)
+            .await
+            .expect("run should return once the connection closes")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_aborts_connections_after_grace_period() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve_until(
+            vec![listener],
+            Arc::new(relay::CapturingMailer::new()),
+            SessionConfig {
+                shutdown_grace_period: Duration::from_millis(100),
+                ..Default::default()
+            },
+            async {
+                let _ = shutdown_rx.await;
+            },
+        ));
+
+        // An idle client never finishes on its own
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        read_reply(&mut stream).await;
+        shutdown_tx.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(2), server)
+            .await
+            .expect("run should give up after the grace period")
+            .unwrap();
+        assert_eq!(stream.read(&mut [0u8; 256]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_logs_interrupted_work() {
+        // Holds every message until the test ends
+        struct StuckMailer;
+        #[async_trait::async_trait]
+        impl Mailer for StuckMailer {
+            async fn send(
+                &self,
+                _raw_email: &[u8],
+                _recipients: &[String],
+                _from: &Option<String>,
+            ) -> Result<(), SmtpRelayError> {
+                std::future::pending().await
+            }
+        }
+
+        let (_guard, rx) = capture_logs();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve_until(
+            vec![listener],
+            Arc::new(StuckMailer),
+            SessionConfig {
+                shutdown_grace_period: Duration::from_millis(100),
+                ..Default::default()
+            },
+            async {
+                let _ = shutdown_rx.await;
+            },
+        ));
+
+        let mut idle = TcpStream::connect(addr).await.unwrap();
+        read_reply(&mut idle).await;
+        let mut sending = TcpStream::connect(addr).await.unwrap();
+        read_reply(&mut sending).await;
+        dialogue(
+            &mut sending,
+            &[
+                ("HELO test.example.com\r\n", "250"),
+                ("MAIL FROM:<from@example.com>\r\n", "250"),
+                ("RCPT TO:<to@example.com>\r\n", "250"),
+                ("DATA\r\n", "354"),
+            ],
+        )
+        .await;
+        sending
+            .write_all(b"Subject: Hi\r\n\r\nHello\r\n.\r\n")
+            .await
+            .unwrap();
+        // Let the message reach the mailer
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        shutdown_tx.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(2), server)
+            .await
+            .unwrap()
+            .unwrap();
         let logs: Vec<String> = rx.try_iter().collect();
-        let found = logs.iter().any(|log| log.contains("peer_addr"));
-        assert!(found, "Expected peer_addr in logs, got: {logs:?}");
+        let shutdown_line = logs
+            .iter()
+            .find(|line| line.contains("Shutting down server"))
+            .unwrap_or_else(|| panic!("no shutdown event in {logs:?}"));
+        assert!(
+            shutdown_line.contains("connections_active=2"),
+            "{shutdown_line}"
+        );
+        assert!(
+            shutdown_line.contains("emails_in_flight=1"),
+            "{shutdown_line}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_command_is_echoed_without_control_characters() {
+        let (_guard, rx) = capture_logs();
+        let mut stream = spawn_session(
+            SessionConfig::default(),
+            Arc::new(relay::CapturingMailer::new()),
+        )
+        .await;
+        dialogue(&mut stream, &[("HELO client.example\r\n", "250")]).await;
+
+        // A terminal escape and a bare CR that would start a forged reply line
+        stream
+            .write_all(b"fo\x1b[31mo\r250 fake\r\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            read_reply(&mut stream).await,
+            "500 5.5.1 Command \"FO[31MO\" not recognized\r\n"
+        );
+
+        close_session(stream).await;
+        let logs: Vec<String> = rx.try_iter().collect();
+        let warning = logs
+            .iter()
+            .find(|line| line.contains("Unrecognized command"))
+            .unwrap_or_else(|| panic!("no warning in {logs:?}"));
+        assert!(
+            warning.contains("command=fo\\u{1b}[31mo\\r250 fake"),
+            "{warning}"
+        );
+        assert!(!warning.contains(['\x1b', '\r']), "{warning:?}");
+    }
+
+    #[tokio::test]
+    async fn test_subject_escape_sequences_are_escaped_in_logs() {
+        let (_guard, rx) = capture_logs();
+        let mut stream = spawn_session(
+            SessionConfig::default(),
+            Arc::new(relay::CapturingMailer::new()),
+        )
+        .await;
+
+        // Clears the operator's terminal and recolours the rest of the line
+        dialogue(
+            &mut stream,
+            &[
+                ("HELO client.example\r\n", "250"),
+                ("MAIL FROM:<from@example.com>\r\n", "250"),
+                ("RCPT TO:<to@example.com>\r\n", "250"),
+                ("DATA\r\n", "354"),
+                (
+                    "Subject: \x1b[2J\x1b[32mAll good\r\n\r\nHello\r\n.\r\n",
+                    "250 2.0.0",
+                ),
+            ],
+        )
+        .await;
+        close_session(stream).await;
+
+        let logs: Vec<String> = rx.try_iter().collect();
+        let relayed = logs
+            .iter()
+            .find(|line| line.contains("Successfully relayed email"))
+            .unwrap_or_else(|| panic!("no relay event in {logs:?}"));
+        assert!(
+            relayed.contains("subject=\\u{1b}[2J\\u{1b}[32mAll good"),
+            "{relayed}"
+        );
+        assert!(logs.iter().all(|line| !line.contains('\x1b')), "{logs:?}");
+    }
+
+    #[tokio::test]
+    async fn test_greet_delay_rejects_early_talkers() {
+        let session = || SessionConfig {
+            greet_delay: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+
+        // A bot that sends EHLO without waiting for the banner
+        let mut early = connect_session(session(), Arc::new(relay::CapturingMailer::new())).await;
+        early.write_all(b"EHLO bot.example.com\r\n").await.unwrap();
+        let mut reply = String::new();
+        early.read_to_string(&mut reply).await.unwrap();
+        assert!(reply.starts_with("554 5.5.0"), "{reply}");
+        assert!(!reply.contains("220"), "{reply}");
+
+        // A client that waits gets the banner once the delay has passed
+        let started = std::time::Instant::now();
+        let mut patient = connect_session(session(), Arc::new(relay::CapturingMailer::new())).await;
+        let banner = read_reply(&mut patient).await;
+        assert!(banner.starts_with("220 "), "{banner}");
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_logs_connection_totals() {
+        let (_guard, rx) = capture_logs();
+        let mut stream = spawn_session(
+            SessionConfig::default(),
+            Arc::new(relay::CapturingMailer::new()),
+        )
+        .await;
+        dialogue(&mut stream, &[("HELO client.example\r\n", "250")]).await;
+
+        // "Subject: Hi\r\n\r\nHello\r\n" is 22 bytes and "Subject: Yo\r\n\r\nBye\r\n" 20
+        for message in [
+            "Subject: Hi\r\n\r\nHello\r\n.\r\n",
+            "Subject: Yo\r\n\r\nBye\r\n.\r\n",
+        ] {
+            dialogue(
+                &mut stream,
+                &[
+                    ("MAIL FROM:<from@example.com>\r\n", "250"),
+                    ("RCPT TO:<to@example.com>\r\n", "250"),
+                    ("DATA\r\n", "354"),
+                    (message, "250 2.0.0"),
+                ],
+            )
+            .await;
+        }
+        close_session(stream).await;
+
+        let logs: Vec<String> = rx.try_iter().collect();
+        let summary = logs
+            .iter()
+            .find(|line| line.contains("Client disconnected cleanly"))
+            .unwrap_or_else(|| panic!("no disconnect event in {logs:?}"));
+        assert!(summary.contains("messages_relayed=2"), "{summary}");
+        assert!(summary.contains("bytes_received=42"), "{summary}");
+    }
+
+    #[test]
+    fn test_accept_backoff_survives_transient_errors() {
+        let mut backoff = AcceptBackoff::new(Duration::from_millis(100));
+        // EMFILE: too many open files
+        let emfile = io::Error::from_raw_os_error(24);
+        let delays: Vec<_> = (0..8).map(|_| backoff.on_error(&emfile)).collect();
+        assert_eq!(delays[0], Some(Duration::from_millis(100)));
+        assert_eq!(delays[1], Some(Duration::from_millis(200)));
+        assert_eq!(delays[7], Some(MAX_ACCEPT_ERROR_BACKOFF));
+
+        let aborted = io::Error::from(io::ErrorKind::ConnectionAborted);
+        assert!(backoff.on_error(&aborted).is_some());
+
+        // A successful accept starts over from the initial delay
+        backoff.reset();
+        assert_eq!(backoff.on_error(&emfile), Some(Duration::from_millis(100)));
+
+        // A listener that is no longer listening can't recover
+        let invalid = io::Error::from(io::ErrorKind::InvalidInput);
+        assert_eq!(backoff.on_error(&invalid), None);
+    }
+
+    #[tokio::test]
+    async fn test_pause_refuses_new_transactions_until_resumed() {
+        use std::sync::atomic::AtomicBool;
+        let paused = Arc::new(AtomicBool::new(false));
+        let session = || SessionConfig {
+            paused: paused.clone(),
+            ..Default::default()
+        };
+
+        let mut stream = spawn_session(session(), Arc::new(relay::CapturingMailer::new())).await;
+        for (command, expected, pause) in [
+            ("HELO test.example.com\r\n", "250", false),
+            ("MAIL FROM:<from@example.com>\r\n", "250", true),
+            // The transaction already under way is finished
+            ("RCPT TO:<to@example.com>\r\n", "250", true),
+            ("DATA\r\n", "354", true),
+            ("Subject: Hi\r\n\r\nHello\r\n.\r\n", "250", true),
+            // A new one is refused
+            (
+                "MAIL FROM:<from@example.com>\r\n",
+                "421 4.3.2 Service not accepting mail",
+                true,
+            ),
+        ] {
+            dialogue(&mut stream, &[(command, expected)]).await;
+            paused.store(pause, Ordering::Relaxed);
+        }
+        // ...and the connection closed
+        assert_eq!(stream.read(&mut [0u8; 256]).await.unwrap(), 0);
+
+        paused.store(false, Ordering::Relaxed);
+        let mut stream = spawn_session(session(), Arc::new(relay::CapturingMailer::new())).await;
+        dialogue(
+            &mut stream,
+            &[
+                ("HELO test.example.com\r\n", "250"),
+                ("MAIL FROM:<from@example.com>\r\n", "250"),
+            ],
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_relay_expires_after_max_age() {
+        let mailer = relay::CapturingMailer::new();
+        let dead_letter_dir =
+            std::env::temp_dir().join(format!("acs-expired-{}", nanoid::nanoid!(8)));
+        let session = SessionConfig {
+            message_max_age: Some(Duration::from_millis(200)),
+            dead_letter_dir: Some(dead_letter_dir.clone()),
+            ..Default::default()
+        };
+        let mut stream = spawn_session(session, Arc::new(mailer.clone())).await;
+        dialogue(
+            &mut stream,
+            &[
+                ("HELO test.example.com\r\n", "250"),
+                ("MAIL FROM:<from@example.com>\r\n", "250"),
+                ("RCPT TO:<to@example.com>\r\n", "250"),
+                ("DATA\r\n", "354"),
+            ],
+        )
+        .await;
+        // A slow client: the message is complete only after the max age has passed
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        tokio::time::timeout(
+            Duration::from_secs(2),
+            dialogue(
+                &mut stream,
+                &[(
+                    "Subject: Hi\r\n\r\nHello\r\n.\r\n",
+                    "451 4.4.7 Message expired",
+                )],
+            ),
+        )
+        .await
+        .expect("no reply");
+        assert!(mailer.messages().is_empty(), "an expired message was sent");
+        // The client was told to retry, so nothing is dead-lettered
+        assert!(!dead_letter_dir.exists());
+    }
+
+    #[test]
+    fn test_strip_headers_removes_folded_fields() {
+        let mut email_data = [
+            "From: a@example.com\r\n",
+            "BCC: hidden@example.com,\r\n",
+            " other@example.com\r\n",
+            "X-Internal-Id: 42\r\n",
+            "Subject: Hi\r\n",
+            "\r\n",
+            "Bcc: this is body text\r\n",
+        ]
+        .concat()
+        .into_bytes();
+        strip_headers(
+            &mut email_data,
+            &["bcc".to_string(), "X-Internal-*".to_string()],
+        );
+        assert_eq!(
+            email_data,
+            b"From: a@example.com\r\nSubject: Hi\r\n\r\nBcc: this is body text\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stripped_header_is_not_relayed() {
+        let mailer = relay::CapturingMailer::new();
+        let session = SessionConfig {
+            strip_headers: vec!["Bcc".to_string()],
+            ..Default::default()
+        };
+        let mut stream = spawn_session(session, Arc::new(mailer.clone())).await;
+        dialogue(
+            &mut stream,
+            &[
+                ("HELO test.example.com\r\n", "250"),
+                ("MAIL FROM:<from@example.com>\r\n", "250"),
+                ("RCPT TO:<to@example.com>\r\n", "250"),
+                ("DATA\r\n", "354"),
+                (
+                    "Subject: Hi\r\nbcc: secret@example.com\r\n\r\nHello\r\n.\r\n",
+                    "250",
+                ),
+            ],
+        )
+        .await;
+        assert_eq!(
+            mailer.messages()[0].raw_email,
+            b"Subject: Hi\r\n\r\nHello\r\n"
+        );
     }
 }
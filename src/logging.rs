@@ -0,0 +1,130 @@
+use std::fmt::Write as _;
+use tracing::Subscriber;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer, Registry};
+
+// Output format of the process-wide log subscriber
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    // One JSON object per line, for log aggregation
+    #[default]
+    Json,
+    // Multi-line, human-readable output for local development
+    Pretty,
+    // Single-line, human-readable output
+    Compact,
+}
+
+// A layer that sends spans somewhere besides the log, such as an OpenTelemetry collector
+pub type ExportLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+// Builds the log subscriber for the given format. Span fields such as `peer_addr` and
+// `conn_id` are included in every format. The filter applies to `export` as well.
+pub fn subscriber<W>(
+    format: LogFormat,
+    filter: EnvFilter,
+    writer: W,
+    ansi: bool,
+    export: Option<ExportLayer>,
+) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let registry = tracing_subscriber::registry().with(export).with(filter);
+    let layer = fmt::layer().with_writer(writer).with_ansi(ansi);
+    match format {
+        LogFormat::Json => Box::new(registry.with(layer.json())),
+        LogFormat::Pretty => Box::new(registry.with(layer.pretty())),
+        LogFormat::Compact => Box::new(registry.with(layer.compact())),
+    }
+}
+
+// Client-supplied text made safe for a log line: control characters are escaped (e.g.
+// `\u{1b}`, `\r`) so terminal escape sequences and forged line breaks are shown, not run.
+pub struct Escaped<'a>(pub &'a str);
+
+impl std::fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for c in self.0.chars() {
+            if c.is_control() {
+                write!(f, "{}", c.escape_default())?;
+            } else {
+                f.write_char(c)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn log_in_connection_span(format: LogFormat) -> String {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = subscriber(
+            format,
+            EnvFilter::new("info"),
+            move || writer.clone(),
+            false,
+            None,
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!(
+                "handle_connection",
+                peer_addr = "192.0.2.1:40000",
+                conn_id = "abc12345"
+            );
+            let _entered = span.enter();
+            tracing::info!("New client connection");
+        });
+        let output = captured.0.lock().unwrap().clone();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_every_format_includes_connection_span_fields() {
+        for format in [LogFormat::Json, LogFormat::Pretty, LogFormat::Compact] {
+            let output = log_in_connection_span(format);
+            assert!(
+                output.contains("New client connection"),
+                "{format:?}: {output}"
+            );
+            assert!(output.contains("192.0.2.1:40000"), "{format:?}: {output}");
+            assert!(output.contains("abc12345"), "{format:?}: {output}");
+        }
+    }
+
+    #[test]
+    fn test_json_format_is_machine_readable() {
+        let output = log_in_connection_span(LogFormat::Json);
+        let event: serde_json::Value = serde_json::from_str(output.trim()).unwrap();
+        assert_eq!(event["span"]["conn_id"], "abc12345");
+        assert_eq!(event["span"]["peer_addr"], "192.0.2.1:40000");
+    }
+
+    #[test]
+    fn test_escaped_shows_control_characters() {
+        assert_eq!(
+            Escaped("Hi \x1b[31mthere\r\n").to_string(),
+            "Hi \\u{1b}[31mthere\\r\\n"
+        );
+        assert_eq!(Escaped("Grüße").to_string(), "Grüße");
+    }
+}
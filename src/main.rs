@@ -1,28 +1,110 @@
-#[cfg(feature = "health-server")]
-use acs_smtp_relay::health;
-use acs_smtp_relay::relay::{AcsMailer, Mailer};
-use acs_smtp_relay::{metrics, run, Config, MetricsCollector};
+use acs_smtp_relay::config::{parse_auth_credentials, parse_sender_map, parse_sender_quotas};
+use acs_smtp_relay::logging::{self, LogFormat};
+use acs_smtp_relay::{
+    CloudEnvironment, Config, DkimConfig, EmptyHtml, HealthTlsConfig, HtmlPolicy, LogVerbosity,
+    MailerBackend, RecipientCase, RecipientPolicy, SenderDomainCheck, Server, SmtpUpstreamConfig,
+    UpstreamTls,
+};
 use anyhow::{Context, Result};
 use std::env;
+use std::io::IsTerminal;
 use std::net::SocketAddr;
-use std::sync::Arc;
-#[cfg(not(feature = "health-server"))]
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpListener;
-use tracing_subscriber::{fmt, EnvFilter};
+use tracing_subscriber::EnvFilter;
+
+// Placeholder that satisfies config validation when ACS is not used
+const LOCAL_CONNECTION_STRING: &str = "endpoint=https://localhost/;accesskey=bG9jYWw=";
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    tracing::subscriber::set_global_default(
-        fmt::Subscriber::builder()
-            .with_env_filter(EnvFilter::from_default_env())
-            .json()
-            .finish(),
-    )
+    let log_format = match env::var("LOG_FORMAT")
+        .unwrap_or_else(|_| "json".to_string())
+        .to_ascii_lowercase()
+        .as_str()
+    {
+        "json" => LogFormat::Json,
+        "pretty" => LogFormat::Pretty,
+        "compact" => LogFormat::Compact,
+        other => {
+            anyhow::bail!("Unknown LOG_FORMAT '{other}' (expected 'json', 'pretty' or 'compact')")
+        }
+    };
+    // Colour human-readable output only when writing to a terminal
+    let ansi = log_format != LogFormat::Json && std::io::stdout().is_terminal();
+    // Spans are exported to an OpenTelemetry collector when its endpoint is set
+    let otel_endpoint = env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+        .ok()
+        .filter(|endpoint| !endpoint.trim().is_empty());
+    #[cfg(feature = "otel")]
+    let otel = otel_endpoint
+        .as_deref()
+        .map(|endpoint| {
+            let service_name =
+                env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "acs-smtp-relay".to_string());
+            acs_smtp_relay::telemetry::otlp_export(endpoint, &service_name)
+        })
+        .transpose()
+        .context("Failed to set up OpenTelemetry export")?;
+    #[cfg(feature = "otel")]
+    let (otel_provider, export) = otel.map(|otel| (otel.provider, otel.layer)).unzip();
+    #[cfg(not(feature = "otel"))]
+    let export = None;
+    tracing::subscriber::set_global_default(logging::subscriber(
+        log_format,
+        EnvFilter::from_default_env(),
+        std::io::stdout,
+        ansi,
+        export,
+    ))
     .context("Failed to set global logger")?;
+    #[cfg(not(feature = "otel"))]
+    if otel_endpoint.is_some() {
+        tracing::warn!("OTEL_EXPORTER_OTLP_ENDPOINT is set but this build lacks the `otel` feature; spans will not be exported");
+    }
+
+    let mailer_backend = match env::var("MAILER_BACKEND")
+        .unwrap_or_else(|_| "acs".to_string())
+        .to_ascii_lowercase()
+        .as_str()
+    {
+        "acs" => MailerBackend::Acs,
+        "maildir" => MailerBackend::Maildir(
+            env::var("MAILDIR_PATH")
+                .unwrap_or_else(|_| "./maildir".to_string())
+                .into(),
+        ),
+        "smtp" => MailerBackend::Smtp(SmtpUpstreamConfig {
+            host: env::var("SMTP_UPSTREAM_HOST")
+                .context("SMTP_UPSTREAM_HOST must be set when MAILER_BACKEND=smtp")?,
+            port: env::var("SMTP_UPSTREAM_PORT")
+                .unwrap_or_else(|_| "25".to_string())
+                .parse::<u16>()
+                .context("Failed to parse SMTP_UPSTREAM_PORT as u16")?,
+            username: env::var("SMTP_UPSTREAM_USERNAME").ok(),
+            password: env::var("SMTP_UPSTREAM_PASSWORD").ok(),
+            tls: match env::var("SMTP_UPSTREAM_TLS")
+                .unwrap_or_else(|_| "starttls".to_string())
+                .to_ascii_lowercase()
+                .as_str()
+            {
+                "none" => UpstreamTls::None,
+                "starttls" => UpstreamTls::StartTls,
+                "tls" => UpstreamTls::Tls,
+                other => anyhow::bail!(
+                    "Unknown SMTP_UPSTREAM_TLS '{other}' (expected 'none', 'starttls' or 'tls')"
+                ),
+            },
+        }),
+        other => {
+            anyhow::bail!("Unknown MAILER_BACKEND '{other}' (expected 'acs', 'maildir' or 'smtp')")
+        }
+    };
 
-    let connection_string =
-        env::var("ACS_CONNECTION_STRING").context("ACS_CONNECTION_STRING must be set")?;
+    // Only the ACS backend contacts Azure, so ACS credentials are optional for the others
+    let connection_string = match env::var("ACS_CONNECTION_STRING") {
+        Ok(connection_string) => connection_string,
+        Err(_) if mailer_backend != MailerBackend::Acs => LOCAL_CONNECTION_STRING.to_string(),
+        Err(_) => anyhow::bail!("ACS_CONNECTION_STRING must be set"),
+    };
     let sender_address =
         env::var("ACS_SENDER_ADDRESS").context("ACS_SENDER_ADDRESS must be set")?;
     let listen_addr = env::var("LISTEN_ADDR").unwrap_or_else(|_| "0.0.0.0:1025".to_string());
@@ -33,103 +115,487 @@ async fn main() -> Result<(), anyhow::Error> {
         .parse::<usize>()
         .context("Failed to parse MAX_EMAIL_SIZE as usize")?;
 
+    // Lower limit for clients that have not authenticated; unset applies MAX_EMAIL_SIZE
+    let unauthenticated_max_email_size = env::var("UNAUTHENTICATED_MAX_EMAIL_SIZE")
+        .ok()
+        .map(|s| s.parse::<usize>())
+        .transpose()
+        .context("Failed to parse UNAUTHENTICATED_MAX_EMAIL_SIZE as usize")?;
+
+    let max_recipients_per_message = env::var("MAX_RECIPIENTS_PER_MESSAGE")
+        .unwrap_or_else(|_| "100".to_string())
+        .parse::<usize>()
+        .context("Failed to parse MAX_RECIPIENTS_PER_MESSAGE as usize")?;
+
+    let max_commands_per_message = env::var("MAX_COMMANDS_PER_MESSAGE")
+        .unwrap_or_else(|_| "100".to_string())
+        .parse::<usize>()
+        .context("Failed to parse MAX_COMMANDS_PER_MESSAGE as usize")?;
+
+    let max_header_count = env::var("MAX_HEADER_COUNT")
+        .unwrap_or_else(|_| "1000".to_string())
+        .parse::<usize>()
+        .context("Failed to parse MAX_HEADER_COUNT as usize")?;
+
+    let max_header_bytes = env::var("MAX_HEADER_BYTES")
+        .unwrap_or_else(|_| "102400".to_string())
+        .parse::<usize>()
+        .context("Failed to parse MAX_HEADER_BYTES as usize")?;
+
+    // 0 disables the limit
+    let max_concurrent_connections = env::var("MAX_CONCURRENT_CONNECTIONS")
+        .unwrap_or_else(|_| "1000".to_string())
+        .parse::<usize>()
+        .context("Failed to parse MAX_CONCURRENT_CONNECTIONS as usize")?;
+
+    // 0 disables the limit
+    let max_concurrent_data_transfers = env::var("MAX_CONCURRENT_DATA_TRANSFERS")
+        .unwrap_or_else(|_| "0".to_string())
+        .parse::<usize>()
+        .context("Failed to parse MAX_CONCURRENT_DATA_TRANSFERS as usize")?;
+
+    let connection_warning_threshold = env::var("CONNECTION_WARNING_THRESHOLD")
+        .ok()
+        .map(|s| s.parse::<usize>())
+        .transpose()
+        .context("Failed to parse CONNECTION_WARNING_THRESHOLD as usize")?;
+
+    let max_messages_per_second = env::var("MAX_MESSAGES_PER_SECOND")
+        .ok()
+        .map(|s| s.parse::<f64>())
+        .transpose()
+        .context("Failed to parse MAX_MESSAGES_PER_SECOND as a number")?;
+
+    let dedup_window = env::var("DEDUP_WINDOW_SECS")
+        .ok()
+        .map(|s| s.parse::<u64>().map(std::time::Duration::from_secs))
+        .transpose()
+        .context("Failed to parse DEDUP_WINDOW_SECS as u64")?;
+
+    let sender_quotas = env::var("SENDER_QUOTAS")
+        .ok()
+        .map(|s| parse_sender_quotas(&s))
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("Failed to parse SENDER_QUOTAS: {}", e))?
+        .unwrap_or_default();
+
+    let sender_quota_window_secs = env::var("SENDER_QUOTA_WINDOW_SECS")
+        .unwrap_or_else(|_| "3600".to_string())
+        .parse::<u64>()
+        .context("Failed to parse SENDER_QUOTA_WINDOW_SECS as u64")?;
+
+    let message_max_age = env::var("MESSAGE_MAX_AGE_SECS")
+        .ok()
+        .map(|s| s.parse::<u64>().map(std::time::Duration::from_secs))
+        .transpose()
+        .context("Failed to parse MESSAGE_MAX_AGE_SECS as u64")?;
+
+    let greet_delay = env::var("SMTP_GREET_DELAY_MS")
+        .ok()
+        .map(|s| s.parse::<u64>().map(std::time::Duration::from_millis))
+        .transpose()
+        .context("Failed to parse SMTP_GREET_DELAY_MS as u64")?;
+
+    let shutdown_grace_period_secs = env::var("SHUTDOWN_GRACE_PERIOD_SECS")
+        .unwrap_or_else(|_| "30".to_string())
+        .parse::<u64>()
+        .context("Failed to parse SHUTDOWN_GRACE_PERIOD_SECS as u64")?;
+
+    let write_timeout_secs = env::var("SMTP_WRITE_TIMEOUT_SECS")
+        .unwrap_or_else(|_| "30".to_string())
+        .parse::<u64>()
+        .context("Failed to parse SMTP_WRITE_TIMEOUT_SECS as u64")?;
+
+    let accept_error_backoff_ms = env::var("SMTP_ACCEPT_ERROR_BACKOFF_MS")
+        .unwrap_or_else(|_| "100".to_string())
+        .parse::<u64>()
+        .context("Failed to parse SMTP_ACCEPT_ERROR_BACKOFF_MS as u64")?;
+
+    let acs_send_path = env::var("ACS_SEND_PATH")
+        .unwrap_or_else(|_| acs_smtp_relay::relay::DEFAULT_SEND_PATH.to_string());
+
+    let circuit_breaker_threshold = env::var("ACS_CIRCUIT_BREAKER_THRESHOLD")
+        .unwrap_or_else(|_| "5".to_string())
+        .parse::<u32>()
+        .context("Failed to parse ACS_CIRCUIT_BREAKER_THRESHOLD as u32")?;
+
+    let circuit_breaker_cooldown_secs = env::var("ACS_CIRCUIT_BREAKER_COOLDOWN_SECS")
+        .unwrap_or_else(|_| "30".to_string())
+        .parse::<u64>()
+        .context("Failed to parse ACS_CIRCUIT_BREAKER_COOLDOWN_SECS as u64")?;
+
+    let http_pool_max_idle_per_host = env::var("HTTP_POOL_MAX_IDLE_PER_HOST")
+        .unwrap_or_else(|_| "10".to_string())
+        .parse::<usize>()
+        .context("Failed to parse HTTP_POOL_MAX_IDLE_PER_HOST as usize")?;
+
+    let http_pool_idle_timeout_secs = env::var("HTTP_POOL_IDLE_TIMEOUT_SECS")
+        .unwrap_or_else(|_| "90".to_string())
+        .parse::<u64>()
+        .context("Failed to parse HTTP_POOL_IDLE_TIMEOUT_SECS as u64")?;
+
+    let http_request_timeout_secs = env::var("HTTP_REQUEST_TIMEOUT_SECS")
+        .unwrap_or_else(|_| "30".to_string())
+        .parse::<u64>()
+        .context("Failed to parse HTTP_REQUEST_TIMEOUT_SECS as u64")?;
+
+    // ACS_HTTP_PROXY takes precedence over the conventional HTTPS_PROXY
+    let http_proxy = ["ACS_HTTP_PROXY", "HTTPS_PROXY", "https_proxy"]
+        .iter()
+        .find_map(|name| env::var(name).ok().filter(|v| !v.is_empty()));
+    let no_proxy = ["NO_PROXY", "no_proxy"]
+        .iter()
+        .find_map(|name| env::var(name).ok().filter(|v| !v.is_empty()));
+
+    let allow_insecure_endpoint = env::var("ACS_ALLOW_INSECURE_ENDPOINT")
+        .unwrap_or_else(|_| "false".to_string())
+        .parse::<bool>()
+        .context("Failed to parse ACS_ALLOW_INSECURE_ENDPOINT as bool")?;
+
+    let proxy_protocol = env::var("PROXY_PROTOCOL")
+        .unwrap_or_else(|_| "false".to_string())
+        .parse::<bool>()
+        .context("Failed to parse PROXY_PROTOCOL as bool")?;
+
+    let xforward_peers = env::var("XFORWARD_PEERS")
+        .ok()
+        .map(|s| {
+            s.split(',')
+                .map(str::trim)
+                .filter(|peer| !peer.is_empty())
+                .map(str::parse::<std::net::IpAddr>)
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .transpose()
+        .context("Failed to parse XFORWARD_PEERS as a list of IP addresses")?
+        .unwrap_or_default();
+
+    let disable_user_engagement_tracking = env::var("ACS_DISABLE_USER_ENGAGEMENT_TRACKING")
+        .unwrap_or_else(|_| "false".to_string())
+        .parse::<bool>()
+        .context("Failed to parse ACS_DISABLE_USER_ENGAGEMENT_TRACKING as bool")?;
+
+    let default_subject = env::var("ACS_DEFAULT_SUBJECT")
+        .ok()
+        .filter(|subject| !subject.trim().is_empty());
+
+    let reject_missing_subject = env::var("ACS_REJECT_MISSING_SUBJECT")
+        .unwrap_or_else(|_| "false".to_string())
+        .parse::<bool>()
+        .context("Failed to parse ACS_REJECT_MISSING_SUBJECT as bool")?;
+
+    let strip_headers = env::var("STRIP_HEADERS")
+        .map(|s| {
+            s.split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    let allowed_content_types = env::var("ACS_ALLOWED_CONTENT_TYPES").ok().map(|s| {
+        s.split(',')
+            .map(|ct| ct.trim().to_ascii_lowercase())
+            .filter(|ct| !ct.is_empty())
+            .collect::<Vec<_>>()
+    });
+
+    let html_policy = match env::var("ACS_HTML_POLICY")
+        .unwrap_or_else(|_| "off".to_string())
+        .to_ascii_lowercase()
+        .as_str()
+    {
+        "off" => HtmlPolicy::Off,
+        "sanitize" => HtmlPolicy::Sanitize,
+        "reject" => HtmlPolicy::Reject,
+        other => {
+            anyhow::bail!(
+                "Unknown ACS_HTML_POLICY '{other}' (expected 'off', 'sanitize' or 'reject')"
+            )
+        }
+    };
+
+    let empty_html = match env::var("ACS_EMPTY_HTML")
+        .unwrap_or_else(|_| "drop".to_string())
+        .to_ascii_lowercase()
+        .as_str()
+    {
+        "drop" => EmptyHtml::Drop,
+        "keep" => EmptyHtml::Keep,
+        "prefer-text" => EmptyHtml::PreferText,
+        other => {
+            anyhow::bail!(
+                "Unknown ACS_EMPTY_HTML '{other}' (expected 'drop', 'keep' or 'prefer-text')"
+            )
+        }
+    };
+
+    let recipient_policy = match env::var("ACS_RECIPIENT_POLICY")
+        .unwrap_or_else(|_| "envelope".to_string())
+        .to_ascii_lowercase()
+        .as_str()
+    {
+        "envelope" => RecipientPolicy::Envelope,
+        "merge" => RecipientPolicy::Merge,
+        other => {
+            anyhow::bail!("Unknown ACS_RECIPIENT_POLICY '{other}' (expected 'envelope' or 'merge')")
+        }
+    };
+
+    let recipient_case = match env::var("ACS_RECIPIENT_CASE")
+        .unwrap_or_else(|_| "preserve".to_string())
+        .to_ascii_lowercase()
+        .as_str()
+    {
+        "preserve" => RecipientCase::Preserve,
+        "lowercase-domain" => RecipientCase::LowercaseDomain,
+        "lowercase" => RecipientCase::Lowercase,
+        other => anyhow::bail!(
+            "Unknown ACS_RECIPIENT_CASE '{other}' (expected 'preserve', 'lowercase-domain' or 'lowercase')"
+        ),
+    };
+
+    let cloud_environment = match env::var("ACS_CLOUD_ENVIRONMENT")
+        .unwrap_or_else(|_| "public".to_string())
+        .to_ascii_lowercase()
+        .as_str()
+    {
+        "public" => CloudEnvironment::Public,
+        "usgovernment" => CloudEnvironment::UsGovernment,
+        "china" => CloudEnvironment::China,
+        other => anyhow::bail!(
+            "Unknown ACS_CLOUD_ENVIRONMENT '{other}' (expected 'public', 'usgovernment' or 'china')"
+        ),
+    };
+
+    // Opt-in: checking the sender domain needs a service principal that can read the Email
+    // Communication Services resource through Azure Resource Manager
+    let verify_sender_domain = env::var("ACS_VERIFY_SENDER_DOMAIN")
+        .unwrap_or_else(|_| "false".to_string())
+        .parse::<bool>()
+        .context("Failed to parse ACS_VERIFY_SENDER_DOMAIN as bool")?;
+    let sender_domain_check = if verify_sender_domain {
+        let required = |name: &str| {
+            env::var(name)
+                .with_context(|| format!("{name} must be set when ACS_VERIFY_SENDER_DOMAIN=true"))
+        };
+        Some(SenderDomainCheck {
+            tenant_id: required("AZURE_TENANT_ID")?,
+            client_id: required("AZURE_CLIENT_ID")?,
+            client_secret: required("AZURE_CLIENT_SECRET")?,
+            email_service_id: required("ACS_EMAIL_SERVICE_ID")?,
+        })
+    } else {
+        None
+    };
+
+    let force_plain_text = env::var("ACS_FORCE_PLAIN_TEXT")
+        .unwrap_or_else(|_| "false".to_string())
+        .parse::<bool>()
+        .context("Failed to parse ACS_FORCE_PLAIN_TEXT as bool")?;
+
+    let enhanced_status_codes = env::var("ENHANCED_STATUS_CODES")
+        .unwrap_or_else(|_| "true".to_string())
+        .parse::<bool>()
+        .context("Failed to parse ENHANCED_STATUS_CODES as bool")?;
+
+    let log_verbosity = match env::var("LOG_VERBOSITY")
+        .unwrap_or_else(|_| "transactions".to_string())
+        .to_ascii_lowercase()
+        .as_str()
+    {
+        "transactions" => LogVerbosity::Transactions,
+        "commands" => LogVerbosity::Commands,
+        other => {
+            anyhow::bail!("Unknown LOG_VERBOSITY '{other}' (expected 'transactions' or 'commands')")
+        }
+    };
+
+    let validate_recipients = env::var("VALIDATE_RECIPIENTS")
+        .unwrap_or_else(|_| "true".to_string())
+        .parse::<bool>()
+        .context("Failed to parse VALIDATE_RECIPIENTS as bool")?;
+
+    let date_header = env::var("ADD_DATE_HEADER")
+        .unwrap_or_else(|_| "true".to_string())
+        .parse::<bool>()
+        .context("Failed to parse ADD_DATE_HEADER as bool")?;
+
+    let received_header = env::var("ADD_RECEIVED_HEADER")
+        .unwrap_or_else(|_| "true".to_string())
+        .parse::<bool>()
+        .context("Failed to parse ADD_RECEIVED_HEADER as bool")?;
+
+    let allow_metrics_reset = env::var("ALLOW_METRICS_RESET")
+        .unwrap_or_else(|_| "false".to_string())
+        .parse::<bool>()
+        .context("Failed to parse ALLOW_METRICS_RESET as bool")?;
+
+    let metrics_auth_token = env::var("METRICS_AUTH_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty());
+    // Opt-in: POST /admin/selftest sends its test message to this address
+    let selftest_recipient = env::var("SELFTEST_RECIPIENT")
+        .ok()
+        .filter(|recipient| !recipient.trim().is_empty());
+    let server_hostname = env::var("SERVER_HOSTNAME").ok();
+    let dead_letter_dir = env::var("DEAD_LETTER_DIR").ok().map(Into::into);
+    let queue_dir = env::var("QUEUE_DIR").ok().map(Into::into);
+
     let allowed_sender_domains = env::var("ACS_ALLOWED_SENDER_DOMAINS")
         .ok()
         .map(|s| s.split(',').map(|d| d.trim().to_string()).collect());
 
-    // Parse listen address
-    let smtp_bind_address: SocketAddr = listen_addr
-        .parse()
-        .context("Failed to parse LISTEN_ADDR as a socket address")?;
+    let unauthenticated_recipient_domains = env::var("UNAUTHENTICATED_RECIPIENT_DOMAINS")
+        .ok()
+        .map(|s| s.split(',').map(|d| d.trim().to_string()).collect());
+
+    // Without credentials any AUTH PLAIN succeeds
+    let auth_credentials = env::var("AUTH_CREDENTIALS")
+        .ok()
+        .map(|s| parse_auth_credentials(&s))
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("Failed to parse AUTH_CREDENTIALS: {}", e))?
+        .unwrap_or_default();
+
+    let sender_map = env::var("ACS_SENDER_MAP")
+        .ok()
+        .map(|s| parse_sender_map(&s))
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("Failed to parse ACS_SENDER_MAP: {}", e))?
+        .unwrap_or_default();
+
+    let dkim_config = match (
+        env::var("DKIM_DOMAIN"),
+        env::var("DKIM_SELECTOR"),
+        env::var("DKIM_PRIVATE_KEY_PATH"),
+    ) {
+        (Ok(domain), Ok(selector), Ok(private_key_path)) => Some(DkimConfig {
+            domain,
+            selector,
+            private_key_path: private_key_path.into(),
+        }),
+        (Err(_), Err(_), Err(_)) => None,
+        _ => anyhow::bail!(
+            "DKIM_DOMAIN, DKIM_SELECTOR and DKIM_PRIVATE_KEY_PATH must be set together"
+        ),
+    };
+
+    let health_tls = match (
+        env::var("HEALTH_TLS_CERT_PATH"),
+        env::var("HEALTH_TLS_KEY_PATH"),
+    ) {
+        (Ok(cert_path), Ok(key_path)) => Some(HealthTlsConfig {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+        }),
+        (Err(_), Err(_)) => None,
+        _ => anyhow::bail!("HEALTH_TLS_CERT_PATH and HEALTH_TLS_KEY_PATH must be set together"),
+    };
+
+    // Parse listen addresses; a comma-separated list serves e.g. IPv4 and IPv6 side by side
+    let mut smtp_bind_addresses = listen_addr
+        .split(',')
+        .map(|addr| {
+            addr.trim().parse::<SocketAddr>().with_context(|| {
+                format!(
+                    "Failed to parse LISTEN_ADDR entry '{}' as a socket address",
+                    addr.trim()
+                )
+            })
+        })
+        .collect::<Result<Vec<_>>>()?
+        .into_iter();
+    let smtp_bind_address = smtp_bind_addresses
+        .next()
+        .context("LISTEN_ADDR must contain at least one address")?;
     let health_bind_address: SocketAddr = health_listen_addr
         .parse()
         .context("Failed to parse HEALTH_LISTEN_ADDR as a socket address")?;
 
-    // Create and validate configuration
+    // Create and validate configuration; the endpoint is checked against the settings
+    // below by Server::from_config
     let mut config = Config::new(
         smtp_bind_address,
         &connection_string,
         sender_address,
         allowed_sender_domains,
     )
-    .map_err(|e| anyhow::anyhow!("Configuration error: {}", e))?;
+    .context("Configuration error")?;
 
     // Override with environment variables if provided
+    config.allow_insecure_endpoint = allow_insecure_endpoint;
+    config.cloud_environment = cloud_environment;
+    config.sender_domain_check = sender_domain_check;
+    config.additional_bind_addresses = smtp_bind_addresses.collect();
+    config.mailer_backend = mailer_backend;
     config.max_message_size = max_email_size;
-
-    // Re-validate after modifications
-    config
-        .validate()
-        .map_err(|e| anyhow::anyhow!("Configuration validation failed: {}", e))?;
-
-    // Create HTTP client with connection pooling
-    let http_client = reqwest::Client::builder()
-        .pool_max_idle_per_host(10)
-        .pool_idle_timeout(std::time::Duration::from_secs(90))
-        .timeout(std::time::Duration::from_secs(30))
-        .build()
-        .context("Failed to create HTTP client")?;
-
-    let mailer: Arc<dyn Mailer> = Arc::new(AcsMailer::new(
-        http_client,
-        config.acs_config.endpoint.clone(),
-        config.acs_config.access_key.clone(),
-        config.sender_address.clone(),
-        config.allowed_sender_domains.clone(),
-    ));
-
-    // Set up metrics collection
-    let metrics_collector = MetricsCollector::new();
-
-    // Start metrics logging every 5 minutes
-    metrics::start_metrics_logger(
-        metrics_collector.clone(),
-        std::time::Duration::from_secs(300),
-    );
-
-    // --- Start the health check server ---
-    #[cfg(feature = "health-server")]
-    {
-        tracing::info!(health_addr = %health_bind_address, "Starting warp-based HTTP health check server");
-        let metrics_collector = metrics_collector.clone();
-        tokio::spawn(async move {
-            if let Err(e) =
-                health::start_health_server(health_bind_address, metrics_collector).await
-            {
-                tracing::error!(error = ?e, "Health check server failed");
-            }
-        });
-    }
-    #[cfg(not(feature = "health-server"))]
-    {
-        let health_listener = TcpListener::bind(health_bind_address).await?;
-        tracing::info!(health_addr = %health_listener.local_addr()?, "Starting silent health check server");
-        tokio::spawn(async move {
-            loop {
-                if let Ok((mut stream, _)) = health_listener.accept().await {
-                    // This is a health check. Accept, write a minimal OK, and immediately close. No logging.
-                    let _ = stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await;
-                    let _ = stream.shutdown().await;
-                }
-            }
-        });
+    config.unauthenticated_max_message_size = unauthenticated_max_email_size;
+    config.unauthenticated_recipient_domains = unauthenticated_recipient_domains;
+    config.auth_credentials = auth_credentials;
+    config.sender_map = sender_map;
+    config.dkim = dkim_config;
+    config.health_tls = health_tls;
+    config.max_recipients_per_message = max_recipients_per_message;
+    config.max_commands_per_message = max_commands_per_message;
+    config.max_header_count = max_header_count;
+    config.max_header_bytes = max_header_bytes;
+    config.max_concurrent_connections = Some(max_concurrent_connections).filter(|&max| max > 0);
+    config.connection_warning_threshold = connection_warning_threshold;
+    config.max_concurrent_data_transfers =
+        Some(max_concurrent_data_transfers).filter(|&max| max > 0);
+    config.http_pool_max_idle_per_host = http_pool_max_idle_per_host;
+    config.http_pool_idle_timeout = std::time::Duration::from_secs(http_pool_idle_timeout_secs);
+    config.http_request_timeout = std::time::Duration::from_secs(http_request_timeout_secs);
+    config.http_proxy = http_proxy;
+    config.no_proxy = no_proxy;
+    config.shutdown_grace_period = std::time::Duration::from_secs(shutdown_grace_period_secs);
+    config.acs_send_path = acs_send_path;
+    config.circuit_breaker_threshold = circuit_breaker_threshold;
+    config.circuit_breaker_cooldown = std::time::Duration::from_secs(circuit_breaker_cooldown_secs);
+    config.write_timeout = std::time::Duration::from_secs(write_timeout_secs);
+    config.accept_error_backoff = std::time::Duration::from_millis(accept_error_backoff_ms);
+    config.proxy_protocol = proxy_protocol;
+    config.xforward_peers = xforward_peers;
+    config.enhanced_status_codes = enhanced_status_codes;
+    config.received_header = received_header;
+    config.date_header = date_header;
+    config.validate_recipients = validate_recipients;
+    config.max_messages_per_second = max_messages_per_second;
+    config.dedup_window = dedup_window;
+    config.sender_quotas = sender_quotas;
+    config.sender_quota_window = std::time::Duration::from_secs(sender_quota_window_secs);
+    config.log_verbosity = log_verbosity;
+    config.server_hostname = server_hostname;
+    config.dead_letter_dir = dead_letter_dir;
+    config.queue_dir = queue_dir;
+    config.message_max_age = message_max_age;
+    config.greet_delay = greet_delay;
+    config.disable_user_engagement_tracking = disable_user_engagement_tracking;
+    config.default_subject = default_subject;
+    config.reject_missing_subject = reject_missing_subject;
+    if let Some(allowed_content_types) = allowed_content_types {
+        config.allowed_content_types = allowed_content_types;
     }
+    config.strip_headers = strip_headers;
+    config.html_policy = html_policy;
+    config.empty_html = empty_html;
+    config.recipient_policy = recipient_policy;
+    config.recipient_case = recipient_case;
+    config.force_plain_text = force_plain_text;
+    config.allow_metrics_reset = allow_metrics_reset;
+    config.metrics_auth_token = metrics_auth_token;
+    config.selftest_recipient = selftest_recipient;
 
-    // --- Start the main SMTP server ---
-    let smtp_listener = TcpListener::bind(config.smtp_bind_address).await?;
-    let actual_addr = smtp_listener.local_addr()?;
-    tracing::info!(
-        listen_addr = %actual_addr,
-        max_email_size_bytes = config.max_message_size,
-        "SMTP-to-ACS relay listening for connections"
-    );
-    run(
-        smtp_listener,
-        mailer,
-        config.max_message_size,
-        actual_addr.ip().to_string(),
-    )
-    .await;
-    tracing::info!("Server has shut down gracefully.");
-    Ok(())
+    config.health_bind_address = Some(health_bind_address);
+
+    let result = Server::from_config(config).await?.run().await;
+    // Flush spans still buffered for export
+    #[cfg(feature = "otel")]
+    if let Some(provider) = otel_provider {
+        if let Err(e) = provider.shutdown() {
+            tracing::warn!(error = %e, "Failed to flush OpenTelemetry spans");
+        }
+    }
+    result
 }
@@ -1,4 +1,6 @@
+use crate::breaker::{CircuitBreaker, CircuitState};
 use serde::Serialize;
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
@@ -9,24 +11,58 @@ use tracing::{info, warn};
 pub struct Metrics {
     pub connections_total: u64,
     pub connections_active: u64,
+    // Connection limit slots currently held (0 when no limit is configured)
+    pub connection_permits_in_use: u64,
+    pub emails_in_flight: u64,
     pub emails_sent_total: u64,
     pub emails_failed_total: u64,
-    pub bytes_processed_total: u64,
+    // Message bytes received from SMTP clients
+    pub bytes_received_total: u64,
+    // Request body bytes posted to ACS, larger than the message due to JSON/base64 overhead
+    pub bytes_sent_to_acs_total: u64,
     pub response_times: Vec<Duration>,
     pub errors_by_type: std::collections::HashMap<String, u64>,
+    // Responses from the ACS send endpoint by HTTP status, successes included
+    pub acs_status_codes: HashMap<u16, u64>,
+    // (sent, failed) message counts keyed by lowercased recipient domain; past
+    // MAX_RECIPIENT_DOMAINS, new domains are counted under OTHER_RECIPIENT_DOMAINS
+    pub by_recipient_domain: HashMap<String, (u64, u64)>,
+    // State of the ACS circuit breaker; None when no breaker is configured
+    pub acs_circuit_state: Option<CircuitState>,
     pub uptime_start: Option<Instant>,
 }
 
+// Number of recipient domains included in the serialized metrics
+const TOP_RECIPIENT_DOMAINS: usize = 20;
+
+// Clients choose recipient domains, so only this many are tracked individually
+const MAX_RECIPIENT_DOMAINS: usize = 1000;
+// Key under which domains beyond MAX_RECIPIENT_DOMAINS are counted together
+const OTHER_RECIPIENT_DOMAINS: &str = "(other)";
+
+#[derive(Debug, Serialize)]
+pub struct RecipientDomainStats {
+    pub domain: String,
+    pub sent: u64,
+    pub failed: u64,
+}
+
 // Serializable version of metrics for JSON output
 #[derive(Debug, Serialize)]
 pub struct SerializableMetrics {
     pub connections_total: u64,
     pub connections_active: u64,
+    pub connection_permits_in_use: u64,
+    pub emails_in_flight: u64,
     pub emails_sent_total: u64,
     pub emails_failed_total: u64,
-    pub bytes_processed_total: u64,
+    pub bytes_received_total: u64,
+    pub bytes_sent_to_acs_total: u64,
     pub response_times_count: usize,
     pub errors_by_type: std::collections::HashMap<String, u64>,
+    pub acs_status_codes: HashMap<u16, u64>,
+    pub top_recipient_domains: Vec<RecipientDomainStats>,
+    pub acs_circuit_state: Option<CircuitState>,
     pub uptime_seconds: Option<u64>,
     pub average_response_time_ms: Option<u64>,
     pub success_rate_percent: f64,
@@ -51,6 +87,20 @@ impl Metrics {
         }
     }
 
+    pub fn set_connection_permits_in_use(&mut self, in_use: u64) {
+        self.connection_permits_in_use = in_use;
+    }
+
+    pub fn increment_emails_in_flight(&mut self) {
+        self.emails_in_flight += 1;
+    }
+
+    pub fn decrement_emails_in_flight(&mut self) {
+        if self.emails_in_flight > 0 {
+            self.emails_in_flight -= 1;
+        }
+    }
+
     pub fn increment_emails_sent(&mut self) {
         self.emails_sent_total += 1;
     }
@@ -59,8 +109,12 @@ impl Metrics {
         self.emails_failed_total += 1;
     }
 
-    pub fn add_bytes_processed(&mut self, bytes: u64) {
-        self.bytes_processed_total += bytes;
+    pub fn add_bytes_received(&mut self, bytes: u64) {
+        self.bytes_received_total += bytes;
+    }
+
+    pub fn add_bytes_sent_to_acs(&mut self, bytes: u64) {
+        self.bytes_sent_to_acs_total += bytes;
     }
 
     pub fn record_response_time(&mut self, duration: Duration) {
@@ -78,6 +132,45 @@ impl Metrics {
             .or_insert(0) += 1;
     }
 
+    pub fn record_acs_status(&mut self, status: u16) {
+        *self.acs_status_codes.entry(status).or_insert(0) += 1;
+    }
+
+    pub fn record_recipient_domain(&mut self, domain: &str, success: bool) {
+        let mut domain = domain.to_lowercase();
+        if !self.by_recipient_domain.contains_key(&domain)
+            && self.by_recipient_domain.len() >= MAX_RECIPIENT_DOMAINS
+        {
+            domain = OTHER_RECIPIENT_DOMAINS.to_string();
+        }
+        let (sent, failed) = self.by_recipient_domain.entry(domain).or_insert((0, 0));
+        if success {
+            *sent += 1;
+        } else {
+            *failed += 1;
+        }
+    }
+
+    // Recipient domains with the most traffic, bounded to keep the output small
+    pub fn top_recipient_domains(&self, limit: usize) -> Vec<RecipientDomainStats> {
+        let mut domains: Vec<RecipientDomainStats> = self
+            .by_recipient_domain
+            .iter()
+            .map(|(domain, &(sent, failed))| RecipientDomainStats {
+                domain: domain.clone(),
+                sent,
+                failed,
+            })
+            .collect();
+        domains.sort_by(|a, b| {
+            (b.sent + b.failed)
+                .cmp(&(a.sent + a.failed))
+                .then_with(|| a.domain.cmp(&b.domain))
+        });
+        domains.truncate(limit);
+        domains
+    }
+
     pub fn get_average_response_time(&self) -> Option<Duration> {
         if self.response_times.is_empty() {
             return None;
@@ -104,11 +197,17 @@ impl Metrics {
         SerializableMetrics {
             connections_total: self.connections_total,
             connections_active: self.connections_active,
+            connection_permits_in_use: self.connection_permits_in_use,
+            emails_in_flight: self.emails_in_flight,
             emails_sent_total: self.emails_sent_total,
             emails_failed_total: self.emails_failed_total,
-            bytes_processed_total: self.bytes_processed_total,
+            bytes_received_total: self.bytes_received_total,
+            bytes_sent_to_acs_total: self.bytes_sent_to_acs_total,
             response_times_count: self.response_times.len(),
             errors_by_type: self.errors_by_type.clone(),
+            acs_status_codes: self.acs_status_codes.clone(),
+            top_recipient_domains: self.top_recipient_domains(TOP_RECIPIENT_DOMAINS),
+            acs_circuit_state: self.acs_circuit_state,
             uptime_seconds: self.get_uptime().map(|d| d.as_secs()),
             average_response_time_ms: self
                 .get_average_response_time()
@@ -122,15 +221,23 @@ impl Metrics {
 #[derive(Debug, Clone)]
 pub struct MetricsCollector {
     inner: Arc<RwLock<Metrics>>,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
 }
 
 impl MetricsCollector {
     pub fn new() -> Self {
         Self {
             inner: Arc::new(RwLock::new(Metrics::new())),
+            circuit_breaker: None,
         }
     }
 
+    // Reports the breaker's current state in every snapshot
+    pub fn with_circuit_breaker(mut self, breaker: Arc<CircuitBreaker>) -> Self {
+        self.circuit_breaker = Some(breaker);
+        self
+    }
+
     pub async fn increment_connections(&self) {
         let mut metrics = self.inner.write().await;
         metrics.increment_connections();
@@ -141,6 +248,21 @@ impl MetricsCollector {
         metrics.decrement_active_connections();
     }
 
+    pub async fn set_connection_permits_in_use(&self, in_use: u64) {
+        let mut metrics = self.inner.write().await;
+        metrics.set_connection_permits_in_use(in_use);
+    }
+
+    pub async fn increment_emails_in_flight(&self) {
+        let mut metrics = self.inner.write().await;
+        metrics.increment_emails_in_flight();
+    }
+
+    pub async fn decrement_emails_in_flight(&self) {
+        let mut metrics = self.inner.write().await;
+        metrics.decrement_emails_in_flight();
+    }
+
     pub async fn increment_emails_sent(&self) {
         let mut metrics = self.inner.write().await;
         metrics.increment_emails_sent();
@@ -151,9 +273,14 @@ impl MetricsCollector {
         metrics.increment_emails_failed();
     }
 
-    pub async fn add_bytes_processed(&self, bytes: u64) {
+    pub async fn add_bytes_received(&self, bytes: u64) {
         let mut metrics = self.inner.write().await;
-        metrics.add_bytes_processed(bytes);
+        metrics.add_bytes_received(bytes);
+    }
+
+    pub async fn add_bytes_sent_to_acs(&self, bytes: u64) {
+        let mut metrics = self.inner.write().await;
+        metrics.add_bytes_sent_to_acs(bytes);
     }
 
     pub async fn record_response_time(&self, duration: Duration) {
@@ -166,16 +293,41 @@ impl MetricsCollector {
         metrics.increment_error(error_type);
     }
 
+    pub async fn record_acs_status(&self, status: u16) {
+        let mut metrics = self.inner.write().await;
+        metrics.record_acs_status(status);
+    }
+
+    pub async fn record_recipient_domain(&self, domain: &str, success: bool) {
+        let mut metrics = self.inner.write().await;
+        metrics.record_recipient_domain(domain, success);
+    }
+
+    // Zero all counters, keeping the original uptime start
+    pub async fn reset(&self) {
+        let mut metrics = self.inner.write().await;
+        *metrics = Metrics {
+            uptime_start: metrics.uptime_start,
+            ..Default::default()
+        };
+    }
+
     pub async fn get_snapshot(&self) -> Metrics {
         let metrics = self.inner.read().await;
         Metrics {
             connections_total: metrics.connections_total,
             connections_active: metrics.connections_active,
+            connection_permits_in_use: metrics.connection_permits_in_use,
+            emails_in_flight: metrics.emails_in_flight,
             emails_sent_total: metrics.emails_sent_total,
             emails_failed_total: metrics.emails_failed_total,
-            bytes_processed_total: metrics.bytes_processed_total,
+            bytes_received_total: metrics.bytes_received_total,
+            bytes_sent_to_acs_total: metrics.bytes_sent_to_acs_total,
             response_times: metrics.response_times.clone(),
             errors_by_type: metrics.errors_by_type.clone(),
+            acs_status_codes: metrics.acs_status_codes.clone(),
+            by_recipient_domain: metrics.by_recipient_domain.clone(),
+            acs_circuit_state: self.circuit_breaker.as_ref().map(|b| b.state()),
             uptime_start: metrics.uptime_start,
         }
     }
@@ -187,18 +339,24 @@ impl MetricsCollector {
         info!(
             connections_total = metrics.connections_total,
             connections_active = metrics.connections_active,
+            emails_in_flight = metrics.emails_in_flight,
             emails_sent = metrics.emails_sent_total,
             emails_failed = metrics.emails_failed_total,
-            bytes_processed = metrics.bytes_processed_total,
+            bytes_received = metrics.bytes_received_total,
+            bytes_sent_to_acs = metrics.bytes_sent_to_acs_total,
             success_rate = format!("{:.2}%", metrics.get_success_rate() * 100.0),
             avg_response_time = ?metrics.get_average_response_time(),
             uptime = ?metrics.get_uptime(),
+            acs_circuit_state = ?metrics.acs_circuit_state,
             "Current metrics"
         );
 
         if !metrics.errors_by_type.is_empty() {
             warn!(errors = ?metrics.errors_by_type, "Error breakdown");
         }
+        if !metrics.acs_status_codes.is_empty() {
+            info!(acs_status_codes = ?metrics.acs_status_codes, "ACS response breakdown");
+        }
     }
 }
 
@@ -230,7 +388,7 @@ mod tests {
 
         collector.increment_connections().await;
         collector.increment_emails_sent().await;
-        collector.add_bytes_processed(1024).await;
+        collector.add_bytes_received(1024).await;
         collector
             .record_response_time(Duration::from_millis(100))
             .await;
@@ -239,10 +397,22 @@ mod tests {
         assert_eq!(metrics.connections_total, 1);
         assert_eq!(metrics.connections_active, 1);
         assert_eq!(metrics.emails_sent_total, 1);
-        assert_eq!(metrics.bytes_processed_total, 1024);
+        assert_eq!(metrics.bytes_received_total, 1024);
         assert_eq!(metrics.response_times.len(), 1);
     }
 
+    #[tokio::test]
+    async fn test_bytes_received_and_sent_are_counted_separately() {
+        let collector = MetricsCollector::new();
+        collector.add_bytes_received(100).await;
+        collector.add_bytes_sent_to_acs(250).await;
+        collector.add_bytes_sent_to_acs(250).await;
+
+        let metrics = collector.get_snapshot().await.to_serializable();
+        assert_eq!(metrics.bytes_received_total, 100);
+        assert_eq!(metrics.bytes_sent_to_acs_total, 500);
+    }
+
     #[tokio::test]
     async fn test_success_rate_calculation() {
         let collector = MetricsCollector::new();
@@ -260,4 +430,46 @@ mod tests {
         let metrics = collector.get_snapshot().await;
         assert_eq!(metrics.get_success_rate(), 0.75);
     }
+
+    #[tokio::test]
+    async fn test_top_recipient_domains_is_bounded() {
+        let collector = MetricsCollector::new();
+        for _ in 0..3 {
+            collector
+                .record_recipient_domain("busy.example", true)
+                .await;
+        }
+        collector
+            .record_recipient_domain("Quiet.Example", false)
+            .await;
+        collector
+            .record_recipient_domain("other.example", true)
+            .await;
+
+        let metrics = collector.get_snapshot().await;
+        assert_eq!(metrics.by_recipient_domain["quiet.example"], (0, 1));
+
+        let top = metrics.top_recipient_domains(2);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].domain, "busy.example");
+        assert_eq!(top[0].sent, 3);
+        assert_eq!(top[1].domain, "other.example");
+    }
+
+    #[tokio::test]
+    async fn test_recipient_domains_past_the_cap_share_a_bucket() {
+        let collector = MetricsCollector::new();
+        for i in 0..MAX_RECIPIENT_DOMAINS + 5 {
+            collector
+                .record_recipient_domain(&format!("d{i}.example"), true)
+                .await;
+        }
+        // Domains already tracked keep their own counts
+        collector.record_recipient_domain("d0.example", false).await;
+
+        let metrics = collector.get_snapshot().await;
+        assert_eq!(metrics.by_recipient_domain.len(), MAX_RECIPIENT_DOMAINS + 1);
+        assert_eq!(metrics.by_recipient_domain["d0.example"], (1, 1));
+        assert_eq!(metrics.by_recipient_domain[OTHER_RECIPIENT_DOMAINS], (5, 0));
+    }
 }
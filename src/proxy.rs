@@ -0,0 +1,94 @@
+use std::net::{IpAddr, SocketAddr};
+
+// Maximum length of a PROXY protocol v1 header line, including the trailing CRLF.
+pub const MAX_V1_HEADER_LEN: usize = 107;
+
+// A parsed PROXY protocol v1 header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyHeader {
+    // The balancer forwarded a TCP connection from `source` to `destination`.
+    Tcp {
+        source: SocketAddr,
+        destination: SocketAddr,
+    },
+    // The balancer could not determine the original addresses (e.g. health checks).
+    Unknown,
+}
+
+// Parses a PROXY protocol v1 line such as "PROXY TCP4 203.0.113.7 10.0.0.1 56324 25\r\n".
+// Returns None if the line is not a well-formed v1 header.
+pub fn parse_v1_header(line: &str) -> Option<ProxyHeader> {
+    if line.len() > MAX_V1_HEADER_LEN {
+        return None;
+    }
+    let line = line
+        .strip_suffix("\r\n")
+        .or_else(|| line.strip_suffix('\n'))?;
+    let mut parts = line.split(' ');
+    if parts.next()? != "PROXY" {
+        return None;
+    }
+
+    let protocol = parts.next()?;
+    if protocol == "UNKNOWN" {
+        return Some(ProxyHeader::Unknown);
+    }
+
+    let source_ip: IpAddr = parts.next()?.parse().ok()?;
+    let destination_ip: IpAddr = parts.next()?.parse().ok()?;
+    let source_port: u16 = parts.next()?.parse().ok()?;
+    let destination_port: u16 = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+
+    let family_matches = match protocol {
+        "TCP4" => source_ip.is_ipv4() && destination_ip.is_ipv4(),
+        "TCP6" => source_ip.is_ipv6() && destination_ip.is_ipv6(),
+        _ => false,
+    };
+    if !family_matches {
+        return None;
+    }
+
+    Some(ProxyHeader::Tcp {
+        source: SocketAddr::new(source_ip, source_port),
+        destination: SocketAddr::new(destination_ip, destination_port),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tcp4_header() {
+        let header = parse_v1_header("PROXY TCP4 203.0.113.7 10.0.0.1 56324 25\r\n").unwrap();
+        assert_eq!(
+            header,
+            ProxyHeader::Tcp {
+                source: "203.0.113.7:56324".parse().unwrap(),
+                destination: "10.0.0.1:25".parse().unwrap(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_tcp6_and_unknown_headers() {
+        let header = parse_v1_header("PROXY TCP6 2001:db8::1 2001:db8::2 4000 25\r\n").unwrap();
+        assert!(matches!(header, ProxyHeader::Tcp { source, .. } if source.port() == 4000));
+        assert_eq!(
+            parse_v1_header("PROXY UNKNOWN\r\n"),
+            Some(ProxyHeader::Unknown)
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_headers() {
+        assert!(parse_v1_header("EHLO client.example.com\r\n").is_none());
+        assert!(parse_v1_header("PROXY TCP4 203.0.113.7 10.0.0.1 56324\r\n").is_none());
+        assert!(parse_v1_header("PROXY TCP4 2001:db8::1 10.0.0.1 1 25\r\n").is_none());
+        assert!(parse_v1_header("PROXY TCP4 203.0.113.7 10.0.0.1 99999 25\r\n").is_none());
+        assert!(parse_v1_header("PROXY TCP4 203.0.113.7 10.0.0.1 56324 25").is_none());
+    }
+}
@@ -0,0 +1,470 @@
+use crate::relay::{Mailer, Repeatability};
+use base64::{engine::general_purpose::STANDARD as B64, Engine as _};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs;
+use tracing::{error, info, warn};
+
+// A message waiting in the on-disk queue for ACS to become available again.
+#[derive(Debug, Serialize, Deserialize)]
+struct QueuedMessage {
+    id: String,
+    enqueued_at: String,
+    from: Option<String>,
+    recipients: Vec<String>,
+    // Raw RFC 5322 message, base64-encoded so the JSON file stays valid for any bytes
+    raw_email: String,
+    attempts: u32,
+    // Missing in messages queued by older versions; assigned on their first retry
+    #[serde(default)]
+    repeatability: Option<Repeatability>,
+    // When the client's MAIL FROM was accepted; missing in messages queued by older
+    // versions, whose age counts from their first relay attempt instead
+    #[serde(default)]
+    received_at: Option<DateTime<Utc>>,
+}
+
+// Outcome of a single pass over the queue.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DrainStats {
+    pub sent: usize,
+    pub retried: usize,
+    pub failed: usize,
+    // Given up on because they outlived the queue's max age
+    pub expired: usize,
+}
+
+// A durable, directory-backed queue of messages whose relay to ACS failed transiently.
+// Each message is one `<id>.json` file, so queued mail survives restarts.
+#[derive(Debug)]
+pub struct MessageQueue {
+    dir: PathBuf,
+    max_age: Option<Duration>,
+}
+
+impl MessageQueue {
+    // Opens (and creates if needed) the queue directory
+    pub async fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).await?;
+        Ok(Self { dir, max_age: None })
+    }
+
+    // Stops retrying messages this long after their MAIL FROM, setting them aside
+    // as `<id>.failed` instead
+    pub fn with_max_age(mut self, max_age: Option<Duration>) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    // Persists a message to the queue, returning the path of the queued file. Retries reuse
+    // the `repeatability` of the failed attempt so ACS can drop duplicates, and the max age
+    // counts from `received_at`.
+    pub async fn enqueue(
+        &self,
+        raw_email: &[u8],
+        from: &Option<String>,
+        recipients: &[String],
+        repeatability: &Repeatability,
+        received_at: DateTime<Utc>,
+    ) -> io::Result<PathBuf> {
+        let now = Utc::now();
+        let message = QueuedMessage {
+            id: format!(
+                "{}-{}",
+                now.format("%Y%m%dT%H%M%S%.3fZ"),
+                nanoid::nanoid!(8)
+            ),
+            enqueued_at: now.to_rfc3339(),
+            from: from.clone(),
+            recipients: recipients.to_vec(),
+            raw_email: B64.encode(raw_email),
+            attempts: 0,
+            repeatability: Some(*repeatability),
+            received_at: Some(received_at),
+        };
+        let path = self.dir.join(format!("{}.json", message.id));
+        self.write_message(&path, &message).await?;
+        info!(path = %path.display(), "Queued message for retry");
+        Ok(path)
+    }
+
+    // Lists queued message files, oldest first. If listing fails partway, the files found
+    // so far are returned, so they can still be drained.
+    pub async fn pending(&self) -> io::Result<Vec<PathBuf>> {
+        let mut entries = fs::read_dir(&self.dir).await?;
+        let mut paths = Vec::new();
+        loop {
+            match entries.next_entry().await {
+                Ok(Some(entry)) => {
+                    let path = entry.path();
+                    if path.extension().is_some_and(|ext| ext == "json") {
+                        paths.push(path);
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    error!(dir = %self.dir.display(), error = ?e, "Failed to list queued messages");
+                    break;
+                }
+            }
+        }
+        // Ids start with a timestamp, so lexical order is enqueue order
+        paths.sort();
+        Ok(paths)
+    }
+
+    // Attempts to relay every queued message once. Sent messages are removed, transient
+    // failures stay queued, and permanent failures are renamed to `<id>.failed`. A message
+    // that can't be read or moved is logged and skipped, so it never holds up the rest.
+    pub async fn drain_once(&self, mailer: &dyn Mailer) -> io::Result<DrainStats> {
+        let mut stats = DrainStats::default();
+        for path in self.pending().await? {
+            let (mut message, raw_email) = match read_message(&path).await {
+                Ok(read) => read,
+                Err(e) => {
+                    error!(path = %path.display(), error = ?e, "Unreadable queued message, setting aside");
+                    set_aside(&path).await;
+                    stats.failed += 1;
+                    continue;
+                }
+            };
+
+            let repeatability = *message.repeatability.get_or_insert_with(Repeatability::new);
+            let received_at = message.received_at.unwrap_or(repeatability.first_sent);
+            let age = (Utc::now() - received_at).to_std().unwrap_or_default();
+            if self.max_age.is_some_and(|max_age| age > max_age) {
+                error!(id = %message.id, attempts = message.attempts, age_secs = age.as_secs(), "Queued message expired, giving up");
+                set_aside(&path).await;
+                stats.expired += 1;
+                continue;
+            }
+
+            match mailer
+                .send_repeatable(
+                    &raw_email,
+                    &message.recipients,
+                    &message.from,
+                    &repeatability,
+                )
+                .await
+            {
+                Ok(()) => {
+                    info!(id = %message.id, attempts = message.attempts + 1, "Relayed queued message");
+                    // Left behind, it is sent again next pass; its repeatability lets ACS
+                    // drop the duplicate
+                    if let Err(e) = fs::remove_file(&path).await {
+                        error!(id = %message.id, error = ?e, "Failed to remove relayed message from queue");
+                    }
+                    stats.sent += 1;
+                }
+                Err(e) if e.is_permanent() => {
+                    error!(id = %message.id, error = ?e, "Queued message failed permanently");
+                    set_aside(&path).await;
+                    stats.failed += 1;
+                }
+                Err(e) => {
+                    message.attempts += 1;
+                    warn!(id = %message.id, attempts = message.attempts, error = ?e, "Queued message still failing, will retry");
+                    if let Err(e) = self.write_message(&path, &message).await {
+                        error!(id = %message.id, error = ?e, "Failed to update queued message");
+                    }
+                    stats.retried += 1;
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    // Writes via a temporary file and rename so a crash never leaves a truncated message
+    async fn write_message(&self, path: &Path, message: &QueuedMessage) -> io::Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_vec(message)?).await?;
+        fs::rename(&tmp_path, path).await
+    }
+}
+
+// Reads a queued message file and decodes the message it holds
+async fn read_message(path: &Path) -> io::Result<(QueuedMessage, Vec<u8>)> {
+    let message: QueuedMessage = serde_json::from_slice(&fs::read(path).await?)?;
+    let raw_email = B64
+        .decode(&message.raw_email)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok((message, raw_email))
+}
+
+// Renames a message that won't be retried to `<id>.failed`. If that fails, the message is
+// left where it is and tried again on the next pass.
+async fn set_aside(path: &Path) {
+    if let Err(e) = fs::rename(path, path.with_extension("failed")).await {
+        error!(path = %path.display(), error = ?e, "Failed to set aside queued message");
+    }
+}
+
+// Start a background task that drains the queue, backing off exponentially (up to
+// max_interval) while messages keep failing
+pub fn start_queue_worker(
+    queue: Arc<MessageQueue>,
+    mailer: Arc<dyn Mailer>,
+    interval: Duration,
+    max_interval: Duration,
+) {
+    tokio::spawn(async move {
+        let mut delay = interval;
+        loop {
+            tokio::time::sleep(delay).await;
+            match queue.drain_once(mailer.as_ref()).await {
+                Ok(stats) if stats.retried > 0 => {
+                    delay = (delay * 2).min(max_interval);
+                    warn!(
+                        ?stats,
+                        next_attempt_secs = delay.as_secs(),
+                        "Queue drain incomplete, backing off"
+                    );
+                }
+                Ok(stats) => {
+                    if stats.sent > 0 || stats.failed > 0 || stats.expired > 0 {
+                        info!(?stats, "Queue drained");
+                    }
+                    delay = interval;
+                }
+                Err(e) => {
+                    error!(error = ?e, "Failed to drain message queue");
+                    delay = (delay * 2).min(max_interval);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{AcsError, SmtpRelayError};
+    use crate::relay::CapturingMailer;
+    use std::sync::Mutex;
+
+    fn temp_queue_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("acs-queue-{}", nanoid::nanoid!(8)))
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_persists_across_reopen() {
+        let dir = temp_queue_dir();
+        let queue = MessageQueue::open(&dir).await.unwrap();
+        queue
+            .enqueue(
+                b"Subject: Queued\r\n\r\nHi\r\n",
+                &Some("from@example.com".to_string()),
+                &["to@example.com".to_string()],
+                &Repeatability::new(),
+                Utc::now(),
+            )
+            .await
+            .unwrap();
+
+        let reopened = MessageQueue::open(&dir).await.unwrap();
+        assert_eq!(reopened.pending().await.unwrap().len(), 1);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drain_success_removes_message() {
+        let dir = temp_queue_dir();
+        let queue = MessageQueue::open(&dir).await.unwrap();
+        let raw_email = b"Subject: Queued\r\n\r\nHi\r\n";
+        queue
+            .enqueue(
+                raw_email,
+                &None,
+                &["to@example.com".to_string()],
+                &Repeatability::new(),
+                Utc::now(),
+            )
+            .await
+            .unwrap();
+
+        let mailer = CapturingMailer::new();
+        let stats = queue.drain_once(&mailer).await.unwrap();
+
+        assert_eq!(stats.sent, 1);
+        assert_eq!(mailer.messages()[0].raw_email, raw_email);
+        assert_eq!(mailer.messages().len(), 1);
+        assert!(queue.pending().await.unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drain_transient_failure_keeps_message() {
+        let dir = temp_queue_dir();
+        let queue = MessageQueue::open(&dir).await.unwrap();
+        queue
+            .enqueue(
+                b"Subject: Queued\r\n\r\nHi\r\n",
+                &None,
+                &["to@example.com".to_string()],
+                &Repeatability::new(),
+                Utc::now(),
+            )
+            .await
+            .unwrap();
+
+        let mailer = CapturingMailer::failing(|| SmtpRelayError::Acs(AcsError::ServiceUnavailable));
+        let stats = queue.drain_once(&mailer).await.unwrap();
+        assert_eq!(stats.retried, 1);
+
+        let pending = queue.pending().await.unwrap();
+        assert_eq!(pending.len(), 1);
+        let message: QueuedMessage =
+            serde_json::from_slice(&fs::read(&pending[0]).await.unwrap()).unwrap();
+        assert_eq!(message.attempts, 1);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retries_reuse_the_original_repeatability() {
+        // Fails the first attempt and records the repeatability of every attempt
+        struct FlakyMailer {
+            attempts: Mutex<Vec<Repeatability>>,
+        }
+
+        #[async_trait::async_trait]
+        impl Mailer for FlakyMailer {
+            async fn send(
+                &self,
+                _raw_email: &[u8],
+                _recipients: &[String],
+                _from: &Option<String>,
+            ) -> Result<(), SmtpRelayError> {
+                unreachable!("the queue always sends with a repeatability")
+            }
+
+            async fn send_repeatable(
+                &self,
+                _raw_email: &[u8],
+                _recipients: &[String],
+                _from: &Option<String>,
+                repeatability: &Repeatability,
+            ) -> Result<(), SmtpRelayError> {
+                let mut attempts = self.attempts.lock().unwrap();
+                attempts.push(*repeatability);
+                if attempts.len() == 1 {
+                    return Err(SmtpRelayError::Acs(AcsError::RateLimited));
+                }
+                Ok(())
+            }
+        }
+
+        let dir = temp_queue_dir();
+        let queue = MessageQueue::open(&dir).await.unwrap();
+        let original = Repeatability::new();
+        queue
+            .enqueue(
+                b"Subject: Queued\r\n\r\nHi\r\n",
+                &None,
+                &["to@example.com".to_string()],
+                &original,
+                Utc::now(),
+            )
+            .await
+            .unwrap();
+
+        let mailer = FlakyMailer {
+            attempts: Mutex::new(Vec::new()),
+        };
+        assert_eq!(queue.drain_once(&mailer).await.unwrap().retried, 1);
+        assert_eq!(queue.drain_once(&mailer).await.unwrap().sent, 1);
+        assert_eq!(mailer.attempts.lock().unwrap().as_slice(), [original; 2]);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bad_entries_do_not_block_the_queue() {
+        let dir = temp_queue_dir();
+        let queue = MessageQueue::open(&dir).await.unwrap();
+        // Not JSON, and a directory in the way of setting it aside
+        fs::write(dir.join("0-garbled.json"), b"{not json")
+            .await
+            .unwrap();
+        fs::create_dir_all(dir.join("0-garbled.failed/keep"))
+            .await
+            .unwrap();
+        // Valid JSON, but the message isn't base64
+        fs::write(
+            dir.join("1-corrupt.json"),
+            serde_json::to_vec(&serde_json::json!({
+                "id": "1-corrupt",
+                "enqueued_at": "2024-05-01T12:00:00Z",
+                "from": null,
+                "recipients": ["to@example.com"],
+                "raw_email": "not base64!",
+                "attempts": 0
+            }))
+            .unwrap(),
+        )
+        .await
+        .unwrap();
+        let raw_email = b"Subject: Queued\r\n\r\nHi\r\n";
+        queue
+            .enqueue(
+                raw_email,
+                &None,
+                &["to@example.com".to_string()],
+                &Repeatability::new(),
+                Utc::now(),
+            )
+            .await
+            .unwrap();
+
+        let mailer = CapturingMailer::new();
+        let stats = queue.drain_once(&mailer).await.unwrap();
+        assert_eq!(stats.failed, 2);
+        assert_eq!(stats.sent, 1);
+        assert_eq!(mailer.messages()[0].raw_email, raw_email);
+        assert_eq!(mailer.messages().len(), 1);
+        // The entry that couldn't be moved stays for the next pass
+        assert_eq!(queue.pending().await.unwrap(), [dir.join("0-garbled.json")]);
+        assert!(dir.join("1-corrupt.failed").is_file());
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_message_past_max_age_expires() {
+        let dir = temp_queue_dir();
+        let queue = MessageQueue::open(&dir)
+            .await
+            .unwrap()
+            .with_max_age(Some(Duration::from_millis(50)));
+        queue
+            .enqueue(
+                b"Subject: Queued\r\n\r\nHi\r\n",
+                &None,
+                &["to@example.com".to_string()],
+                &Repeatability::new(),
+                Utc::now(),
+            )
+            .await
+            .unwrap();
+
+        // Still failing, but young enough to retry
+        let mailer = CapturingMailer::failing(|| SmtpRelayError::Acs(AcsError::ServiceUnavailable));
+        assert_eq!(queue.drain_once(&mailer).await.unwrap().retried, 1);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let stats = queue.drain_once(&mailer).await.unwrap();
+        assert_eq!(stats.expired, 1);
+        assert_eq!(stats.retried, 0);
+        assert!(queue.pending().await.unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
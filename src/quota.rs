@@ -0,0 +1,181 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Key in the quota map whose limit applies to every sender without an entry of its own
+pub const DEFAULT_SENDER: &str = "*";
+
+// Per-sender caps on messages relayed within a rolling window, shared across connections, so
+// one tenant of a shared relay can't use up the whole ACS quota. Senders are the MAIL FROM
+// address, compared case-insensitively. That address is chosen by the client, so quotas only
+// hold well-behaved tenants to their share: a client can send as another address, and under
+// a `*` limit every new address starts with a fresh quota.
+#[derive(Debug)]
+pub struct SenderQuotas {
+    window: Duration,
+    limits: HashMap<String, u32>,
+    // When each sender's messages within the window were relayed, oldest first
+    sent: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl SenderQuotas {
+    pub fn new(limits: &HashMap<String, u32>, window: Duration) -> Self {
+        Self {
+            window,
+            limits: limits
+                .iter()
+                .map(|(sender, limit)| (sender.to_ascii_lowercase(), *limit))
+                .collect(),
+            sent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Takes a slot in `sender`'s quota for a message, or returns None if the quota for the
+    // current window is used up. Senders without a limit are always allowed.
+    pub fn try_acquire(&self, sender: &str) -> Option<QuotaSlot<'_>> {
+        self.try_acquire_at(sender, Instant::now())
+    }
+
+    fn try_acquire_at(&self, sender: &str, now: Instant) -> Option<QuotaSlot<'_>> {
+        let sender = sender.to_ascii_lowercase();
+        let mut slot = QuotaSlot {
+            quotas: self,
+            taken: None,
+        };
+        let Some(&limit) = self
+            .limits
+            .get(&sender)
+            .or_else(|| self.limits.get(DEFAULT_SENDER))
+        else {
+            return Some(slot);
+        };
+
+        let mut sent = self.sent.lock().unwrap();
+        // Drop senders whose window has passed, so the map only holds recent senders
+        sent.retain(|_, times| {
+            while times
+                .front()
+                .is_some_and(|&time| now.saturating_duration_since(time) >= self.window)
+            {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        let times = sent.entry(sender.clone()).or_default();
+        if times.len() >= limit as usize {
+            return None;
+        }
+        times.push_back(now);
+        slot.taken = Some((sender, now));
+        Some(slot)
+    }
+}
+
+// A message counted against its sender's quota. Unless kept once the message is relayed
+// (or queued), the slot is given back when dropped, so deferred and failed messages don't
+// use up the quota.
+#[derive(Debug)]
+pub struct QuotaSlot<'a> {
+    quotas: &'a SenderQuotas,
+    // The sender and time counted, or None for senders without a limit
+    taken: Option<(String, Instant)>,
+}
+
+impl QuotaSlot<'_> {
+    pub fn keep(mut self) {
+        self.taken = None;
+    }
+}
+
+impl Drop for QuotaSlot<'_> {
+    fn drop(&mut self) {
+        let Some((sender, time)) = self.taken.take() else {
+            return;
+        };
+        let mut sent = self.quotas.sent.lock().unwrap();
+        if let Some(times) = sent.get_mut(&sender) {
+            if let Some(index) = times.iter().position(|&t| t == time) {
+                times.remove(index);
+            }
+            if times.is_empty() {
+                sent.remove(&sender);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Takes and keeps a slot, as for a message that was relayed
+    fn acquire(quotas: &SenderQuotas, sender: &str, now: Instant) -> bool {
+        quotas
+            .try_acquire_at(sender, now)
+            .map(QuotaSlot::keep)
+            .is_some()
+    }
+
+    #[test]
+    fn test_quota_rolls_over_with_the_window() {
+        let limits = HashMap::from([
+            ("Busy@Example.com".to_string(), 2),
+            (DEFAULT_SENDER.to_string(), 1),
+        ]);
+        let quotas = SenderQuotas::new(&limits, Duration::from_secs(3600));
+        let start = Instant::now();
+
+        assert!(acquire(&quotas, "busy@example.com", start));
+        assert!(acquire(
+            &quotas,
+            "BUSY@example.com",
+            start + Duration::from_secs(1800)
+        ));
+        assert!(!acquire(
+            &quotas,
+            "busy@example.com",
+            start + Duration::from_secs(1801)
+        ));
+        // The first message leaves the window, freeing one slot
+        assert!(acquire(
+            &quotas,
+            "busy@example.com",
+            start + Duration::from_secs(3600)
+        ));
+        assert!(!acquire(
+            &quotas,
+            "busy@example.com",
+            start + Duration::from_secs(3601)
+        ));
+
+        // Other senders share the default limit individually
+        assert!(acquire(&quotas, "a@example.com", start));
+        assert!(!acquire(&quotas, "a@example.com", start));
+        assert!(acquire(&quotas, "b@example.com", start));
+    }
+
+    #[test]
+    fn test_senders_without_a_limit_are_not_counted() {
+        let limits = HashMap::from([("limited@example.com".to_string(), 1)]);
+        let quotas = SenderQuotas::new(&limits, Duration::from_secs(60));
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert!(acquire(&quotas, "free@example.com", now));
+        }
+        assert!(quotas.sent.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_dropped_slot_is_given_back() {
+        let limits = HashMap::from([(DEFAULT_SENDER.to_string(), 1)]);
+        let quotas = SenderQuotas::new(&limits, Duration::from_secs(60));
+        let now = Instant::now();
+        // Deferred or failed before it was relayed
+        let slot = quotas.try_acquire_at("a@example.com", now).unwrap();
+        assert!(quotas.try_acquire_at("a@example.com", now).is_none());
+        drop(slot);
+        assert!(quotas.sent.lock().unwrap().is_empty());
+        assert!(acquire(&quotas, "a@example.com", now));
+        assert!(!acquire(&quotas, "a@example.com", now));
+    }
+}
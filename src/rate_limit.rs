@@ -0,0 +1,118 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Longest a message is held back waiting for the rate to allow it before it is refused
+const MAX_WAIT: Duration = Duration::from_secs(1);
+
+// Global token bucket capping how many messages per second are handed to the mailer, e.g.
+// to stay within an ACS subscription's send quota. Bursts of up to one second's worth
+// (at least one message) pass immediately.
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_second: f64,
+    capacity: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    // May go negative: each message waiting for its turn has reserved a future token
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: f64) -> Self {
+        let capacity = per_second.max(1.0);
+        Self {
+            per_second,
+            capacity,
+            bucket: Mutex::new(Bucket {
+                tokens: capacity,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    // Waits until the rate allows another message. Returns false, without waiting, if that
+    // would take longer than MAX_WAIT.
+    pub async fn acquire(&self) -> bool {
+        match self.reserve_at(Instant::now()) {
+            Some(delay) => {
+                if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Takes a token, returning how long to wait before it may be used, or None if the
+    // wait would exceed MAX_WAIT.
+    fn reserve_at(&self, now: Instant) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        bucket.tokens =
+            (bucket.tokens + elapsed.as_secs_f64() * self.per_second).min(self.capacity);
+        bucket.last_refill = now;
+
+        let tokens = bucket.tokens - 1.0;
+        let delay = Duration::from_secs_f64((-tokens).max(0.0) / self.per_second);
+        if delay > MAX_WAIT {
+            return None;
+        }
+        bucket.tokens = tokens;
+        Some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_is_capped_at_the_rate() {
+        let limiter = RateLimiter::new(4.0);
+        let now = Instant::now();
+        let delays: Vec<Option<Duration>> = (0..10).map(|_| limiter.reserve_at(now)).collect();
+
+        // One second's worth passes at once, the next second's worth is spread over it,
+        // and the rest is refused
+        assert!(delays[..4].iter().all(|d| *d == Some(Duration::ZERO)));
+        assert_eq!(delays[4], Some(Duration::from_millis(250)));
+        assert_eq!(delays[7], Some(Duration::from_secs(1)));
+        assert!(delays[8..].iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn test_tokens_refill_over_time() {
+        let limiter = RateLimiter::new(2.0);
+        let now = Instant::now();
+        for _ in 0..2 {
+            assert_eq!(limiter.reserve_at(now), Some(Duration::ZERO));
+        }
+        assert_eq!(
+            limiter.reserve_at(now + Duration::from_millis(500)),
+            Some(Duration::ZERO)
+        );
+        // The bucket never holds more than its capacity
+        let later = now + Duration::from_secs(60);
+        for _ in 0..2 {
+            assert_eq!(limiter.reserve_at(later), Some(Duration::ZERO));
+        }
+        assert_eq!(limiter.reserve_at(later), Some(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_slow_rate_refuses_instead_of_waiting_long() {
+        let limiter = RateLimiter::new(0.5);
+        let now = Instant::now();
+        assert_eq!(limiter.reserve_at(now), Some(Duration::ZERO));
+        assert_eq!(limiter.reserve_at(now), None);
+        assert_eq!(
+            limiter.reserve_at(now + Duration::from_secs(2)),
+            Some(Duration::ZERO)
+        );
+    }
+}
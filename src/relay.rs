@@ -1,27 +1,68 @@
-use crate::error::{AcsError, EmailError, SmtpRelayError};
-use anyhow::{Context, Result};
+use crate::breaker::CircuitBreaker;
+use crate::config::{domain_matches, EmptyHtml, RecipientCase, RecipientPolicy};
+use crate::error::{AcsError, ConfigError, EmailError, SmtpRelayError};
+#[cfg(feature = "smtp-forward")]
+use crate::error::{NetworkError, SmtpError};
+use crate::headers::fold_header;
+use crate::logging::Escaped;
+use crate::metrics::MetricsCollector;
+use crate::redact::{redact_authorization, Redacted};
+use anyhow::Result;
 use async_trait::async_trait;
 use base64::{engine::general_purpose::STANDARD as B64, Engine as _};
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use hmac::{Hmac, Mac};
-use mail_parser::{Message, MessageParser};
+use mail_parser::decoders::base64::base64_decode;
+use mail_parser::decoders::charsets::map::charset_decoder;
+use mail_parser::decoders::html::html_to_text;
+use mail_parser::decoders::quoted_printable::quoted_printable_decode;
+use mail_parser::{Address, Message, MessageParser, MimeHeaders};
 use reqwest::{header, Client, Method};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use tracing::{info, instrument, warn};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, instrument, warn};
 use url::Url;
+use uuid::Uuid;
+
+// Version of the ACS Email REST API targeted by this relay.
+const API_VERSION: &str = "2023-03-31";
+
+// Path of the ACS send endpoint, relative to the resource endpoint.
+pub const DEFAULT_SEND_PATH: &str = "/emails:send";
+
+// Top-level content types relayed by default: the bodies ACS can render, and the
+// multipart containers that carry them.
+pub const DEFAULT_ALLOWED_CONTENT_TYPES: &[&str] = &[
+    "text/plain",
+    "text/html",
+    "multipart/alternative",
+    "multipart/mixed",
+    "multipart/related",
+];
 
 // --- Data Structures for the ACS Email API Payload ---
 
 #[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct AcsEmailAddress<'a> {
-    address: &'a str,
+    address: Cow<'a, str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    display_name: Option<&'a str>,
 }
 
 #[derive(Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct AcsRecipients<'a> {
     to: Vec<AcsEmailAddress<'a>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    cc: Vec<AcsEmailAddress<'a>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    bcc: Vec<AcsEmailAddress<'a>>,
 }
 
 #[derive(Serialize, Debug)]
@@ -34,12 +75,63 @@ pub struct AcsEmailContent {
     html: Option<String>,
 }
 
+// Message importance. The ACS API has no importance property, so it is sent as the
+// custom headers mail clients read it from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcsImportance {
+    High,
+    Normal,
+    Low,
+}
+
+impl AcsImportance {
+    // The `X-Priority` and `Importance` header values for this importance
+    fn headers(self) -> [(&'static str, &'static str); 2] {
+        match self {
+            Self::High => [("X-Priority", "1"), ("Importance", "high")],
+            Self::Normal => [("X-Priority", "3"), ("Importance", "normal")],
+            Self::Low => [("X-Priority", "5"), ("Importance", "low")],
+        }
+    }
+}
+
 #[derive(Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct AcsEmailRequest<'a> {
     sender_address: &'a str,
     content: AcsEmailContent,
     recipients: AcsRecipients<'a>,
+    // Custom headers added to the message ACS builds
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<&'static str, &'static str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    reply_to: Vec<AcsEmailAddress<'a>>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    user_engagement_tracking_disabled: bool,
+}
+
+// Identifies one logical message across send attempts, so ACS can recognise a retry of a
+// request it already accepted and not deliver it twice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Repeatability {
+    pub request_id: Uuid,
+    // When the first attempt was made; retries must repeat it unchanged
+    pub first_sent: DateTime<Utc>,
+}
+
+impl Repeatability {
+    pub fn new() -> Self {
+        Self {
+            request_id: Uuid::new_v4(),
+            first_sent: Utc::now(),
+        }
+    }
+}
+
+impl Default for Repeatability {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[cfg(feature = "mocks")]
@@ -54,19 +146,214 @@ pub trait Mailer: Send + Sync {
         raw_email: &[u8],
         recipients: &[String],
         from: &Option<String>,
-    ) -> Result<()>;
+    ) -> Result<(), SmtpRelayError>;
+
+    // Sends an attempt of a message that may be retried later with the same
+    // `repeatability`. Mailers without duplicate detection just send it.
+    async fn send_repeatable(
+        &self,
+        raw_email: &[u8],
+        recipients: &[String],
+        from: &Option<String>,
+        _repeatability: &Repeatability,
+    ) -> Result<(), SmtpRelayError> {
+        self.send(raw_email, recipients, from).await
+    }
 }
 
 // A concrete Mailer implementation for Azure Communication Services.
 pub struct AcsMailer {
     client: Client,
     api_endpoint: String,
+    // Path requests are sent to (and signed for), e.g. DEFAULT_SEND_PATH
+    send_path: String,
     api_key: String,
     sender_address: String,
     allowed_sender_domains: Option<Vec<String>>,
+    // Maps a MAIL FROM domain (lowercased) to the ACS sender address used for it.
+    sender_map: HashMap<String, String>,
+    content: ContentOptions,
+    allowed_content_types: Vec<String>,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    metrics: Option<MetricsCollector>,
+    #[cfg(feature = "html-sanitize")]
+    html_policy: crate::config::HtmlPolicy,
+}
+
+// How a parsed message is mapped onto the ACS request content
+#[derive(Debug, Clone, Default)]
+struct ContentOptions {
+    disable_user_engagement_tracking: bool,
+    // Subject used when a message has none; None means "No Subject"
+    default_subject: Option<String>,
+    // Reject messages without a subject instead of applying the default
+    reject_missing_subject: bool,
+    empty_html: EmptyHtml,
+    // Never send an HTML body
+    force_plain_text: bool,
+    recipient_policy: RecipientPolicy,
+    recipient_case: RecipientCase,
+}
+
+// Builds an AcsMailer. Defaults: a plain reqwest client, no sender allow-list, no sender
+// map, user engagement tracking left on, "No Subject" for messages without one, empty
+// HTML bodies dropped, envelope recipients only with their case kept,
+// DEFAULT_ALLOWED_CONTENT_TYPES, DEFAULT_SEND_PATH and no circuit breaker.
+pub struct AcsMailerBuilder {
+    client: Option<Client>,
+    endpoint: String,
+    send_path: String,
+    key: String,
+    sender: String,
+    allowed_sender_domains: Option<Vec<String>>,
+    sender_map: HashMap<String, String>,
+    content: ContentOptions,
+    // Top-level content types accepted for relay; empty accepts any
+    allowed_content_types: Vec<String>,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    metrics: Option<MetricsCollector>,
+    #[cfg(feature = "html-sanitize")]
+    html_policy: crate::config::HtmlPolicy,
+}
+
+impl AcsMailerBuilder {
+    // HTTP client used for ACS requests, e.g. one built from `HttpClientSettings`
+    pub fn client(mut self, client: Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    // Domains whose MAIL FROM address may be used as the ACS sender
+    pub fn allowed_sender_domains(mut self, domains: Option<Vec<String>>) -> Self {
+        self.allowed_sender_domains = domains;
+        self
+    }
+
+    // Maps MAIL FROM domains to the ACS sender address used for them
+    pub fn sender_map(mut self, sender_map: HashMap<String, String>) -> Self {
+        self.sender_map = sender_map;
+        self
+    }
+
+    pub fn disable_user_engagement_tracking(mut self, disable: bool) -> Self {
+        self.content.disable_user_engagement_tracking = disable;
+        self
+    }
+
+    // Subject applied to messages that have none, instead of "No Subject"
+    pub fn default_subject(mut self, subject: Option<String>) -> Self {
+        self.content.default_subject = subject;
+        self
+    }
+
+    // Reject messages without a subject rather than applying the default
+    pub fn reject_missing_subject(mut self, reject: bool) -> Self {
+        self.content.reject_missing_subject = reject;
+        self
+    }
+
+    // What to do with HTML bodies that render nothing
+    pub fn empty_html(mut self, empty_html: EmptyHtml) -> Self {
+        self.content.empty_html = empty_html;
+        self
+    }
+
+    // Send only plain text; HTML-only messages get a text body stripped from their HTML
+    pub fn force_plain_text(mut self, force: bool) -> Self {
+        self.content.force_plain_text = force;
+        self
+    }
+
+    // How To/Cc/Bcc header recipients are reconciled with the envelope recipients
+    pub fn recipient_policy(mut self, policy: RecipientPolicy) -> Self {
+        self.content.recipient_policy = policy;
+        self
+    }
+
+    // How the case of recipient addresses is normalized before they are sent
+    pub fn recipient_case(mut self, case: RecipientCase) -> Self {
+        self.content.recipient_case = case;
+        self
+    }
+
+    // Top-level content types accepted for relay (`type/subtype` or `type/*`); an empty
+    // list accepts any
+    pub fn allowed_content_types(mut self, content_types: Vec<String>) -> Self {
+        self.allowed_content_types = content_types;
+        self
+    }
+
+    // Path of the send endpoint, e.g. with a prefix added by a proxy in front of ACS
+    pub fn send_path(mut self, path: impl Into<String>) -> Self {
+        self.send_path = path.into();
+        self
+    }
+
+    // Fail sends fast with a 451 for `cooldown` after `failure_threshold` consecutive ACS
+    // failures, then probe with a single request; a threshold of 0 disables the breaker
+    pub fn circuit_breaker(mut self, failure_threshold: u32, cooldown: Duration) -> Self {
+        self.circuit_breaker = (failure_threshold > 0)
+            .then(|| Arc::new(CircuitBreaker::new(failure_threshold, cooldown)));
+        self
+    }
+
+    // Collector that request bytes sent to ACS are counted in
+    pub fn metrics(mut self, metrics: MetricsCollector) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    // Whether HTML bodies are relayed as-is, sanitized, or rejected if they contain
+    // active content
+    #[cfg(feature = "html-sanitize")]
+    pub fn html_policy(mut self, policy: crate::config::HtmlPolicy) -> Self {
+        self.html_policy = policy;
+        self
+    }
+
+    pub fn build(self) -> AcsMailer {
+        AcsMailer {
+            client: self.client.unwrap_or_default(),
+            api_endpoint: self.endpoint,
+            send_path: self.send_path,
+            api_key: self.key,
+            sender_address: self.sender,
+            allowed_sender_domains: self.allowed_sender_domains,
+            sender_map: self
+                .sender_map
+                .into_iter()
+                .map(|(domain, sender)| (domain.to_ascii_lowercase(), sender))
+                .collect(),
+            content: self.content,
+            allowed_content_types: self
+                .allowed_content_types
+                .into_iter()
+                .map(|ct| ct.to_ascii_lowercase())
+                .collect(),
+            circuit_breaker: self.circuit_breaker,
+            metrics: self.metrics,
+            #[cfg(feature = "html-sanitize")]
+            html_policy: self.html_policy,
+        }
+    }
+}
+
+// Hand-written so the API key never reaches logs through `{:?}`
+impl std::fmt::Debug for AcsMailer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AcsMailer")
+            .field("api_endpoint", &self.api_endpoint)
+            .field("send_path", &self.send_path)
+            .field("api_key", &Redacted(&self.api_key))
+            .field("sender_address", &self.sender_address)
+            .field("allowed_sender_domains", &self.allowed_sender_domains)
+            .field("sender_map", &self.sender_map)
+            .finish_non_exhaustive()
+    }
 }
 
 impl AcsMailer {
+    // Positional constructor kept for existing callers; prefer `AcsMailer::builder`.
     pub fn new(
         client: Client,
         endpoint: String,
@@ -74,29 +361,100 @@ impl AcsMailer {
         sender: String,
         allowed_sender_domains: Option<Vec<String>>,
     ) -> Self {
-        Self {
-            client,
-            api_endpoint: endpoint,
-            api_key: key,
-            sender_address: sender,
-            allowed_sender_domains,
+        Self::builder(endpoint, key, sender)
+            .client(client)
+            .allowed_sender_domains(allowed_sender_domains)
+            .build()
+    }
+
+    // Starts a builder from the required settings; everything else has a default.
+    pub fn builder(
+        endpoint: impl Into<String>,
+        key: impl Into<String>,
+        sender: impl Into<String>,
+    ) -> AcsMailerBuilder {
+        AcsMailerBuilder {
+            client: None,
+            endpoint: endpoint.into(),
+            send_path: DEFAULT_SEND_PATH.to_string(),
+            key: key.into(),
+            sender: sender.into(),
+            allowed_sender_domains: None,
+            sender_map: HashMap::new(),
+            content: ContentOptions::default(),
+            allowed_content_types: DEFAULT_ALLOWED_CONTENT_TYPES
+                .iter()
+                .map(|ct| ct.to_string())
+                .collect(),
+            circuit_breaker: None,
+            metrics: None,
+            #[cfg(feature = "html-sanitize")]
+            html_policy: crate::config::HtmlPolicy::Off,
         }
     }
 
+    // The breaker guarding ACS requests, if one is configured, e.g. to report its state
+    pub fn circuit_breaker(&self) -> Option<Arc<CircuitBreaker>> {
+        self.circuit_breaker.clone()
+    }
+
+    // Chooses the ACS sender address for a message from the client's MAIL FROM.
+    // A sender mapped from the MAIL FROM domain wins, then an allow-listed client address,
+    // and otherwise the default sender address is used.
+    fn select_sender(&self, from: &Option<String>) -> String {
+        let Some(from_address) = from else {
+            return self.sender_address.clone();
+        };
+        let trimmed_from = from_address.trim_matches(|c| c == '<' || c == '>');
+        // `MAIL FROM:<>` is the null reverse-path used by bounces; it has no domain to map
+        if trimmed_from.is_empty() {
+            debug!("Null reverse-path, using default sender");
+            return self.sender_address.clone();
+        }
+        let Some(from_domain) = trimmed_from.split('@').nth(1) else {
+            if self.allowed_sender_domains.is_some() || !self.sender_map.is_empty() {
+                warn!(invalid_from = %Escaped(from_address), "Could not parse domain from MAIL FROM, using default");
+            }
+            return self.sender_address.clone();
+        };
+
+        if let Some(mapped_sender) = self.sender_map.get(&from_domain.to_ascii_lowercase()) {
+            info!(client_sender = %Escaped(trimmed_from), mapped_sender = %mapped_sender, "Using sender mapped from MAIL FROM domain");
+            return mapped_sender.clone();
+        }
+
+        if let Some(allowed_domains) = &self.allowed_sender_domains {
+            if allowed_domains
+                .iter()
+                .any(|pattern| domain_matches(pattern, from_domain))
+            {
+                info!(client_sender = %Escaped(trimmed_from), "Using client-provided sender address");
+                return trimmed_from.to_string();
+            }
+            warn!(client_sender = %Escaped(trimmed_from), fallback_sender = %self.sender_address, "Sender not in allow-list, using default");
+        }
+
+        self.sender_address.clone()
+    }
+
     // Generates the necessary headers for HMAC-SHA256 authentication with the ACS API.
     fn sign_request(
         &self,
         method: &Method,
         url_path: &str,
         body_bytes: &[u8],
-    ) -> Result<(String, String, String)> {
+    ) -> Result<(String, String, String), SmtpRelayError> {
         let full_url = format!(
             "{api_endpoint}{url_path}",
             api_endpoint = self.api_endpoint,
             url_path = url_path
         );
         let parsed_url = Url::parse(&full_url)?;
-        let host = parsed_url.host_str().context("Endpoint URL has no host")?;
+        let host = parsed_url.host_str().ok_or_else(|| {
+            SmtpRelayError::Config(ConfigError::InvalidConnectionString(
+                "Endpoint URL has no host".to_string(),
+            ))
+        })?;
 
         // The timestamp MUST be in RFC1123 format, per Azure documentation.
         let timestamp = Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
@@ -114,20 +472,219 @@ impl AcsMailer {
             host = host,
             content_hash = &content_hash
         );
-        info!(string_to_sign = %string_to_sign, "Generated string-to-sign for HMAC");
+        // Never log the key or the resulting signature
+        debug!(%method, %url_path, %timestamp, %host, "Generated string-to-sign for HMAC");
 
-        let decoded_key = B64
-            .decode(&self.api_key)
-            .context("Failed to decode API key")?;
-        let mut mac = Hmac::<Sha256>::new_from_slice(&decoded_key)?;
+        let decoded_key = B64.decode(&self.api_key).map_err(|_| {
+            SmtpRelayError::Config(ConfigError::InvalidConnectionString(
+                "Failed to decode API key".to_string(),
+            ))
+        })?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&decoded_key).map_err(|_| {
+            SmtpRelayError::Config(ConfigError::InvalidConnectionString(
+                "Invalid API key length".to_string(),
+            ))
+        })?;
         mac.update(string_to_sign.as_bytes());
         let signature = B64.encode(mac.finalize().into_bytes());
 
         let auth_header = format!(
             "HMAC-SHA256 SignedHeaders=x-ms-date;host;x-ms-content-sha256&Signature={signature}"
         );
+        debug!(authorization = %redact_authorization(&auth_header), "Signed ACS request");
         Ok((timestamp, content_hash, auth_header))
     }
+
+    // Sends a serialized email request to the ACS send endpoint. The span records the ACS
+    // status and response time, e.g. for trace export.
+    #[instrument(skip_all, fields(acs_status = tracing::field::Empty, acs_latency_ms = tracing::field::Empty))]
+    async fn post_email(
+        &self,
+        body_bytes: Vec<u8>,
+        sender: &str,
+        repeatability: &Repeatability,
+    ) -> Result<(), SmtpRelayError> {
+        // The signed path must be exactly the one requested, or ACS rejects the HMAC
+        let url_path = format!(
+            "{send_path}?api-version={API_VERSION}",
+            send_path = self.send_path
+        );
+        let (timestamp, content_hash, auth_header) =
+            self.sign_request(&Method::POST, &url_path, &body_bytes)?;
+
+        let body_len = body_bytes.len() as u64;
+        info!(url = %self.api_endpoint, sender = %Escaped(sender), request_id = %repeatability.request_id, "Sending signed request to ACS API.");
+        let request_started = Instant::now();
+        let response = self
+            .client
+            .post(format!(
+                "{api_endpoint}{url_path}",
+                api_endpoint = self.api_endpoint,
+                url_path = url_path
+            ))
+            .header("x-ms-date", timestamp)
+            .header("x-ms-content-sha256", content_hash)
+            .header(header::AUTHORIZATION, auth_header)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(
+                "Repeatability-Request-ID",
+                repeatability.request_id.to_string(),
+            )
+            .header(
+                "Repeatability-First-Sent",
+                repeatability
+                    .first_sent
+                    .format("%a, %d %b %Y %H:%M:%S GMT")
+                    .to_string(),
+            )
+            .body(body_bytes)
+            .send()
+            .await?;
+
+        let span = tracing::Span::current();
+        span.record("acs_status", response.status().as_u16());
+        span.record(
+            "acs_latency_ms",
+            request_started.elapsed().as_millis() as u64,
+        );
+        info!(status = %response.status(), "Received response from ACS");
+        if let Some(metrics) = &self.metrics {
+            metrics.add_bytes_sent_to_acs(body_len).await;
+            metrics.record_acs_status(response.status().as_u16()).await;
+        }
+
+        if !response.status().is_success() {
+            let status = response.status().as_u16();
+            let body = response.text().await.unwrap_or_default();
+            return Err(SmtpRelayError::Acs(AcsError::from_status_code(
+                status, &body,
+            )));
+        }
+
+        info!("Successfully relayed email to ACS.");
+        Ok(())
+    }
+}
+
+// Reads the importance from the standard `Importance` and `X-Priority` headers.
+// `Importance` takes precedence; `X-Priority` values 1-2 are high, 3 normal and 4-5 low.
+fn parse_importance(parsed_email: &Message) -> Option<AcsImportance> {
+    if let Some(value) = parsed_email.header_raw("Importance") {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "high" => return Some(AcsImportance::High),
+            "normal" => return Some(AcsImportance::Normal),
+            "low" => return Some(AcsImportance::Low),
+            _ => {}
+        }
+    }
+
+    // X-Priority values usually look like "1 (Highest)", so only the leading digit matters.
+    let priority = parsed_email
+        .header_raw("X-Priority")?
+        .trim()
+        .chars()
+        .next()?
+        .to_digit(10)?;
+    match priority {
+        1 | 2 => Some(AcsImportance::High),
+        3 => Some(AcsImportance::Normal),
+        4 | 5 => Some(AcsImportance::Low),
+        _ => None,
+    }
+}
+
+// Rejects messages ACS can't render faithfully: a top-level content type outside
+// `allowed_content_types` (lowercased; empty allows any), or a part whose transfer encoding
+// could not be decoded.
+fn validate_content(
+    parsed_email: &Message,
+    allowed_content_types: &[String],
+) -> Result<(), SmtpRelayError> {
+    if !allowed_content_types.is_empty() {
+        // RFC 2045: a message without Content-Type is text/plain
+        let (ty, subtype) = parsed_email.content_type().map_or_else(
+            || ("text".to_string(), "plain".to_string()),
+            |ct| {
+                (
+                    ct.ctype().to_ascii_lowercase(),
+                    ct.subtype().unwrap_or_default().to_ascii_lowercase(),
+                )
+            },
+        );
+        let allowed = allowed_content_types.iter().any(|allowed| {
+            allowed
+                .split_once('/')
+                .is_some_and(|(a_ty, a_sub)| a_ty == ty && (a_sub == "*" || a_sub == subtype))
+        });
+        if !allowed {
+            return Err(SmtpRelayError::Email(EmailError::UnsupportedContentType(
+                format!("{ty}/{subtype}"),
+            )));
+        }
+    }
+
+    if let Some(part) = parsed_email.parts.iter().find(|p| p.is_encoding_problem) {
+        let encoding = part.content_transfer_encoding().unwrap_or("unknown");
+        return Err(SmtpRelayError::Email(EmailError::InvalidEncoding(
+            encoding.to_string(),
+        )));
+    }
+
+    for &index in parsed_email.text_body.iter().chain(&parsed_email.html_body) {
+        validate_text_charset(parsed_email, index)?;
+    }
+    Ok(())
+}
+
+// mail-parser decodes text bodies in a charset it knows, and otherwise (no charset, UTF-8,
+// or one it doesn't support) reads them as UTF-8, silently replacing invalid bytes with
+// U+FFFD. Check the transfer-decoded bytes of those bodies so mojibake is rejected instead of
+// relayed.
+fn validate_text_charset(parsed_email: &Message, index: u32) -> Result<(), SmtpRelayError> {
+    let Some(part) = parsed_email.part(index) else {
+        return Ok(());
+    };
+    let charset = part.content_type().and_then(|ct| ct.attribute("charset"));
+    if charset.is_some_and(|charset| charset_decoder(charset.as_bytes()).is_some()) {
+        return Ok(());
+    }
+
+    let raw = parsed_email
+        .raw_message()
+        .get(part.raw_body_offset() as usize..part.raw_end_offset() as usize)
+        .unwrap_or_default();
+    let encoding = part.content_transfer_encoding().unwrap_or_default();
+    let decoded = if encoding.eq_ignore_ascii_case("base64") {
+        base64_decode(raw)
+    } else if encoding.eq_ignore_ascii_case("quoted-printable") {
+        quoted_printable_decode(raw)
+    } else {
+        Some(raw.to_vec())
+    };
+    // Undecodable bodies are reported by the transfer-encoding check above
+    let Some(decoded) = decoded else {
+        return Ok(());
+    };
+    if std::str::from_utf8(&decoded).is_err() {
+        return Err(SmtpRelayError::Email(EmailError::InvalidEncoding(
+            match charset {
+                Some(charset) if !is_utf8_charset(charset) => {
+                    format!("unsupported charset {charset}")
+                }
+                _ => "invalid UTF-8 text".to_string(),
+            },
+        )));
+    }
+    Ok(())
+}
+
+fn is_utf8_charset(charset: &str) -> bool {
+    matches!(charset.to_ascii_lowercase().as_str(), "utf-8" | "utf8")
+}
+
+// Whether an HTML body renders nothing: no text once tags are stripped, and no images.
+fn html_is_empty(html: &str) -> bool {
+    html_to_text(html).trim().is_empty() && !html.to_ascii_lowercase().contains("<img")
 }
 
 // Helper function to build the ACS request payload from a parsed email.
@@ -135,27 +692,48 @@ fn build_acs_request<'a>(
     parsed_email: &'a Message,
     recipients: &'a [String],
     sender_address: &'a str,
+    options: &ContentOptions,
 ) -> Result<AcsEmailRequest<'a>, SmtpRelayError> {
     if recipients.is_empty() {
         return Err(SmtpRelayError::Email(EmailError::MissingContent));
     }
-    let subject = parsed_email.subject().unwrap_or("No Subject").to_string();
+    // A blank Subject header counts as missing
+    let subject = match parsed_email.subject().filter(|s| !s.trim().is_empty()) {
+        Some(subject) => subject.to_string(),
+        None if options.reject_missing_subject => {
+            return Err(SmtpRelayError::Email(EmailError::MissingSubject))
+        }
+        None => options
+            .default_subject
+            .as_deref()
+            .unwrap_or("No Subject")
+            .to_string(),
+    };
 
-    // Prioritize HTML body if it exists and is not empty.
-    // Only include HTML if it's explicitly present and non-empty.
-    let html_body = parsed_email.body_html(0).and_then(|s| {
+    // Only include plain text if a text body is present. For an HTML-only message
+    // mail-parser derives it from the HTML by stripping tags.
+    let text_body = parsed_email.body_text(0).and_then(|s| {
         let trimmed = s.trim();
-        if trimmed.is_empty() || trimmed == "<html><body></body></html>" {
+        if trimmed.is_empty() {
             None
         } else {
             Some(trimmed.to_string())
         }
     });
 
-    // Only include plain text if a text body is present.
-    let text_body = parsed_email.body_text(0).and_then(|s| {
+    // Only include HTML if it's present; what happens to HTML that renders nothing
+    // (e.g. `<html><body> </body></html>`) depends on the empty-HTML setting.
+    let html_body = parsed_email.body_html(0).and_then(|s| {
+        if options.force_plain_text {
+            return None;
+        }
         let trimmed = s.trim();
-        if trimmed.is_empty() {
+        let drop_empty = match options.empty_html {
+            EmptyHtml::Drop => true,
+            EmptyHtml::Keep => false,
+            EmptyHtml::PreferText => text_body.is_some(),
+        };
+        if trimmed.is_empty() || (drop_empty && html_is_empty(trimmed)) {
             None
         } else {
             Some(trimmed.to_string())
@@ -171,47 +749,121 @@ fn build_acs_request<'a>(
         plain_text: text_body,
         html: html_body,
     };
-    let recipients_struct = AcsRecipients {
-        to: recipients
-            .iter()
-            .map(|addr| AcsEmailAddress { address: addr })
-            .collect(),
+    let recipients_struct = match options.recipient_policy {
+        RecipientPolicy::Envelope => AcsRecipients {
+            to: recipients
+                .iter()
+                .map(|addr| AcsEmailAddress {
+                    address: options.recipient_case.normalize(addr),
+                    display_name: None,
+                })
+                .collect(),
+            cc: Vec::new(),
+            bcc: Vec::new(),
+        },
+        RecipientPolicy::Merge => {
+            merge_recipients(parsed_email, recipients, options.recipient_case)
+        }
     };
     Ok(AcsEmailRequest {
         sender_address,
         content,
         recipients: recipients_struct,
+        headers: parse_importance(parsed_email)
+            .map(|importance| BTreeMap::from(importance.headers()))
+            .unwrap_or_default(),
+        reply_to: reply_to(parsed_email, sender_address),
+        user_engagement_tracking_disabled: options.disable_user_engagement_tracking,
     })
 }
 
+// ACS shows recipients its verified senderAddress, not the message's From header. So that
+// replies still reach the author, a Reply-To header is passed on, and otherwise a From
+// address other than the sender becomes the reply-to address.
+fn reply_to<'a>(parsed_email: &'a Message, sender_address: &str) -> Vec<AcsEmailAddress<'a>> {
+    let mailboxes = |header: Option<&'a Address>| -> Vec<AcsEmailAddress<'a>> {
+        header
+            .into_iter()
+            .flat_map(|addresses| addresses.iter())
+            .filter_map(|addr| {
+                let address = addr
+                    .address()
+                    .filter(|a| crate::config::is_valid_email(a))?;
+                Some(AcsEmailAddress {
+                    address: address.into(),
+                    display_name: addr.name(),
+                })
+            })
+            .collect()
+    };
+    let reply_to = mailboxes(parsed_email.reply_to());
+    if !reply_to.is_empty() {
+        return reply_to;
+    }
+    mailboxes(parsed_email.from())
+        .into_iter()
+        .filter(|author| !author.address.eq_ignore_ascii_case(sender_address))
+        .collect()
+}
+
+// Places each header recipient in its ACS field, adding addresses missing from the
+// envelope. Envelope recipients not named in any header were blind copied, so they go in
+// bcc. Addresses are compared case-insensitively and listed once, then normalized by `case`.
+fn merge_recipients<'a>(
+    parsed_email: &'a Message,
+    envelope: &'a [String],
+    case: RecipientCase,
+) -> AcsRecipients<'a> {
+    let mut seen = HashSet::new();
+    let mut from_header = |header: Option<&'a Address>| -> Vec<AcsEmailAddress<'a>> {
+        header
+            .into_iter()
+            .flat_map(|addresses| addresses.iter())
+            .filter_map(|addr| addr.address())
+            .filter(|address| crate::config::is_valid_email(address))
+            .filter(|address| seen.insert(address.to_ascii_lowercase()))
+            .map(|address| AcsEmailAddress {
+                address: case.normalize(address),
+                display_name: None,
+            })
+            .collect()
+    };
+    let to = from_header(parsed_email.to());
+    let cc = from_header(parsed_email.cc());
+    let mut bcc = from_header(parsed_email.bcc());
+    bcc.extend(
+        envelope
+            .iter()
+            .filter(|address| seen.insert(address.to_ascii_lowercase()))
+            .map(|address| AcsEmailAddress {
+                address: case.normalize(address),
+                display_name: None,
+            }),
+    );
+    AcsRecipients { to, cc, bcc }
+}
+
 #[async_trait]
 impl Mailer for AcsMailer {
-    #[instrument(skip_all, fields(recipient_count = recipients.len()))]
     async fn send(
         &self,
         raw_email: &[u8],
         recipients: &[String],
         from: &Option<String>,
-    ) -> Result<()> {
-        let sender_for_request = if let (Some(allowed_domains), Some(from_address)) =
-            (&self.allowed_sender_domains, from)
-        {
-            let trimmed_from = from_address.trim_matches(|c| c == '<' || c == '>');
-            if let Some(from_domain) = trimmed_from.split('@').nth(1) {
-                if allowed_domains.iter().any(|d| d == from_domain) {
-                    info!(client_sender = %trimmed_from, "Using client-provided sender address");
-                    trimmed_from.to_string()
-                } else {
-                    warn!(client_sender = %trimmed_from, fallback_sender = %self.sender_address, "Sender not in allow-list, using default");
-                    self.sender_address.clone()
-                }
-            } else {
-                warn!(invalid_from = %from_address, "Could not parse domain from MAIL FROM, using default");
-                self.sender_address.clone()
-            }
-        } else {
-            self.sender_address.clone()
-        };
+    ) -> Result<(), SmtpRelayError> {
+        self.send_repeatable(raw_email, recipients, from, &Repeatability::new())
+            .await
+    }
+
+    #[instrument(skip_all, fields(recipient_count = recipients.len()))]
+    async fn send_repeatable(
+        &self,
+        raw_email: &[u8],
+        recipients: &[String],
+        from: &Option<String>,
+        repeatability: &Repeatability,
+    ) -> Result<(), SmtpRelayError> {
+        let sender_for_request = self.select_sender(from);
 
         info!("Parsing raw email data.");
 
@@ -219,41 +871,245 @@ impl Mailer for AcsMailer {
             SmtpRelayError::Email(EmailError::ParseFailed("Invalid email format".to_string()))
         })?;
 
+        validate_content(&parsed_email, &self.allowed_content_types)?;
+
         info!("Building ACS request payload.");
-        let request_payload = build_acs_request(&parsed_email, recipients, &sender_for_request)?;
+        let request_payload = build_acs_request(
+            &parsed_email,
+            recipients,
+            &sender_for_request,
+            &self.content,
+        )?;
+
+        #[cfg(feature = "html-sanitize")]
+        let request_payload = {
+            let mut request_payload = request_payload;
+            crate::html::apply_policy(&mut request_payload.content.html, self.html_policy)?;
+            request_payload
+        };
+
         let body_bytes = serde_json::to_vec(&request_payload)?;
 
-        const API_VERSION: &str = "2023-03-31";
-        let url_path = format!("/emails:send?api-version={API_VERSION}");
-        let (timestamp, content_hash, auth_header) =
-            self.sign_request(&Method::POST, &url_path, &body_bytes)?;
+        let Some(breaker) = &self.circuit_breaker else {
+            return self
+                .post_email(body_bytes, &sender_for_request, repeatability)
+                .await;
+        };
+        if !breaker.try_acquire() {
+            warn!("Circuit breaker open, not sending to ACS");
+            return Err(SmtpRelayError::Acs(AcsError::CircuitOpen));
+        }
+        let result = self
+            .post_email(body_bytes, &sender_for_request, repeatability)
+            .await;
+        // ACS rejecting a request still shows it is up; only transient failures count
+        breaker.record(result.as_ref().map_or_else(|e| e.is_permanent(), |_| true));
+        result
+    }
+}
 
-        info!(url = %self.api_endpoint, sender = %sender_for_request, "Sending signed request to ACS API.");
-        let response = self
-            .client
-            .post(format!(
-                "{api_endpoint}{url_path}",
-                api_endpoint = self.api_endpoint,
-                url_path = url_path
-            ))
-            .header("x-ms-date", timestamp)
-            .header("x-ms-content-sha256", content_hash)
-            .header(header::AUTHORIZATION, auth_header)
-            .header(header::CONTENT_TYPE, "application/json")
-            .body(body_bytes)
-            .send()
-            .await
-            .context("Failed to send HTTP request to ACS")?;
+// A Mailer that delivers into a local maildir instead of calling ACS, so the SMTP side
+// can be exercised without an Azure account.
+#[derive(Debug)]
+pub struct MaildirMailer {
+    root: PathBuf,
+}
 
-        info!(status = %response.status(), "Received response from ACS");
+impl MaildirMailer {
+    // Creates the maildir's `tmp`, `new` and `cur` subdirectories if needed
+    pub fn new(root: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let root = root.into();
+        for subdir in ["tmp", "new", "cur"] {
+            std::fs::create_dir_all(root.join(subdir))?;
+        }
+        Ok(Self { root })
+    }
+}
 
-        if !response.status().is_success() {
-            let status = response.status().as_u16();
-            let body = response.text().await.unwrap_or_default();
-            return Err(SmtpRelayError::Acs(AcsError::from_status_code(status, &body)).into());
+#[async_trait]
+impl Mailer for MaildirMailer {
+    #[instrument(skip_all, fields(recipient_count = recipients.len()))]
+    async fn send(
+        &self,
+        raw_email: &[u8],
+        recipients: &[String],
+        from: &Option<String>,
+    ) -> Result<(), SmtpRelayError> {
+        // Envelope details go in the headers a local delivery agent would add
+        let mut message = fold_header(
+            "Return-Path",
+            &format!("<{}>", from.as_deref().unwrap_or("")),
+        );
+        for recipient in recipients {
+            message.push_str(&fold_header("Delivered-To", recipient));
         }
+        let mut message = message.into_bytes();
+        message.extend_from_slice(raw_email);
 
-        info!("Successfully relayed email to ACS.");
+        // Write under tmp/ then rename into new/, so readers never see a partial message
+        let name = format!(
+            "{}.P{}_{}.acs-smtp-relay",
+            Utc::now().timestamp(),
+            std::process::id(),
+            nanoid::nanoid!(10)
+        );
+        let tmp_path = self.root.join("tmp").join(&name);
+        let new_path = self.root.join("new").join(&name);
+        tokio::fs::write(&tmp_path, &message).await?;
+        tokio::fs::rename(&tmp_path, &new_path).await?;
+
+        info!(path = %new_path.display(), "Delivered email to maildir");
+        Ok(())
+    }
+}
+
+// A message received by a CapturingMailer, as the relay handed it over
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedMessage {
+    pub raw_email: Vec<u8>,
+    pub recipients: Vec<String>,
+    pub from: Option<String>,
+}
+
+// A Mailer that keeps every message in memory, for tests that assert on relayed mail.
+// Clones share the same store, so keep one clone and hand another to the server.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, Clone, Default)]
+pub struct CapturingMailer {
+    messages: Arc<std::sync::Mutex<Vec<CapturedMessage>>>,
+    // Error returned for every send, after the message has been captured
+    fail_with: Option<fn() -> SmtpRelayError>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl CapturingMailer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Captures every message but fails to send it, e.g. to test retries
+    pub fn failing(error: fn() -> SmtpRelayError) -> Self {
+        Self {
+            fail_with: Some(error),
+            ..Self::default()
+        }
+    }
+
+    // The messages sent so far, oldest first
+    pub fn messages(&self) -> Vec<CapturedMessage> {
+        self.messages.lock().unwrap().clone()
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+#[async_trait]
+impl Mailer for CapturingMailer {
+    async fn send(
+        &self,
+        raw_email: &[u8],
+        recipients: &[String],
+        from: &Option<String>,
+    ) -> Result<(), SmtpRelayError> {
+        self.messages.lock().unwrap().push(CapturedMessage {
+            raw_email: raw_email.to_vec(),
+            recipients: recipients.to_vec(),
+            from: from.clone(),
+        });
+        match self.fail_with {
+            Some(error) => Err(error()),
+            None => Ok(()),
+        }
+    }
+}
+
+// A Mailer that forwards messages unchanged to an upstream SMTP server, turning the
+// bridge into a general authenticating and metering SMTP proxy.
+#[cfg(feature = "smtp-forward")]
+pub struct SmtpRelayMailer {
+    transport: lettre::AsyncSmtpTransport<lettre::Tokio1Executor>,
+}
+
+#[cfg(feature = "smtp-forward")]
+impl SmtpRelayMailer {
+    pub fn new(upstream: &crate::config::SmtpUpstreamConfig) -> Result<Self, SmtpRelayError> {
+        use crate::config::UpstreamTls;
+        use lettre::transport::smtp::authentication::Credentials;
+        use lettre::{AsyncSmtpTransport, Tokio1Executor};
+
+        let invalid = |e: lettre::transport::smtp::Error| {
+            SmtpRelayError::Config(ConfigError::InvalidSmtpUpstream(e.to_string()))
+        };
+        let builder = match upstream.tls {
+            UpstreamTls::None => {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&upstream.host)
+            }
+            UpstreamTls::StartTls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&upstream.host)
+                    .map_err(invalid)?
+            }
+            UpstreamTls::Tls => {
+                AsyncSmtpTransport::<Tokio1Executor>::relay(&upstream.host).map_err(invalid)?
+            }
+        }
+        .port(upstream.port);
+        let builder = match (&upstream.username, &upstream.password) {
+            (Some(username), Some(password)) => {
+                builder.credentials(Credentials::new(username.clone(), password.clone()))
+            }
+            _ => builder,
+        };
+        Ok(Self {
+            transport: builder.build(),
+        })
+    }
+}
+
+// Parses an envelope path as received over SMTP; the null path `<>` yields None
+#[cfg(feature = "smtp-forward")]
+fn parse_envelope_address(path: &str) -> Result<Option<lettre::Address>, SmtpRelayError> {
+    let address = path.trim_matches(|c| c == '<' || c == '>');
+    if address.is_empty() {
+        return Ok(None);
+    }
+    address
+        .parse()
+        .map(Some)
+        .map_err(|_| SmtpRelayError::Smtp(SmtpError::InvalidAddress(address.to_string())))
+}
+
+#[cfg(feature = "smtp-forward")]
+#[async_trait]
+impl Mailer for SmtpRelayMailer {
+    #[instrument(skip_all, fields(recipient_count = recipients.len()))]
+    async fn send(
+        &self,
+        raw_email: &[u8],
+        recipients: &[String],
+        from: &Option<String>,
+    ) -> Result<(), SmtpRelayError> {
+        use lettre::AsyncTransport;
+
+        let from = parse_envelope_address(from.as_deref().unwrap_or(""))?;
+        let to = recipients
+            .iter()
+            .filter_map(|r| parse_envelope_address(r).transpose())
+            .collect::<Result<Vec<_>, _>>()?;
+        let envelope = lettre::address::Envelope::new(from, to)
+            .map_err(|e| SmtpRelayError::Smtp(SmtpError::InvalidAddress(e.to_string())))?;
+
+        self.transport
+            .send_raw(&envelope, raw_email)
+            .await
+            .map_err(|e| {
+                if e.is_permanent() {
+                    SmtpRelayError::Smtp(SmtpError::UpstreamRejected(e.to_string()))
+                } else {
+                    SmtpRelayError::Network(NetworkError::Upstream(Box::new(e)))
+                }
+            })?;
+
+        info!("Forwarded email to upstream SMTP server.");
         Ok(())
     }
 }
@@ -268,11 +1124,568 @@ mod tests {
             .parse(b"Subject: Empty\r\n\r\n")
             .unwrap();
         let recipients = vec!["to@example.com".to_string()];
-        let result = build_acs_request(&empty_message, &recipients, "sender@example.com");
+        let result = build_acs_request(
+            &empty_message,
+            &recipients,
+            "sender@example.com",
+            &ContentOptions::default(),
+        );
         assert!(result.is_err());
         assert!(matches!(
             result.unwrap_err(),
             SmtpRelayError::Email(EmailError::MissingContent)
         ));
     }
+
+    fn mailer_with_sender_map() -> AcsMailer {
+        let sender_map =
+            HashMap::from([("Brand-A.com".to_string(), "noreply@brand-a.com".to_string())]);
+        AcsMailer::builder(
+            "https://example.communication.azure.com",
+            "dGVzdA==",
+            "default@sender.com",
+        )
+        .sender_map(sender_map)
+        .build()
+    }
+
+    #[test]
+    fn test_builder_applies_defaults() {
+        let mailer = AcsMailer::builder(
+            "https://example.communication.azure.com",
+            "dGVzdA==",
+            "default@sender.com",
+        )
+        .build();
+        assert_eq!(
+            mailer.api_endpoint,
+            "https://example.communication.azure.com"
+        );
+        assert_eq!(mailer.sender_address, "default@sender.com");
+        assert!(mailer.allowed_sender_domains.is_none());
+        assert!(mailer.sender_map.is_empty());
+        assert!(!mailer.content.disable_user_engagement_tracking);
+        assert_eq!(
+            mailer.select_sender(&Some("app@anywhere.com".to_string())),
+            "default@sender.com"
+        );
+    }
+
+    #[test]
+    fn test_builder_matches_positional_constructor() {
+        let built = AcsMailer::builder(
+            "https://example.communication.azure.com",
+            "dGVzdA==",
+            "default@sender.com",
+        )
+        .allowed_sender_domains(Some(vec!["example.com".to_string()]))
+        .disable_user_engagement_tracking(true)
+        .build();
+        let positional = AcsMailer::new(
+            Client::new(),
+            "https://example.communication.azure.com".to_string(),
+            "dGVzdA==".to_string(),
+            "default@sender.com".to_string(),
+            Some(vec!["example.com".to_string()]),
+        );
+        assert_eq!(format!("{built:?}"), format!("{positional:?}"));
+        assert!(built.content.disable_user_engagement_tracking);
+        assert!(!positional.content.disable_user_engagement_tracking);
+        assert_eq!(
+            built.select_sender(&Some("app@example.com".to_string())),
+            "app@example.com"
+        );
+    }
+
+    #[test]
+    fn test_select_sender_matches_wildcard_domains() {
+        let mailer = AcsMailer::builder(
+            "https://example.communication.azure.com",
+            "dGVzdA==",
+            "default@sender.com",
+        )
+        .allowed_sender_domains(Some(vec![
+            "example.com".to_string(),
+            "*.apps.example.net".to_string(),
+        ]))
+        .build();
+        let select = |from: &str| mailer.select_sender(&Some(from.to_string()));
+        assert_eq!(select("app@example.com"), "app@example.com");
+        assert_eq!(select("app@eu.apps.example.net"), "app@eu.apps.example.net");
+        // The wildcard covers subdomains only, and exact entries don't cover subdomains
+        assert_eq!(select("app@apps.example.net"), "default@sender.com");
+        assert_eq!(select("app@mail.example.com"), "default@sender.com");
+        assert_eq!(select("app@evilapps.example.net"), "default@sender.com");
+    }
+
+    #[test]
+    fn test_select_sender_uses_mapped_domain() {
+        let mailer = mailer_with_sender_map();
+        let from = Some("<app@brand-a.com>".to_string());
+        assert_eq!(mailer.select_sender(&from), "noreply@brand-a.com");
+    }
+
+    #[test]
+    fn test_select_sender_falls_back_for_unmapped_domain() {
+        let mailer = mailer_with_sender_map();
+        let from = Some("app@brand-b.com".to_string());
+        assert_eq!(mailer.select_sender(&from), "default@sender.com");
+    }
+
+    #[test]
+    fn test_select_sender_falls_back_without_from() {
+        let mailer = mailer_with_sender_map();
+        assert_eq!(mailer.select_sender(&None), "default@sender.com");
+    }
+
+    #[test]
+    fn test_select_sender_uses_default_for_null_sender() {
+        let mailer = mailer_with_sender_map();
+        assert_eq!(
+            mailer.select_sender(&Some(String::new())),
+            "default@sender.com"
+        );
+    }
+
+    #[test]
+    fn test_build_acs_request_maps_importance_header() {
+        let message = MessageParser::new()
+            .parse(b"Subject: Urgent\r\nImportance: High\r\n\r\nPlease read.\r\n")
+            .unwrap();
+        let recipients = vec!["to@example.com".to_string()];
+        let request = build_acs_request(
+            &message,
+            &recipients,
+            "sender@example.com",
+            &ContentOptions::default(),
+        )
+        .unwrap();
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(
+            json["headers"],
+            serde_json::json!({"Importance": "high", "X-Priority": "1"})
+        );
+    }
+
+    #[test]
+    fn test_build_acs_request_maps_x_priority_header() {
+        let message = MessageParser::new()
+            .parse(b"Subject: FYI\r\nX-Priority: 5 (Lowest)\r\n\r\nNo rush.\r\n")
+            .unwrap();
+        let recipients = vec!["to@example.com".to_string()];
+        let request = build_acs_request(
+            &message,
+            &recipients,
+            "sender@example.com",
+            &ContentOptions::default(),
+        )
+        .unwrap();
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(
+            json["headers"],
+            serde_json::json!({"Importance": "low", "X-Priority": "5"})
+        );
+    }
+
+    #[test]
+    fn test_build_acs_request_applies_default_subject() {
+        let message = MessageParser::new()
+            .parse(b"From: a@example.com\r\n\r\nNo subject here.\r\n")
+            .unwrap();
+        let recipients = vec!["to@example.com".to_string()];
+
+        let request = build_acs_request(
+            &message,
+            &recipients,
+            "sender@example.com",
+            &ContentOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(request.content.subject, "No Subject");
+
+        let request = build_acs_request(
+            &message,
+            &recipients,
+            "sender@example.com",
+            &ContentOptions {
+                default_subject: Some("Notification".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(request.content.subject, "Notification");
+    }
+
+    #[test]
+    fn test_build_acs_request_rejects_missing_subject() {
+        let recipients = vec!["to@example.com".to_string()];
+        for raw in [
+            &b"From: a@example.com\r\n\r\nBody\r\n"[..],
+            b"Subject:   \r\n\r\nBody\r\n",
+        ] {
+            let message = MessageParser::new().parse(raw).unwrap();
+            let result = build_acs_request(
+                &message,
+                &recipients,
+                "sender@example.com",
+                &ContentOptions {
+                    default_subject: Some("Notification".to_string()),
+                    reject_missing_subject: true,
+                    ..Default::default()
+                },
+            );
+            assert!(matches!(
+                result,
+                Err(SmtpRelayError::Email(EmailError::MissingSubject))
+            ));
+        }
+
+        // A message with a subject is unaffected
+        let message = MessageParser::new()
+            .parse(b"Subject: Present\r\n\r\nBody\r\n")
+            .unwrap();
+        let request = build_acs_request(
+            &message,
+            &recipients,
+            "sender@example.com",
+            &ContentOptions {
+                reject_missing_subject: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(request.content.subject, "Present");
+    }
+
+    #[test]
+    fn test_build_acs_request_omits_headers_without_importance() {
+        let message = MessageParser::new()
+            .parse(b"Subject: Plain\r\n\r\nHello.\r\n")
+            .unwrap();
+        let recipients = vec!["to@example.com".to_string()];
+        let request = build_acs_request(
+            &message,
+            &recipients,
+            "sender@example.com",
+            &ContentOptions::default(),
+        )
+        .unwrap();
+        let json = serde_json::to_value(&request).unwrap();
+        assert!(json.get("headers").is_none(), "{json}");
+        assert!(json.get("importance").is_none(), "{json}");
+    }
+
+    #[test]
+    fn test_html_is_empty() {
+        for html in [
+            "<html><body></body></html>",
+            "<html><body> </body></html>",
+            "<HTML>\r\n<BODY>\r\n</BODY>\r\n</HTML>",
+            "<html><body><p>&nbsp;</p><br/></body></html>",
+            "<html><head><title>Newsletter</title><style>p { color: red }</style></head><body><!-- empty --></body></html>",
+        ] {
+            assert!(html_is_empty(html), "{html}");
+        }
+        for html in [
+            "<html><body>Hi</body></html>",
+            "<p>&amp;</p>",
+            "<html><body><IMG src=\"cid:logo\"></body></html>",
+        ] {
+            assert!(!html_is_empty(html), "{html}");
+        }
+    }
+
+    #[test]
+    fn test_reply_to_prefers_reply_to_header_then_from() {
+        let reply_to_json = |raw: &[u8], sender: &str| {
+            let message = MessageParser::new().parse(raw).unwrap();
+            serde_json::to_value(reply_to(&message, sender)).unwrap()
+        };
+
+        assert_eq!(
+            reply_to_json(
+                b"From: Alice <alice@example.org>\r\nReply-To: help@example.org\r\n\r\nHi",
+                "noreply@example.com",
+            ),
+            serde_json::json!([{ "address": "help@example.org" }])
+        );
+        assert_eq!(
+            reply_to_json(
+                b"From: Alice <alice@example.org>\r\n\r\nHi",
+                "noreply@example.com",
+            ),
+            serde_json::json!([{ "address": "alice@example.org", "displayName": "Alice" }])
+        );
+        // Nothing to add when the header From is the sender itself
+        assert_eq!(
+            reply_to_json(
+                b"From: NoReply@Example.com\r\n\r\nHi",
+                "noreply@example.com",
+            ),
+            serde_json::json!([])
+        );
+    }
+
+    #[test]
+    fn test_build_acs_request_recipient_policies() {
+        let message = MessageParser::new()
+            .parse(
+                b"To: Alice <alice@example.com>, bob@example.com\r\n\
+Cc: carol@example.com\r\nBcc: dave@example.com\r\nSubject: Hi\r\n\r\nHello\r\n",
+            )
+            .unwrap();
+        // Bob is in the envelope with different casing; Erin was blind copied
+        let envelope = vec![
+            "BOB@example.com".to_string(),
+            "erin@example.com".to_string(),
+        ];
+        let recipients = |policy| {
+            let options = ContentOptions {
+                recipient_policy: policy,
+                ..Default::default()
+            };
+            let request =
+                build_acs_request(&message, &envelope, "sender@example.com", &options).unwrap();
+            serde_json::to_value(&request.recipients).unwrap()
+        };
+
+        assert_eq!(
+            recipients(RecipientPolicy::Envelope),
+            serde_json::json!({
+                "to": [{"address": "BOB@example.com"}, {"address": "erin@example.com"}]
+            })
+        );
+        assert_eq!(
+            recipients(RecipientPolicy::Merge),
+            serde_json::json!({
+                "to": [{"address": "alice@example.com"}, {"address": "bob@example.com"}],
+                "cc": [{"address": "carol@example.com"}],
+                "bcc": [{"address": "dave@example.com"}, {"address": "erin@example.com"}]
+            })
+        );
+    }
+
+    #[test]
+    fn test_build_acs_request_recipient_case() {
+        let message = MessageParser::new()
+            .parse(b"To: Alice@Example.COM\r\nSubject: Hi\r\n\r\nHello\r\n")
+            .unwrap();
+        let envelope = vec!["Bob.Smith@Example.COM".to_string()];
+        let recipients = |policy, case| {
+            let options = ContentOptions {
+                recipient_policy: policy,
+                recipient_case: case,
+                ..Default::default()
+            };
+            let request =
+                build_acs_request(&message, &envelope, "sender@example.com", &options).unwrap();
+            serde_json::to_value(&request.recipients).unwrap()
+        };
+
+        assert_eq!(
+            recipients(RecipientPolicy::Envelope, RecipientCase::Preserve),
+            serde_json::json!({"to": [{"address": "Bob.Smith@Example.COM"}]})
+        );
+        assert_eq!(
+            recipients(RecipientPolicy::Envelope, RecipientCase::LowercaseDomain),
+            serde_json::json!({"to": [{"address": "Bob.Smith@example.com"}]})
+        );
+        assert_eq!(
+            recipients(RecipientPolicy::Envelope, RecipientCase::Lowercase),
+            serde_json::json!({"to": [{"address": "bob.smith@example.com"}]})
+        );
+        // Header recipients are normalized too
+        assert_eq!(
+            recipients(RecipientPolicy::Merge, RecipientCase::LowercaseDomain),
+            serde_json::json!({
+                "to": [{"address": "Alice@example.com"}],
+                "bcc": [{"address": "Bob.Smith@example.com"}]
+            })
+        );
+    }
+
+    #[test]
+    fn test_build_acs_request_empty_html_handling() {
+        let recipients = vec!["to@example.com".to_string()];
+        let alternative = MessageParser::new()
+            .parse(
+                b"Subject: Alt\r\nContent-Type: multipart/alternative; boundary=b\r\n\r\n\
+--b\r\nContent-Type: text/plain\r\n\r\nHello.\r\n\
+--b\r\nContent-Type: text/html\r\n\r\n<html><body> </body></html>\r\n--b--\r\n",
+            )
+            .unwrap();
+        let html_only = MessageParser::new()
+            .parse(b"Subject: Html\r\nContent-Type: text/html\r\n\r\n<html><body><p> </p></body></html>\r\n")
+            .unwrap();
+        let build = |message, empty_html| {
+            let options = ContentOptions {
+                empty_html,
+                ..Default::default()
+            };
+            build_acs_request(message, &recipients, "sender@example.com", &options)
+                .map(|request| (request.content.plain_text, request.content.html))
+        };
+
+        // Drop: the empty HTML part is omitted, and an HTML-only message has no content
+        let (text, html) = build(&alternative, EmptyHtml::Drop).unwrap();
+        assert_eq!(text.as_deref(), Some("Hello."));
+        assert!(html.is_none());
+        assert!(matches!(
+            build(&html_only, EmptyHtml::Drop),
+            Err(SmtpRelayError::Email(EmailError::MissingContent))
+        ));
+
+        // Keep: the HTML part is always sent
+        let (_, html) = build(&alternative, EmptyHtml::Keep).unwrap();
+        assert_eq!(html.as_deref(), Some("<html><body> </body></html>"));
+
+        // PreferText: omitted only when there is text to send instead
+        let (text, html) = build(&alternative, EmptyHtml::PreferText).unwrap();
+        assert!(text.is_some() && html.is_none());
+        let (text, html) = build(&html_only, EmptyHtml::PreferText).unwrap();
+        assert!(text.is_none());
+        assert_eq!(html.as_deref(), Some("<html><body><p> </p></body></html>"));
+    }
+
+    #[test]
+    fn test_build_acs_request_force_plain_text_strips_html() {
+        let message = MessageParser::new()
+            .parse(
+                b"Subject: Html\r\nContent-Type: text/html\r\n\r\n\
+<html><body><p>Hello <b>there</b></p><script>track()</script></body></html>\r\n",
+            )
+            .unwrap();
+        let recipients = vec!["to@example.com".to_string()];
+        let options = ContentOptions {
+            force_plain_text: true,
+            ..Default::default()
+        };
+        let request =
+            build_acs_request(&message, &recipients, "sender@example.com", &options).unwrap();
+        let json = serde_json::to_value(&request).unwrap();
+        assert!(json["content"].get("html").is_none());
+        let text = json["content"]["plainText"].as_str().unwrap();
+        assert!(text.contains("Hello there"), "{text}");
+        assert!(!text.contains('<') && !text.contains("track"), "{text}");
+    }
+
+    fn default_content_types() -> Vec<String> {
+        DEFAULT_ALLOWED_CONTENT_TYPES
+            .iter()
+            .map(|ct| ct.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_validate_content_accepts_default_types() {
+        for raw in [
+            &b"Subject: Untyped\r\n\r\nHello.\r\n"[..],
+            b"Subject: Html\r\nContent-Type: text/html\r\n\r\n<p>Hello.</p>\r\n",
+            b"Subject: Alt\r\nContent-Type: multipart/alternative; boundary=b\r\n\r\n\
+--b\r\nContent-Type: text/plain\r\n\r\nHello.\r\n--b--\r\n",
+        ] {
+            let message = MessageParser::new().parse(raw).unwrap();
+            validate_content(&message, &default_content_types()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_validate_content_rejects_unsupported_content_type() {
+        let message = MessageParser::new()
+            .parse(b"Subject: Invoice\r\nContent-Type: Application/PDF\r\n\r\n%PDF-1.4\r\n")
+            .unwrap();
+        let err = validate_content(&message, &default_content_types()).unwrap_err();
+        assert!(matches!(
+            &err,
+            SmtpRelayError::Email(EmailError::UnsupportedContentType(ct)) if ct == "application/pdf"
+        ));
+        assert_eq!(err.reply().code, 554);
+
+        // Allowed by a wildcard entry, or by an empty list
+        validate_content(&message, &["application/*".to_string()]).unwrap();
+        validate_content(&message, &[]).unwrap();
+    }
+
+    #[test]
+    fn test_validate_content_rejects_undecodable_encoding() {
+        let message = MessageParser::new()
+            .parse(
+                b"Subject: Broken\r\nContent-Type: multipart/mixed; boundary=b\r\n\r\n\
+--b\r\nContent-Type: text/plain\r\nContent-Transfer-Encoding: base64\r\n\r\n\
+!!! not base64 !!!\r\n--b--\r\n",
+            )
+            .unwrap();
+        let err = validate_content(&message, &default_content_types()).unwrap_err();
+        assert!(matches!(
+            err,
+            SmtpRelayError::Email(EmailError::InvalidEncoding(ref enc)) if enc == "base64"
+        ));
+    }
+
+    #[test]
+    fn test_validate_content_rejects_text_that_is_not_utf8() {
+        fn parse(raw: &[u8]) -> Message<'_> {
+            MessageParser::new().parse(raw).unwrap()
+        }
+
+        // Unlabelled 8-bit text must be UTF-8
+        let message = parse(b"Subject: Latin-1\r\n\r\ncaf\xe9\r\n");
+        let err = validate_content(&message, &[]).unwrap_err();
+        assert!(matches!(
+            err,
+            SmtpRelayError::Email(EmailError::InvalidEncoding(ref enc)) if enc == "invalid UTF-8 text"
+        ));
+
+        // A charset mail-parser can't decode is only accepted when the text is UTF-8 anyway
+        let message = parse(
+            b"Subject: Unknown\r\nContent-Type: text/plain; charset=x-unknown\r\n\
+Content-Transfer-Encoding: base64\r\n\r\nY2Fm6Q==\r\n",
+        );
+        let err = validate_content(&message, &[]).unwrap_err();
+        assert!(matches!(
+            err,
+            SmtpRelayError::Email(EmailError::InvalidEncoding(ref enc))
+                if enc == "unsupported charset x-unknown"
+        ));
+        let message = parse(
+            b"Subject: Unknown\r\nContent-Type: text/plain; charset=x-unknown\r\n\r\nplain\r\n",
+        );
+        validate_content(&message, &[]).unwrap();
+
+        // Text in a known charset is decoded by mail-parser
+        let message = parse(
+            b"Subject: Latin-1\r\nContent-Type: text/plain; charset=iso-8859-1\r\n\r\ncaf\xe9\r\n",
+        );
+        validate_content(&message, &[]).unwrap();
+        assert_eq!(message.body_text(0).unwrap(), "caf\u{e9}\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_maildir_mailer_delivers_to_new() {
+        let root = std::env::temp_dir().join(format!("acs-maildir-{}", nanoid::nanoid!(8)));
+        let mailer = MaildirMailer::new(&root).unwrap();
+
+        let raw_email = b"Subject: Local\r\n\r\nHello maildir\r\n";
+        mailer
+            .send(
+                raw_email,
+                &["to@example.com".to_string()],
+                &Some("from@example.com".to_string()),
+            )
+            .await
+            .unwrap();
+
+        let delivered: Vec<_> = std::fs::read_dir(root.join("new"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(delivered.len(), 1);
+        let contents = std::fs::read(&delivered[0]).unwrap();
+        assert!(contents
+            .starts_with(b"Return-Path: <from@example.com>\r\nDelivered-To: to@example.com\r\n"));
+        assert!(contents.ends_with(raw_email));
+        assert_eq!(std::fs::read_dir(root.join("tmp")).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 }
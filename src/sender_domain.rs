@@ -0,0 +1,178 @@
+use crate::config::{CloudEnvironment, SenderDomainCheck};
+use crate::error::{ConfigError, SmtpRelayError};
+use anyhow::{Context, Result};
+use reqwest::Client;
+use serde::Deserialize;
+use tracing::{info, instrument};
+
+// Version of the Microsoft.Communication resource provider API used to list email domains
+const ARM_API_VERSION: &str = "2023-04-01";
+
+// Checks at startup that the sender address's domain is verified on the Email Communication
+// Services resource, so a misconfigured sender fails fast instead of with a 400 on the first
+// send. Domains are only listed by Azure Resource Manager, which takes an Azure AD token for
+// a service principal with read access to the resource rather than the ACS access key.
+pub struct SenderDomainVerifier {
+    client: Client,
+    // Base URLs of the Azure AD authority and of Resource Manager
+    authority_url: String,
+    resource_manager_url: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DomainList {
+    #[serde(default)]
+    value: Vec<Domain>,
+    next_link: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Domain {
+    name: String,
+    #[serde(default)]
+    properties: DomainProperties,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct DomainProperties {
+    domain_management: Option<String>,
+    verification_states: Option<VerificationStates>,
+}
+
+#[derive(Deserialize)]
+struct VerificationStates {
+    #[serde(rename = "Domain")]
+    domain: Option<VerificationState>,
+}
+
+#[derive(Deserialize)]
+struct VerificationState {
+    status: String,
+}
+
+impl Domain {
+    // Azure-managed domains are verified by Azure; customer-managed ones once their TXT
+    // record has been checked
+    fn is_verified(&self) -> bool {
+        self.properties.domain_management.as_deref() == Some("AzureManaged")
+            || self
+                .properties
+                .verification_states
+                .as_ref()
+                .and_then(|states| states.domain.as_ref())
+                .is_some_and(|state| state.status == "Verified")
+    }
+}
+
+impl SenderDomainVerifier {
+    // Talks to the Azure AD authority and Resource Manager of the given cloud
+    pub fn new(client: Client, cloud: CloudEnvironment) -> Self {
+        Self::with_endpoints(
+            client,
+            format!("https://{}", cloud.authority_host()),
+            format!("https://{}", cloud.resource_manager_host()),
+        )
+    }
+
+    // Talks to the given base URLs instead, e.g. a mock server in tests
+    pub fn with_endpoints(
+        client: Client,
+        authority_url: impl Into<String>,
+        resource_manager_url: impl Into<String>,
+    ) -> Self {
+        Self {
+            client,
+            authority_url: authority_url.into().trim_end_matches('/').to_string(),
+            resource_manager_url: resource_manager_url
+                .into()
+                .trim_end_matches('/')
+                .to_string(),
+        }
+    }
+
+    #[instrument(skip_all, fields(sender = %sender_address))]
+    pub async fn verify(&self, check: &SenderDomainCheck, sender_address: &str) -> Result<()> {
+        let sender_domain = sender_address
+            .rsplit_once('@')
+            .map(|(_, domain)| domain.to_ascii_lowercase())
+            .ok_or_else(|| {
+                SmtpRelayError::Config(ConfigError::InvalidSenderAddress(
+                    sender_address.to_string(),
+                ))
+            })?;
+
+        let token = self.acquire_token(check).await?;
+        info!("Querying Azure Resource Manager for verified sender domains.");
+        let mut next = Some(format!(
+            "{}{}/domains?api-version={ARM_API_VERSION}",
+            self.resource_manager_url,
+            check.email_service_id.trim_end_matches('/')
+        ));
+        while let Some(url) = next {
+            let response = self
+                .client
+                .get(&url)
+                .bearer_auth(&token)
+                .send()
+                .await
+                .context("Failed to send HTTP request to Azure Resource Manager")?;
+            if !response.status().is_success() {
+                let status = response.status().as_u16();
+                let body = response.text().await.unwrap_or_default();
+                anyhow::bail!("Listing email domains failed: HTTP {status}: {body}");
+            }
+            let domains: DomainList = response
+                .json()
+                .await
+                .context("Failed to parse email domains response")?;
+            if domains
+                .value
+                .iter()
+                .any(|d| d.name.eq_ignore_ascii_case(&sender_domain) && d.is_verified())
+            {
+                info!(%sender_domain, "Sender domain is verified in ACS");
+                return Ok(());
+            }
+            next = domains.next_link;
+        }
+
+        Err(SmtpRelayError::Config(ConfigError::UnverifiedSenderDomain(sender_domain)).into())
+    }
+
+    // Client credentials grant for the service principal, scoped to Resource Manager
+    async fn acquire_token(&self, check: &SenderDomainCheck) -> Result<String> {
+        let scope = format!("{}/.default", self.resource_manager_url);
+        let response = self
+            .client
+            .post(format!(
+                "{}/{}/oauth2/v2.0/token",
+                self.authority_url, check.tenant_id
+            ))
+            .form(&[
+                ("grant_type", "client_credentials"),
+                ("client_id", check.client_id.as_str()),
+                ("client_secret", check.client_secret.as_str()),
+                ("scope", scope.as_str()),
+            ])
+            .send()
+            .await
+            .context("Failed to send HTTP request to Azure AD")?;
+        if !response.status().is_success() {
+            let status = response.status().as_u16();
+            let body = response.text().await.unwrap_or_default();
+            anyhow::bail!("Azure AD token request failed: HTTP {status}: {body}");
+        }
+        let token: TokenResponse = response
+            .json()
+            .await
+            .context("Failed to parse Azure AD token response")?;
+        Ok(token.access_token)
+    }
+}
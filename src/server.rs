@@ -0,0 +1,443 @@
+use crate::config::{Config, MailerBackend, SessionConfig};
+use crate::metrics::{self, MetricsCollector};
+use crate::relay::{AcsMailer, MaildirMailer, Mailer};
+use crate::sender_domain::SenderDomainVerifier;
+use crate::{bind_listener, serve_until, shutdown_signal};
+use anyhow::{Context, Result};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+#[cfg(not(feature = "health-server"))]
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tracing::info;
+
+// The complete relay (mailer, SMTP listeners, metrics and health server) built from a
+// Config, for running the bridge standalone or embedded in another service.
+pub struct Server {
+    config: Config,
+    mailer: Arc<dyn Mailer>,
+    metrics: MetricsCollector,
+    listeners: Vec<TcpListener>,
+}
+
+impl Server {
+    // Validates the config, builds the configured mailer and binds the SMTP listeners
+    pub async fn from_config(config: Config) -> Result<Self> {
+        config
+            .validate()
+            .context("Configuration validation failed")?;
+
+        let mut metrics = MetricsCollector::new();
+        let mailer: Arc<dyn Mailer> = match &config.mailer_backend {
+            MailerBackend::Acs => {
+                // ACS builds a new message from the parsed content, dropping these headers
+                if config.received_header || config.date_header {
+                    info!(
+                        received_header = config.received_header,
+                        date_header = config.date_header,
+                        "Received and Date headers added by the relay are not sent to ACS; they are kept only in dead-lettered and queued copies"
+                    );
+                }
+                let acs_mailer = build_acs_mailer(&config, &metrics).await?;
+                if let Some(breaker) = acs_mailer.circuit_breaker() {
+                    metrics = metrics.with_circuit_breaker(breaker);
+                }
+                Arc::new(acs_mailer)
+            }
+            MailerBackend::Maildir(path) => {
+                info!(maildir = %path.display(), "Delivering mail to a local maildir instead of ACS");
+                Arc::new(MaildirMailer::new(path).context("Failed to create maildir")?)
+            }
+            #[cfg(feature = "smtp-forward")]
+            MailerBackend::Smtp(upstream) => {
+                info!(upstream_host = %upstream.host, upstream_port = upstream.port, "Forwarding mail to an upstream SMTP server instead of ACS");
+                Arc::new(
+                    crate::relay::SmtpRelayMailer::new(upstream)
+                        .context("Failed to configure upstream SMTP server")?,
+                )
+            }
+            #[cfg(not(feature = "smtp-forward"))]
+            MailerBackend::Smtp(_) => {
+                anyhow::bail!(
+                    "MAILER_BACKEND=smtp requires a build with the `smtp-forward` feature"
+                )
+            }
+        };
+
+        // Validation has ruled out DKIM with the ACS backend
+        #[cfg(feature = "dkim")]
+        let mailer: Arc<dyn Mailer> = match &config.dkim {
+            Some(dkim_config) => {
+                let signer = crate::dkim::MessageSigner::from_config(dkim_config)
+                    .context("Failed to load DKIM signing key")?;
+                info!(dkim_domain = %dkim_config.domain, dkim_selector = %dkim_config.selector, "DKIM signing enabled");
+                Arc::new(crate::dkim::SigningMailer::new(mailer, signer))
+            }
+            None => mailer,
+        };
+        #[cfg(not(feature = "dkim"))]
+        if config.dkim.is_some() {
+            tracing::warn!("DKIM settings provided but this build lacks the `dkim` feature; messages will not be signed");
+        }
+
+        let unauthenticated_limits = config.unauthenticated_max_message_size.is_some()
+            || config.unauthenticated_recipient_domains.is_some();
+        if unauthenticated_limits && config.auth_credentials.is_empty() {
+            tracing::warn!("UNAUTHENTICATED_* settings provided without AUTH_CREDENTIALS; any AUTH PLAIN credentials lift them, so they only steer well-behaved clients");
+        }
+
+        let bind_addresses = config.bind_addresses();
+        // With several addresses, keep IPv6 sockets v6-only so an IPv4 wildcard can share the port
+        let only_v6 = bind_addresses.len() > 1;
+        let mut listeners = Vec::with_capacity(bind_addresses.len());
+        for addr in bind_addresses {
+            let listener = bind_listener(addr, only_v6)
+                .with_context(|| format!("Failed to bind SMTP listener on {addr}"))?;
+            info!(
+                listen_addr = %listener.local_addr()?,
+                max_email_size_bytes = config.max_message_size,
+                "SMTP-to-ACS relay listening for connections"
+            );
+            listeners.push(listener);
+        }
+
+        Ok(Self {
+            config,
+            mailer,
+            metrics,
+            listeners,
+        })
+    }
+
+    // Addresses the SMTP listeners are bound to
+    pub fn local_addrs(&self) -> std::io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(TcpListener::local_addr).collect()
+    }
+
+    pub fn metrics(&self) -> MetricsCollector {
+        self.metrics.clone()
+    }
+
+    // Serves until SIGINT/SIGTERM, then shuts down gracefully
+    pub async fn run(self) -> Result<()> {
+        self.run_until(shutdown_signal()).await
+    }
+
+    // Serves until `shutdown` completes, then shuts down gracefully
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) -> Result<()> {
+        let Self {
+            config,
+            mailer,
+            metrics,
+            listeners,
+        } = self;
+
+        // Start metrics logging every 5 minutes
+        metrics::start_metrics_logger(metrics.clone(), std::time::Duration::from_secs(300));
+
+        let local_addr = listeners[0].local_addr()?;
+        #[cfg_attr(not(feature = "queue"), allow(unused_mut))]
+        let mut session = config.session_config(&local_addr, metrics.clone());
+
+        if let Some(health_bind_address) = config.health_bind_address {
+            start_health_server(&config, health_bind_address, metrics, &session, &mailer).await?;
+        }
+
+        // Accept mail during ACS outages and retry it from disk in the background
+        #[cfg(feature = "queue")]
+        if let Some(queue_dir) = &config.queue_dir {
+            let queue = Arc::new(
+                crate::queue::MessageQueue::open(queue_dir)
+                    .await
+                    .context("Failed to open QUEUE_DIR")?
+                    .with_max_age(config.message_max_age),
+            );
+            info!(queue_dir = %queue_dir.display(), "Offline queue enabled");
+            crate::queue::start_queue_worker(
+                queue.clone(),
+                mailer.clone(),
+                std::time::Duration::from_secs(30),
+                std::time::Duration::from_secs(900),
+            );
+            session.queue = Some(queue);
+        }
+        #[cfg(not(feature = "queue"))]
+        if config.queue_dir.is_some() {
+            tracing::warn!("QUEUE_DIR provided but this build lacks the `queue` feature; failed messages will not be retried");
+        }
+
+        serve_until(listeners, mailer, session, shutdown).await;
+        info!("Server has shut down gracefully.");
+        Ok(())
+    }
+}
+
+// Builds the ACS mailer and the HTTP client it sends with
+async fn build_acs_mailer(config: &Config, metrics: &MetricsCollector) -> Result<AcsMailer> {
+    // Create HTTP client with connection pooling
+    let http_client_settings = config.http_client_settings();
+    if let Some(proxy) = &http_client_settings.proxy {
+        info!(proxy = %crate::redact::redact_url_password(proxy), "Routing ACS requests through proxy");
+    }
+    let http_client = http_client_settings
+        .build_client()
+        .context("Failed to create HTTP client")?;
+
+    // Optionally fail fast if the sender domain isn't verified on the ACS resource
+    if let Some(check) = &config.sender_domain_check {
+        SenderDomainVerifier::new(http_client.clone(), config.cloud_environment)
+            .verify(check, &config.sender_address)
+            .await
+            .context("Sender domain verification failed")?;
+    }
+
+    #[cfg_attr(not(feature = "html-sanitize"), allow(unused_mut))]
+    let mut builder = AcsMailer::builder(
+        config.acs_config.endpoint.clone(),
+        config.acs_config.access_key.clone(),
+        config.sender_address.clone(),
+    )
+    .client(http_client)
+    .send_path(config.acs_send_path.clone())
+    .allowed_sender_domains(config.allowed_sender_domains.clone())
+    .sender_map(config.sender_map.clone())
+    .disable_user_engagement_tracking(config.disable_user_engagement_tracking)
+    .default_subject(config.default_subject.clone())
+    .reject_missing_subject(config.reject_missing_subject)
+    .empty_html(config.empty_html)
+    .recipient_policy(config.recipient_policy)
+    .recipient_case(config.recipient_case)
+    .force_plain_text(config.force_plain_text)
+    .allowed_content_types(config.allowed_content_types.clone())
+    .circuit_breaker(
+        config.circuit_breaker_threshold,
+        config.circuit_breaker_cooldown,
+    )
+    .metrics(metrics.clone());
+
+    #[cfg(feature = "html-sanitize")]
+    {
+        builder = builder.html_policy(config.html_policy);
+    }
+    #[cfg(not(feature = "html-sanitize"))]
+    if config.html_policy != crate::config::HtmlPolicy::Off {
+        anyhow::bail!("ACS_HTML_POLICY requires a build with the `html-sanitize` feature");
+    }
+    let acs_mailer = builder.build();
+
+    Ok(acs_mailer)
+}
+
+#[cfg(feature = "health-server")]
+async fn start_health_server(
+    config: &Config,
+    health_bind_address: SocketAddr,
+    metrics: MetricsCollector,
+    session: &SessionConfig,
+    mailer: &Arc<dyn Mailer>,
+) -> Result<()> {
+    #[cfg(not(feature = "health-tls"))]
+    if config.health_tls.is_some() {
+        tracing::warn!("HEALTH_TLS_* settings provided but this build lacks the `health-tls` feature; the health server will use plain HTTP");
+    }
+
+    // Like the other admin routes, the self-test needs the bearer token
+    let selftest = config
+        .selftest_recipient
+        .clone()
+        .map(|recipient| crate::health::SelfTest {
+            mailer: mailer.clone(),
+            sender: config.sender_address.clone(),
+            recipient,
+        });
+    if selftest.is_some() && config.metrics_auth_token.is_none() {
+        tracing::warn!("SELFTEST_RECIPIENT provided without METRICS_AUTH_TOKEN; /admin/selftest will not be served");
+    }
+
+    let (_, server) = crate::health::bind_health_server(
+        health_bind_address,
+        metrics,
+        config.allow_metrics_reset,
+        config.metrics_auth_token.clone(),
+        session.paused.clone(),
+        selftest,
+        config.health_tls.clone(),
+    )
+    .with_context(|| format!("Failed to start health server on {health_bind_address}"))?;
+    tokio::spawn(server);
+    Ok(())
+}
+
+#[cfg(not(feature = "health-server"))]
+async fn start_health_server(
+    config: &Config,
+    health_bind_address: SocketAddr,
+    _metrics: MetricsCollector,
+    _session: &SessionConfig,
+    _mailer: &Arc<dyn Mailer>,
+) -> Result<()> {
+    if config.health_tls.is_some() {
+        tracing::warn!("HEALTH_TLS_* settings provided but this build lacks the `health-tls` feature; the health server will use plain HTTP");
+    }
+    if config.selftest_recipient.is_some() {
+        tracing::warn!("SELFTEST_RECIPIENT provided but this build lacks the `health-server` feature; /admin/selftest will not be served");
+    }
+
+    let health_listener = TcpListener::bind(health_bind_address)
+        .await
+        .with_context(|| format!("Failed to start health server on {health_bind_address}"))?;
+    info!(health_addr = %health_listener.local_addr()?, "Starting silent health check server");
+    tokio::spawn(async move {
+        loop {
+            match health_listener.accept().await {
+                Ok((mut stream, _)) => {
+                    // This is a health check. Accept, write a minimal OK, and immediately close. No logging.
+                    let _ = stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await;
+                    let _ = stream.shutdown().await;
+                }
+                Err(e) => {
+                    // Usually fd exhaustion; pause so a persistent failure doesn't spin
+                    tracing::warn!(error = %e, "Health check server failed to accept a connection");
+                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn test_server_relays_message_from_programmatic_config() {
+        // Config validation rejects port 0, so reserve a free port up front
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let maildir = std::env::temp_dir().join(format!("acs-server-{}", nanoid::nanoid!(8)));
+        let mut config = Config::new(
+            SocketAddr::from(([127, 0, 0, 1], port)),
+            "endpoint=https://example.communication.azure.com/;accesskey=dGVzdA==",
+            "sender@example.com".to_string(),
+            None,
+        )
+        .unwrap();
+        config.mailer_backend = MailerBackend::Maildir(maildir.clone());
+        config.health_bind_address = None;
+
+        let server = Server::from_config(config).await.unwrap();
+        let addr = server.local_addrs().unwrap()[0];
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(server.run_until(async {
+            let _ = shutdown_rx.await;
+        }));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0u8; 1024];
+        let _ = stream.read(&mut buf).await.unwrap();
+        for command in [
+            "EHLO client.example.com\r\n",
+            "MAIL FROM:<app@example.com>\r\n",
+            "RCPT TO:<user@example.org>\r\n",
+            "DATA\r\n",
+        ] {
+            stream.write_all(command.as_bytes()).await.unwrap();
+            let _ = stream.read(&mut buf).await.unwrap();
+        }
+        stream
+            .write_all(b"Subject: Embedded\r\n\r\nHello\r\n.\r\n")
+            .await
+            .unwrap();
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(String::from_utf8_lossy(&buf[..n]).starts_with("250"));
+        stream.write_all(b"QUIT\r\n").await.unwrap();
+        let _ = stream.read(&mut buf).await.unwrap();
+
+        assert_eq!(std::fs::read_dir(maildir.join("new")).unwrap().count(), 1);
+
+        shutdown_tx.send(()).unwrap();
+        running.await.unwrap().unwrap();
+        std::fs::remove_dir_all(&maildir).unwrap();
+    }
+
+    // Reserves a free port, since config validation rejects port 0
+    fn free_addr() -> SocketAddr {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    fn maildir_config(maildir: &std::path::Path, health_bind_address: SocketAddr) -> Config {
+        let mut config = Config::new(
+            free_addr(),
+            "endpoint=https://example.communication.azure.com/;accesskey=dGVzdA==",
+            "sender@example.com".to_string(),
+            None,
+        )
+        .unwrap();
+        config.mailer_backend = MailerBackend::Maildir(maildir.to_path_buf());
+        config.health_bind_address = Some(health_bind_address);
+        config
+    }
+
+    #[tokio::test]
+    async fn test_health_server_bind_failure_fails_startup() {
+        let occupied = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let maildir = std::env::temp_dir().join(format!("acs-server-{}", nanoid::nanoid!(8)));
+        let config = maildir_config(&maildir, occupied.local_addr().unwrap());
+
+        let server = Server::from_config(config).await.unwrap();
+        let result = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            server.run_until(std::future::pending()),
+        )
+        .await
+        .expect("startup did not fail");
+        let error = format!("{:#}", result.unwrap_err());
+        assert!(error.contains("Failed to start health server"), "{error}");
+
+        std::fs::remove_dir_all(&maildir).unwrap();
+    }
+
+    #[cfg(feature = "health-server")]
+    #[tokio::test]
+    async fn test_health_endpoint_is_reachable() {
+        let health_addr = free_addr();
+        let maildir = std::env::temp_dir().join(format!("acs-server-{}", nanoid::nanoid!(8)));
+        let server = Server::from_config(maildir_config(&maildir, health_addr))
+            .await
+            .unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(server.run_until(async {
+            let _ = shutdown_rx.await;
+        }));
+
+        let url = format!("http://{health_addr}/health");
+        let mut response = None;
+        for _ in 0..50 {
+            match reqwest::get(&url).await {
+                Ok(r) => {
+                    response = Some(r);
+                    break;
+                }
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(20)).await,
+            }
+        }
+        let response = response.expect("health server did not answer");
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["status"], "healthy");
+
+        shutdown_tx.send(()).unwrap();
+        running.await.unwrap().unwrap();
+        std::fs::remove_dir_all(&maildir).unwrap();
+    }
+}
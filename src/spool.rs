@@ -0,0 +1,53 @@
+use chrono::Utc;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::info;
+
+// Metadata stored next to each dead-lettered message.
+#[derive(Debug, Serialize)]
+struct DeadLetterMetadata<'a> {
+    id: &'a str,
+    failed_at: String,
+    envelope_from: Option<&'a str>,
+    recipients: &'a [String],
+    error: &'a str,
+}
+
+// Writes a message that permanently failed to relay into the dead-letter spool directory.
+// The raw message is stored as `<id>.eml` and the envelope and failure reason as `<id>.json`,
+// so it can be inspected and replayed later. Returns the path of the `.eml` file.
+pub async fn write_dead_letter(
+    dir: &Path,
+    raw_email: &[u8],
+    from: &Option<String>,
+    recipients: &[String],
+    error: &str,
+) -> std::io::Result<PathBuf> {
+    fs::create_dir_all(dir).await?;
+
+    let now = Utc::now();
+    let id = format!(
+        "{}-{}",
+        now.format("%Y%m%dT%H%M%S%.3fZ"),
+        nanoid::nanoid!(8)
+    );
+    let metadata = DeadLetterMetadata {
+        id: &id,
+        failed_at: now.to_rfc3339(),
+        envelope_from: from.as_deref(),
+        recipients,
+        error,
+    };
+
+    let eml_path = dir.join(format!("{id}.eml"));
+    fs::write(&eml_path, raw_email).await?;
+    fs::write(
+        dir.join(format!("{id}.json")),
+        serde_json::to_vec_pretty(&metadata)?,
+    )
+    .await?;
+
+    info!(path = %eml_path.display(), "Wrote message to dead-letter spool");
+    Ok(eml_path)
+}
@@ -0,0 +1,67 @@
+use crate::logging::ExportLayer;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::Layer;
+
+// Span export to an OpenTelemetry collector over OTLP/HTTP. Keep the provider for the life of
+// the process and shut it down on exit so buffered spans are flushed.
+pub struct OtelExport {
+    pub provider: SdkTracerProvider,
+    pub layer: ExportLayer,
+}
+
+// Sets up export to the collector at `endpoint`, the OTLP base URL (e.g.
+// `http://otel-collector:4318`); spans are sent to its `/v1/traces` path in batches.
+pub fn otlp_export(
+    endpoint: &str,
+    service_name: &str,
+) -> Result<OtelExport, opentelemetry_otlp::ExporterBuildError> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(service_name.to_string())
+                .build(),
+        )
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    // Only this crate's spans: the exporter's own HTTP requests must not be traced in turn
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(Targets::new().with_target("acs_smtp_relay", LevelFilter::TRACE))
+        .boxed();
+    Ok(OtelExport { provider, layer })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::{subscriber, LogFormat};
+    use tracing_subscriber::EnvFilter;
+
+    #[tokio::test]
+    async fn test_otlp_export_initializes() {
+        let export = otlp_export("http://127.0.0.1:4318/", "acs-smtp-relay-test").unwrap();
+        let subscriber = subscriber(
+            LogFormat::Json,
+            EnvFilter::new("info"),
+            std::io::sink,
+            false,
+            Some(export.layer),
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("handle_connection", peer_addr = "192.0.2.1:40000");
+            let _entered = span.enter();
+            tracing::info!("New client connection");
+        });
+        // Nothing listens on the endpoint; shutdown reports the failed export without panicking
+        let _ = export.provider.shutdown();
+    }
+}
@@ -0,0 +1,128 @@
+use std::net::IpAddr;
+
+// Keywords offered after XFORWARD in the EHLO response
+pub const EHLO_KEYWORDS: &str = "NAME ADDR PROTO HELO";
+
+// Client details a trusted upstream MTA forwarded with XFORWARD (a Postfix extension), used in
+// place of the upstream's own for the next transaction. Attributes sent as `[UNAVAILABLE]`
+// or `[TEMPUNAVAIL]` are left unset.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct XForward {
+    // The original client's hostname, as the upstream resolved it
+    pub name: Option<String>,
+    pub addr: Option<IpAddr>,
+    // SMTP or ESMTP
+    pub proto: Option<String>,
+    pub helo: Option<String>,
+}
+
+impl XForward {
+    // Applies the attributes of one XFORWARD command, such as
+    // `ADDR=192.0.2.1 HELO=client.example`. Attributes not listed keep their earlier values, as
+    // an upstream may split them across several commands. Returns None, changing nothing, for
+    // an unknown attribute or a malformed value.
+    pub fn apply(&mut self, args: &str) -> Option<()> {
+        let mut updated = self.clone();
+        let mut any = false;
+        for attribute in args.split_whitespace() {
+            let (name, value) = attribute.split_once('=')?;
+            let value = decode_xtext(value)?;
+            let value =
+                (!matches!(value.as_str(), "[UNAVAILABLE]" | "[TEMPUNAVAIL]")).then_some(value);
+            match name.to_ascii_uppercase().as_str() {
+                "NAME" => updated.name = value,
+                "ADDR" => {
+                    updated.addr = match value {
+                        Some(addr) => {
+                            // IPv6 addresses may carry the address literal tag
+                            let addr = match addr.get(..5) {
+                                Some(tag) if tag.eq_ignore_ascii_case("IPv6:") => &addr[5..],
+                                _ => &addr,
+                            };
+                            Some(addr.parse().ok()?)
+                        }
+                        None => None,
+                    }
+                }
+                "PROTO" => updated.proto = value,
+                "HELO" => updated.helo = value,
+                // Accepted but not used
+                "IDENT" | "SOURCE" | "PORT" => {}
+                _ => return None,
+            }
+            any = true;
+        }
+        if !any {
+            return None;
+        }
+        *self = updated;
+        Some(())
+    }
+}
+
+// Decodes an RFC 3461 xtext value, in which `+XX` stands for the byte with hex value XX.
+fn decode_xtext(value: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'+' {
+            let hex = [bytes.next()?, bytes.next()?];
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    // Forwarded values end up in headers and logs; keep them to printable ASCII
+    decoded
+        .iter()
+        .all(u8::is_ascii_graphic)
+        .then(|| String::from_utf8(decoded).ok())
+        .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xforward_attributes_accumulate() {
+        let mut xforward = XForward::default();
+        xforward
+            .apply("NAME=mail.example.com ADDR=192.0.2.1 PROTO=ESMTP")
+            .unwrap();
+        xforward.apply("helo=client+2Eexample IDENT=123").unwrap();
+        assert_eq!(
+            xforward,
+            XForward {
+                name: Some("mail.example.com".to_string()),
+                addr: Some("192.0.2.1".parse().unwrap()),
+                proto: Some("ESMTP".to_string()),
+                helo: Some("client.example".to_string()),
+            }
+        );
+
+        xforward
+            .apply("NAME=[UNAVAILABLE] ADDR=IPv6:2001:db8::1")
+            .unwrap();
+        assert_eq!(xforward.name, None);
+        assert_eq!(xforward.addr, Some("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_xforward_rejects_malformed_attributes() {
+        let mut xforward = XForward::default();
+        for args in [
+            "",
+            "ADDR",
+            "ADDR=not-an-ip",
+            "COLOR=blue",
+            "HELO=bad+0Aline",
+            "HELO=trailing+2",
+            // Nothing is applied when any attribute is bad
+            "HELO=client.example ADDR=999.0.0.1",
+        ] {
+            assert!(xforward.apply(args).is_none(), "{args}");
+        }
+        assert_eq!(xforward, XForward::default());
+    }
+}
@@ -1,7 +1,10 @@
-use acs_smtp_relay::error::{AcsError, SmtpRelayError};
+use acs_smtp_relay::error::{AcsError, ConfigError, EmailError, SmtpRelayError};
 use acs_smtp_relay::relay::{AcsMailer, Mailer};
+use acs_smtp_relay::sender_domain::SenderDomainVerifier;
+use acs_smtp_relay::{HttpClientSettings, SenderDomainCheck};
 use base64::Engine;
-use wiremock::matchers::{body_json, method, path, query_param};
+use std::collections::HashMap;
+use wiremock::matchers::{body_json, body_string_contains, header, method, path, query_param};
 use wiremock::{Mock, MockServer, ResponseTemplate};
 
 #[tokio::test]
@@ -20,7 +23,9 @@ async fn test_acs_mailer_sends_correct_request() {
       },
       "recipients": {
         "to": [ { "address": "<to@example.com>" } ]
-      }
+      },
+      // The header From differs from the ACS sender, so replies go to it
+      "replyTo": [ { "address": "sender@example.com" } ]
     });
 
     Mock::given(method("POST"))
@@ -164,10 +169,655 @@ async fn test_acs_mailer_handles_429_too_many_requests() {
 
     // Assert
     assert!(result.is_err(), "Expected send to fail");
-    let error = result.unwrap_err();
+    assert!(matches!(
+        result.unwrap_err(),
+        SmtpRelayError::Acs(AcsError::RateLimited)
+    ));
+}
+
+#[tokio::test]
+async fn test_acs_mailer_disables_user_engagement_tracking() {
+    // Arrange
+    let server = MockServer::start().await;
+
+    let expected_body = serde_json::json!({
+      "senderAddress": "default@sender.com",
+      "content": {
+        "subject": "Tracking Test",
+        "plainText": "No pixels here.",
+        "html": "<html><body>No pixels here.</body></html>"
+      },
+      "recipients": {
+        "to": [ { "address": "to@example.com" } ]
+      },
+      "userEngagementTrackingDisabled": true
+    });
+
+    Mock::given(method("POST"))
+        .and(path("/emails:send"))
+        .and(body_json(expected_body))
+        .respond_with(ResponseTemplate::new(202))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let http_client = reqwest::Client::new();
+    let access_key = base64::engine::general_purpose::STANDARD.encode("dummy_key");
+    let mailer = AcsMailer::builder(server.uri(), access_key, "default@sender.com")
+        .client(http_client)
+        .disable_user_engagement_tracking(true)
+        .build();
+
+    // Act
+    let raw_email =
+        "Subject: Tracking Test\r\nContent-Type: text/plain\r\n\r\nNo pixels here.".as_bytes();
+    let recipients = vec!["to@example.com".to_string()];
+    let result = mailer.send(raw_email, &recipients, &None).await;
+
+    // Assert
+    assert!(result.is_ok(), "AcsMailer::send error: {result:?}");
+    server.verify().await;
+}
+
+#[tokio::test]
+async fn test_acs_mailer_omits_tracking_flag_by_default() {
+    // Arrange
+    let server = MockServer::start().await;
+
+    Mock::given(method("POST"))
+        .and(path("/emails:send"))
+        .and(|request: &wiremock::Request| {
+            let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+            body.get("userEngagementTrackingDisabled").is_none()
+        })
+        .respond_with(ResponseTemplate::new(202))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let http_client = reqwest::Client::new();
+    let access_key = base64::engine::general_purpose::STANDARD.encode("dummy_key");
+    let mailer = AcsMailer::new(
+        http_client,
+        server.uri(),
+        access_key,
+        "default@sender.com".to_string(),
+        None,
+    );
+
+    // Act
+    let raw_email = "Subject: Tracking Test\r\n\r\nTracked as usual.".as_bytes();
+    let recipients = vec!["to@example.com".to_string()];
+    let result = mailer.send(raw_email, &recipients, &None).await;
+
+    // Assert
+    assert!(result.is_ok(), "AcsMailer::send error: {result:?}");
+    server.verify().await;
+}
+
+#[tokio::test]
+async fn test_acs_mailer_never_logs_access_key() {
+    use std::sync::{mpsc, Arc, Mutex};
+    use tracing_subscriber::{fmt, EnvFilter};
+
+    // Capture every log line, at every level, emitted during the send
+    let (tx, rx) = mpsc::channel();
+    let tx = Arc::new(Mutex::new(tx));
+    struct ChannelWriter {
+        tx: Arc<Mutex<mpsc::Sender<String>>>,
+    }
+    impl std::io::Write for ChannelWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let _ = self
+                .tx
+                .lock()
+                .unwrap()
+                .send(String::from_utf8_lossy(buf).to_string());
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let subscriber = fmt()
+        .with_env_filter(EnvFilter::new("trace"))
+        .with_writer(move || ChannelWriter { tx: tx.clone() })
+        .with_ansi(false)
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/emails:send"))
+        .respond_with(ResponseTemplate::new(202))
+        .mount(&server)
+        .await;
+
+    let raw_key = "very-secret-access-key";
+    let access_key = base64::engine::general_purpose::STANDARD.encode(raw_key);
+    let mailer = AcsMailer::new(
+        reqwest::Client::new(),
+        server.uri(),
+        access_key.clone(),
+        "default@sender.com".to_string(),
+        None,
+    );
+    tracing::info!(?mailer, "Mailer configured");
+
+    let raw_email = "Subject: Secret\r\n\r\nBody".as_bytes();
+    mailer
+        .send(raw_email, &["to@example.com".to_string()], &None)
+        .await
+        .unwrap();
+
+    let logs: String = rx.try_iter().collect();
+    assert!(logs.contains("Mailer configured"), "logs were not captured");
+    assert!(!logs.contains(&access_key), "access key leaked: {logs}");
+    assert!(!logs.contains(raw_key), "decoded access key leaked: {logs}");
+    assert!(
+        !logs.contains("Signature="),
+        "authorization header leaked: {logs}"
+    );
+}
+
+fn proxied_mailer(endpoint: String, proxy: String, no_proxy: Option<String>) -> AcsMailer {
+    let settings = HttpClientSettings {
+        pool_max_idle_per_host: 10,
+        pool_idle_timeout: std::time::Duration::from_secs(90),
+        request_timeout: std::time::Duration::from_secs(5),
+        proxy: Some(proxy),
+        no_proxy,
+    };
+    AcsMailer::new(
+        settings.build_client().unwrap(),
+        endpoint,
+        base64::engine::general_purpose::STANDARD.encode("test-key"),
+        "default@sender.com".to_string(),
+        None,
+    )
+}
+
+#[tokio::test]
+async fn test_acs_mailer_routes_through_configured_proxy() {
+    // The proxy answers for ACS; the endpoint itself does not resolve
+    let proxy = MockServer::start().await;
+    let credentials = base64::engine::general_purpose::STANDARD.encode("proxyuser:proxypass");
+    Mock::given(method("POST"))
+        .and(path("/emails:send"))
+        .and(header(
+            "proxy-authorization",
+            format!("Basic {credentials}").as_str(),
+        ))
+        .respond_with(ResponseTemplate::new(202))
+        .expect(1)
+        .mount(&proxy)
+        .await;
+
+    let proxy_url = proxy
+        .uri()
+        .replace("http://", "http://proxyuser:proxypass@");
+    let mailer = proxied_mailer("http://acs.invalid".to_string(), proxy_url, None);
+
+    let raw_email = "Subject: Proxied\r\n\r\nBody".as_bytes();
+    mailer
+        .send(raw_email, &["to@example.com".to_string()], &None)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_acs_mailer_bypasses_proxy_for_no_proxy_hosts() {
+    let proxy = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(0)
+        .mount(&proxy)
+        .await;
+    let acs = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/emails:send"))
+        .respond_with(ResponseTemplate::new(202))
+        .expect(1)
+        .mount(&acs)
+        .await;
+
+    let mailer = proxied_mailer(acs.uri(), proxy.uri(), Some("127.0.0.1".to_string()));
+
+    let raw_email = "Subject: Direct\r\n\r\nBody".as_bytes();
+    mailer
+        .send(raw_email, &["to@example.com".to_string()], &None)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_acs_mailer_circuit_breaker_fails_fast_then_recovers() {
+    use acs_smtp_relay::breaker::CircuitState;
+
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/emails:send"))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(2)
+        .expect(2)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/emails:send"))
+        .respond_with(ResponseTemplate::new(202))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let access_key = base64::engine::general_purpose::STANDARD.encode("dummy_key");
+    let mailer = AcsMailer::builder(server.uri(), access_key, "default@sender.com")
+        .circuit_breaker(2, std::time::Duration::from_millis(200))
+        .build();
+    let breaker = mailer.circuit_breaker().unwrap();
+    let raw_email = "Subject: Outage\r\n\r\nBody".as_bytes();
+    let recipients = vec!["to@example.com".to_string()];
+
+    for _ in 0..2 {
+        let result = mailer.send(raw_email, &recipients, &None).await;
+        assert!(matches!(
+            result,
+            Err(SmtpRelayError::Acs(AcsError::ServiceUnavailable))
+        ));
+    }
+    assert_eq!(breaker.state(), CircuitState::Open);
+
+    // Open: fails without contacting ACS
+    let result = mailer.send(raw_email, &recipients, &None).await;
+    assert!(matches!(
+        result,
+        Err(SmtpRelayError::Acs(AcsError::CircuitOpen))
+    ));
+
+    // After the cooldown a probe goes through and closes the circuit
+    tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+    mailer.send(raw_email, &recipients, &None).await.unwrap();
+    assert_eq!(breaker.state(), CircuitState::Closed);
+    server.verify().await;
+}
+
+#[tokio::test]
+async fn test_acs_mailer_signs_custom_send_path() {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/relay/v2/emails:send"))
+        .and(query_param("api-version", "2023-03-31"))
+        .respond_with(ResponseTemplate::new(202))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let access_key = base64::engine::general_purpose::STANDARD.encode("dummy_key");
+    let mailer = AcsMailer::builder(server.uri(), access_key, "default@sender.com")
+        .send_path("/relay/v2/emails:send")
+        .build();
+    mailer
+        .send(
+            "Subject: Routed\r\n\r\nBody".as_bytes(),
+            &["to@example.com".to_string()],
+            &None,
+        )
+        .await
+        .unwrap();
+
+    // Recompute the signature over the path that was actually requested
+    let request = &server.received_requests().await.unwrap()[0];
+    let header = |name: &str| request.headers.get(name).unwrap().to_str().unwrap();
+    let path_and_query = format!(
+        "{}?{}",
+        request.url.path(),
+        request.url.query().unwrap_or_default()
+    );
+    assert_eq!(
+        path_and_query,
+        "/relay/v2/emails:send?api-version=2023-03-31"
+    );
+    let string_to_sign = format!(
+        "POST\n{path_and_query}\n{};{};{}",
+        header("x-ms-date"),
+        // The Host header without its port, as signed
+        header("host").rsplit_once(':').unwrap().0,
+        header("x-ms-content-sha256")
+    );
+    let mut mac = Hmac::<Sha256>::new_from_slice(b"dummy_key").unwrap();
+    mac.update(string_to_sign.as_bytes());
+    let signature = base64::engine::general_purpose::STANDARD.encode(mac.finalize().into_bytes());
+    assert!(
+        header("authorization").ends_with(&format!("&Signature={signature}")),
+        "{}",
+        header("authorization")
+    );
+    server.verify().await;
+}
+
+#[tokio::test]
+async fn test_acs_mailer_retry_repeats_the_request_id() {
+    use acs_smtp_relay::relay::Repeatability;
+
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/emails:send"))
+        .respond_with(ResponseTemplate::new(429))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/emails:send"))
+        .respond_with(ResponseTemplate::new(202))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let access_key = base64::engine::general_purpose::STANDARD.encode("dummy_key");
+    let mailer = AcsMailer::builder(server.uri(), access_key, "default@sender.com").build();
+    let raw_email = "Subject: Retried\r\n\r\nBody".as_bytes();
+    let recipients = vec!["to@example.com".to_string()];
+    let repeatability = Repeatability::new();
+
+    let result = mailer
+        .send_repeatable(raw_email, &recipients, &None, &repeatability)
+        .await;
+    assert!(matches!(
+        result,
+        Err(SmtpRelayError::Acs(AcsError::RateLimited))
+    ));
+    mailer
+        .send_repeatable(raw_email, &recipients, &None, &repeatability)
+        .await
+        .unwrap();
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 2);
+    let request_id = repeatability.request_id.to_string();
+    let first_sent = repeatability
+        .first_sent
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string();
+    for request in &requests {
+        let header = |name: &str| request.headers.get(name).unwrap().to_str().unwrap();
+        assert_eq!(header("repeatability-request-id"), request_id);
+        assert_eq!(header("repeatability-first-sent"), first_sent);
+    }
+    server.verify().await;
+}
+
+#[tokio::test]
+async fn test_acs_mailer_counts_bytes_sent_to_acs() {
+    use acs_smtp_relay::metrics::MetricsCollector;
+
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/emails:send"))
+        .respond_with(ResponseTemplate::new(202))
+        .mount(&server)
+        .await;
+
+    let metrics = MetricsCollector::new();
+    let access_key = base64::engine::general_purpose::STANDARD.encode("dummy_key");
+    let mailer = AcsMailer::builder(server.uri(), access_key, "default@sender.com")
+        .metrics(metrics.clone())
+        .build();
+    mailer
+        .send(
+            "Subject: Counted\r\n\r\nBody".as_bytes(),
+            &["to@example.com".to_string()],
+            &None,
+        )
+        .await
+        .unwrap();
+
+    let request = &server.received_requests().await.unwrap()[0];
+    let snapshot = metrics.get_snapshot().await;
+    assert_eq!(snapshot.bytes_sent_to_acs_total, request.body.len() as u64);
+    // Only the SMTP side counts received bytes
+    assert_eq!(snapshot.bytes_received_total, 0);
+}
+
+#[tokio::test]
+async fn test_acs_mailer_records_acs_status_codes() {
+    use acs_smtp_relay::metrics::MetricsCollector;
+
+    let server = MockServer::start().await;
+    // Two accepted, then throttled twice, then one bad request
+    for (status, times) in [(202, 2), (429, 2), (400, 1)] {
+        Mock::given(method("POST"))
+            .and(path("/emails:send"))
+            .respond_with(ResponseTemplate::new(status))
+            .up_to_n_times(times)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+    }
+
+    let metrics = MetricsCollector::new();
+    let access_key = base64::engine::general_purpose::STANDARD.encode("dummy_key");
+    let mailer = AcsMailer::builder(server.uri(), access_key, "default@sender.com")
+        .metrics(metrics.clone())
+        .build();
+    for _ in 0..5 {
+        let _ = mailer
+            .send(
+                "Subject: Counted\r\n\r\nBody".as_bytes(),
+                &["to@example.com".to_string()],
+                &None,
+            )
+            .await;
+    }
+
+    let snapshot = metrics.get_snapshot().await;
+    assert_eq!(
+        snapshot.acs_status_codes,
+        HashMap::from([(202, 2), (429, 2), (400, 1)])
+    );
+    let serialized = serde_json::to_value(snapshot.to_serializable()).unwrap();
+    assert_eq!(
+        serialized["acs_status_codes"],
+        serde_json::json!({ "202": 2, "429": 2, "400": 1 })
+    );
+}
+
+#[tokio::test]
+async fn test_acs_mailer_keeps_header_from_as_reply_to() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/emails:send"))
+        .respond_with(ResponseTemplate::new(202))
+        .mount(&server)
+        .await;
+
+    let access_key = base64::engine::general_purpose::STANDARD.encode("dummy_key");
+    let mailer = AcsMailer::builder(server.uri(), access_key, "DoNotReply@sender.com").build();
+    let raw_email = concat!(
+        "From: Alice Author <alice@author.example>\r\n",
+        "To: to@example.com\r\n",
+        "Subject: Envelope and header differ\r\n",
+        "\r\n",
+        "Body"
+    );
+    mailer
+        .send(
+            raw_email.as_bytes(),
+            &["to@example.com".to_string()],
+            &Some("bounces@client.example".to_string()),
+        )
+        .await
+        .unwrap();
+
+    let request = &server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+    // ACS sends from the verified sender, and replies reach the header From
+    assert_eq!(body["senderAddress"], "DoNotReply@sender.com");
+    assert_eq!(
+        body["replyTo"],
+        serde_json::json!([{ "address": "alice@author.example", "displayName": "Alice Author" }])
+    );
+}
+
+#[tokio::test]
+async fn test_acs_mailer_sends_decoded_base64_and_quoted_printable_bodies() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/emails:send"))
+        .respond_with(ResponseTemplate::new(202))
+        .mount(&server)
+        .await;
+
+    let access_key = base64::engine::general_purpose::STANDARD.encode("dummy_key");
+    let mailer = AcsMailer::builder(server.uri(), access_key, "DoNotReply@sender.com").build();
+    let raw_email = concat!(
+        "From: sender@example.com\r\n",
+        "To: to@example.com\r\n",
+        "Subject: Encoded bodies\r\n",
+        "Content-Type: multipart/alternative; boundary=b\r\n",
+        "\r\n",
+        "--b\r\n",
+        "Content-Type: text/plain; charset=utf-8\r\n",
+        "Content-Transfer-Encoding: base64\r\n",
+        "\r\n",
+        "R3LDvMOfZSBhdXMgTcO8bmNoZW4NCg==\r\n",
+        "--b\r\n",
+        "Content-Type: text/html; charset=iso-8859-1\r\n",
+        "Content-Transfer-Encoding: quoted-printable\r\n",
+        "\r\n",
+        "<p>Caf=E9 au lait, tr=E8s =\r\n",
+        "bien</p>\r\n",
+        "--b--\r\n"
+    );
+    mailer
+        .send(raw_email.as_bytes(), &["to@example.com".to_string()], &None)
+        .await
+        .unwrap();
+
+    let request = &server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+    assert_eq!(body["content"]["plainText"], "Grüße aus München");
+    assert_eq!(body["content"]["html"], "<p>Café au lait, très bien</p>");
+}
+
+#[tokio::test]
+async fn test_acs_mailer_rejects_body_that_is_not_utf8() {
+    let server = MockServer::start().await;
+    let access_key = base64::engine::general_purpose::STANDARD.encode("dummy_key");
+    let mailer = AcsMailer::builder(server.uri(), access_key, "DoNotReply@sender.com").build();
+    // Latin-1 "café" labelled as UTF-8 would be relayed as "caf\u{FFFD}"
+    let raw_email = concat!(
+        "From: sender@example.com\r\n",
+        "To: to@example.com\r\n",
+        "Subject: Mislabelled\r\n",
+        "Content-Type: text/plain; charset=utf-8\r\n",
+        "Content-Transfer-Encoding: quoted-printable\r\n",
+        "\r\n",
+        "caf=E9\r\n"
+    );
+    let result = mailer
+        .send(raw_email.as_bytes(), &["to@example.com".to_string()], &None)
+        .await;
+
+    assert!(matches!(
+        result.unwrap_err(),
+        SmtpRelayError::Email(EmailError::InvalidEncoding(_))
+    ));
+    assert!(server.received_requests().await.unwrap().is_empty());
+}
+
+const EMAIL_SERVICE_ID: &str =
+    "/subscriptions/sub/resourceGroups/rg/providers/Microsoft.Communication/emailServices/mail";
+
+fn sender_domain_check() -> SenderDomainCheck {
+    SenderDomainCheck {
+        tenant_id: "tenant".to_string(),
+        client_id: "client".to_string(),
+        client_secret: "secret".to_string(),
+        email_service_id: EMAIL_SERVICE_ID.to_string(),
+    }
+}
+
+// Mounts an Azure AD token endpoint and an ARM domain list on the same mock server
+async fn mount_domain_list(server: &MockServer, domains: serde_json::Value) {
+    Mock::given(method("POST"))
+        .and(path("/tenant/oauth2/v2.0/token"))
+        .and(body_string_contains("grant_type=client_credentials"))
+        .and(body_string_contains("client_secret=secret"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "token_type": "Bearer",
+            "expires_in": 3599,
+            "access_token": "arm-token"
+        })))
+        .expect(1)
+        .mount(server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(format!("{EMAIL_SERVICE_ID}/domains")))
+        .and(query_param("api-version", "2023-04-01"))
+        .and(header("authorization", "Bearer arm-token"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(domains))
+        .expect(1)
+        .mount(server)
+        .await;
+}
+
+#[tokio::test]
+async fn test_sender_domain_verifier_accepts_verified_domain() {
+    let server = MockServer::start().await;
+    mount_domain_list(
+        &server,
+        serde_json::json!({ "value": [
+            { "name": "other.com", "properties": { "domainManagement": "CustomerManaged" } },
+            {
+                "name": "Sender.com",
+                "properties": {
+                    "domainManagement": "CustomerManaged",
+                    "verificationStates": { "Domain": { "status": "Verified" } }
+                }
+            }
+        ] }),
+    )
+    .await;
+    let verifier =
+        SenderDomainVerifier::with_endpoints(reqwest::Client::new(), server.uri(), server.uri());
+
+    let result = verifier
+        .verify(&sender_domain_check(), "DoNotReply@sender.com")
+        .await;
+
+    assert!(result.is_ok(), "verify error: {result:?}");
+    server.verify().await;
+}
+
+#[tokio::test]
+async fn test_sender_domain_verifier_rejects_unverified_domain() {
+    let server = MockServer::start().await;
+    // Listed, but the TXT record has not been checked yet
+    mount_domain_list(
+        &server,
+        serde_json::json!({ "value": [ {
+            "name": "sender.com",
+            "properties": {
+                "domainManagement": "CustomerManaged",
+                "verificationStates": { "Domain": { "status": "VerificationRequested" } }
+            }
+        } ] }),
+    )
+    .await;
+    let verifier =
+        SenderDomainVerifier::with_endpoints(reqwest::Client::new(), server.uri(), server.uri());
+
+    let error = verifier
+        .verify(&sender_domain_check(), "DoNotReply@sender.com")
+        .await
+        .unwrap_err();
+
     let root_cause = error.root_cause().downcast_ref::<SmtpRelayError>().unwrap();
     assert!(matches!(
         root_cause,
-        SmtpRelayError::Acs(AcsError::RateLimited)
+        SmtpRelayError::Config(ConfigError::UnverifiedSenderDomain(domain)) if domain == "sender.com"
     ));
 }
@@ -0,0 +1,67 @@
+use acs_smtp_relay::relay::CapturingMailer;
+use acs_smtp_relay::{run, SessionConfig};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+// Sends one command and returns the reply line
+async fn command(
+    reader: &mut BufReader<tokio::net::tcp::OwnedReadHalf>,
+    writer: &mut tokio::net::tcp::OwnedWriteHalf,
+    line: &str,
+) -> String {
+    writer.write_all(line.as_bytes()).await.unwrap();
+    let mut reply = String::new();
+    reader.read_line(&mut reply).await.unwrap();
+    reply
+}
+
+#[tokio::test]
+async fn test_capturing_mailer_records_relayed_messages() {
+    let mailer = CapturingMailer::new();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(run(
+        listener,
+        Arc::new(mailer.clone()),
+        SessionConfig::default(),
+    ));
+
+    let (read_half, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut reader = BufReader::new(read_half);
+    let mut banner = String::new();
+    reader.read_line(&mut banner).await.unwrap();
+    command(&mut reader, &mut writer, "HELO client.example\r\n").await;
+
+    for (from, to, body) in [
+        (
+            "alice@example.com",
+            "bob@example.com",
+            "Subject: One\r\n\r\nFirst\r\n",
+        ),
+        (
+            "carol@example.com",
+            "dave@example.com",
+            "Subject: Two\r\n\r\nSecond\r\n",
+        ),
+    ] {
+        command(&mut reader, &mut writer, &format!("MAIL FROM:<{from}>\r\n")).await;
+        command(&mut reader, &mut writer, &format!("RCPT TO:<{to}>\r\n")).await;
+        command(&mut reader, &mut writer, "DATA\r\n").await;
+        let reply = command(&mut reader, &mut writer, &format!("{body}.\r\n")).await;
+        assert!(reply.starts_with("250"), "{reply}");
+    }
+
+    let messages = mailer.messages();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0].from.as_deref(), Some("alice@example.com"));
+    assert_eq!(messages[0].recipients, ["bob@example.com"]);
+    assert!(messages[0]
+        .raw_email
+        .ends_with(b"Subject: One\r\n\r\nFirst\r\n"));
+    assert_eq!(messages[1].from.as_deref(), Some("carol@example.com"));
+    assert_eq!(messages[1].recipients, ["dave@example.com"]);
+    assert!(messages[1]
+        .raw_email
+        .ends_with(b"Subject: Two\r\n\r\nSecond\r\n"));
+}
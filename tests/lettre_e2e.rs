@@ -1,4 +1,4 @@
-use acs_smtp_relay::{config::parse_connection_string, relay::AcsMailer, run};
+use acs_smtp_relay::{config::parse_connection_string, relay::AcsMailer, run, SessionConfig};
 use base64::Engine;
 use lettre::{
     message::{header::ContentType, MultiPart, SinglePart},
@@ -60,7 +60,12 @@ async fn test_lettre_sends_email_through_bridge_to_mock_acs() -> anyhow::Result<
 
     let server_handle = tokio::spawn(async move {
         // Use a proper server name for EHLO response
-        run(listener, mailer, 10_000_000, "localhost".to_string()).await;
+        let session = SessionConfig {
+            max_email_size: 10_000_000,
+            server_name: "localhost".to_string(),
+            ..Default::default()
+        };
+        run(listener, mailer, session).await;
     });
 
     // Give the server a moment to start up.
@@ -1,5 +1,6 @@
-use acs_smtp_relay::handle_connection;
+use acs_smtp_relay::error::{AcsError, SmtpRelayError};
 use acs_smtp_relay::relay::{Mailer, MockMailer};
+use acs_smtp_relay::{handle_connection, SessionConfig};
 use std::sync::Arc;
 use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
@@ -27,14 +28,14 @@ async fn test_smtp_session_flow() {
     let raw_email_body = "Subject: Test\r\n\r\nHello world\r\n";
 
     mock_mailer
-        .expect_send()
-        .withf(move |data, recipients, from| {
+        .expect_send_repeatable()
+        .withf(move |data, recipients, from, _| {
             data == raw_email_body.as_bytes()
                 && recipients == ["to@example.com"]
                 && from.as_deref() == Some("from@example.com")
         })
         .times(1)
-        .returning(|_, _, _| Ok(()));
+        .returning(|_, _, _, _| Ok(()));
 
     let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
     let addr = listener.local_addr().unwrap();
@@ -42,7 +43,16 @@ async fn test_smtp_session_flow() {
 
     tokio::spawn(async move {
         let (stream, _) = listener.accept().await.unwrap();
-        handle_connection(stream, mailer_arc, 10_000_000, addr.ip().to_string()).await;
+        handle_connection(
+            stream,
+            mailer_arc,
+            Arc::new(SessionConfig {
+                max_email_size: 10_000_000,
+                server_name: addr.ip().to_string(),
+                ..Default::default()
+            }),
+        )
+        .await;
     });
 
     let (read_half, mut write_half) = io::split(TcpStream::connect(addr).await.unwrap());
@@ -100,7 +110,7 @@ async fn test_smtp_auth_flow() {
 
     // AUTH is a no-op, so we don't expect a send.
     // This test just verifies the AUTH command is accepted.
-    mock_mailer.expect_send().times(0);
+    mock_mailer.expect_send_repeatable().times(0);
 
     let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
     let addr = listener.local_addr().unwrap();
@@ -108,7 +118,16 @@ async fn test_smtp_auth_flow() {
 
     tokio::spawn(async move {
         let (stream, _) = listener.accept().await.unwrap();
-        handle_connection(stream, mailer_arc, 10_000_000, addr.ip().to_string()).await;
+        handle_connection(
+            stream,
+            mailer_arc,
+            Arc::new(SessionConfig {
+                max_email_size: 10_000_000,
+                server_name: addr.ip().to_string(),
+                ..Default::default()
+            }),
+        )
+        .await;
     });
 
     let (read_half, mut write_half) = io::split(TcpStream::connect(addr).await.unwrap());
@@ -138,3 +157,838 @@ async fn test_smtp_auth_flow() {
     reader.read_line(&mut line_buf).await.unwrap();
     assert!(line_buf.starts_with("221"));
 }
+
+#[tokio::test]
+async fn test_commands_before_greeting_are_rejected() {
+    let mut mock_mailer = MockMailer::new();
+    mock_mailer.expect_send_repeatable().times(0);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mailer_arc: Arc<dyn Mailer> = Arc::new(mock_mailer);
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        handle_connection(stream, mailer_arc, Arc::new(SessionConfig::default())).await;
+    });
+
+    let (read_half, mut write_half) = io::split(TcpStream::connect(addr).await.unwrap());
+    let mut reader = BufReader::new(read_half);
+    let mut line_buf = String::new();
+
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("220"));
+
+    for (command, expected) in [
+        (
+            "MAIL FROM:<from@example.com>\r\n",
+            "503 5.5.1 Send HELO/EHLO first",
+        ),
+        (
+            "RCPT TO:<to@example.com>\r\n",
+            "503 5.5.1 Send HELO/EHLO first",
+        ),
+        ("NOOP\r\n", "250 2.0.0 Ok"),
+        // The chunk is consumed before the rejection, keeping the session in sync
+        (
+            "BDAT 8 LAST\r\nNOOP\r\nHi",
+            "503 5.5.1 Send HELO/EHLO first",
+        ),
+        ("HELO client.example.com\r\n", "250"),
+        ("MAIL FROM:<from@example.com>\r\n", "250 2.1.0 Ok"),
+    ] {
+        write_half.write_all(command.as_bytes()).await.unwrap();
+        line_buf.clear();
+        reader.read_line(&mut line_buf).await.unwrap();
+        assert!(
+            line_buf.starts_with(expected),
+            "{command:?} got: {line_buf}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_out_of_sequence_commands_keep_connection_open() {
+    let mut mock_mailer = MockMailer::new();
+    let raw_email_body = "Subject: Retry\r\n\r\nSecond time lucky\r\n";
+
+    mock_mailer
+        .expect_send_repeatable()
+        .withf(move |data, recipients, from, _| {
+            data == raw_email_body.as_bytes()
+                && recipients == ["to@example.com"]
+                && from.as_deref() == Some("from@example.com")
+        })
+        .times(1)
+        .returning(|_, _, _, _| Ok(()));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mailer_arc: Arc<dyn Mailer> = Arc::new(mock_mailer);
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        handle_connection(
+            stream,
+            mailer_arc,
+            Arc::new(SessionConfig {
+                max_email_size: 10_000_000,
+                server_name: addr.ip().to_string(),
+                ..Default::default()
+            }),
+        )
+        .await;
+    });
+
+    let (read_half, mut write_half) = io::split(TcpStream::connect(addr).await.unwrap());
+    let mut reader = BufReader::new(read_half);
+    let mut line_buf = String::new();
+
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("220"));
+
+    write_half
+        .write_all(b"EHLO client.example.com\r\n")
+        .await
+        .unwrap();
+    read_ehlo_response(&mut reader).await;
+
+    // RCPT TO before MAIL FROM is rejected, but the session continues.
+    write_half
+        .write_all(b"RCPT TO:<to@example.com>\r\n")
+        .await
+        .unwrap();
+    line_buf.clear();
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("503"));
+
+    // So is DATA without any recipients.
+    write_half.write_all(b"DATA\r\n").await.unwrap();
+    line_buf.clear();
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("503"));
+
+    write_half
+        .write_all(b"MAIL FROM:<from@example.com>\r\n")
+        .await
+        .unwrap();
+    line_buf.clear();
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("250"));
+
+    write_half
+        .write_all(b"RCPT TO:<to@example.com>\r\n")
+        .await
+        .unwrap();
+    line_buf.clear();
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("250"));
+
+    write_half.write_all(b"DATA\r\n").await.unwrap();
+    line_buf.clear();
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("354"));
+
+    write_half
+        .write_all(raw_email_body.as_bytes())
+        .await
+        .unwrap();
+    write_half.write_all(b".\r\n").await.unwrap();
+    line_buf.clear();
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("250"));
+
+    write_half.write_all(b"QUIT\r\n").await.unwrap();
+    line_buf.clear();
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("221"));
+}
+
+#[tokio::test]
+async fn test_recipient_limit_returns_452() {
+    let mut mock_mailer = MockMailer::new();
+
+    mock_mailer
+        .expect_send_repeatable()
+        .withf(|_, recipients, _, _| recipients == ["one@example.com", "two@example.com"])
+        .times(1)
+        .returning(|_, _, _, _| Ok(()));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mailer_arc: Arc<dyn Mailer> = Arc::new(mock_mailer);
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        handle_connection(
+            stream,
+            mailer_arc,
+            Arc::new(SessionConfig {
+                max_email_size: 10_000_000,
+                server_name: addr.ip().to_string(),
+                max_recipients: 2,
+                ..Default::default()
+            }),
+        )
+        .await;
+    });
+
+    let (read_half, mut write_half) = io::split(TcpStream::connect(addr).await.unwrap());
+    let mut reader = BufReader::new(read_half);
+    let mut line_buf = String::new();
+
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("220"));
+
+    write_half
+        .write_all(b"EHLO client.example.com\r\n")
+        .await
+        .unwrap();
+    read_ehlo_response(&mut reader).await;
+
+    write_half
+        .write_all(b"MAIL FROM:<from@example.com>\r\n")
+        .await
+        .unwrap();
+    line_buf.clear();
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("250"));
+
+    for rcpt in ["one@example.com", "two@example.com"] {
+        write_half
+            .write_all(format!("RCPT TO:<{rcpt}>\r\n").as_bytes())
+            .await
+            .unwrap();
+        line_buf.clear();
+        reader.read_line(&mut line_buf).await.unwrap();
+        assert!(line_buf.starts_with("250"));
+    }
+
+    // The third recipient exceeds the limit and is not added.
+    write_half
+        .write_all(b"RCPT TO:<three@example.com>\r\n")
+        .await
+        .unwrap();
+    line_buf.clear();
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("452"), "Expected 452, got: {line_buf}");
+
+    // The transaction can still complete with the accepted recipients.
+    write_half.write_all(b"DATA\r\n").await.unwrap();
+    line_buf.clear();
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("354"));
+
+    write_half
+        .write_all(b"Subject: Limit\r\n\r\nHi\r\n.\r\n")
+        .await
+        .unwrap();
+    line_buf.clear();
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("250"));
+}
+
+#[tokio::test]
+async fn test_command_limit_closes_connection() {
+    let mut mock_mailer = MockMailer::new();
+    mock_mailer.expect_send_repeatable().times(0);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mailer_arc: Arc<dyn Mailer> = Arc::new(mock_mailer);
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        handle_connection(
+            stream,
+            mailer_arc,
+            Arc::new(SessionConfig {
+                max_email_size: 10_000_000,
+                server_name: addr.ip().to_string(),
+                max_commands: 5,
+                ..Default::default()
+            }),
+        )
+        .await;
+    });
+
+    let (read_half, mut write_half) = io::split(TcpStream::connect(addr).await.unwrap());
+    let mut reader = BufReader::new(read_half);
+    let mut line_buf = String::new();
+
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("220"));
+
+    write_half
+        .write_all(b"EHLO client.example.com\r\n")
+        .await
+        .unwrap();
+    read_ehlo_response(&mut reader).await;
+
+    // EHLO and four unrecognized commands reach the limit of five
+    for _ in 0..4 {
+        write_half.write_all(b"BOGUS\r\n").await.unwrap();
+        line_buf.clear();
+        reader.read_line(&mut line_buf).await.unwrap();
+        assert!(line_buf.starts_with("500"));
+    }
+
+    write_half.write_all(b"BOGUS\r\n").await.unwrap();
+    line_buf.clear();
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("421"), "Expected 421, got: {line_buf}");
+
+    // The server closes the connection after the 421.
+    line_buf.clear();
+    let n = reader.read_line(&mut line_buf).await.unwrap();
+    assert_eq!(n, 0);
+}
+
+#[tokio::test]
+async fn test_permanent_failure_writes_dead_letter() {
+    let mut mock_mailer = MockMailer::new();
+    let raw_email_body = "Subject: Doomed\r\n\r\nThis will be rejected\r\n";
+
+    mock_mailer
+        .expect_send_repeatable()
+        .times(1)
+        .returning(|_, _, _, _| {
+            Err(SmtpRelayError::Acs(AcsError::BadRequest(
+                "HTTP 400: bad sender".to_string(),
+            )))
+        });
+
+    let spool_dir = std::env::temp_dir().join(format!("dead-letter-{}", nanoid::nanoid!(8)));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mailer_arc: Arc<dyn Mailer> = Arc::new(mock_mailer);
+    let session = Arc::new(SessionConfig {
+        dead_letter_dir: Some(spool_dir.clone()),
+        ..Default::default()
+    });
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        handle_connection(stream, mailer_arc, session).await;
+    });
+
+    let (read_half, mut write_half) = io::split(TcpStream::connect(addr).await.unwrap());
+    let mut reader = BufReader::new(read_half);
+    let mut line_buf = String::new();
+
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("220"));
+
+    write_half
+        .write_all(b"EHLO client.example.com\r\n")
+        .await
+        .unwrap();
+    read_ehlo_response(&mut reader).await;
+
+    for (command, expected) in [
+        ("MAIL FROM:<from@example.com>\r\n", "250"),
+        ("RCPT TO:<to@example.com>\r\n", "250"),
+        ("DATA\r\n", "354"),
+    ] {
+        write_half.write_all(command.as_bytes()).await.unwrap();
+        line_buf.clear();
+        reader.read_line(&mut line_buf).await.unwrap();
+        assert!(line_buf.starts_with(expected));
+    }
+
+    write_half
+        .write_all(raw_email_body.as_bytes())
+        .await
+        .unwrap();
+    write_half.write_all(b".\r\n").await.unwrap();
+    line_buf.clear();
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("550 5.6.0"));
+
+    let eml_files: Vec<_> = std::fs::read_dir(&spool_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "eml"))
+        .collect();
+    assert_eq!(eml_files.len(), 1);
+    assert_eq!(
+        std::fs::read(&eml_files[0]).unwrap(),
+        raw_email_body.as_bytes()
+    );
+    let metadata = std::fs::read_to_string(eml_files[0].with_extension("json")).unwrap();
+    assert!(metadata.contains("to@example.com"));
+    assert!(metadata.contains("bad sender"));
+
+    std::fs::remove_dir_all(&spool_dir).unwrap();
+}
+
+#[cfg(feature = "queue")]
+#[tokio::test]
+async fn test_transient_failure_is_queued_for_retry() {
+    use acs_smtp_relay::queue::MessageQueue;
+
+    let mut mock_mailer = MockMailer::new();
+    mock_mailer
+        .expect_send_repeatable()
+        .times(1)
+        .returning(|_, _, _, _| Err(SmtpRelayError::Acs(AcsError::ServiceUnavailable)));
+
+    let queue_dir = std::env::temp_dir().join(format!("queue-{}", nanoid::nanoid!(8)));
+    let queue = Arc::new(MessageQueue::open(&queue_dir).await.unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mailer_arc: Arc<dyn Mailer> = Arc::new(mock_mailer);
+    let session = Arc::new(SessionConfig {
+        queue: Some(queue.clone()),
+        ..Default::default()
+    });
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        handle_connection(stream, mailer_arc, session).await;
+    });
+
+    let (read_half, mut write_half) = io::split(TcpStream::connect(addr).await.unwrap());
+    let mut reader = BufReader::new(read_half);
+    let mut line_buf = String::new();
+
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("220"));
+
+    write_half
+        .write_all(b"EHLO client.example.com\r\n")
+        .await
+        .unwrap();
+    read_ehlo_response(&mut reader).await;
+
+    for (command, expected) in [
+        ("MAIL FROM:<from@example.com>\r\n", "250"),
+        ("RCPT TO:<to@example.com>\r\n", "250"),
+        ("DATA\r\n", "354"),
+        ("Subject: Outage\r\n\r\nPlease retry\r\n.\r\n", "250"),
+    ] {
+        write_half.write_all(command.as_bytes()).await.unwrap();
+        line_buf.clear();
+        reader.read_line(&mut line_buf).await.unwrap();
+        assert!(line_buf.starts_with(expected), "got: {line_buf}");
+    }
+    assert_eq!(queue.pending().await.unwrap().len(), 1);
+
+    std::fs::remove_dir_all(&queue_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_smtputf8_recipient_reaches_mailer_intact() {
+    let mut mock_mailer = MockMailer::new();
+    mock_mailer
+        .expect_send_repeatable()
+        .withf(|_, recipients, from, _| {
+            recipients == ["用户@example.com"] && from.as_deref() == Some("José@example.com")
+        })
+        .times(1)
+        .returning(|_, _, _, _| Ok(()));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mailer_arc: Arc<dyn Mailer> = Arc::new(mock_mailer);
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        handle_connection(stream, mailer_arc, Arc::new(SessionConfig::default())).await;
+    });
+
+    let (read_half, mut write_half) = io::split(TcpStream::connect(addr).await.unwrap());
+    let mut reader = BufReader::new(read_half);
+    let mut line_buf = String::new();
+
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("220"));
+
+    write_half
+        .write_all(b"EHLO client.example.com\r\n")
+        .await
+        .unwrap();
+    let mut ehlo = String::new();
+    loop {
+        line_buf.clear();
+        reader.read_line(&mut line_buf).await.unwrap();
+        ehlo.push_str(&line_buf);
+        if line_buf.starts_with("250 ") {
+            break;
+        }
+    }
+    assert!(ehlo.contains("250-SMTPUTF8\r\n"));
+
+    for (command, expected) in [
+        ("MAIL FROM:<José@example.com> SMTPUTF8\r\n", "250"),
+        ("RCPT TO:<用户@example.com>\r\n", "250"),
+        ("DATA\r\n", "354"),
+        ("Subject: Hola\r\n\r\nBody\r\n.\r\n", "250"),
+    ] {
+        write_half.write_all(command.as_bytes()).await.unwrap();
+        line_buf.clear();
+        reader.read_line(&mut line_buf).await.unwrap();
+        assert!(line_buf.starts_with(expected), "got: {line_buf}");
+    }
+}
+
+#[tokio::test]
+async fn test_non_ascii_address_without_smtputf8_is_rejected() {
+    let mut mock_mailer = MockMailer::new();
+    mock_mailer.expect_send_repeatable().times(0);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mailer_arc: Arc<dyn Mailer> = Arc::new(mock_mailer);
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        handle_connection(stream, mailer_arc, Arc::new(SessionConfig::default())).await;
+    });
+
+    let (read_half, mut write_half) = io::split(TcpStream::connect(addr).await.unwrap());
+    let mut reader = BufReader::new(read_half);
+    let mut line_buf = String::new();
+
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("220"));
+
+    write_half
+        .write_all(b"EHLO client.example.com\r\n")
+        .await
+        .unwrap();
+    read_ehlo_response(&mut reader).await;
+
+    for (command, expected) in [
+        ("MAIL FROM:<from@example.com>\r\n", "250"),
+        ("RCPT TO:<用户@example.com>\r\n", "553 5.6.7"),
+        ("MAIL FROM:<José@example.com>\r\n", "553 5.6.7"),
+    ] {
+        write_half.write_all(command.as_bytes()).await.unwrap();
+        line_buf.clear();
+        reader.read_line(&mut line_buf).await.unwrap();
+        assert!(line_buf.starts_with(expected), "got: {line_buf}");
+    }
+}
+
+#[tokio::test]
+async fn test_mixed_case_verbs_preserve_address_case() {
+    let mut mock_mailer = MockMailer::new();
+    mock_mailer
+        .expect_send_repeatable()
+        .withf(|_, recipients, from, _| {
+            recipients == ["Jane.Doe@Example.com"]
+                && from.as_deref() == Some("John.Smith@Example.com")
+        })
+        .times(1)
+        .returning(|_, _, _, _| Ok(()));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mailer_arc: Arc<dyn Mailer> = Arc::new(mock_mailer);
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        handle_connection(stream, mailer_arc, Arc::new(SessionConfig::default())).await;
+    });
+
+    let (read_half, mut write_half) = io::split(TcpStream::connect(addr).await.unwrap());
+    let mut reader = BufReader::new(read_half);
+    let mut line_buf = String::new();
+
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("220"));
+
+    write_half
+        .write_all(b"EHLO client.example.com\r\n")
+        .await
+        .unwrap();
+    read_ehlo_response(&mut reader).await;
+
+    for (command, expected) in [
+        ("MaIl FrOm:<John.Smith@Example.com>\r\n", "250"),
+        ("rCpT tO:<Jane.Doe@Example.com>\r\n", "250"),
+        ("data\r\n", "354"),
+        ("Subject: Case\r\n\r\nBody\r\n.\r\n", "250"),
+        ("qUiT\r\n", "221"),
+    ] {
+        write_half.write_all(command.as_bytes()).await.unwrap();
+        line_buf.clear();
+        reader.read_line(&mut line_buf).await.unwrap();
+        assert!(line_buf.starts_with(expected), "got: {line_buf}");
+    }
+}
+
+#[tokio::test]
+async fn test_envelope_whitespace_and_parameter_variants() {
+    let mut mock_mailer = MockMailer::new();
+    mock_mailer
+        .expect_send_repeatable()
+        .withf(|_, recipients, from, _| {
+            recipients == ["one@example.com", "two@example.com", "three@example.com"]
+                && from.as_deref() == Some("from@example.com")
+        })
+        .times(1)
+        .returning(|_, _, _, _| Ok(()));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mailer_arc: Arc<dyn Mailer> = Arc::new(mock_mailer);
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        handle_connection(stream, mailer_arc, Arc::new(SessionConfig::default())).await;
+    });
+
+    let (read_half, mut write_half) = io::split(TcpStream::connect(addr).await.unwrap());
+    let mut reader = BufReader::new(read_half);
+    let mut line_buf = String::new();
+
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("220"));
+
+    write_half
+        .write_all(b"EHLO client.example.com\r\n")
+        .await
+        .unwrap();
+    read_ehlo_response(&mut reader).await;
+
+    for (command, expected) in [
+        ("MAIL FROM <from@example.com>\r\n", "501"),
+        (
+            "MAIL FROM: <from@example.com> SIZE=100 BODY=8BITMIME\r\n",
+            "250",
+        ),
+        ("RCPT  TO:<one@example.com>\r\n", "250"),
+        ("RCPT TO : <two@example.com>\r\n", "250"),
+        ("RCPT TO:three@example.com\r\n", "250"),
+        ("DATA\r\n", "354"),
+        ("Subject: Spaces\r\n\r\nBody\r\n.\r\n", "250"),
+    ] {
+        write_half.write_all(command.as_bytes()).await.unwrap();
+        line_buf.clear();
+        reader.read_line(&mut line_buf).await.unwrap();
+        assert!(
+            line_buf.starts_with(expected),
+            "{command:?} got: {line_buf}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_size_limit_enforced_at_mail_from_and_during_data() {
+    let mut mock_mailer = MockMailer::new();
+    // "Subject: x\r\n\r\n" (14 bytes) + ".." unstuffed to "." (3 bytes) = 17 bytes exactly
+    mock_mailer
+        .expect_send_repeatable()
+        .withf(|data, _, _, _| data == b"Subject: x\r\n\r\n.\r\n")
+        .times(1)
+        .returning(|_, _, _, _| Ok(()));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mailer_arc: Arc<dyn Mailer> = Arc::new(mock_mailer);
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        handle_connection(
+            stream,
+            mailer_arc,
+            Arc::new(SessionConfig {
+                max_email_size: 17,
+                ..Default::default()
+            }),
+        )
+        .await;
+    });
+
+    let (read_half, mut write_half) = io::split(TcpStream::connect(addr).await.unwrap());
+    let mut reader = BufReader::new(read_half);
+    let mut line_buf = String::new();
+
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("220"));
+
+    write_half
+        .write_all(b"EHLO client.example.com\r\n")
+        .await
+        .unwrap();
+    read_ehlo_response(&mut reader).await;
+
+    for (command, expected) in [
+        // Declared size over the limit is refused up front
+        ("MAIL FROM:<from@example.com> SIZE=18\r\n", "552 5.3.4"),
+        // A message exactly at the limit is accepted
+        ("MAIL FROM:<from@example.com> SIZE=17\r\n", "250"),
+        ("RCPT TO:<to@example.com>\r\n", "250"),
+        ("DATA\r\n", "354"),
+        ("Subject: x\r\n\r\n..\r\n.\r\n", "250"),
+        // Declared size passes, but the actual body exceeds it
+        ("MAIL FROM:<from@example.com> SIZE=10\r\n", "250"),
+        ("RCPT TO:<to@example.com>\r\n", "250"),
+        ("DATA\r\n", "354"),
+        ("Subject: x\r\n\r\nToo long\r\n.\r\n", "552 5.3.4"),
+    ] {
+        write_half.write_all(command.as_bytes()).await.unwrap();
+        line_buf.clear();
+        reader.read_line(&mut line_buf).await.unwrap();
+        assert!(
+            line_buf.starts_with(expected),
+            "{command:?} got: {line_buf}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_oversized_message_leaves_connection_usable() {
+    let mut mock_mailer = MockMailer::new();
+    mock_mailer
+        .expect_send_repeatable()
+        .withf(|data, recipients, _, _| {
+            data == b"Subject: Small\r\n\r\nHi\r\n" && recipients == ["small@example.com"]
+        })
+        .times(1)
+        .returning(|_, _, _, _| Ok(()));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mailer_arc: Arc<dyn Mailer> = Arc::new(mock_mailer);
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        handle_connection(
+            stream,
+            mailer_arc,
+            Arc::new(SessionConfig {
+                max_email_size: 64,
+                ..Default::default()
+            }),
+        )
+        .await;
+    });
+
+    let (read_half, mut write_half) = io::split(TcpStream::connect(addr).await.unwrap());
+    let mut reader = BufReader::new(read_half);
+    let mut line_buf = String::new();
+
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("220"));
+
+    write_half
+        .write_all(b"EHLO client.example.com\r\n")
+        .await
+        .unwrap();
+    read_ehlo_response(&mut reader).await;
+
+    // The oversized body runs on past the limit; none of its lines may be read as commands
+    let big_body = format!("Subject: Big\r\n\r\n{}.\r\n", "RSET\r\nQUIT\r\n".repeat(50));
+    for (command, expected) in [
+        ("MAIL FROM:<big@example.com>\r\n", "250"),
+        ("RCPT TO:<big@example.com>\r\n", "250"),
+        ("DATA\r\n", "354"),
+        (big_body.as_str(), "552 5.3.4"),
+        // The rejected transaction is over
+        ("RCPT TO:<small@example.com>\r\n", "503"),
+        ("MAIL FROM:<small@example.com>\r\n", "250"),
+        ("RCPT TO:<small@example.com>\r\n", "250"),
+        ("DATA\r\n", "354"),
+        ("Subject: Small\r\n\r\nHi\r\n.\r\n", "250"),
+        ("QUIT\r\n", "221"),
+    ] {
+        write_half.write_all(command.as_bytes()).await.unwrap();
+        line_buf.clear();
+        reader.read_line(&mut line_buf).await.unwrap();
+        assert!(
+            line_buf.starts_with(expected),
+            "{command:?} got: {line_buf}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_multiple_messages_on_one_connection() {
+    let delivered = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mut mock_mailer = MockMailer::new();
+    let recorded = delivered.clone();
+    mock_mailer
+        .expect_send_repeatable()
+        .times(2)
+        .returning(move |data, recipients, from, _| {
+            recorded
+                .lock()
+                .unwrap()
+                .push((data.to_vec(), recipients.to_vec(), from.clone()));
+            Ok(())
+        });
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mailer_arc: Arc<dyn Mailer> = Arc::new(mock_mailer);
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        handle_connection(
+            stream,
+            mailer_arc,
+            Arc::new(SessionConfig {
+                max_recipients: 1,
+                server_name: addr.ip().to_string(),
+                ..Default::default()
+            }),
+        )
+        .await;
+    });
+
+    let (read_half, mut write_half) = io::split(TcpStream::connect(addr).await.unwrap());
+    let mut reader = BufReader::new(read_half);
+    let mut line_buf = String::new();
+
+    reader.read_line(&mut line_buf).await.unwrap();
+    assert!(line_buf.starts_with("220"));
+
+    write_half
+        .write_all(b"EHLO client.example.com\r\n")
+        .await
+        .unwrap();
+    read_ehlo_response(&mut reader).await;
+
+    // Protocol errors between and within transactions get a reply, never a closed connection
+    let steps: [(&[u8], &str); 16] = [
+        (b"MAIL FROM:<first@example.com>\r\n", "250"),
+        (b"RCPT TO:<a@example.com>\r\n", "250"),
+        (b"RCPT TO:<b@example.com>\r\n", "452"),
+        (b"DATA\r\n", "354"),
+        // 8-bit body content is passed through, not treated as a read error
+        (b"Subject: One\r\n\r\nCaf\xe9\r\n.\r\n", "250"),
+        (b"DATA\r\n", "503"),
+        (b"FROB\r\n", "500"),
+        (b"\xff\xfe\r\n", "500"),
+        (b"MAIL FROM:<second@example.com>\r\n", "250"),
+        (b"MAIL FROM <malformed>\r\n", "501"),
+        (b"RCPT TO:<c@example.com>\r\n", "250"),
+        (b"DATA\r\n", "354"),
+        (b"Subject: Two\r\n\r\nHello again\r\n.\r\n", "250"),
+        (b"RSET\r\n", "250"),
+        (b"NOOP\r\n", "250"),
+        (b"QUIT\r\n", "221"),
+    ];
+    for (command, expected) in steps {
+        write_half.write_all(command).await.unwrap();
+        line_buf.clear();
+        reader.read_line(&mut line_buf).await.unwrap();
+        assert!(
+            line_buf.starts_with(expected),
+            "{:?}: expected {expected}, got {line_buf}",
+            String::from_utf8_lossy(command)
+        );
+    }
+
+    let delivered = delivered.lock().unwrap();
+    assert_eq!(delivered.len(), 2);
+    assert_eq!(delivered[0].0, b"Subject: One\r\n\r\nCaf\xe9\r\n");
+    assert_eq!(delivered[0].1, ["a@example.com"]);
+    assert_eq!(delivered[0].2.as_deref(), Some("first@example.com"));
+    assert_eq!(delivered[1].0, b"Subject: Two\r\n\r\nHello again\r\n");
+    assert_eq!(delivered[1].1, ["c@example.com"]);
+    assert_eq!(delivered[1].2.as_deref(), Some("second@example.com"));
+}
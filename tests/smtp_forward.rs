@@ -0,0 +1,124 @@
+use acs_smtp_relay::relay::SmtpRelayMailer;
+use acs_smtp_relay::{run, SessionConfig, SmtpUpstreamConfig, UpstreamTls};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+// What the mock upstream server received in one transaction
+#[derive(Debug, Default)]
+struct SinkDelivery {
+    mail_from: String,
+    rcpt_to: Vec<String>,
+    data: String,
+}
+
+// A minimal SMTP sink that reports the first message it accepts over `delivered`
+async fn run_smtp_sink(listener: TcpListener, delivered: oneshot::Sender<SinkDelivery>) {
+    let (stream, _) = listener.accept().await.unwrap();
+    let (read_half, mut write_half) = tokio::io::split(stream);
+    let mut reader = BufReader::new(read_half);
+    let mut delivery = SinkDelivery::default();
+    let mut delivered = Some(delivered);
+    write_half.write_all(b"220 sink ESMTP\r\n").await.unwrap();
+
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await.unwrap() == 0 {
+            break;
+        }
+        let command = line.trim_end().to_string();
+        let upper = command.to_ascii_uppercase();
+        if upper.starts_with("EHLO") || upper.starts_with("HELO") {
+            write_half.write_all(b"250 sink\r\n").await.unwrap();
+        } else if upper.starts_with("MAIL FROM:") {
+            delivery.mail_from = command["MAIL FROM:".len()..].to_string();
+            write_half.write_all(b"250 Ok\r\n").await.unwrap();
+        } else if upper.starts_with("RCPT TO:") {
+            delivery
+                .rcpt_to
+                .push(command["RCPT TO:".len()..].to_string());
+            write_half.write_all(b"250 Ok\r\n").await.unwrap();
+        } else if upper == "DATA" {
+            write_half
+                .write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n")
+                .await
+                .unwrap();
+            loop {
+                line.clear();
+                reader.read_line(&mut line).await.unwrap();
+                if line == ".\r\n" {
+                    break;
+                }
+                delivery.data.push_str(&line);
+            }
+            write_half.write_all(b"250 Ok: queued\r\n").await.unwrap();
+            // The client may keep the connection pooled, so report without waiting for QUIT
+            if let Some(delivered) = delivered.take() {
+                let _ = delivered.send(std::mem::take(&mut delivery));
+            }
+        } else if upper == "QUIT" {
+            write_half.write_all(b"221 Bye\r\n").await.unwrap();
+            break;
+        } else {
+            write_half.write_all(b"250 Ok\r\n").await.unwrap();
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_message_is_forwarded_to_upstream_smtp_server() {
+    // --- Upstream SMTP sink ---
+    let sink_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let sink_port = sink_listener.local_addr().unwrap().port();
+    let (delivered_tx, delivered_rx) = oneshot::channel();
+    tokio::spawn(run_smtp_sink(sink_listener, delivered_tx));
+
+    // --- Bridge forwarding to the sink ---
+    let mailer = SmtpRelayMailer::new(&SmtpUpstreamConfig {
+        host: "127.0.0.1".to_string(),
+        port: sink_port,
+        username: None,
+        password: None,
+        tls: UpstreamTls::None,
+    })
+    .unwrap();
+    let bridge_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let bridge_addr = bridge_listener.local_addr().unwrap();
+    tokio::spawn(run(
+        bridge_listener,
+        Arc::new(mailer),
+        SessionConfig::default(),
+    ));
+
+    // --- Client talking to the bridge ---
+    let mut stream = TcpStream::connect(bridge_addr).await.unwrap();
+    let mut buf = [0u8; 1024];
+    let _ = stream.read(&mut buf).await.unwrap();
+    for command in [
+        "EHLO client.example.com\r\n",
+        "MAIL FROM:<app@example.com>\r\n",
+        "RCPT TO:<user@example.org>\r\n",
+        "DATA\r\n",
+    ] {
+        stream.write_all(command.as_bytes()).await.unwrap();
+        let _ = stream.read(&mut buf).await.unwrap();
+    }
+    stream
+        .write_all(b"Subject: Forwarded\r\n\r\nHello upstream\r\n.\r\n")
+        .await
+        .unwrap();
+    let n = stream.read(&mut buf).await.unwrap();
+    let response = String::from_utf8_lossy(&buf[..n]);
+    assert!(response.starts_with("250"), "unexpected reply: {response}");
+
+    let delivery = tokio::time::timeout(std::time::Duration::from_secs(5), delivered_rx)
+        .await
+        .expect("upstream never received the message")
+        .unwrap();
+    assert_eq!(delivery.mail_from, "<app@example.com>");
+    assert_eq!(delivery.rcpt_to, vec!["<user@example.org>".to_string()]);
+    assert!(delivery.data.contains("Subject: Forwarded"));
+    assert!(delivery.data.contains("Hello upstream"));
+}